@@ -12,7 +12,7 @@ use tui::{
     }
 };
 use crate::{
-    app::application_ui::ApplicationUI,
+    app::application_ui::{ ApplicationUI, ConnectionState },
     components::{
         BaseComponent, Command, DrawableComponent
     },
@@ -25,6 +25,7 @@ use crate::{
     db::{
         models::UserInfo
     },
+    net::Network,
 };
 use crate::common::app_event::Notification;
 
@@ -38,7 +39,9 @@ pub struct Application {
 
 impl Application {
     pub fn new(
-        user_info: Option<UserInfo>, tx_notification: Sender<AppEvent>
+        user_info: Option<UserInfo>,
+        network: Network,
+        tx_notification: Sender<AppEvent>
     ) -> Self {
         let command_keys = Rc::new(CommandKeys::default());
         let user_registration = match user_info {
@@ -57,6 +60,7 @@ impl Application {
             ui: ApplicationUI::new(
                 Rc::clone(&user_info),
                 Rc::clone(&command_keys),
+                Rc::new(network),
             ),
             command: CommandComponent::new(),
             user_registration,
@@ -70,14 +74,12 @@ impl Application {
     }
 
     fn get_common_commands(&self) -> Vec<Command> {
-        let mut commands = Vec::new();
-
-        commands.push(Command {
-            label: "Quit [^c]".to_string(),
-            enable: true
-        });
-
-        commands
+        vec![
+            Command {
+                label: "Quit [^c]".to_string(),
+                enable: true
+            }
+        ]
     }
 
     pub fn disable_user_registration(&mut self) {
@@ -99,8 +101,12 @@ impl BaseComponent for Application {
 
                     Ok(true)
                 }
-                _ => Ok(false)
+                Notification::UserJoined { .. } | Notification::UserLeft { .. } => {
+                    self.ui.event(AppEvent::NotificationEvent(notification))
+                }
             }
+        } else if let AppEvent::NetworkEvent(_) | AppEvent::Tick = event {
+            return self.ui.event(event);
         } else if let AppEvent::InputEvent(evt) = event {
             if let Event::Key(ke) = evt {
                 return if ke == self.command_keys.quit {
@@ -149,7 +155,9 @@ impl DrawableComponent for Application {
             }
         };
 
+        let connection = self.ui.connection_state();
         self.command.update_commands(commands);
+        self.command.set_status(connection.label(), connection == ConnectionState::Connected);
         self.command.draw(f, layout[1]);
     }
 }
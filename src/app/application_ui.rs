@@ -1,60 +1,1003 @@
-use std::rc::Rc;
+use std::{
+    collections::HashMap,
+    rc::Rc,
+    time::{ Duration, Instant },
+};
+use crossterm::event::Event;
 use tui::{
     Frame,
     backend::Backend,
     layout::{
         Rect, Constraint, Direction, Layout
     },
+    text::{ Span, Spans },
     widgets::{
-        Block, Borders, BorderType
+        Block, Borders, BorderType, Clear, Paragraph, Wrap
     }
 };
 use crate::{
     common::{
+        self,
         command_keys::CommandKeys,
-        app_event::AppEvent,
+        chat_command::ChatCommand,
+        app_event::{ AppEvent, NetworkEvent, Notification },
+        typing::{ TypingThrottle, TypingUpdate },
     },
     components::{
         BaseComponent, DrawableComponent,
         userinfo::UserInfoComponent,
         text_input::TextInput,
-        chat_area::ChatArea
+        chat_area::ChatArea,
+        contact_list::ContactList,
+        typing_indicator::TypingIndicator
+    },
+    constants,
+    crypto::{ CryptoError, KeyPair, Keyring, SigningKeyPair },
+    db::{
+        self,
+        models::{ Attachment, ContactKey, DeliveryStatus, ReadPosition, TransferStatus, UserInfo }
+    },
+    net::{
+        self,
+        Network,
+        protocol::{ self, Body, Message, Presence }
     },
-    db::models::UserInfo,
     styles,
+    transfer::{ self, Progress, Transfers },
 };
+use super::chats::Chats;
+
+const HISTORY_LIMIT: usize = 200;
+/// crossterm does not report terminal focus, the window counts as
+/// focused for this long after the last input
+const FOCUS_TIMEOUT: Duration = Duration::from_secs(120);
+/// Without input for this long the user is shown as away
+const AWAY_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// State of the connection to the server
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConnectionState {
+    Connected,
+    /// waiting to retry, the next attempt starts at the given time
+    Reconnecting(Instant),
+    Offline,
+}
+
+impl ConnectionState {
+    pub fn label(&self) -> String {
+        match self {
+            ConnectionState::Connected => "connected".to_string(),
+            ConnectionState::Reconnecting(at) => {
+                let remaining = at.saturating_duration_since(Instant::now());
+                format!("reconnecting in {}s", remaining.as_secs_f32().ceil())
+            },
+            ConnectionState::Offline => "offline".to_string(),
+        }
+    }
+}
+
+/// Why no more attempts are made to connect to the server
+enum Refusal {
+    /// the server could not be verified
+    Tls(String),
+    /// the server turned down our user id
+    Auth(String),
+}
 
 pub struct ApplicationUI {
     user_info: Rc<UserInfo>,
+    command_keys: Rc<CommandKeys>,
+    network: Rc<Network>,
+    connection: ConnectionState,
     message_input: TextInput,
-    chat_area: ChatArea
+    chat_area: ChatArea,
+    contact_list: ContactList,
+    typing_indicator: TypingIndicator,
+    typing: TypingThrottle,
+    /// user id of the one we chat with, directly if connected to them and
+    /// through the server otherwise. `None` chats with everyone on the server.
+    conversation: Option<String>,
+    /// peers with an open direct connection, user id to user name
+    direct_peers: HashMap<String, String>,
+    /// `/connect` target we are waiting on, the conversation switches
+    /// to it once it said hello
+    connecting: Option<String>,
+    /// room we asked to join, the conversation switches to it once the relay confirms
+    joining: Option<String>,
+    chats: Chats,
+    /// last message we have seen in each conversation
+    read_positions: HashMap<String, String>,
+    read_receipts: bool,
+    last_input: Instant,
+    /// what we last told the relay, online or away
+    presence: Presence,
+    /// `None` until the user has registered
+    keyring: Option<Keyring>,
+    /// files we send and receive, `None` until the user has registered
+    transfers: Option<Transfers>,
+    /// why the server will not be connected to, shown instead of the chat
+    refusal: Option<Refusal>
 }
 
 impl ApplicationUI {
     pub fn new(
         user_info: Rc<UserInfo>,
-        _command_keys: Rc<CommandKeys>
+        command_keys: Rc<CommandKeys>,
+        network: Rc<Network>
     ) -> Self {
         let mut message_input = TextInput::with_placeholder(
             "type message...".to_string(),
         );
         message_input.set_focus(true);
 
-        ApplicationUI {
-            user_info,
+        let history = db::operations::get_messages(HISTORY_LIMIT)
+            .unwrap_or_default();
+
+        let mut application_ui = ApplicationUI {
+            user_info: Rc::clone(&user_info),
+            command_keys,
+            network,
+            connection: ConnectionState::Offline,
             message_input,
-            chat_area: ChatArea::new()
+            chat_area: ChatArea::with_messages(history),
+            contact_list: ContactList::new(),
+            typing_indicator: TypingIndicator::new(),
+            typing: TypingThrottle::new(),
+            conversation: None,
+            direct_peers: HashMap::new(),
+            connecting: None,
+            joining: None,
+            chats: Chats::new(&user_info.user_id),
+            read_positions: HashMap::new(),
+            read_receipts: db::operations::get_setting(constants::SETTING_READ_RECEIPTS)
+                .unwrap_or_default()
+                .is_none_or(|value| value != "off"),
+            last_input: Instant::now(),
+            presence: Presence::Online,
+            keyring: None,
+            transfers: None,
+            refusal: None
+        };
+        application_ui.set_user_info(user_info);
+
+        for position in db::operations::get_read_positions().unwrap_or_default() {
+            if position.user_id == application_ui.user_info.user_id {
+                application_ui.read_positions.insert(position.conversation, position.message_id);
+            } else {
+                application_ui.chat_area.mark_seen(&position.user_id, &position.message_id);
+            }
         }
+
+        application_ui
     }
 
     pub fn set_user_info(&mut self, user_info: Rc<UserInfo>) {
         self.user_info = Rc::clone(&user_info);
+        self.chats.set_user_id(&user_info.user_id);
+
+        // nothing to introduce until the user has registered
+        if user_info.user_id.is_empty() {
+            return;
+        }
+
+        let key_pair = match KeyPair::from_hex(&user_info.secret_key) {
+            Some(key_pair) => key_pair,
+            None => {
+                // registered before chats were encrypted
+                let key_pair = KeyPair::generate();
+                let saved = db::operations::save_key_pair(
+                    &user_info.user_id,
+                    &key_pair.public_hex(),
+                    &key_pair.secret_hex()
+                );
+                if let Err(e) = saved {
+                    self.chat_area.push_notice(format!("not able to save encryption key: {}", e), true);
+                }
+                key_pair
+            }
+        };
+
+        let signing_key = match SigningKeyPair::from_hex(&user_info.signing_key) {
+            Some(signing_key) => signing_key,
+            None => {
+                // registered before relays checked user ids
+                let signing_key = SigningKeyPair::generate();
+                if let Err(e) = db::operations::save_signing_key(&user_info.user_id, &signing_key.secret_hex()) {
+                    self.chat_area.push_notice(format!("not able to save signing key: {}", e), true);
+                }
+                signing_key
+            }
+        };
+
+        self.network.set_identity(&user_info.user_id, &user_info.user_name, key_pair.public_bytes(), signing_key);
+        let mut keyring = Keyring::new(&user_info.user_id, key_pair);
+        for contact_key in db::operations::get_contact_keys().unwrap_or_default() {
+            if let Ok(public_key) = hex::decode(&contact_key.public_key) {
+                keyring.add_public_key(&contact_key.user_id, &public_key);
+            }
+        }
+        self.keyring = Some(keyring);
+
+        match transfer::downloads_dir() {
+            Ok(downloads) => self.transfers = Some(Transfers::new(&user_info.user_id, downloads)),
+            Err(e) => self.chat_area.push_notice(format!("file transfers unavailable: {}", e), true),
+        }
+    }
+
+    fn send_message(&mut self) -> bool {
+        let text = self.message_input.get_text().trim().to_string();
+        if text.is_empty() {
+            return false;
+        }
+
+        let stopped = self.typing.stopped();
+        self.send_typing(stopped);
+
+        if let Some(command) = ChatCommand::parse(&text) {
+            match command {
+                Ok(command) => self.run_command(command),
+                Err(usage) => self.chat_area.push_notice(usage, true),
+            }
+
+            self.message_input.clear();
+            return true;
+        }
+
+        let message = self.chats.write(self.conversation.clone(), text);
+
+        // nothing leaves unencrypted for a single user, the input is kept to retry
+        let sealed = match seal(self.keyring.as_mut(), message.clone()) {
+            Ok(sealed) => sealed,
+            Err(e) => {
+                self.chat_area.push_notice(format!("{}, message not sent", e), true);
+                return true;
+            }
+        };
+        self.add_chat(&message, Some(DeliveryStatus::Pending));
+
+        match &self.conversation {
+            Some(user_id) if self.direct_peers.contains_key(user_id) => {
+                self.network.send_direct(user_id, sealed);
+            },
+            _ => self.queue_message(&message, &sealed),
+        }
+
+        self.message_input.clear();
+        true
+    }
+
+    /// Decrypts the body of a chat sent to us, only rooms and chats to
+    /// everyone come unencrypted
+    fn open(&mut self, message: Message) -> Result<Message, CryptoError> {
+        match (message, self.keyring.as_mut()) {
+            (Message::Chat { id, from, to, body, clock }, Some(keyring))
+                if matches!(body, Body::Sealed { .. }) || to.as_deref().is_some_and(|to| !protocol::is_room(to)) => {
+                let text = keyring.open(&id, &from, &body)?;
+                Ok(Message::Chat { id, from, to, body: Body::Plain(text), clock })
+            },
+            (message, _) => Ok(message),
+        }
+    }
+
+    /// Remembers the key `user_id` encrypts with
+    fn add_public_key(&mut self, user_id: &str, public_key: &[u8]) {
+        let keyring = match self.keyring.as_mut() {
+            Some(keyring) => keyring,
+            None => return,
+        };
+        if public_key.is_empty() && keyring.has_key(user_id) {
+            let notice = format!("{} was announced without an encryption key, chats stay encrypted", user_id);
+            self.chat_area.push_notice(notice, true);
+        }
+        let changed = keyring.add_public_key(user_id, public_key);
+
+        // kept so a key changing while we were away is noticed as well
+        if let Some(public_key) = keyring.public_key(user_id) {
+            let contact_key = ContactKey { user_id: user_id.to_string(), public_key: hex::encode(public_key) };
+            if let Err(e) = db::operations::save_contact_key(&contact_key) {
+                self.chat_area.push_notice(format!("not able to save encryption key of {}: {}", user_id, e), true);
+            }
+        }
+
+        if changed {
+            self.chat_area.push_notice(format!("encryption key of {} changed", user_id), true);
+        }
+        self.update_title();
+    }
+
+    /// Sends a message that is not a chat to `user_id`, directly if connected
+    fn send_to(&self, user_id: &str, message: Message) {
+        if self.direct_peers.contains_key(user_id) {
+            self.network.send_direct(user_id, message);
+        } else {
+            self.network.send(&message);
+        }
+    }
+
+    /// Keeps a chat for the server in the outbox, it is sent right
+    /// away when connected and again after reconnecting if not acknowledged
+    fn queue_message(&mut self, message: &Message, sealed: &Message) {
+        if let Err(e) = self.chats.queue(message) {
+            self.chat_area.push_notice(format!("not able to queue message: {}", e), true);
+        }
+
+        if self.connection == ConnectionState::Connected && self.network.send(sealed) {
+            if let Message::Chat { id, .. } = message {
+                self.set_status(id, DeliveryStatus::Sent);
+            }
+        }
+    }
+
+    fn run_command(&mut self, command: ChatCommand) {
+        match command {
+            ChatCommand::Connect(target) => {
+                // a peer we already talk to, or one found on the LAN, or an address
+                if self.direct_peers.contains_key(&target) {
+                    self.set_conversation(Some(target));
+                    return;
+                }
+
+                let addr = match self.contact_list.peers().iter().find(|peer| peer.user_id == target) {
+                    Some(peer) => peer.addr,
+                    // known through the server only, the chats go through it
+                    None if self.contact_list.name(&target).is_some() => {
+                        self.set_conversation(Some(target));
+                        return;
+                    },
+                    None => match net::resolve(&target) {
+                        Ok(addr) => addr,
+                        Err(e) => {
+                            self.chat_area.push_notice(e.to_string(), true);
+                            return;
+                        }
+                    }
+                };
+
+                self.chat_area.push_notice(format!("connecting to {}", addr), false);
+                self.connecting = Some(target);
+                self.network.connect_peer(addr);
+            },
+            ChatCommand::Relay => self.set_conversation(None),
+            ChatCommand::Receipts(enabled) => {
+                self.read_receipts = enabled;
+                let value = if enabled { "on" } else { "off" };
+                let _ = db::operations::set_setting(constants::SETTING_READ_RECEIPTS, value);
+
+                self.chat_area.push_notice(format!("read receipts {}", value), false);
+            },
+            ChatCommand::Send(path) => self.send_file(&path),
+            ChatCommand::Join(room) => {
+                if !protocol::is_valid_room(&room) {
+                    self.chat_area.push_notice(format!("{} is not a room name", room), true);
+                    return;
+                }
+                if self.is_member(&room) {
+                    self.set_conversation(Some(room));
+                    return;
+                }
+
+                // the relay holds the rooms, there is nothing to join without it
+                let join = Message::Join { room: room.clone(), user_id: self.user_info.user_id.clone() };
+                match self.connection == ConnectionState::Connected && self.network.send(&join) {
+                    true => self.joining = Some(room),
+                    false => self.chat_area.push_notice("rooms need the server, not connected".to_string(), true),
+                }
+            },
+            ChatCommand::Leave(room) => {
+                let room = match room.or_else(|| self.conversation.clone()) {
+                    Some(room) if self.is_member(&room) => room,
+                    _ => {
+                        self.chat_area.push_notice("not in that room, usage: /leave [#room]".to_string(), true);
+                        return;
+                    }
+                };
+
+                self.network.send(&Message::Leave { room, user_id: self.user_info.user_id.clone() });
+            },
+            ChatCommand::Plain => {
+                let allowed = match (&self.conversation, self.keyring.as_mut()) {
+                    (Some(user_id), Some(keyring)) => keyring.allow_plain(user_id),
+                    _ => false,
+                };
+                match allowed {
+                    true => self.update_title(),
+                    false => {
+                        let notice = "only chats with users without a key can be unencrypted".to_string();
+                        self.chat_area.push_notice(notice, true);
+                    },
+                }
+            },
+        }
+    }
+
+    fn is_member(&self, room: &str) -> bool {
+        self.contact_list.members(room).is_some_and(|members| members.contains(&self.user_info.user_id))
+    }
+
+    fn user_joined(&mut self, room: String, user_id: String) -> bool {
+        self.contact_list.join_room(&room, &user_id);
+
+        if user_id == self.user_info.user_id {
+            if self.joining.as_deref() == Some(room.as_str()) {
+                self.joining = None;
+                let created = self.contact_list.members(&room).is_some_and(|members| members.len() == 1);
+                let verb = if created { "created" } else { "joined" };
+                self.chat_area.push_notice(format!("{} {}", verb, room), false);
+                self.set_conversation(Some(room));
+            }
+        } else if self.conversation.as_deref() == Some(room.as_str()) {
+            let name = self.display_name(&user_id);
+            self.chat_area.push_notice(format!("{} joined {}", name, room), false);
+            self.update_title();
+        }
+
+        true
+    }
+
+    fn user_left(&mut self, room: String, user_id: String) -> bool {
+        let current = self.conversation.as_deref() == Some(room.as_str());
+
+        if user_id == self.user_info.user_id {
+            self.contact_list.remove_room(&room);
+            self.chat_area.push_notice(format!("left {}", room), false);
+            if current {
+                self.set_conversation(None);
+            }
+        } else {
+            self.contact_list.leave_room(&room, &user_id);
+            if current {
+                let name = self.display_name(&user_id);
+                self.chat_area.push_notice(format!("{} left {}", name, room), false);
+                self.update_title();
+            }
+        }
+
+        true
+    }
+
+    /// Offers the file at `path` to the user of the conversation
+    fn send_file(&mut self, path: &str) {
+        let to = match &self.conversation {
+            Some(user_id) if !protocol::is_room(user_id) => user_id.clone(),
+            _ => {
+                self.chat_area.push_notice("files are sent to one user, /connect to them first".to_string(), true);
+                return;
+            }
+        };
+        let path = match (path.strip_prefix("~/"), home::home_dir()) {
+            (Some(rest), Some(home_dir)) => home_dir.join(rest),
+            _ => path.into(),
+        };
+
+        let id = protocol::new_message_id(&self.user_info.user_id);
+        let offered = match self.transfers.as_mut() {
+            Some(transfers) => transfers.offer(&id, &to, &path),
+            None => return,
+        };
+
+        match offered {
+            Ok((offer, progress)) => {
+                self.send_file_part(offer);
+                self.show_transfer(progress);
+            },
+            Err(e) => self.chat_area.push_notice(format!("not able to send {}: {}", path.display(), e), true),
+        }
+    }
+
+    /// Offers the unfinished files to `to` again, or to everyone if `None`
+    fn resume_transfers(&mut self, to: Option<&str>) {
+        let offers = match self.transfers.as_mut() {
+            Some(transfers) => transfers.resume(to),
+            None => return,
+        };
+
+        for offer in offers {
+            self.send_file_part(offer);
+        }
+    }
+
+    /// Sends a step of a file transfer, sealed for its recipient like chats
+    fn send_file_part(&mut self, message: Message) {
+        let (id, from, to, part) = match message {
+            Message::File { id, from, to, part } => (id, from, to, part),
+            _ => return,
+        };
+
+        let part = match self.keyring.as_mut() {
+            Some(keyring) => keyring.seal_part(&id, &to, part),
+            None => Ok(part),
+        };
+        match part {
+            Ok(part) => self.send_to(&to.clone(), Message::File { id, from, to, part }),
+            Err(e) => self.chat_area.push_notice(format!("{}, file not sent", e), true),
+        }
+    }
+
+    /// Draws the progress of a transfer, and stores it when it starts or ends
+    fn show_transfer(&mut self, progress: Progress) {
+        let finished = matches!(progress.status, TransferStatus::Complete | TransferStatus::Failed);
+        let attachment = Attachment {
+            transfer_id: progress.id.clone(),
+            conversation: progress.user_id.clone(),
+            from_user: match progress.outgoing {
+                true => self.user_info.user_id.clone(),
+                false => progress.user_id.clone(),
+            },
+            file_name: progress.name.clone(),
+            file_size: progress.size,
+            sha256: progress.sha256.clone(),
+            path: progress.path.display().to_string(),
+            status: progress.status,
+        };
+
+        if self.chat_area.set_transfer(progress) || finished {
+            if let Err(e) = db::operations::save_attachment(&attachment) {
+                self.chat_area.push_notice(format!("not able to save attachment: {}", e), true);
+            }
+        }
+    }
+
+    /// Tells the current conversation whether we are typing
+    fn send_typing(&self, update: TypingUpdate) {
+        if self.user_info.user_id.is_empty() {
+            return;
+        }
+
+        let (from, to) = (self.user_info.user_id.clone(), self.conversation.clone());
+        let message = match update {
+            TypingUpdate::Typing => Message::Typing { from, to },
+            TypingUpdate::StoppedTyping => Message::StoppedTyping { from, to },
+            TypingUpdate::None => return,
+        };
+
+        match &self.conversation {
+            Some(user_id) => self.send_to(user_id, message),
+            None => {
+                self.network.send(&message);
+            },
+        }
+    }
+
+    /// Name to show for `user_id`, falls back to the id when unknown
+    fn display_name(&self, user_id: &str) -> String {
+        self.direct_peers.get(user_id)
+            .map(String::as_str)
+            .or_else(|| self.contact_list.name(user_id))
+            .unwrap_or(user_id)
+            .to_string()
+    }
+
+    /// Our presence as shown next to our name
+    fn own_presence(&self) -> Presence {
+        match self.connection {
+            ConnectionState::Connected => self.presence,
+            _ => Presence::Offline,
+        }
+    }
+
+    fn send_presence(&self) {
+        if self.connection == ConnectionState::Connected && !self.user_info.user_id.is_empty() {
+            self.network.send(&Message::Presence {
+                user_id: self.user_info.user_id.clone(),
+                user_name: self.user_info.user_name.clone(),
+                presence: self.presence,
+            });
+        }
+    }
+
+    /// Goes away after `AWAY_TIMEOUT` without input and back online on
+    /// the next input, returns `true` if the presence changed
+    fn update_presence(&mut self) -> bool {
+        let presence = match self.last_input.elapsed() >= AWAY_TIMEOUT {
+            true => Presence::Away,
+            false => Presence::Online,
+        };
+        if presence == self.presence {
+            return false;
+        }
+
+        self.presence = presence;
+        self.send_presence();
+        true
+    }
+
+    /// `true` if a message between `from` and `to` belongs to the
+    /// conversation on screen
+    fn is_current_conversation(&self, from: &str, to: Option<&str>) -> bool {
+        let current = self.conversation.as_deref().unwrap_or(constants::RELAY_CONVERSATION);
+        self.chats.conversation_of(from, to) == current
+    }
+
+    /// Expires stale typing state, returns `true` if anything changed on screen
+    fn tick(&mut self) -> bool {
+        let now = Instant::now();
+        let update = self.typing.tick(now);
+        self.send_typing(update);
+
+        let expired = self.typing_indicator.expire(now);
+        let presence_changed = self.update_presence();
+        // the reconnect countdown in the command bar moves on every tick
+        expired || presence_changed || matches!(self.connection, ConnectionState::Reconnecting(_))
+    }
+
+    fn is_focused(&self) -> bool {
+        self.last_input.elapsed() < FOCUS_TIMEOUT
+    }
+
+    /// Moves our read position to the messages of others in view and
+    /// tells their authors, unless read receipts are turned off
+    fn report_read(&mut self) {
+        if !self.is_focused() || self.user_info.user_id.is_empty() {
+            return;
+        }
+
+        let visible: Vec<(String, String, String)> = self.chat_area.visible_messages()
+            .into_iter()
+            .filter(|message| message.status.is_none() && !message.message_id.is_empty())
+            .filter(|message| message.from_user != self.user_info.user_id)
+            .map(|message| (
+                message.conversation.clone(),
+                message.from_user.clone(),
+                message.message_id.clone()
+            ))
+            .collect();
+
+        let mut latest: Vec<(String, String)> = Vec::new();
+        for (conversation, _, message_id) in &visible {
+            match latest.iter_mut().find(|(c, _)| c == conversation) {
+                Some(entry) => entry.1 = message_id.clone(),
+                None => latest.push((conversation.clone(), message_id.clone())),
+            }
+        }
+
+        for (conversation, message_id) in latest {
+            let previous = self.read_positions.get(&conversation).cloned();
+            if previous.as_ref() == Some(&message_id) {
+                continue;
+            }
+
+            // the newest message of each author read since the previous position
+            let in_conversation: Vec<&(String, String, String)> = visible.iter()
+                .filter(|(c, _, _)| *c == conversation)
+                .collect();
+            let first_unread = in_conversation.iter()
+                .position(|(_, _, id)| previous.as_ref() == Some(id))
+                .map_or(0, |index| index + 1);
+
+            let mut authors: Vec<(String, String)> = Vec::new();
+            for (_, from, id) in &in_conversation[first_unread..] {
+                match authors.iter_mut().find(|(author, _)| author == from) {
+                    Some(author) => author.1 = id.clone(),
+                    None => authors.push((from.clone(), id.clone())),
+                }
+            }
+
+            let _ = db::operations::set_read_position(&ReadPosition {
+                conversation: conversation.clone(),
+                user_id: self.user_info.user_id.clone(),
+                message_id: message_id.clone(),
+            });
+            self.read_positions.insert(conversation.clone(), message_id);
+
+            if !self.read_receipts {
+                continue;
+            }
+            for (author, id) in authors {
+                let read = Message::Read {
+                    by: self.user_info.user_id.clone(),
+                    to: author,
+                    id,
+                };
+
+                if conversation == constants::RELAY_CONVERSATION {
+                    self.network.send(&read);
+                } else {
+                    self.send_to(&conversation, read);
+                }
+            }
+        }
+    }
+
+    fn set_conversation(&mut self, conversation: Option<String>) {
+        let stopped = self.typing.stopped();
+        self.send_typing(stopped);
+        self.typing_indicator.clear();
+
+        self.conversation = conversation;
+        self.update_title();
+    }
+
+    fn update_title(&mut self) {
+        let title = match &self.conversation {
+            Some(room) if protocol::is_room(room) => {
+                let members = self.contact_list.members(room).map_or(0, |members| members.len());
+                format!("Room {} ({} members)", room, members)
+            },
+            Some(user_id) if self.direct_peers.contains_key(user_id) => {
+                format!("Conversation with {} (direct)", self.display_name(user_id))
+            },
+            Some(user_id) => format!("Conversation with {}", self.display_name(user_id)),
+            None => "Conversation".to_string(),
+        };
+        let plain = match (&self.conversation, &self.keyring) {
+            (Some(user_id), Some(keyring)) => keyring.is_plain(user_id),
+            _ => false,
+        };
+        let title = match plain {
+            true => format!("{} (unencrypted)", title),
+            false => title,
+        };
+        let encrypted = match (&self.conversation, &self.keyring) {
+            (Some(user_id), Some(keyring)) => keyring.has_key(user_id),
+            _ => false,
+        };
+
+        self.chat_area.set_title(title);
+        self.chat_area.set_encrypted(encrypted);
+    }
+
+    pub fn connection_state(&self) -> ConnectionState {
+        self.connection
+    }
+
+    /// Sends the chats queued while offline, in order, and asks the
+    /// relay for the chats sent since the last one we received. Queued
+    /// chats stay queued until the server acknowledges them.
+    fn catch_up(&mut self) {
+        // the relay sends the keys it knows before welcome, without
+        // one the chat waits for a later flush
+        let mut keyring = self.keyring.as_mut();
+        let network = &self.network;
+        let caught_up = self.chats.welcomed(
+            |message| seal(keyring.as_deref_mut(), message),
+            |message| network.send(message)
+        );
+
+        match caught_up {
+            Ok(sent) => for id in sent {
+                self.chat_area.set_status(&id, DeliveryStatus::Sent);
+            },
+            Err(e) => self.chat_area.push_notice(format!("not able to catch up: {}", e), true),
+        }
+    }
+
+    /// Stores and shows a chat message, `status` is set for our own ones
+    fn add_chat(&mut self, message: &Message, status: Option<DeliveryStatus>) {
+        if let Some(chat_message) = self.chats.store(message, status) {
+            self.chat_area.push_message(chat_message);
+        }
+    }
+
+    fn set_status(&mut self, message_id: &str, status: DeliveryStatus) {
+        self.chats.set_status(message_id, status);
+        self.chat_area.set_status(message_id, status);
+    }
+
+    fn add_message(&mut self, message: Message) {
+        match message {
+            Message::Chat { .. } if !self.chats.receive(&message) => {},
+            Message::Chat { .. } => match self.open(message) {
+                Ok(message) => {
+                    if let Message::Chat { from, .. } = &message {
+                        let name = self.display_name(from);
+                        self.typing_indicator.stopped(&name);
+                    }
+                    self.add_chat(&message, None);
+                },
+                Err(e) => self.chat_area.push_notice(e.to_string(), true),
+            },
+            Message::PublicKey { user_id, public_key } => self.add_public_key(&user_id, &public_key),
+            Message::Typing { from, to } if self.is_current_conversation(&from, to.as_deref()) => {
+                let name = self.display_name(&from);
+                self.typing_indicator.typing(&name, Instant::now());
+            },
+            Message::Presence { user_id, user_name, presence } => {
+                self.contact_list.set_presence(&user_id, &user_name, presence);
+                // files waiting for them can continue
+                if presence == Presence::Online {
+                    self.resume_transfers(Some(&user_id));
+                }
+            },
+            Message::File { id, from, to, part } => {
+                let part = match self.keyring.as_mut() {
+                    Some(keyring) => keyring.open_part(&id, &from, part),
+                    None => Ok(part),
+                };
+                let message = match part {
+                    Ok(part) => Message::File { id, from, to, part },
+                    Err(e) => {
+                        self.chat_area.push_notice(e.to_string(), true);
+                        return;
+                    }
+                };
+
+                let (out, progress) = match self.transfers.as_mut() {
+                    Some(transfers) => transfers.handle(message),
+                    None => return,
+                };
+
+                for reply in out {
+                    self.send_file_part(reply);
+                }
+                if let Some(progress) = progress {
+                    self.show_transfer(progress);
+                }
+            },
+            Message::StoppedTyping { from, .. } => {
+                let name = self.display_name(&from);
+                self.typing_indicator.stopped(&name);
+            },
+            Message::Welcome { version, relay } => {
+                self.chat_area.push_notice(format!("connected to oisg-server {}", version), false);
+                if let Some(keyring) = self.keyring.as_mut() {
+                    keyring.set_relay(&relay);
+                }
+                self.catch_up();
+                self.resume_transfers(None);
+                // the relay assumes we are online after hello
+                if self.presence != Presence::Online {
+                    self.send_presence();
+                }
+            },
+            Message::HistoryEnd { count } if count > 0 => {
+                self.chat_area.push_notice(format!("caught up on {} messages", count), false);
+            },
+            Message::Stored { id } => {
+                if self.chats.stored(&id) {
+                    self.chat_area.set_status(&id, DeliveryStatus::Stored);
+                }
+            },
+            Message::Ack { id } => {
+                self.chats.acknowledged(&id);
+                self.chat_area.set_status(&id, DeliveryStatus::Delivered);
+            },
+            Message::Read { by, id, .. } => {
+                let conversation = self.chat_area.find_message(&id)
+                    .map(|message| message.conversation.clone())
+                    .unwrap_or_else(|| constants::RELAY_CONVERSATION.to_string());
+
+                let _ = db::operations::set_read_position(&ReadPosition {
+                    conversation,
+                    user_id: by.clone(),
+                    message_id: id.clone(),
+                });
+                self.chat_area.mark_seen(&by, &id);
+            },
+            Message::Error { code, message, id } => {
+                // the server will not take the message, retrying does not help.
+                // Chats refused for now stay queued for the next welcome.
+                if let Some(id) = id.filter(|_| !code.is_transient()) {
+                    self.chats.refused(&id);
+                    self.chat_area.set_status(&id, DeliveryStatus::Failed);
+                }
+                self.chat_area.push_notice(message, true);
+            },
+            _ => {}
+        }
+    }
+
+    fn network_event(&mut self, event: NetworkEvent) -> bool {
+        match event {
+            NetworkEvent::Connected => self.connection = ConnectionState::Connected,
+            NetworkEvent::ConnectionFailed(reason) => {
+                // retries are shown in the command bar, no need to repeat them here
+                if self.connection == ConnectionState::Offline {
+                    let notice = match reason {
+                        Some(reason) => format!("not able to connect to server: {}", reason),
+                        None => "not able to connect to server".to_string(),
+                    };
+                    self.chat_area.push_notice(notice, true);
+                }
+            },
+            NetworkEvent::Disconnected => {
+                self.connection = ConnectionState::Offline;
+                self.contact_list.clear_presence();
+                self.contact_list.clear_rooms();
+                self.chat_area.push_notice("disconnected from server".to_string(), true);
+            },
+            NetworkEvent::Reconnecting(delay) => {
+                self.connection = ConnectionState::Reconnecting(Instant::now() + delay);
+            },
+            NetworkEvent::TlsFailed(reason) => {
+                self.connection = ConnectionState::Offline;
+                self.refusal = Some(Refusal::Tls(reason));
+            },
+            NetworkEvent::AuthFailed(reason) => {
+                self.connection = ConnectionState::Offline;
+                self.refusal = Some(Refusal::Auth(reason));
+            },
+            NetworkEvent::MessageReceived(message) => self.add_message(message),
+            NetworkEvent::ProtocolError(e) => self.chat_area.push_notice(e.to_string(), true),
+            NetworkEvent::PeerDiscovered(peer) => self.contact_list.add_peer(peer),
+            NetworkEvent::PeerLost(user_id) => self.contact_list.remove_peer(&user_id),
+            NetworkEvent::DiscoveryUnavailable(reason) => {
+                self.chat_area.push_notice(format!("peer discovery unavailable: {}", reason), true);
+            },
+            NetworkEvent::PeerConnected { user_id, user_name, public_key } => {
+                // peers are not authenticated, anyone on the LAN can say hello
+                // with our contact's user id
+                if self.keyring.as_ref().is_some_and(|keyring| keyring.has_other_key(&user_id, &public_key)) {
+                    self.network.disconnect_peer(&user_id);
+                    self.connecting = None;
+                    self.chat_area.push_notice(
+                        format!("{} connected directly with another key than we know, disconnected", user_id),
+                        true
+                    );
+                    return true;
+                }
+
+                self.direct_peers.insert(user_id.clone(), user_name.clone());
+                self.add_public_key(&user_id, &public_key);
+                self.resume_transfers(Some(&user_id));
+
+                if self.connecting.take().is_some() {
+                    self.set_conversation(Some(user_id));
+                } else {
+                    self.chat_area.push_notice(
+                        format!("{} connected directly, /connect {} to reply", user_name, user_id),
+                        false
+                    );
+                }
+            },
+            NetworkEvent::PeerDisconnected(user_id) => {
+                self.direct_peers.remove(&user_id);
+                self.chat_area.push_notice(format!("{} disconnected", user_id), true);
+
+                if self.conversation.as_deref() == Some(user_id.as_str()) {
+                    self.set_conversation(None);
+                }
+            },
+            NetworkEvent::MessageSent(id) => self.set_status(&id, DeliveryStatus::Sent),
+            NetworkEvent::MessageFailed { id, reason } => {
+                self.set_status(&id, DeliveryStatus::Failed);
+                self.chat_area.push_notice(reason, true);
+            },
+            NetworkEvent::PeerUnreachable(reason) => {
+                self.connecting = None;
+                self.chat_area.push_notice(reason, true);
+            },
+        }
+
+        true
     }
 }
 
 impl BaseComponent for ApplicationUI {
     fn event(&mut self, event: AppEvent) -> Result<bool, ()> {
-        self.message_input.event(event)
+        if let AppEvent::InputEvent(_) = event {
+            self.last_input = Instant::now();
+            self.update_presence();
+        }
+
+        let result = match event {
+            AppEvent::Tick => return Ok(self.tick()),
+            AppEvent::NotificationEvent(Notification::UserJoined { room, user_id }) => {
+                Ok(self.user_joined(room, user_id))
+            },
+            AppEvent::NotificationEvent(Notification::UserLeft { room, user_id }) => {
+                Ok(self.user_left(room, user_id))
+            },
+            AppEvent::InputEvent(Event::Key(ke)) if ke == self.command_keys.send_message => {
+                Ok(self.send_message())
+            },
+            AppEvent::NetworkEvent(evt) => Ok(self.network_event(evt)),
+            _ => {
+                let before = self.message_input.get_text().to_string();
+                let result = self.message_input.event(event);
+
+                let text = self.message_input.get_text();
+                if text != before {
+                    let update = self.typing.edited(text.trim().is_empty(), Instant::now());
+                    self.send_typing(update);
+                }
+                result
+            }
+        };
+
+        // messages that arrived or came back into focus are about to be drawn
+        self.report_read();
+
+        result
     }
 }
 
@@ -80,11 +1023,12 @@ impl DrawableComponent for ApplicationUI {
             .direction(Direction::Vertical)
             .constraints([
                 Constraint::Min(10),
+                Constraint::Length(1),
                 Constraint::Length(4)
             ].as_ref())
             .split(hor_split[1]);
 
-        let mut userinfo_comp = UserInfoComponent::new(Rc::clone(&self.user_info));
+        let mut userinfo_comp = UserInfoComponent::new(Rc::clone(&self.user_info), self.own_presence());
         let userinfo = Block::default()
             .title("User Info")
             .border_type(BorderType::Plain)
@@ -95,12 +1039,7 @@ impl DrawableComponent for ApplicationUI {
         f.render_widget(userinfo, ver_split_1[0]);
         userinfo_comp.draw(f, inner_rect);
 
-        let list = Block::default()
-            .title("List")
-            .border_type(BorderType::Plain)
-            .borders(Borders::ALL)
-            .border_style(styles::border_style(false));
-        f.render_widget(list, ver_split_1[1]);
+        self.contact_list.draw(f, ver_split_1[1]);
 
         // let conversation = Block::default()
         //     .title("Conversation")
@@ -109,15 +1048,81 @@ impl DrawableComponent for ApplicationUI {
         //     .border_style(styles::border_style(false));
         // f.render_widget(conversation, ver_split_2[0]);
         self.chat_area.draw(f, ver_split_2[0]);
+        self.typing_indicator.draw(f, ver_split_2[1]);
 
         let input = Block::default()
-            .title("Input")
+            .title(match self.connection {
+                ConnectionState::Connected => "Input",
+                _ => "Input (offline)"
+            })
             .border_type(BorderType::Plain)
             .borders(Borders::ALL);
 
-        let message_rect = input.inner(ver_split_2[1]);
+        let message_rect = input.inner(ver_split_2[2]);
 
-        f.render_widget(input, ver_split_2[1]);
+        f.render_widget(input, ver_split_2[2]);
         self.message_input.draw(f, message_rect);
+
+        if let Some(refusal) = &self.refusal {
+            draw_refusal(f, area, refusal);
+        }
+    }
+}
+
+/// Encrypts the body of a chat to a single user, chats to everyone
+/// on the server and to rooms stay readable by it
+fn seal(keyring: Option<&mut Keyring>, message: Message) -> Result<Message, CryptoError> {
+    match (message, keyring) {
+        (Message::Chat { id, from, to: Some(to), body: Body::Plain(text), clock }, Some(keyring)) if !protocol::is_room(&to) => {
+            let body = keyring.seal(&id, &to, &text)?;
+            Ok(Message::Chat { id, from, to: Some(to), body, clock })
+        },
+        (message, _) => Ok(message),
     }
 }
+
+/// Covers the chat, nothing can be sent to a server we do not trust
+/// or that does not take us
+fn draw_refusal<B: Backend>(f: &mut Frame<B>, area: Rect, refusal: &Refusal) {
+    let (title, intro, reason, hint) = match refusal {
+        Refusal::Tls(reason) => (
+            "Secure connection failed",
+            "The server could not be verified:",
+            reason,
+            "Check the --ca or --pin option you started with."
+        ),
+        Refusal::Auth(reason) => (
+            "Sign in failed",
+            "The server did not accept your user id:",
+            reason,
+            "Someone else registered it there first, pick another user id."
+        ),
+    };
+
+    let block = Block::default()
+        .title(title)
+        .borders(Borders::ALL)
+        .border_type(BorderType::Thick)
+        .border_style(styles::error_msg_style());
+
+    let center_area = common::get_center_rect_absolute(
+        70.min(area.width),
+        9.min(area.height),
+        area
+    );
+    let text = vec![
+        Spans::from(Span::styled(intro, styles::error_msg_style())),
+        Spans::from(reason.to_string()),
+        Spans::from(""),
+        Spans::from(hint),
+        Spans::from("Press ctrl+c to quit."),
+    ];
+
+    f.render_widget(Clear, center_area);
+    f.render_widget(
+        Paragraph::new(text)
+            .block(block)
+            .wrap(Wrap { trim: true }),
+        center_area
+    );
+}
@@ -0,0 +1,184 @@
+use std::io;
+use crate::{
+    common::clock::LamportClock,
+    constants,
+    db::{
+        self,
+        models::{ ChatMessage, DeliveryStatus, OutboxMessage }
+    },
+    net::protocol::{ self, Body, Message },
+};
+
+/// `Chats` keeps the chats of the user in MESSAGES, and the ones the
+/// server has not acknowledged yet in OUTBOX. It is everything
+/// `ApplicationUI` does with chats apart from showing them.
+pub struct Chats {
+    user_id: String,
+    /// stamps our chats after every chat we have seen
+    clock: LamportClock,
+}
+
+impl Chats {
+    pub fn new(user_id: &str) -> Self {
+        Chats {
+            user_id: user_id.to_string(),
+            clock: LamportClock::starting_at(db::operations::get_last_clock().unwrap_or_default()),
+        }
+    }
+
+    pub fn set_user_id(&mut self, user_id: &str) {
+        self.user_id = user_id.to_string();
+    }
+
+    /// Chat from us to `to`, or to everyone if `None`, at the next Lamport time
+    pub fn write(&mut self, to: Option<String>, text: String) -> Message {
+        Message::Chat {
+            id: protocol::new_message_id(&self.user_id),
+            from: self.user_id.clone(),
+            to,
+            body: Body::Plain(text),
+            clock: self.clock.tick(),
+        }
+    }
+
+    /// Conversation a chat message belongs to
+    pub fn conversation_of(&self, from: &str, to: Option<&str>) -> String {
+        match to {
+            None => constants::RELAY_CONVERSATION.to_string(),
+            Some(to) if from == self.user_id || protocol::is_room(to) => to.to_string(),
+            Some(_) => from.to_string(),
+        }
+    }
+
+    /// Moves the clock past a chat that arrived, returns `false` if we
+    /// have the chat already. History sent on reconnect can repeat chats.
+    pub fn receive(&mut self, message: &Message) -> bool {
+        match message {
+            Message::Chat { id, clock, .. } => {
+                self.clock.observe(*clock);
+                !db::operations::has_message(id).unwrap_or(false)
+            },
+            _ => false,
+        }
+    }
+
+    /// Stores a readable chat, `status` is set for our own ones
+    pub fn store(&self, message: &Message, status: Option<DeliveryStatus>) -> Option<ChatMessage> {
+        match message {
+            Message::Chat { id, from, to, body: Body::Plain(text), clock } => {
+                let chat_message = ChatMessage {
+                    message_id: id.clone(),
+                    conversation: self.conversation_of(from, to.as_deref()),
+                    from_user: from.clone(),
+                    message: text.clone(),
+                    status,
+                    clock: *clock,
+                };
+
+                let _ = db::operations::save_message(&chat_message);
+                Some(chat_message)
+            },
+            _ => None,
+        }
+    }
+
+    /// Keeps a chat for the server in the outbox, `flush` sends it again
+    /// until the server acknowledges it
+    pub fn queue(&self, message: &Message) -> io::Result<()> {
+        match message {
+            Message::Chat { id, to, body: Body::Plain(text), clock, .. } => {
+                db::operations::queue_message(&OutboxMessage {
+                    message_id: id.clone(),
+                    to_user: to.clone(),
+                    message: text.clone(),
+                    clock: *clock,
+                })
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// Sends the queued chats in order, each after `seal` made it ready
+    /// for the wire. A chat `seal` refuses waits for a later flush, the
+    /// flush stops at the first chat `send` could not write. Returns the
+    /// ids of the chats sent, they stay queued until acknowledged.
+    pub fn flush<E>(
+        &self,
+        mut seal: impl FnMut(Message) -> Result<Message, E>,
+        mut send: impl FnMut(&Message) -> bool
+    ) -> io::Result<Vec<String>> {
+        let mut sent = Vec::new();
+        for queued in db::operations::get_outbox()? {
+            let message = seal(Message::Chat {
+                id: queued.message_id.clone(),
+                from: self.user_id.clone(),
+                to: queued.to_user,
+                body: Body::Plain(queued.message),
+                clock: queued.clock,
+            });
+
+            let message = match message {
+                Ok(message) => message,
+                Err(_) => continue,
+            };
+            if !send(&message) {
+                break;
+            }
+            self.set_status(&queued.message_id, DeliveryStatus::Sent);
+            sent.push(queued.message_id);
+        }
+
+        Ok(sent)
+    }
+
+    /// Catches up once the relay welcomed us: flushes the outbox, see
+    /// `flush`, and asks for the chats sent since the last one we
+    /// received. Returns the ids of the chats sent.
+    pub fn welcomed<E>(
+        &self,
+        seal: impl FnMut(Message) -> Result<Message, E>,
+        mut send: impl FnMut(&Message) -> bool
+    ) -> io::Result<Vec<String>> {
+        let sent = self.flush(seal, &mut send);
+        send(&Message::HistoryRequest { since: self.history_since()? });
+        sent
+    }
+
+    /// Unix time to ask the relay for history from, a margin before the
+    /// last chat others sent us
+    pub fn history_since(&self) -> io::Result<u64> {
+        let since = db::operations::get_last_received_time(&self.user_id)?
+            .map_or(0, |time| time.saturating_sub(constants::HISTORY_SYNC_MARGIN.as_secs()));
+
+        Ok(since)
+    }
+
+    /// The server keeps `message_id` until its recipient is back, it
+    /// leaves the outbox. Returns `false` if the chat was delivered
+    /// already, a chat sent again can be stored after the first got through.
+    pub fn stored(&self, message_id: &str) -> bool {
+        let _ = db::operations::remove_from_outbox(message_id);
+        if db::operations::get_message_status(message_id).ok().flatten() == Some(DeliveryStatus::Delivered) {
+            return false;
+        }
+
+        self.set_status(message_id, DeliveryStatus::Stored);
+        true
+    }
+
+    /// The recipient has `message_id`, it leaves the outbox
+    pub fn acknowledged(&self, message_id: &str) {
+        let _ = db::operations::remove_from_outbox(message_id);
+        self.set_status(message_id, DeliveryStatus::Delivered);
+    }
+
+    /// The server will not take `message_id`, retrying does not help
+    pub fn refused(&self, message_id: &str) {
+        let _ = db::operations::remove_from_outbox(message_id);
+        self.set_status(message_id, DeliveryStatus::Failed);
+    }
+
+    pub fn set_status(&self, message_id: &str, status: DeliveryStatus) {
+        let _ = db::operations::set_message_status(message_id, status);
+    }
+}
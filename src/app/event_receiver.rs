@@ -12,7 +12,8 @@ use crate::common::app_event::AppEvent;
 
 const TICK_RATE: Duration = Duration::from_millis(200);
 
-///
+/// `EventReceiver` polls terminal input on a background thread
+/// and exposes it as a channel of `AppEvent`, with a `Tick` every `TICK_RATE`
 pub struct EventReceiver {
     receiver: Receiver<AppEvent>
 }
@@ -28,7 +29,7 @@ impl EventReceiver {
     }
 
     fn input_loop(sender: Sender<AppEvent>) {
-        let last_tick = Instant::now();
+        let mut last_tick = Instant::now();
 
         thread::spawn(move || {
             loop {
@@ -45,6 +46,11 @@ impl EventReceiver {
                     Err(_) => {},
                     _ => {}
                 }
+
+                if last_tick.elapsed() >= TICK_RATE {
+                    let _ = sender.send(AppEvent::Tick);
+                    last_tick = Instant::now();
+                }
             }
         });
     }
@@ -53,3 +59,9 @@ impl EventReceiver {
         self.receiver
     }
 }
+
+impl Default for EventReceiver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,3 +1,4 @@
 pub mod application;
 mod application_ui;
+pub mod chats;
 pub mod event_receiver;
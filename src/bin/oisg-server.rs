@@ -0,0 +1,58 @@
+use std::error::Error;
+use log::{ LevelFilter, Log, Metadata, Record };
+use oisg::{
+    net::tls,
+    server::{
+        Server,
+        config::ServerConfig
+    }
+};
+
+/// Prints what the relay logs, connections coming and going and links to other relays
+struct Stdout;
+
+impl Log for Stdout {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.target().starts_with("oisg")
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            println!("{}", record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let config = ServerConfig::from_args()?;
+    log::set_logger(&Stdout)?;
+    log::set_max_level(LevelFilter::Info);
+
+    let server = Server::bind(&config)?;
+    println!("oisg-server listening on {}", server.local_addr());
+    if let Some(ws_addr) = server.ws_addr() {
+        println!("listening for WebSocket clients on ws://{}", ws_addr);
+    }
+    if let Some(udp_addr) = server.udp_addr() {
+        println!("listening for UDP clients on udp://{}", udp_addr);
+    }
+    if let Some(unix_path) = server.unix_path() {
+        println!("listening for local clients on unix://{}", unix_path.display());
+    }
+    if let Some(irc_addr) = server.irc_addr() {
+        println!("IRC gateway listening on {}", irc_addr);
+    }
+    if let (Some(name), Some(link_addr)) = (&config.name, server.link_addr()) {
+        println!("relay {} accepting links from other relays on {}", name, link_addr);
+    }
+    if let Some(cert_file) = &config.cert_file {
+        // clients trusting this exact certificate pass it to --pin
+        println!("serving TLS, certificate fingerprint {}", tls::fingerprint_of_file(cert_file)?);
+    }
+
+    server.run();
+
+    Ok(())
+}
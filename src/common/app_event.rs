@@ -1,14 +1,67 @@
+use std::time::Duration;
 use crossterm::event::Event;
+use crate::net::{
+    Message,
+    discovery::Peer,
+    protocol::FrameError
+};
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Notification {
     UserInfoSaved,
-    UserJoined,
+    /// `user_id` is a member of `room`, the relay tells us on join and reconnect
+    UserJoined {
+        room: String,
+        user_id: String,
+    },
+    UserLeft {
+        room: String,
+        user_id: String,
+    },
 }
 
-#[derive(Debug, Copy, Clone)]
+/// Events produced by `net::Network`
+#[derive(Debug, Clone, PartialEq)]
+pub enum NetworkEvent {
+    Connected,
+    /// the server could not be reached, with the reason when it is known
+    ConnectionFailed(Option<String>),
+    Disconnected,
+    /// the next connection attempt starts after this delay
+    Reconnecting(Duration),
+    /// the server could not be verified, no more connection attempts are made
+    TlsFailed(String),
+    /// the server refused our user id, no more connection attempts are made
+    AuthFailed(String),
+    MessageReceived(Message),
+    ProtocolError(FrameError),
+    PeerDiscovered(Peer),
+    /// the peer with this user id stopped announcing itself
+    PeerLost(String),
+    DiscoveryUnavailable(String),
+    /// a direct connection to another client completed its handshake
+    PeerConnected {
+        user_id: String,
+        user_name: String,
+        public_key: Vec<u8>,
+    },
+    PeerDisconnected(String),
+    /// a direct connection could not be opened
+    PeerUnreachable(String),
+    /// a direct chat message was written to the peer connection
+    MessageSent(String),
+    MessageFailed {
+        id: String,
+        reason: String,
+    },
+}
+
+#[derive(Debug, Clone)]
 pub enum AppEvent {
     InputEvent(Event),
     NotificationEvent(Notification),
+    NetworkEvent(NetworkEvent),
+    /// Sent every `TICK_RATE` by the `EventReceiver`
+    Tick,
     None
 }
@@ -0,0 +1,105 @@
+use crate::net::protocol;
+
+/// Commands typed in the message input, they start with `/`
+#[derive(Debug, PartialEq)]
+pub enum ChatCommand {
+    /// `/connect <host:port | user id>` chat directly with a peer
+    Connect(String),
+    /// `/relay` chat through the server again
+    Relay,
+    /// `/receipts on|off` whether others are told what we have read
+    Receipts(bool),
+    /// `/send <path>` offer a file to the user of the conversation
+    Send(String),
+    /// `/join <#room>` join a room, creating it if nobody is in it
+    Join(String),
+    /// `/leave [#room]` leave the room, the current one if none is given
+    Leave(Option<String>),
+    /// `/plain` chat unencrypted with the user of the conversation, who
+    /// has no key to decrypt with
+    Plain,
+}
+
+impl ChatCommand {
+    /// Parses `input`, returns `None` if it is not a command
+    pub fn parse(input: &str) -> Option<Result<Self, String>> {
+        let input = input.trim();
+        if !input.starts_with('/') {
+            return None;
+        }
+
+        // paths can have spaces, everything after the command is the path
+        if input == "/send" || input.starts_with("/send ") {
+            return Some(match input["/send".len()..].trim() {
+                "" => Err("usage: /send <path>".to_string()),
+                path => Ok(ChatCommand::Send(path.to_string())),
+            });
+        }
+
+        let mut parts = input.split_whitespace();
+        let command = parts.next().unwrap_or_default();
+        let args: Vec<&str> = parts.collect();
+
+        Some(match (command, args.as_slice()) {
+            ("/connect", [target]) => Ok(ChatCommand::Connect(target.to_string())),
+            ("/connect", _) => Err("usage: /connect <host:port | user id>".to_string()),
+            ("/relay", []) => Ok(ChatCommand::Relay),
+            ("/relay", _) => Err("usage: /relay".to_string()),
+            ("/receipts", ["on"]) => Ok(ChatCommand::Receipts(true)),
+            ("/receipts", ["off"]) => Ok(ChatCommand::Receipts(false)),
+            ("/receipts", _) => Err("usage: /receipts on|off".to_string()),
+            ("/join", [room]) => Ok(ChatCommand::Join(room_name(room))),
+            ("/join", _) => Err("usage: /join <#room>".to_string()),
+            ("/leave", []) => Ok(ChatCommand::Leave(None)),
+            ("/leave", [room]) => Ok(ChatCommand::Leave(Some(room_name(room)))),
+            ("/leave", _) => Err("usage: /leave [#room]".to_string()),
+            ("/plain", []) => Ok(ChatCommand::Plain),
+            ("/plain", _) => Err("usage: /plain".to_string()),
+            _ => Err(format!("unknown command {}", command)),
+        })
+    }
+}
+
+/// `rust` and `#rust` both name the room `#rust`
+fn room_name(name: &str) -> String {
+    match protocol::is_room(name) {
+        true => name.to_string(),
+        false => format!("{}{}", protocol::ROOM_PREFIX, name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(ChatCommand::parse("hello"), None);
+        assert_eq!(
+            ChatCommand::parse("/connect 10.0.0.5:7879"),
+            Some(Ok(ChatCommand::Connect("10.0.0.5:7879".to_string())))
+        );
+        assert_eq!(ChatCommand::parse(" /relay "), Some(Ok(ChatCommand::Relay)));
+        assert_eq!(ChatCommand::parse("/receipts off"), Some(Ok(ChatCommand::Receipts(false))));
+        assert_eq!(ChatCommand::parse("/join rust"), Some(Ok(ChatCommand::Join("#rust".to_string()))));
+        assert_eq!(ChatCommand::parse("/join #rust"), Some(Ok(ChatCommand::Join("#rust".to_string()))));
+        assert_eq!(ChatCommand::parse("/leave"), Some(Ok(ChatCommand::Leave(None))));
+        assert_eq!(ChatCommand::parse("/plain"), Some(Ok(ChatCommand::Plain)));
+        assert_eq!(
+            ChatCommand::parse("/send ~/My Documents/notes.txt"),
+            Some(Ok(ChatCommand::Send("~/My Documents/notes.txt".to_string())))
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(matches!(ChatCommand::parse("/connect"), Some(Err(_))));
+        assert!(matches!(ChatCommand::parse("/connect a b"), Some(Err(_))));
+        assert!(matches!(ChatCommand::parse("/dance"), Some(Err(_))));
+        assert!(matches!(ChatCommand::parse("/receipts maybe"), Some(Err(_))));
+        assert!(matches!(ChatCommand::parse("/send"), Some(Err(_))));
+        assert!(matches!(ChatCommand::parse("/join"), Some(Err(_))));
+        assert!(matches!(ChatCommand::parse("/plain bob"), Some(Err(_))));
+        assert!(matches!(ChatCommand::parse("/sendit now"), Some(Err(_))));
+    }
+}
@@ -0,0 +1,54 @@
+/// `LamportClock` orders chats by cause rather than by when they arrived.
+/// Every chat carries the time of its sender's clock, and a clock that
+/// sees a chat moves past it, so an answer is always later than the chat
+/// it answers.
+#[derive(Debug, Default)]
+pub struct LamportClock {
+    time: u64,
+}
+
+impl LamportClock {
+    /// Clock continuing after the latest `time` seen, as stored
+    pub fn starting_at(time: u64) -> Self {
+        LamportClock { time }
+    }
+
+    /// Time for a chat we are about to send
+    pub fn tick(&mut self) -> u64 {
+        self.time += 1;
+        self.time
+    }
+
+    /// Moves the clock past the time of a chat we received
+    pub fn observe(&mut self, time: u64) {
+        self.time = self.time.max(time);
+    }
+
+    pub fn time(&self) -> u64 {
+        self.time
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_answers_come_later() {
+        let mut alice = LamportClock::default();
+        let mut bob = LamportClock::starting_at(10);
+
+        let question = alice.tick();
+        assert_eq!(question, 1);
+        assert_eq!(bob.tick(), 11);
+
+        // carol has sent nothing, her answer still follows the question
+        let mut carol = LamportClock::default();
+        carol.observe(bob.time());
+        carol.observe(question);
+        assert!(carol.tick() > 11);
+
+        alice.observe(0);
+        assert_eq!(alice.tick(), 2);
+    }
+}
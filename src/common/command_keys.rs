@@ -10,10 +10,13 @@ pub struct CommandKeys {
     pub clear: KeyEvent,
     pub next: KeyEvent,
     pub save: KeyEvent,
+
+    // conversation
+    pub send_message: KeyEvent,
 }
 
-impl CommandKeys {
-    pub fn default() -> Self {
+impl Default for CommandKeys {
+    fn default() -> Self {
         CommandKeys {
             quit: KeyEvent::new(KeyCode::Char('c'), KeyModifiers::CONTROL),
             help: KeyEvent::new(KeyCode::Char('h'), KeyModifiers::CONTROL),
@@ -21,6 +24,7 @@ impl CommandKeys {
             clear: KeyEvent::new(KeyCode::Char('r'), KeyModifiers::CONTROL),
             next: KeyEvent::new(KeyCode::Char('n'), KeyModifiers::CONTROL),
             save: KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE),
+            send_message: KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE),
         }
     }
 }
\ No newline at end of file
@@ -1,5 +1,8 @@
 pub mod command_keys;
 pub mod app_event;
+pub mod chat_command;
+pub mod typing;
+pub mod clock;
 
 use tui::{
     layout::{ Rect, Layout, Direction }
@@ -0,0 +1,94 @@
+use std::time::{ Duration, Instant };
+
+/// `Typing` is not repeated more often than this while the user types
+pub const TYPING_THROTTLE: Duration = Duration::from_secs(3);
+/// The user counts as stopped after not editing for this long
+pub const TYPING_IDLE: Duration = Duration::from_secs(5);
+
+/// What should be told to the conversation after an update
+#[derive(Debug, PartialEq)]
+pub enum TypingUpdate {
+    Typing,
+    StoppedTyping,
+    None,
+}
+
+/// `TypingThrottle` decides when the local user's typing state has to be
+/// sent, so that not every key stroke becomes a message
+#[derive(Default)]
+pub struct TypingThrottle {
+    /// when `Typing` was last sent, `None` if we are not typing
+    last_sent: Option<Instant>,
+    last_edit: Option<Instant>,
+}
+
+impl TypingThrottle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The input text changed, `empty` if it was cleared
+    pub fn edited(&mut self, empty: bool, now: Instant) -> TypingUpdate {
+        if empty {
+            return self.stopped();
+        }
+
+        self.last_edit = Some(now);
+        match self.last_sent {
+            Some(sent) if now.duration_since(sent) < TYPING_THROTTLE => TypingUpdate::None,
+            _ => {
+                self.last_sent = Some(now);
+                TypingUpdate::Typing
+            }
+        }
+    }
+
+    /// The message was sent or the conversation changed
+    pub fn stopped(&mut self) -> TypingUpdate {
+        self.last_edit = None;
+        match self.last_sent.take() {
+            Some(_) => TypingUpdate::StoppedTyping,
+            None => TypingUpdate::None,
+        }
+    }
+
+    /// Called on every tick, stops typing once the user paused long enough
+    pub fn tick(&mut self, now: Instant) -> TypingUpdate {
+        match self.last_edit {
+            Some(edit) if now.duration_since(edit) >= TYPING_IDLE => self.stopped(),
+            _ => TypingUpdate::None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_typing_is_throttled() {
+        let mut throttle = TypingThrottle::new();
+        let now = Instant::now();
+
+        assert_eq!(throttle.edited(false, now), TypingUpdate::Typing);
+        assert_eq!(throttle.edited(false, now + Duration::from_secs(1)), TypingUpdate::None);
+        assert_eq!(throttle.edited(false, now + TYPING_THROTTLE), TypingUpdate::Typing);
+    }
+
+    #[test]
+    fn test_stopped_typing() {
+        let mut throttle = TypingThrottle::new();
+        let now = Instant::now();
+
+        assert_eq!(throttle.stopped(), TypingUpdate::None);
+
+        throttle.edited(false, now);
+        assert_eq!(throttle.edited(true, now), TypingUpdate::StoppedTyping);
+        assert_eq!(throttle.stopped(), TypingUpdate::None);
+
+        throttle.edited(false, now);
+        assert_eq!(throttle.tick(now + Duration::from_secs(1)), TypingUpdate::None);
+        assert_eq!(throttle.tick(now + TYPING_IDLE), TypingUpdate::StoppedTyping);
+        assert_eq!(throttle.tick(now + TYPING_IDLE * 2), TypingUpdate::None);
+    }
+}
@@ -1,41 +1,414 @@
+use std::collections::HashMap;
 use tui::backend::Backend;
 use tui::Frame;
 use tui::layout::Rect;
+use tui::text::{Span, Spans};
 use tui::widgets::{
     Block, Borders, BorderType, Paragraph
 };
 use crate::{
-    AppEvent, styles
+    common::app_event::AppEvent, styles
 };
 use crate::components::{
     BaseComponent, DrawableComponent
 };
+use crate::db::models::{ ChatMessage, DeliveryStatus, TransferStatus };
+use crate::transfer::Progress;
+
+enum ChatEntry {
+    Message(ChatMessage),
+    /// file sent or received, drawn with a progress bar
+    Transfer(Progress),
+    /// status line from the application, not part of the conversation
+    Notice {
+        text: String,
+        error: bool,
+    },
+}
+
+/// Drawn before the title of end-to-end encrypted conversations
+const LOCK: &str = "\u{1f512}";
+/// Cells of the file transfer progress bar
+const PROGRESS_WIDTH: usize = 20;
 
 pub struct ChatArea {
+    title: String,
+    /// the conversation is end-to-end encrypted
+    encrypted: bool,
+    entries: Vec<ChatEntry>,
+    /// last message each reader has seen of ours, drawn as "seen by"
+    seen_by: HashMap<String, String>,
+    /// lines that fit in the viewport when last drawn
+    height: usize
 }
 
 impl ChatArea {
-    pub fn new() -> Self {
-        ChatArea{}
+    pub fn with_messages(messages: Vec<ChatMessage>) -> Self {
+        ChatArea {
+            title: "Conversation".to_string(),
+            encrypted: false,
+            entries: messages.into_iter().map(ChatEntry::Message).collect(),
+            seen_by: HashMap::new(),
+            height: 0
+        }
+    }
+
+    /// Records that `reader` has seen our message `message_id`
+    pub fn mark_seen(&mut self, reader: &str, message_id: &str) {
+        self.seen_by.insert(reader.to_string(), message_id.to_string());
+    }
+
+    pub fn find_message(&self, message_id: &str) -> Option<&ChatMessage> {
+        self.entries.iter().rev().find_map(|entry| match entry {
+            ChatEntry::Message(message) if message.message_id == message_id => Some(message),
+            _ => None,
+        })
+    }
+
+    /// Messages that were in the viewport when last drawn
+    pub fn visible_messages(&self) -> Vec<&ChatMessage> {
+        let lines = self.get_draw_lines();
+        let first_visible = lines.len().saturating_sub(self.height);
+
+        let mut visible: Vec<usize> = lines[first_visible..].iter()
+            .filter_map(|(_, index)| *index)
+            .collect();
+        visible.dedup();
+
+        visible.into_iter()
+            .filter_map(|index| match &self.entries[index] {
+                ChatEntry::Message(message) => Some(message),
+                ChatEntry::Transfer(_) | ChatEntry::Notice { .. } => None,
+            })
+            .collect()
+    }
+
+    pub fn set_title(&mut self, title: String) {
+        self.title = title;
+    }
+
+    pub fn set_encrypted(&mut self, encrypted: bool) {
+        self.encrypted = encrypted;
+    }
+
+    fn get_title(&self) -> Spans<'static> {
+        match self.encrypted {
+            true => Spans::from(vec![
+                Span::styled(LOCK, styles::encrypted_style()),
+                Span::raw(" "),
+                Span::raw(self.title.clone()),
+            ]),
+            false => Spans::from(self.title.clone()),
+        }
+    }
+
+    /// Shows a message after every message sent before it, so that
+    /// answers never come before what they answer. Messages at the
+    /// same time stay in the order they arrived.
+    pub fn push_message(&mut self, message: ChatMessage) {
+        let later = self.entries.iter().rposition(|entry| match entry {
+            ChatEntry::Message(shown) => shown.clock <= message.clock,
+            ChatEntry::Transfer(_) | ChatEntry::Notice { .. } => false,
+        });
+        let index = match later {
+            // nothing sent later is shown after it, notices included
+            Some(index) if self.is_last_message(index) => self.entries.len(),
+            Some(index) => index + 1,
+            None => self.entries.iter()
+                .position(|entry| matches!(entry, ChatEntry::Message(_)))
+                .unwrap_or(self.entries.len()),
+        };
+
+        self.entries.insert(index, ChatEntry::Message(message));
+    }
+
+    fn is_last_message(&self, index: usize) -> bool {
+        !self.entries[index + 1..].iter().any(|entry| matches!(entry, ChatEntry::Message(_)))
+    }
+
+    /// Updates the delivery status of our message `message_id`
+    pub fn set_status(&mut self, message_id: &str, status: DeliveryStatus) {
+        let message = self.entries.iter_mut().rev().find_map(|entry| match entry {
+            ChatEntry::Message(message) if message.message_id == message_id => Some(message),
+            _ => None,
+        });
+
+        if let Some(message) = message {
+            message.status = Some(status);
+        }
+    }
+
+    /// Shows the new state of a file transfer, returns `true` if it
+    /// was not shown yet
+    pub fn set_transfer(&mut self, progress: Progress) -> bool {
+        let shown = self.entries.iter_mut().rev().find_map(|entry| match entry {
+            ChatEntry::Transfer(shown) if shown.id == progress.id => Some(shown),
+            _ => None,
+        });
+
+        match shown {
+            Some(shown) => {
+                *shown = progress;
+                false
+            },
+            None => {
+                self.entries.push(ChatEntry::Transfer(progress));
+                true
+            }
+        }
+    }
+
+    pub fn push_notice(&mut self, text: String, error: bool) {
+        self.entries.push(ChatEntry::Notice { text, error });
+    }
+
+    /// Lines to draw, each with the index of the message entry it belongs to
+    fn get_draw_lines(&self) -> Vec<(Spans<'_>, Option<usize>)> {
+        let mut lines = Vec::new();
+
+        for (index, entry) in self.entries.iter().enumerate() {
+            match entry {
+                ChatEntry::Message(message) => {
+                    let mut spans = vec![
+                        Span::styled(message.from_user.as_str(), styles::user_id_style()),
+                        Span::raw(": "),
+                        Span::raw(message.message.as_str()),
+                    ];
+                    if let Some(status) = message.status {
+                        spans.push(Span::raw(" "));
+                        spans.push(Span::styled(status_glyph(status), styles::delivery_style(status)));
+                    }
+                    lines.push((Spans::from(spans), Some(index)));
+
+                    if let Some(readers) = self.get_readers(message) {
+                        lines.push((
+                            Spans::from(Span::styled(format!("   seen by {}", readers), styles::notice_style(false))),
+                            Some(index)
+                        ));
+                    }
+                },
+                ChatEntry::Transfer(progress) => lines.push((transfer_line(progress), None)),
+                ChatEntry::Notice { text, error } => lines.push((
+                    Spans::from(Span::styled(format!("-- {}", text), styles::notice_style(*error))),
+                    None
+                )),
+            }
+        }
+
+        lines
+    }
+
+    fn get_readers(&self, message: &ChatMessage) -> Option<String> {
+        if message.status.is_none() || message.message_id.is_empty() {
+            return None;
+        }
+
+        let mut readers: Vec<&str> = self.seen_by.iter()
+            .filter(|(_, message_id)| **message_id == message.message_id)
+            .map(|(reader, _)| reader.as_str())
+            .collect();
+        readers.sort_unstable();
+
+        match readers.is_empty() {
+            true => None,
+            false => Some(readers.join(", ")),
+        }
+    }
+}
+
+fn transfer_line(progress: &Progress) -> Spans<'_> {
+    let arrow = if progress.outgoing { "\u{2191}" } else { "\u{2193}" };
+    let state = match (progress.status, progress.outgoing) {
+        (TransferStatus::Complete, true) => "sent".to_string(),
+        (TransferStatus::Complete, false) => format!("saved to {}", progress.path.display()),
+        (TransferStatus::Failed, _) => "failed".to_string(),
+        (_, true) => format!("to {}", progress.user_id),
+        (_, false) => format!("from {}", progress.user_id),
+    };
+
+    Spans::from(vec![
+        Span::raw(format!("{} {} ", arrow, progress.name)),
+        Span::styled(progress_bar(progress.done, progress.size), styles::transfer_style(progress.status)),
+        Span::raw(format!(" {} {}", format_size(progress.size), state)),
+    ])
+}
+
+/// `[#####     ]  50%`
+fn progress_bar(done: u64, size: u64) -> String {
+    let percent = match size {
+        0 => 100,
+        size => (done.min(size) * 100 / size) as usize,
+    };
+    let filled = percent * PROGRESS_WIDTH / 100;
+
+    format!(
+        "[{}{}] {:>3}%",
+        "#".repeat(filled),
+        " ".repeat(PROGRESS_WIDTH - filled),
+        percent
+    )
+}
+
+fn format_size(bytes: u64) -> String {
+    match bytes {
+        0..=1023 => format!("{} B", bytes),
+        1024..=1_048_575 => format!("{:.1} KiB", bytes as f64 / 1024.0),
+        _ => format!("{:.1} MiB", bytes as f64 / 1_048_576.0),
+    }
+}
+
+fn status_glyph(status: DeliveryStatus) -> &'static str {
+    match status {
+        DeliveryStatus::Pending => "\u{25cb}",
+        DeliveryStatus::Sent => "\u{2713}",
+        DeliveryStatus::Stored => "\u{2713}\u{25cb}",
+        DeliveryStatus::Delivered => "\u{2713}\u{2713}",
+        DeliveryStatus::Failed => "\u{2717}",
     }
 }
 
 impl BaseComponent for ChatArea {
-    fn event(&mut self, event: AppEvent) -> Result<bool, ()> {
-        todo!()
+    fn event(&mut self, _event: AppEvent) -> Result<bool, ()> {
+        Ok(false)
     }
 }
 
 impl DrawableComponent for ChatArea {
     fn draw<B: Backend>(&mut self, f: &mut Frame<B>, area: Rect) {
         let conversation = Block::default()
-            .title("Conversation")
+            .title(self.get_title())
             .border_type(BorderType::Plain)
             .borders(Borders::ALL)
             .border_style(styles::border_style(false));
 
-        let paragraph = Paragraph::new("Some Text").block(conversation);
+        // keep the latest messages in view
+        self.height = conversation.inner(area).height as usize;
+        let lines: Vec<Spans> = self.get_draw_lines()
+            .into_iter()
+            .map(|(spans, _)| spans)
+            .collect();
+        let scroll = lines.len().saturating_sub(self.height) as u16;
+
+        let paragraph = Paragraph::new(lines)
+            .block(conversation)
+            .scroll((scroll, 0));
 
         f.render_widget(paragraph, area);
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: &str, from: &str, status: Option<DeliveryStatus>) -> ChatMessage {
+        ChatMessage {
+            message_id: id.to_string(),
+            from_user: from.to_string(),
+            message: format!("text of {}", id),
+            status,
+            ..ChatMessage::default()
+        }
+    }
+
+    fn visible_ids(chat_area: &ChatArea) -> Vec<&str> {
+        chat_area.visible_messages().iter().map(|m| m.message_id.as_str()).collect()
+    }
+
+    #[test]
+    fn test_visible_messages() {
+        let mut chat_area = ChatArea::with_messages(vec![
+            message("b1", "bob", None),
+            message("b2", "bob", None),
+        ]);
+        assert!(visible_ids(&chat_area).is_empty());
+
+        chat_area.height = 2;
+        chat_area.push_notice("connected".to_string(), false);
+        chat_area.push_message(message("b3", "bob", None));
+
+        assert_eq!(visible_ids(&chat_area), vec!["b3"]);
+
+        chat_area.height = 10;
+        assert_eq!(visible_ids(&chat_area), vec!["b1", "b2", "b3"]);
+    }
+
+    fn ids(chat_area: &ChatArea) -> Vec<&str> {
+        chat_area.entries.iter()
+            .filter_map(|entry| match entry {
+                ChatEntry::Message(message) => Some(message.message_id.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_causal_order() {
+        let at = |id: &str, clock: u64| ChatMessage { clock, ..message(id, "bob", None) };
+        let mut chat_area = ChatArea::with_messages(vec![at("b1", 1), at("b3", 3)]);
+
+        // an answer that overtook the chat it answers
+        chat_area.push_message(at("a5", 5));
+        chat_area.push_message(at("c4", 4));
+        chat_area.push_message(at("c2", 2));
+        chat_area.push_message(at("d3", 3));
+        assert_eq!(ids(&chat_area), vec!["b1", "c2", "b3", "d3", "c4", "a5"]);
+
+        chat_area.push_message(at("old", 0));
+        chat_area.push_notice("connected".to_string(), false);
+        chat_area.push_message(at("a6", 6));
+        assert_eq!(ids(&chat_area).first(), Some(&"old"));
+        assert!(matches!(chat_area.entries.last(), Some(ChatEntry::Message(message)) if message.message_id == "a6"));
+    }
+
+    #[test]
+    fn test_seen_by() {
+        let mut chat_area = ChatArea::with_messages(vec![
+            message("a1", "alice", Some(DeliveryStatus::Delivered)),
+            message("a2", "alice", Some(DeliveryStatus::Delivered)),
+        ]);
+        chat_area.mark_seen("carol", "a1");
+        chat_area.mark_seen("bob", "a1");
+        let lines = chat_area.get_draw_lines();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[1].1, Some(0));
+        assert_eq!(
+            chat_area.get_readers(&message("a1", "alice", Some(DeliveryStatus::Sent))),
+            Some("bob, carol".to_string())
+        );
+
+        // readers move on to the newer message
+        chat_area.mark_seen("bob", "a2");
+        chat_area.mark_seen("carol", "a2");
+        let lines = chat_area.get_draw_lines();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[2].1, Some(1));
+    }
+
+    #[test]
+    fn test_transfer_progress() {
+        assert_eq!(progress_bar(0, 100), format!("[{}]   0%", " ".repeat(20)));
+        assert_eq!(progress_bar(50, 100), format!("[{}{}]  50%", "#".repeat(10), " ".repeat(10)));
+        assert_eq!(progress_bar(0, 0), format!("[{}] 100%", "#".repeat(20)));
+        assert_eq!(format_size(1536), "1.5 KiB");
+
+        let mut chat_area = ChatArea::with_messages(vec![]);
+        let mut progress = Progress {
+            id: "f1".to_string(),
+            user_id: "bob".to_string(),
+            outgoing: true,
+            name: "notes.txt".to_string(),
+            size: 100,
+            done: 0,
+            sha256: String::new(),
+            path: "notes.txt".into(),
+            status: TransferStatus::Sending,
+        };
+        assert!(chat_area.set_transfer(progress.clone()));
+
+        progress.done = 100;
+        progress.status = TransferStatus::Complete;
+        assert!(!chat_area.set_transfer(progress));
+        assert_eq!(chat_area.entries.len(), 1);
+    }
+}
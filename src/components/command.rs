@@ -9,15 +9,28 @@ use crate::components::{
 use crate::styles;
 
 pub struct CommandComponent {
-    commands: Vec<Command>
+    commands: Vec<Command>,
+    /// connection status drawn after the commands
+    status: Option<(String, bool)>
 }
 
 impl CommandComponent {
     pub fn new() -> Self {
         CommandComponent {
             commands: Vec::new(),
+            status: None,
         }
     }
+
+    pub fn set_status(&mut self, label: String, connected: bool) {
+        self.status = Some((label, connected));
+    }
+}
+
+impl Default for CommandComponent {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl DrawableComponent for CommandComponent {
@@ -34,6 +47,14 @@ impl DrawableComponent for CommandComponent {
             }
         }
 
+        if let Some((label, connected)) = &self.status {
+            command_spans.push(Span::raw(" "));
+            command_spans.push(Span::styled(
+                format!(" {} ", label),
+                styles::status_style(*connected)
+            ));
+        }
+
         let command_bar = Paragraph::new(Spans(command_spans))
             .alignment(Alignment::Left);
 
@@ -0,0 +1,219 @@
+use std::collections::{ BTreeMap, BTreeSet, HashMap };
+use tui::backend::Backend;
+use tui::Frame;
+use tui::layout::Rect;
+use tui::text::{Span, Spans};
+use tui::widgets::{
+    Block, Borders, BorderType, List, ListItem
+};
+use crate::{
+    common::app_event::AppEvent,
+    net::{
+        discovery::Peer,
+        protocol::Presence
+    },
+    styles
+};
+use crate::components::{
+    BaseComponent, DrawableComponent,
+    userinfo::PRESENCE_DOT
+};
+
+/// `ContactList` shows the rooms we joined, then the peers found on the
+/// local network and the users the relay told us about, with their presence
+#[derive(Default)]
+pub struct ContactList {
+    peers: Vec<Peer>,
+    /// user id to name and presence, as reported by the relay
+    presence: HashMap<String, (String, Presence)>,
+    /// members of the rooms we are in
+    rooms: BTreeMap<String, BTreeSet<String>>
+}
+
+impl ContactList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `peer`, or updates it if it is already listed
+    pub fn add_peer(&mut self, peer: Peer) {
+        match self.peers.iter_mut().find(|p| p.user_id == peer.user_id) {
+            Some(known) => *known = peer,
+            None => {
+                self.peers.push(peer);
+                self.peers.sort_by(|a, b| a.user_name.cmp(&b.user_name));
+            }
+        }
+    }
+
+    pub fn remove_peer(&mut self, user_id: &str) {
+        self.peers.retain(|peer| peer.user_id != user_id);
+    }
+
+    pub fn peers(&self) -> &[Peer] {
+        &self.peers
+    }
+
+    /// Name of a peer or of a user the relay told us about
+    pub fn name(&self, user_id: &str) -> Option<&str> {
+        self.peers.iter()
+            .find(|peer| peer.user_id == user_id)
+            .map(|peer| peer.user_name.as_str())
+            .or_else(|| self.presence.get(user_id).map(|(user_name, _)| user_name.as_str()))
+    }
+
+    pub fn set_presence(&mut self, user_id: &str, user_name: &str, presence: Presence) {
+        self.presence.insert(user_id.to_string(), (user_name.to_string(), presence));
+    }
+
+    /// Everyone is offline once we lose the relay, LAN peers aside
+    pub fn clear_presence(&mut self) {
+        for (_, presence) in self.presence.values_mut() {
+            *presence = Presence::Offline;
+        }
+    }
+
+    pub fn join_room(&mut self, room: &str, user_id: &str) {
+        self.rooms.entry(room.to_string()).or_default().insert(user_id.to_string());
+    }
+
+    pub fn leave_room(&mut self, room: &str, user_id: &str) {
+        if let Some(members) = self.rooms.get_mut(room) {
+            members.remove(user_id);
+        }
+    }
+
+    /// Forgets a room we left
+    pub fn remove_room(&mut self, room: &str) {
+        self.rooms.remove(room);
+    }
+
+    /// The relay tells us the rooms again when we reconnect
+    pub fn clear_rooms(&mut self) {
+        self.rooms.clear();
+    }
+
+    pub fn members(&self, room: &str) -> Option<&BTreeSet<String>> {
+        self.rooms.get(room)
+    }
+
+    /// `(user_name, user_id, presence)` of every contact, sorted by name.
+    /// Peers on the LAN are online unless the relay says otherwise.
+    pub fn contacts(&self) -> Vec<(&str, &str, Presence)> {
+        let mut contacts: Vec<(&str, &str, Presence)> = self.peers.iter()
+            .map(|peer| {
+                let presence = match self.presence.get(&peer.user_id) {
+                    Some((_, Presence::Away)) => Presence::Away,
+                    _ => Presence::Online,
+                };
+                (peer.user_name.as_str(), peer.user_id.as_str(), presence)
+            })
+            .collect();
+
+        contacts.extend(self.presence.iter()
+            .filter(|(user_id, _)| !self.peers.iter().any(|peer| peer.user_id == **user_id))
+            .map(|(user_id, (user_name, presence))| (user_name.as_str(), user_id.as_str(), *presence)));
+        contacts.sort_by(|a, b| a.0.cmp(b.0).then(a.1.cmp(b.1)));
+
+        contacts
+    }
+}
+
+impl BaseComponent for ContactList {
+    fn event(&mut self, _event: AppEvent) -> Result<bool, ()> {
+        Ok(false)
+    }
+}
+
+impl DrawableComponent for ContactList {
+    fn draw<B: Backend>(&mut self, f: &mut Frame<B>, area: Rect) {
+        let block = Block::default()
+            .title("List")
+            .border_type(BorderType::Plain)
+            .borders(Borders::ALL)
+            .border_style(styles::border_style(false));
+
+        let mut items: Vec<ListItem> = vec![];
+        if !self.rooms.is_empty() {
+            items.push(ListItem::new(Span::styled("Rooms", styles::section_style())));
+            items.extend(self.rooms.iter().map(|(room, members)| ListItem::new(Spans::from(vec![
+                Span::styled(room.as_str(), styles::user_name_style()),
+                Span::styled(format!(" ({})", members.len()), styles::user_id_style()),
+            ]))));
+            items.push(ListItem::new(Span::styled("Direct messages", styles::section_style())));
+        }
+
+        items.extend(self.contacts().into_iter()
+            .map(|(user_name, user_id, presence)| ListItem::new(vec![
+                Spans::from(vec![
+                    Span::styled(PRESENCE_DOT, styles::presence_style(presence)),
+                    Span::raw(" "),
+                    Span::styled(user_name, styles::user_name_style()),
+                ]),
+                Spans::from(Span::styled(format!("  {}", user_id), styles::user_id_style())),
+            ])));
+
+        f.render_widget(List::new(items).block(block), area);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use super::*;
+
+    fn peer(user_id: &str, user_name: &str) -> Peer {
+        Peer {
+            user_id: user_id.to_string(),
+            user_name: user_name.to_string(),
+            addr: SocketAddr::from(([10, 0, 0, 5], 7879)),
+        }
+    }
+
+    #[test]
+    fn test_add_and_remove_peers() {
+        let mut list = ContactList::new();
+        list.add_peer(peer("bob-1", "bob"));
+        list.add_peer(peer("alice-1", "alice"));
+        list.add_peer(peer("bob-1", "bobby"));
+
+        let names: Vec<&str> = list.peers().iter().map(|p| p.user_name.as_str()).collect();
+        assert_eq!(names, vec!["alice", "bobby"]);
+
+        list.remove_peer("alice-1");
+        assert_eq!(list.peers().len(), 1);
+        assert_eq!(list.peers()[0].user_id, "bob-1");
+    }
+
+    #[test]
+    fn test_contacts_with_presence() {
+        let mut list = ContactList::new();
+        list.add_peer(peer("bob-1", "bob"));
+        list.set_presence("carol-1", "carol", Presence::Away);
+        list.set_presence("alice-1", "alice", Presence::Offline);
+        list.set_presence("bob-1", "bob", Presence::Offline);
+
+        assert_eq!(list.contacts(), vec![
+            ("alice", "alice-1", Presence::Offline),
+            ("bob", "bob-1", Presence::Online),
+            ("carol", "carol-1", Presence::Away),
+        ]);
+
+        list.clear_presence();
+        assert_eq!(list.contacts()[2], ("carol", "carol-1", Presence::Offline));
+    }
+
+    #[test]
+    fn test_rooms() {
+        let mut list = ContactList::new();
+        list.join_room("#rust", "alice");
+        list.join_room("#rust", "bob");
+        list.leave_room("#rust", "alice");
+
+        assert_eq!(list.members("#rust").unwrap().len(), 1);
+        assert!(list.members("#go").is_none());
+
+        list.remove_room("#rust");
+        assert!(list.members("#rust").is_none());
+    }
+}
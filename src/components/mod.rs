@@ -4,6 +4,8 @@ pub mod user_registration;
 pub mod command;
 pub mod userinfo;
 pub mod chat_area;
+pub mod contact_list;
+pub mod typing_indicator;
 
 use tui::{
     backend::Backend,
@@ -15,9 +17,10 @@ use crate::common::app_event::AppEvent;
 /// `BaseComponent` trait defines the methods that should be
 /// declare in component
 pub trait BaseComponent {
+    #[allow(clippy::result_unit_err)]
     fn event(&mut self, event: AppEvent) -> Result<bool, ()>;
 
-    fn set_focus(&mut self, focus: bool) {
+    fn set_focus(&mut self, _focus: bool) {
         // unimplemented!()
     }
 
@@ -2,7 +2,7 @@ use crossterm::event::{
     Event, KeyCode, KeyModifiers
 };
 use crate::{
-    BaseComponent,
+    components::BaseComponent,
     common::app_event::AppEvent
 };
 
@@ -120,21 +120,19 @@ impl TextEditor {
 
 impl BaseComponent for TextEditor {
     fn event(&mut self, event: AppEvent) -> Result<bool, ()> {
-        if let AppEvent::InputEvent(evt) = event {
-            if let Event::Key(ke) = evt {
-                return match ke.code {
-                    KeyCode::Left => Ok(self.decr_cursor()),
-                    KeyCode::Right => Ok(self.incr_cursor()),
-                    KeyCode::Char(c) if !ke.modifiers.contains(KeyModifiers::CONTROL) => {
-                        self.insert(c);
-                        Ok(true)
-                    },
-                    KeyCode::Backspace => Ok(self.backspace()),
-                    KeyCode::Home => Ok(self.home()),
-                    KeyCode::End => Ok(self.end()),
-                    KeyCode::Delete => Ok(self.delete()),
-                    _ => Ok(false)
-                }
+        if let AppEvent::InputEvent(Event::Key(ke)) = event {
+            return match ke.code {
+                KeyCode::Left => Ok(self.decr_cursor()),
+                KeyCode::Right => Ok(self.incr_cursor()),
+                KeyCode::Char(c) if !ke.modifiers.contains(KeyModifiers::CONTROL) => {
+                    self.insert(c);
+                    Ok(true)
+                },
+                KeyCode::Backspace => Ok(self.backspace()),
+                KeyCode::Home => Ok(self.home()),
+                KeyCode::End => Ok(self.end()),
+                KeyCode::Delete => Ok(self.delete()),
+                _ => Ok(false)
             }
         }
 
@@ -321,16 +319,16 @@ mod tests {
         let mut text_editor = TextEditor::from(String::from("नमुना मजकूर"));
 
         let ke_right = AppEvent::InputEvent(Event::Key(KeyEvent::from(KeyCode::Right)));
-        assert_eq!(text_editor.event(ke_right), Ok(true));
+        assert_eq!(text_editor.event(ke_right.clone()), Ok(true));
         assert_eq!(text_editor.event(ke_right), Ok(true));
 
         assert_eq!(text_editor.cur_pos, 6);
 
         let ke_left = AppEvent::InputEvent(Event::Key(KeyEvent::from(KeyCode::Left)));
-        assert_eq!(text_editor.event(ke_left), Ok(true));
+        assert_eq!(text_editor.event(ke_left.clone()), Ok(true));
         assert_eq!(text_editor.cur_pos, 3);
 
-        assert_eq!(text_editor.event(ke_left), Ok(true));
+        assert_eq!(text_editor.event(ke_left.clone()), Ok(true));
         assert_eq!(text_editor.cur_pos, 0);
 
         assert_eq!(text_editor.event(ke_left), Ok(false));
@@ -358,7 +356,7 @@ mod tests {
         let ke_backspace = AppEvent::InputEvent(
             Event::Key(KeyEvent::from(KeyCode::Backspace))
         );
-        assert_eq!(text_editor.event(ke_backspace), Ok(false));
+        assert_eq!(text_editor.event(ke_backspace.clone()), Ok(false));
 
         let ke_home = AppEvent::InputEvent(Event::Key(KeyEvent::from(KeyCode::End)));
         assert_eq!(text_editor.event(ke_home), Ok(true));
@@ -386,12 +384,12 @@ mod tests {
         let mut text_editor = TextEditor::from(String::from("Hello"));
 
         let ke_delete = AppEvent::InputEvent(Event::Key(KeyEvent::from(KeyCode::Delete)));
-        assert_eq!(text_editor.event(ke_delete), Ok(true));
+        assert_eq!(text_editor.event(ke_delete.clone()), Ok(true));
         assert_eq!(text_editor.text, String::from("ello"));
 
         let ke_right = AppEvent::InputEvent(Event::Key(KeyEvent::from(KeyCode::Right)));
-        assert_eq!(text_editor.event(ke_right), Ok(true));
-        assert_eq!(text_editor.event(ke_right), Ok(true));
+        assert_eq!(text_editor.event(ke_right.clone()), Ok(true));
+        assert_eq!(text_editor.event(ke_right.clone()), Ok(true));
         assert_eq!(text_editor.event(ke_right), Ok(true));
         assert_eq!(text_editor.event(ke_delete), Ok(true));
         assert_eq!(text_editor.text, String::from("ell"));
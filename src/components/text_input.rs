@@ -76,9 +76,9 @@ impl TextInput {
         self.editor.cur_pos = 0;
     }
 
-    fn get_draw_text(&self) -> Option<Vec<Span>> {
+    fn get_draw_text(&self) -> Option<Vec<Span<'_>>> {
         // no text
-        if self.get_text().len() ==  0 {
+        if self.get_text().is_empty() {
             return None
         }
 
@@ -106,6 +106,12 @@ impl TextInput {
     }
 }
 
+impl Default for TextInput {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl BaseComponent for TextInput {
     fn event(&mut self, event: AppEvent) -> Result<bool, ()> {
         if !self.focus {
@@ -0,0 +1,111 @@
+use std::{
+    collections::HashMap,
+    time::{ Duration, Instant },
+};
+use tui::backend::Backend;
+use tui::Frame;
+use tui::layout::Rect;
+use tui::text::Span;
+use tui::widgets::Paragraph;
+use crate::{
+    common::app_event::AppEvent,
+    styles
+};
+use crate::components::{
+    BaseComponent, DrawableComponent
+};
+
+/// A typing user is forgotten if no update arrives for this long
+pub const TYPING_TIMEOUT: Duration = Duration::from_secs(6);
+
+/// `TypingIndicator` shows who is typing in the current conversation
+#[derive(Default)]
+pub struct TypingIndicator {
+    typing: HashMap<String, Instant>,
+}
+
+impl TypingIndicator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn typing(&mut self, user_id: &str, now: Instant) {
+        self.typing.insert(user_id.to_string(), now + TYPING_TIMEOUT);
+    }
+
+    pub fn stopped(&mut self, user_id: &str) {
+        self.typing.remove(user_id);
+    }
+
+    pub fn clear(&mut self) {
+        self.typing.clear();
+    }
+
+    /// Forgets the users whose indicator timed out, returns `true` if any
+    pub fn expire(&mut self, now: Instant) -> bool {
+        let count = self.typing.len();
+        self.typing.retain(|_, expires_at| *expires_at > now);
+
+        count != self.typing.len()
+    }
+
+    pub fn label(&self) -> Option<String> {
+        let mut users: Vec<&str> = self.typing.keys().map(String::as_str).collect();
+        users.sort_unstable();
+
+        match users.len() {
+            0 => None,
+            1 => Some(format!("{} is typing\u{2026}", users[0])),
+            _ => Some(format!("{} are typing\u{2026}", users.join(", "))),
+        }
+    }
+}
+
+impl BaseComponent for TypingIndicator {
+    fn event(&mut self, _event: AppEvent) -> Result<bool, ()> {
+        Ok(false)
+    }
+}
+
+impl DrawableComponent for TypingIndicator {
+    fn draw<B: Backend>(&mut self, f: &mut Frame<B>, area: Rect) {
+        if let Some(label) = self.label() {
+            let paragraph = Paragraph::new(Span::styled(label, styles::typing_style()));
+            f.render_widget(paragraph, area);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_label() {
+        let mut indicator = TypingIndicator::new();
+        let now = Instant::now();
+        assert_eq!(indicator.label(), None);
+
+        indicator.typing("bob", now);
+        assert_eq!(indicator.label(), Some("bob is typing\u{2026}".to_string()));
+
+        indicator.typing("alice", now);
+        assert_eq!(indicator.label(), Some("alice, bob are typing\u{2026}".to_string()));
+
+        indicator.stopped("alice");
+        assert_eq!(indicator.label(), Some("bob is typing\u{2026}".to_string()));
+    }
+
+    #[test]
+    fn test_expire() {
+        let mut indicator = TypingIndicator::new();
+        let now = Instant::now();
+
+        indicator.typing("bob", now);
+        assert!(!indicator.expire(now + Duration::from_secs(1)));
+
+        indicator.typing("alice", now + Duration::from_secs(2));
+        assert!(indicator.expire(now + TYPING_TIMEOUT));
+        assert_eq!(indicator.label(), Some("alice is typing\u{2026}".to_string()));
+    }
+}
@@ -25,6 +25,8 @@ use crate::{
         command_keys::CommandKeys,
         app_event::{ AppEvent, Notification },
     },
+    crypto::{ KeyPair, SigningKeyPair },
+    net::protocol,
     styles,
     db::{ self, models::UserInfo }
 };
@@ -35,7 +37,10 @@ pub struct UserRegistration {
     command_keys: Rc<CommandKeys>,
     tx_notification: Sender<AppEvent>,
     focus: bool,
-    err_msg: Option<String>
+    err_msg: Option<String>,
+    /// generated when the details are saved
+    key_pair: Option<KeyPair>,
+    signing_key: Option<SigningKeyPair>
 }
 
 impl UserRegistration {
@@ -74,7 +79,9 @@ impl UserRegistration {
             command_keys: Rc::clone(&command_keys),
             tx_notification,
             err_msg: Some("Please enter name".to_string()),
-            focus: true
+            focus: true,
+            key_pair: None,
+            signing_key: None
         }
     }
 
@@ -103,7 +110,9 @@ impl UserRegistration {
         let name_text = self.name.get_text();
         let userid_text = self.userid.get_text();
 
-        if name_text.trim().len() > 0 && userid_text.trim().len() > 0 {
+        // users of other relays are addressed as userid@relay
+        let separator = protocol::RELAY_SEPARATOR;
+        if name_text.trim().len() > 0 && userid_text.trim().len() > 0 && !userid_text.contains(separator) {
             self.err_msg = None;
             return;
         }
@@ -112,10 +121,15 @@ impl UserRegistration {
             self.err_msg = Some("Please enter name".to_string());
         } else if userid_text.trim().len() == 0 {
             self.err_msg = Some("Please enter userid".to_string());
+        } else {
+            self.err_msg = Some(format!("userid can not contain {}", separator));
         }
     }
 
-    fn save_user_details(&self) -> io::Result<()> {
+    fn save_user_details(&mut self) -> io::Result<()> {
+        self.key_pair = Some(KeyPair::generate());
+        self.signing_key = Some(SigningKeyPair::generate());
+
         let user_info = self.get_user_info();
         db::operations::save_user_details(user_info)
     }
@@ -124,7 +138,10 @@ impl UserRegistration {
         UserInfo {
             user_name: self.name.get_text().to_string(),
             user_id: self.userid.get_text().to_string(),
-            joined_at: "".to_string()
+            joined_at: "".to_string(),
+            public_key: self.key_pair.as_ref().map(KeyPair::public_hex).unwrap_or_default(),
+            secret_key: self.key_pair.as_ref().map(KeyPair::secret_hex).unwrap_or_default(),
+            signing_key: self.signing_key.as_ref().map(SigningKeyPair::secret_hex).unwrap_or_default()
         }
     }
 }
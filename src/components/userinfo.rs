@@ -6,21 +6,28 @@ use tui::{
         Layout, Rect, Direction, Constraint
     },
 };
+use tui::text::{ Span, Spans };
 use tui::widgets::Paragraph;
 use crate::{
     db::models::UserInfo,
-    DrawableComponent,
+    components::DrawableComponent,
+    net::protocol::Presence,
     styles
 };
 
+/// Dot drawn in the presence colour next to user names
+pub const PRESENCE_DOT: &str = "\u{25cf}";
+
 pub struct UserInfoComponent {
-    user_info: Rc<UserInfo>
+    user_info: Rc<UserInfo>,
+    presence: Presence
 }
 
 impl UserInfoComponent {
-    pub fn new(user_info: Rc<UserInfo>) -> Self {
+    pub fn new(user_info: Rc<UserInfo>, presence: Presence) -> Self {
         UserInfoComponent {
-            user_info
+            user_info,
+            presence
         }
     }
 }
@@ -38,13 +45,15 @@ impl DrawableComponent for UserInfoComponent {
 
         let user_icon = "";
         let user_name_str = format!("{} {}", user_icon, self.user_info.user_name);
-        let user_name = Paragraph::new(
+        let user_name = Paragraph::new(Spans::from(vec![
+            Span::styled(PRESENCE_DOT, styles::presence_style(self.presence)),
+            Span::raw(" "),
             // self.user_info.user_name.as_str()
-            user_name_str
-        ).style(styles::user_name_style());
+            Span::styled(user_name_str, styles::user_name_style()),
+        ]));
         let user_id = Paragraph::new(
             self.user_info.user_id.as_str()
-        ).style(styles::user_id_style());;
+        ).style(styles::user_id_style());
 
         f.render_widget(user_name, ver_layout[0]);
         f.render_widget(user_id, ver_layout[1]);
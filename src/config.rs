@@ -0,0 +1,128 @@
+use std::io;
+use crate::{
+    constants,
+    net::tls::TrustAnchor
+};
+
+/// `Config` holds the options the application was started with
+#[derive(Debug, PartialEq)]
+pub struct Config {
+    pub server_addr: String,
+    /// TCP port other clients can reach us on, 0 picks a free one
+    pub port: u16,
+    /// announce ourselves and look for peers on the LAN
+    pub discovery: bool,
+    /// talk TLS to the server, trusting certificates as given
+    pub tls: Option<TrustAnchor>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            server_addr: constants::DEFAULT_SERVER_ADDR.to_string(),
+            port: 0,
+            discovery: true,
+            tls: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_args() -> io::Result<Self> {
+        Self::parse(std::env::args().skip(1))
+    }
+
+    /// Parses command line options
+    ///
+    /// `--server <host:port>` address of the chat server to connect to, `ws://host:port` over WebSocket,
+    /// `udp://host:port` over UDP, `unix:///path` over a local Unix socket
+    /// `--port <port>` TCP port to listen on for peers
+    /// `--no-discovery` do not take part in LAN peer discovery
+    /// `--tls` connect to the server over TLS, trusting the public web CAs
+    /// `--ca <file>` connect over TLS, trusting the CA certificate in the PEM file
+    /// `--pin <sha256>` connect over TLS, trusting only the certificate with this fingerprint
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> io::Result<Self> {
+        let mut config = Config::default();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-s" | "--server" => config.server_addr = value_of(&arg, args.next())?,
+                "-p" | "--port" => {
+                    config.port = value_of(&arg, args.next())?
+                        .parse()
+                        .map_err(|_| io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("Invalid value for {}", arg)
+                        ))?;
+                },
+                "--no-discovery" => config.discovery = false,
+                "--tls" => config.tls = Some(TrustAnchor::WebPki),
+                "--ca" => config.tls = Some(TrustAnchor::CaFile(value_of(&arg, args.next())?)),
+                "--pin" => config.tls = Some(TrustAnchor::Pinned(value_of(&arg, args.next())?)),
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("Unknown option {}", arg)
+                    ));
+                }
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+pub(crate) fn value_of(arg: &str, value: Option<String>) -> io::Result<String> {
+    value.ok_or_else(|| io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("Missing value for {}", arg)
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> io::Result<Config> {
+        Config::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_default_config() {
+        assert_eq!(parse(&[]).unwrap(), Config::default());
+        assert_eq!(Config::default().server_addr, constants::DEFAULT_SERVER_ADDR);
+    }
+
+    #[test]
+    fn test_server_option() {
+        let config = parse(&["--server", "10.0.0.2:9000"]).unwrap();
+        assert_eq!(config.server_addr, "10.0.0.2:9000");
+
+        let config = parse(&["-s", "chat.local:7878"]).unwrap();
+        assert_eq!(config.server_addr, "chat.local:7878");
+    }
+
+    #[test]
+    fn test_peer_options() {
+        let config = parse(&["--port", "7879", "--no-discovery"]).unwrap();
+        assert_eq!(config.port, 7879);
+        assert!(!config.discovery);
+        assert!(Config::default().discovery);
+    }
+
+    #[test]
+    fn test_tls_options() {
+        assert_eq!(Config::default().tls, None);
+        assert_eq!(parse(&["--tls"]).unwrap().tls, Some(TrustAnchor::WebPki));
+        assert_eq!(parse(&["--ca", "ca.pem"]).unwrap().tls, Some(TrustAnchor::CaFile("ca.pem".to_string())));
+        assert_eq!(parse(&["--pin", "ab:cd"]).unwrap().tls, Some(TrustAnchor::Pinned("ab:cd".to_string())));
+    }
+
+    #[test]
+    fn test_invalid_options() {
+        assert!(parse(&["--server"]).is_err());
+        assert!(parse(&["--ca"]).is_err());
+        assert!(parse(&["--port", "seventy"]).is_err());
+        assert!(parse(&["--unknown"]).is_err());
+    }
+}
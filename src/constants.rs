@@ -1,3 +1,20 @@
+use std::time::Duration;
+
 pub const APP_NAME: &str = "oisg";
 pub const APP_VERSION: &str = "0.1.0";
-pub const DB_FILE_NAME: &str = "oisg.db";
\ No newline at end of file
+pub const DB_FILE_NAME: &str = "oisg.db";
+/// Directory under the app directory received files are saved in
+pub const DOWNLOADS_DIR_NAME: &str = "downloads";
+pub const DEFAULT_SERVER_ADDR: &str = "127.0.0.1:7878";
+pub const DEFAULT_LISTEN_ADDR: &str = "0.0.0.0:7878";
+/// Conversation of the chats sent to everyone on the relay
+pub const RELAY_CONVERSATION: &str = "*";
+/// Clients ping the server this often so it knows they are still there
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+/// The server drops clients it has not heard from for this long
+pub const PRESENCE_TIMEOUT: Duration = Duration::from_secs(30);
+/// `SETTINGS` key, "off" stops sending read receipts
+pub const SETTING_READ_RECEIPTS: &str = "READ_RECEIPTS";
+/// History is asked for from this long before the last received chat,
+/// for clocks of clients and server that do not agree
+pub const HISTORY_SYNC_MARGIN: Duration = Duration::from_secs(60);
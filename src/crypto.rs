@@ -0,0 +1,559 @@
+use std::{
+    collections::{ HashMap, HashSet },
+    fmt,
+};
+use chacha20poly1305::{
+    aead::{ Aead, KeyInit, Payload },
+    ChaCha20Poly1305, Key, Nonce
+};
+use ed25519_dalek::{ Signature, Signer, SigningKey, Verifier, VerifyingKey };
+use hkdf::Hkdf;
+use rand::{ rngs::OsRng, RngCore };
+use sha2::Sha256;
+use x25519_dalek::{ PublicKey, StaticSecret };
+use crate::net::protocol::{ self, Body, FilePart };
+
+pub const KEY_LEN: usize = 32;
+pub const NONCE_LEN: usize = 12;
+/// Salt of the conversation key derivation, changing it changes every key
+const KDF_SALT: &[u8] = b"oisg conversation key v1";
+/// Prefix of the signed challenges, so the signatures are good for nothing else
+const CHALLENGE_CONTEXT: &[u8] = b"oisg relay challenge v1";
+
+#[derive(Debug, PartialEq)]
+pub enum CryptoError {
+    /// we do not know the public key of the other side yet
+    UnknownKey(String),
+    /// the other side has no key, chats to it are only sent after `/plain`
+    Unencrypted(String),
+    /// the other side has a key but the message came unencrypted, anyone
+    /// on the way may have written it
+    Plaintext(String),
+    /// the message was tampered with or sealed for someone else
+    InvalidMessage,
+}
+
+impl fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CryptoError::UnknownKey(user_id) => write!(f, "no encryption key of {} yet", user_id),
+            CryptoError::Unencrypted(user_id) => {
+                write!(f, "{} can not decrypt, /plain to chat unencrypted", user_id)
+            },
+            CryptoError::Plaintext(user_id) => {
+                write!(f, "unencrypted message from {} refused, {} has an encryption key", user_id, user_id)
+            },
+            CryptoError::InvalidMessage => write!(f, "message could not be decrypted"),
+        }
+    }
+}
+
+/// The X25519 identity of the local user
+pub struct KeyPair {
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+impl KeyPair {
+    pub fn generate() -> Self {
+        Self::from_secret(StaticSecret::random_from_rng(OsRng))
+    }
+
+    /// Restores the key pair saved as hex, `None` if `secret` is malformed
+    pub fn from_hex(secret: &str) -> Option<Self> {
+        let bytes: [u8; KEY_LEN] = hex::decode(secret).ok()?.try_into().ok()?;
+        Some(Self::from_secret(StaticSecret::from(bytes)))
+    }
+
+    fn from_secret(secret: StaticSecret) -> Self {
+        let public = PublicKey::from(&secret);
+        KeyPair { secret, public }
+    }
+
+    pub fn secret_hex(&self) -> String {
+        hex::encode(self.secret.to_bytes())
+    }
+
+    pub fn public_hex(&self) -> String {
+        hex::encode(self.public.as_bytes())
+    }
+
+    pub fn public_bytes(&self) -> Vec<u8> {
+        self.public.as_bytes().to_vec()
+    }
+}
+
+/// The Ed25519 identity of the local user, proves to the relay that a
+/// user id is ours
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SigningKeyPair {
+    key: SigningKey,
+}
+
+impl SigningKeyPair {
+    pub fn generate() -> Self {
+        let mut secret = [0u8; KEY_LEN];
+        OsRng.fill_bytes(&mut secret);
+        Self::from_bytes(secret)
+    }
+
+    pub fn from_bytes(secret: [u8; KEY_LEN]) -> Self {
+        SigningKeyPair {
+            key: SigningKey::from_bytes(&secret),
+        }
+    }
+
+    /// Restores the key pair saved as hex, `None` if `secret` is malformed
+    pub fn from_hex(secret: &str) -> Option<Self> {
+        let bytes: [u8; KEY_LEN] = hex::decode(secret).ok()?.try_into().ok()?;
+        Some(Self::from_bytes(bytes))
+    }
+
+    pub fn secret_hex(&self) -> String {
+        hex::encode(self.key.to_bytes())
+    }
+
+    pub fn public_bytes(&self) -> Vec<u8> {
+        self.key.verifying_key().to_bytes().to_vec()
+    }
+
+    /// Signs the `nonce` a relay challenged `user_id` with
+    pub fn sign_challenge(&self, user_id: &str, nonce: &[u8]) -> Vec<u8> {
+        self.key.sign(&challenge(user_id, nonce)).to_bytes().to_vec()
+    }
+}
+
+/// Whether `signature` is the answer of the owner of `public_key` to the
+/// challenge `nonce` sent to `user_id`
+pub fn verify_challenge(public_key: &[u8], user_id: &str, nonce: &[u8], signature: &[u8]) -> bool {
+    let key = match public_key.try_into().ok().and_then(|bytes| VerifyingKey::from_bytes(bytes).ok()) {
+        Some(key) => key,
+        None => return false,
+    };
+    let signature = match Signature::from_slice(signature) {
+        Ok(signature) => signature,
+        Err(_) => return false,
+    };
+
+    key.verify(&challenge(user_id, nonce), &signature).is_ok()
+}
+
+fn challenge(user_id: &str, nonce: &[u8]) -> Vec<u8> {
+    let mut message = CHALLENGE_CONTEXT.to_vec();
+    message.push(b'\n');
+    message.extend_from_slice(user_id.as_bytes());
+    message.push(b'\n');
+    message.extend_from_slice(nonce);
+    message
+}
+
+/// Key shared by the two users of a conversation, derived from their
+/// X25519 keys and user ids so every pair of users gets its own
+pub struct ConversationKey {
+    cipher: ChaCha20Poly1305,
+}
+
+impl ConversationKey {
+    pub fn derive(own: &KeyPair, own_user_id: &str, their_key: &PublicKey, their_user_id: &str) -> Self {
+        let shared = own.secret.diffie_hellman(their_key);
+
+        let mut users = [own_user_id, their_user_id];
+        users.sort_unstable();
+        let info = users.join("\n");
+
+        let mut key = [0u8; KEY_LEN];
+        Hkdf::<Sha256>::new(Some(KDF_SALT), shared.as_bytes())
+            .expand(info.as_bytes(), &mut key)
+            .expect("32 bytes is a valid hkdf output length");
+
+        ConversationKey {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&key)),
+        }
+    }
+
+    /// Encrypts `text`, `aad` is authenticated but sent in the clear
+    pub fn seal(&self, text: &str, aad: &[u8]) -> Body {
+        let (nonce, ciphertext) = self.encrypt(text.as_bytes(), aad);
+        Body::Sealed { nonce, ciphertext }
+    }
+
+    pub fn open(&self, nonce: &[u8], ciphertext: &[u8], aad: &[u8]) -> Result<String, CryptoError> {
+        let text = self.decrypt(nonce, ciphertext, aad)?;
+        String::from_utf8(text).map_err(|_| CryptoError::InvalidMessage)
+    }
+
+    /// Encrypts `data` with a fresh nonce, returns the nonce and the ciphertext
+    fn encrypt(&self, data: &[u8], aad: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let nonce: [u8; NONCE_LEN] = rand::random();
+        let ciphertext = self.cipher
+            .encrypt(Nonce::from_slice(&nonce), Payload { msg: data, aad })
+            .expect("encrypting to memory does not fail");
+
+        (nonce.to_vec(), ciphertext)
+    }
+
+    fn decrypt(&self, nonce: &[u8], ciphertext: &[u8], aad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        if nonce.len() != NONCE_LEN {
+            return Err(CryptoError::InvalidMessage);
+        }
+
+        self.cipher
+            .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+            .map_err(|_| CryptoError::InvalidMessage)
+    }
+}
+
+/// `Keyring` holds our key pair and the public keys of the other users,
+/// and seals or opens the chats of each conversation
+pub struct Keyring {
+    user_id: String,
+    key_pair: KeyPair,
+    public_keys: HashMap<String, PublicKey>,
+    /// users announced without a key, like IRC users of the relay
+    guests: HashSet<String>,
+    /// guests we chose to chat with unencrypted
+    plain: HashSet<String>,
+    conversations: HashMap<String, ConversationKey>,
+    /// name of our relay, users of other relays know us as `user_id@relay`
+    relay: String,
+}
+
+impl Keyring {
+    pub fn new(user_id: &str, key_pair: KeyPair) -> Self {
+        Keyring {
+            user_id: user_id.to_string(),
+            key_pair,
+            public_keys: HashMap::new(),
+            guests: HashSet::new(),
+            plain: HashSet::new(),
+            conversations: HashMap::new(),
+            relay: String::new(),
+        }
+    }
+
+    /// Sets the name of the relay we are on, as it welcomed us
+    pub fn set_relay(&mut self, relay: &str) {
+        if self.relay != relay {
+            self.relay = relay.to_string();
+            self.conversations.retain(|user_id, _| !user_id.contains(protocol::RELAY_SEPARATOR));
+        }
+    }
+
+    pub fn key_pair(&self) -> &KeyPair {
+        &self.key_pair
+    }
+
+    /// Remembers the key of `user_id`, returns `true` if it replaced a
+    /// different one. An empty key marks a user that can not decrypt,
+    /// unless we know a key of the user already. Malformed keys are ignored.
+    pub fn add_public_key(&mut self, user_id: &str, key: &[u8]) -> bool {
+        if key.is_empty() {
+            // the relay must not be able to turn encryption off
+            if !self.has_key(user_id) {
+                self.guests.insert(user_id.to_string());
+            }
+            return false;
+        }
+
+        let bytes: [u8; KEY_LEN] = match key.try_into() {
+            Ok(bytes) => bytes,
+            Err(_) => return false,
+        };
+
+        let key = PublicKey::from(bytes);
+        self.guests.remove(user_id);
+        self.plain.remove(user_id);
+        match self.public_keys.insert(user_id.to_string(), key) {
+            Some(previous) if previous != key => {
+                self.conversations.remove(user_id);
+                true
+            },
+            _ => false,
+        }
+    }
+
+    pub fn has_key(&self, user_id: &str) -> bool {
+        self.public_keys.contains_key(user_id)
+    }
+
+    pub fn public_key(&self, user_id: &str) -> Option<Vec<u8>> {
+        self.public_keys.get(user_id).map(|key| key.as_bytes().to_vec())
+    }
+
+    /// Chats to `user_id` are sent unencrypted from now on, returns
+    /// `false` if the user has a key or was never announced without one
+    pub fn allow_plain(&mut self, user_id: &str) -> bool {
+        if !self.guests.contains(user_id) {
+            return false;
+        }
+
+        self.plain.insert(user_id.to_string());
+        true
+    }
+
+    /// Whether chats to `user_id` are sent unencrypted
+    pub fn is_plain(&self, user_id: &str) -> bool {
+        self.plain.contains(user_id)
+    }
+
+    /// Whether we know a key of `user_id` and it is not `key`
+    pub fn has_other_key(&self, user_id: &str, key: &[u8]) -> bool {
+        self.public_keys.get(user_id).is_some_and(|known| known.as_bytes().as_slice() != key)
+    }
+
+    /// Encrypts a chat to `to`, bound to the message id and both users
+    pub fn seal(&mut self, id: &str, to: &str, text: &str) -> Result<Body, CryptoError> {
+        if self.plain.contains(to) {
+            return Ok(Body::Plain(text.to_string()));
+        }
+        if self.guests.contains(to) {
+            return Err(CryptoError::Unencrypted(to.to_string()));
+        }
+
+        let aad = Self::aad(id, &self.own_id_for(to), to);
+        Ok(self.conversation(to)?.seal(text, &aad))
+    }
+
+    /// Decrypts a chat `from` sent to us. Users with a key only send
+    /// sealed chats, unencrypted ones from them are forged.
+    pub fn open(&mut self, id: &str, from: &str, body: &Body) -> Result<String, CryptoError> {
+        match body {
+            Body::Plain(_) if self.has_key(from) => Err(CryptoError::Plaintext(from.to_string())),
+            Body::Plain(text) => Ok(text.clone()),
+            Body::Sealed { nonce, ciphertext } => {
+                let aad = Self::aad(id, from, &self.own_id_for(from));
+                self.conversation(from)?.open(nonce, ciphertext, &aad)
+            },
+        }
+    }
+
+    /// Encrypts a step of the file transfer `id` to `to`, like `seal`
+    pub fn seal_part(&mut self, id: &str, to: &str, part: FilePart) -> Result<FilePart, CryptoError> {
+        if self.plain.contains(to) {
+            return Ok(part);
+        }
+        if self.guests.contains(to) {
+            return Err(CryptoError::Unencrypted(to.to_string()));
+        }
+
+        let data = bincode::serialize(&part).map_err(|_| CryptoError::InvalidMessage)?;
+        let aad = Self::file_aad(id, &self.own_id_for(to), to);
+        let (nonce, ciphertext) = self.conversation(to)?.encrypt(&data, &aad);
+        Ok(FilePart::Sealed { nonce, ciphertext })
+    }
+
+    /// Decrypts a step of the file transfer `id` `from` sent us, like `open`
+    pub fn open_part(&mut self, id: &str, from: &str, part: FilePart) -> Result<FilePart, CryptoError> {
+        let (nonce, ciphertext) = match part {
+            FilePart::Sealed { nonce, ciphertext } => (nonce, ciphertext),
+            _ if self.has_key(from) => return Err(CryptoError::Plaintext(from.to_string())),
+            part => return Ok(part),
+        };
+
+        let aad = Self::file_aad(id, from, &self.own_id_for(from));
+        let data = self.conversation(from)?.decrypt(&nonce, &ciphertext, &aad)?;
+        match bincode::deserialize(&data) {
+            Ok(FilePart::Sealed { .. }) | Err(_) => Err(CryptoError::InvalidMessage),
+            Ok(part) => Ok(part),
+        }
+    }
+
+    fn conversation(&mut self, user_id: &str) -> Result<&ConversationKey, CryptoError> {
+        if !self.conversations.contains_key(user_id) {
+            let their_key = self.public_keys.get(user_id)
+                .ok_or_else(|| CryptoError::UnknownKey(user_id.to_string()))?;
+            let key = ConversationKey::derive(&self.key_pair, &self.own_id_for(user_id), their_key, user_id);
+            self.conversations.insert(user_id.to_string(), key);
+        }
+
+        Ok(&self.conversations[user_id])
+    }
+
+    /// Our user id as `user_id` knows it, with our relay for users of other relays
+    fn own_id_for(&self, user_id: &str) -> String {
+        match protocol::split_relay(user_id).1 {
+            Some(_) => format!("{}{}{}", self.user_id, protocol::RELAY_SEPARATOR, self.relay),
+            None => self.user_id.clone(),
+        }
+    }
+
+    fn aad(id: &str, from: &str, to: &str) -> Vec<u8> {
+        format!("{}\n{}\n{}", id, from, to).into_bytes()
+    }
+
+    /// Like `aad`, a sealed file part can not pass for a chat of the same id
+    fn file_aad(id: &str, from: &str, to: &str) -> Vec<u8> {
+        format!("file\n{}\n{}\n{}", id, from, to).into_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keyrings() -> (Keyring, Keyring) {
+        let mut alice = Keyring::new("alice", KeyPair::generate());
+        let mut bob = Keyring::new("bob", KeyPair::generate());
+
+        let alice_key = alice.key_pair().public_bytes();
+        assert!(!alice.add_public_key("bob", &bob.key_pair().public_bytes()));
+        assert!(!bob.add_public_key("alice", &alice_key));
+
+        (alice, bob)
+    }
+
+    #[test]
+    fn test_seal_and_open() {
+        let (mut alice, mut bob) = keyrings();
+
+        let body = alice.seal("m1", "bob", "secret plans").unwrap();
+        match &body {
+            Body::Sealed { ciphertext, .. } => {
+                assert!(!ciphertext.windows(6).any(|window| window == b"secret"));
+            },
+            other => panic!("expected sealed body, got {:?}", other),
+        }
+
+        assert_eq!(bob.open("m1", "alice", &body), Ok("secret plans".to_string()));
+        // the relay can not move the message to another id or sender
+        assert_eq!(bob.open("m2", "alice", &body), Err(CryptoError::InvalidMessage));
+        assert!(bob.open("m1", "carol", &body).is_err());
+    }
+
+    #[test]
+    fn test_users_of_other_relays() {
+        let mut alice = Keyring::new("alice", KeyPair::generate());
+        let mut bob = Keyring::new("bob", KeyPair::generate());
+        alice.set_relay("london");
+        bob.set_relay("berlin");
+        alice.add_public_key("bob@berlin", &bob.key_pair().public_bytes());
+        bob.add_public_key("alice@london", &alice.key_pair().public_bytes());
+
+        // each side names the other with its relay, the key is the same
+        let body = alice.seal("m1", "bob@berlin", "hi").unwrap();
+        assert_eq!(bob.open("m1", "alice@london", &body), Ok("hi".to_string()));
+        assert!(bob.open("m1", "alice@paris", &body).is_err());
+    }
+
+    #[test]
+    fn test_unknown_key() {
+        let (mut alice, _) = keyrings();
+
+        assert_eq!(alice.seal("m1", "carol", "hi"), Err(CryptoError::UnknownKey("carol".to_string())));
+        assert!(!alice.has_key("carol"));
+        assert!(!alice.add_public_key("carol", &[1, 2, 3]));
+        assert!(!alice.has_key("carol"));
+    }
+
+    #[test]
+    fn test_key_change() {
+        let (mut alice, mut bob) = keyrings();
+        let body = alice.seal("m1", "bob", "hi").unwrap();
+
+        // alice registered again with a new key, her old messages no longer open
+        let new_alice = KeyPair::generate();
+        assert!(bob.has_other_key("alice", &new_alice.public_bytes()));
+        assert!(!bob.has_other_key("alice", &alice.key_pair().public_bytes()));
+        assert!(!bob.has_other_key("carol", &new_alice.public_bytes()));
+        assert!(bob.add_public_key("alice", &new_alice.public_bytes()));
+        assert_eq!(bob.open("m1", "alice", &body), Err(CryptoError::InvalidMessage));
+    }
+
+    #[test]
+    fn test_key_pair_from_hex() {
+        let key_pair = KeyPair::generate();
+        let restored = KeyPair::from_hex(&key_pair.secret_hex()).unwrap();
+
+        assert_eq!(restored.public_hex(), key_pair.public_hex());
+        assert!(KeyPair::from_hex("not hex").is_none());
+        assert!(KeyPair::from_hex("abcd").is_none());
+    }
+
+    #[test]
+    fn test_signed_challenge() {
+        let key_pair = SigningKeyPair::generate();
+        let public_key = key_pair.public_bytes();
+        let signature = key_pair.sign_challenge("alice", b"nonce");
+
+        assert!(verify_challenge(&public_key, "alice", b"nonce", &signature));
+        // the signature is for this user id and nonce only
+        assert!(!verify_challenge(&public_key, "mallory", b"nonce", &signature));
+        assert!(!verify_challenge(&public_key, "alice", b"other", &signature));
+        assert!(!verify_challenge(&SigningKeyPair::generate().public_bytes(), "alice", b"nonce", &signature));
+        assert!(!verify_challenge(&public_key, "alice", b"nonce", &signature[1..]));
+
+        let restored = SigningKeyPair::from_hex(&key_pair.secret_hex()).unwrap();
+        assert_eq!(restored, key_pair);
+    }
+
+    #[test]
+    fn test_users_without_key() {
+        let (mut alice, _) = keyrings();
+
+        // an empty key is announced for users that can not decrypt, only
+        // chats we chose to send unencrypted go out
+        assert!(!alice.add_public_key("carol", &[]));
+        assert_eq!(alice.seal("m1", "carol", "hi"), Err(CryptoError::Unencrypted("carol".to_string())));
+        assert!(alice.allow_plain("carol"));
+        assert!(alice.is_plain("carol"));
+        assert_eq!(alice.seal("m1", "carol", "hi"), Ok(Body::Plain("hi".to_string())));
+        assert!(!alice.has_key("carol"));
+
+        assert!(!alice.allow_plain("dave"));
+        assert_eq!(alice.seal("m1", "dave", "hi"), Err(CryptoError::UnknownKey("dave".to_string())));
+
+        // a key ends the plain chats
+        assert!(!alice.add_public_key("carol", &KeyPair::generate().public_bytes()));
+        assert!(!alice.is_plain("carol"));
+        assert!(matches!(alice.seal("m2", "carol", "hi"), Ok(Body::Sealed { .. })));
+    }
+
+    #[test]
+    fn test_forged_plain_chat() {
+        let (_, mut bob) = keyrings();
+
+        // the relay can not pass off a chat of its own as alice's
+        let forged = Body::Plain("send me your password".to_string());
+        assert_eq!(bob.open("m1", "alice", &forged), Err(CryptoError::Plaintext("alice".to_string())));
+
+        // users without a key can only chat unencrypted
+        bob.add_public_key("carol", &[]);
+        assert_eq!(bob.open("m1", "carol", &Body::Plain("hi".to_string())), Ok("hi".to_string()));
+    }
+
+    #[test]
+    fn test_sealed_file_parts() {
+        let (mut alice, mut bob) = keyrings();
+        let offer = FilePart::Offer { name: "plans.txt".to_string(), size: 3, sha256: "00".repeat(32) };
+
+        let sealed = alice.seal_part("f1", "bob", offer.clone()).unwrap();
+        match &sealed {
+            FilePart::Sealed { ciphertext, .. } => {
+                assert!(!ciphertext.windows(5).any(|window| window == b"plans"));
+            },
+            other => panic!("expected sealed part, got {:?}", other),
+        }
+        assert_eq!(bob.open_part("f1", "alice", sealed.clone()), Ok(offer.clone()));
+        assert_eq!(bob.open_part("f2", "alice", sealed), Err(CryptoError::InvalidMessage));
+
+        // nor is a part sent in the clear taken from a user with a key
+        assert_eq!(bob.open_part("f1", "alice", offer.clone()), Err(CryptoError::Plaintext("alice".to_string())));
+
+        // guests get them unencrypted once we chose to
+        alice.add_public_key("carol", &[]);
+        assert_eq!(alice.seal_part("f1", "carol", offer.clone()), Err(CryptoError::Unencrypted("carol".to_string())));
+        alice.allow_plain("carol");
+        assert_eq!(alice.seal_part("f1", "carol", offer.clone()), Ok(offer));
+    }
+
+    #[test]
+    fn test_no_downgrade_to_plain() {
+        let (mut alice, _) = keyrings();
+
+        // bob has a key, the relay announcing him without one changes nothing
+        assert!(!alice.add_public_key("bob", &[]));
+        assert!(alice.has_key("bob"));
+        assert!(!alice.allow_plain("bob"));
+        assert!(matches!(alice.seal("m1", "bob", "hi"), Ok(Body::Sealed { .. })));
+    }
+}
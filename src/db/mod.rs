@@ -3,18 +3,34 @@ pub mod operations;
 pub mod models;
 
 use std::io;
+#[cfg(test)]
+use std::{ cell::RefCell, path::Path };
 
-use home;
-use sqlite;
 use crate::constants;
 
+#[cfg(test)]
+thread_local! {
+    /// database the tests of this thread use instead of the one of the user
+    static TEST_DB_PATH: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Runs `f` on the database at `path` instead of the one of the user
+#[cfg(test)]
+pub(crate) fn with_path<T>(path: &Path, f: impl FnOnce() -> T) -> T {
+    let previous = TEST_DB_PATH.with(|db_path| db_path.replace(Some(path.display().to_string())));
+    let result = f();
+    TEST_DB_PATH.with(|db_path| *db_path.borrow_mut() = previous);
+
+    result
+}
+
 pub fn ensure_db_exists() -> io::Result<()> {
     if !is_db_exists()? {
         create_db_file()?;
-        tables::create_all()?;
     }
 
-    Ok(())
+    // tables added in later versions are created in existing databases too
+    tables::create_all()
 }
 
 fn is_db_exists() -> io::Result<bool> {
@@ -36,11 +52,16 @@ pub fn create_db_file() -> io::Result<()> {
 }
 
 fn get_db_path() -> io::Result<(String, String)> {
+    #[cfg(test)]
+    if let Some(path) = TEST_DB_PATH.with(|db_path| db_path.borrow().clone()) {
+        let dir_path = Path::new(&path).parent().map(|dir| dir.display().to_string()).unwrap_or_default();
+        return Ok((dir_path, path));
+    }
+
     let home_dir = match home::home_dir() {
         Some(dir) => dir,
         None => {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
+            return Err(io::Error::other(
                 "Not able to get home directory"
             ))
         },
@@ -55,11 +76,15 @@ fn get_connection() -> io::Result<sqlite::Connection> {
     let connection = match sqlite::open(db_path) {
         Ok(conn) => conn,
         Err(e) => {
-            return Err(io::Error::new(
-                io::ErrorKind::Other, e.message.unwrap()
+            return Err(io::Error::other(
+                e.message.unwrap()
             ));
         }
     };
 
     Ok(connection)
-}
\ No newline at end of file
+}
+
+fn to_io_error(e: sqlite::Error) -> io::Error {
+    io::Error::other(e.to_string())
+}
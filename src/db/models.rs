@@ -3,4 +3,118 @@ pub struct UserInfo {
     pub user_name: String,
     pub user_id: String,
     pub joined_at: String,
+    /// X25519 key pair as hex, empty for users registered before encryption
+    pub public_key: String,
+    pub secret_key: String,
+    /// Ed25519 secret key as hex, proves the user id to relays
+    pub signing_key: String,
+}
+
+/// Delivery state of a message we sent
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DeliveryStatus {
+    /// stored locally, not written to the network yet
+    Pending,
+    Sent,
+    /// kept by the relay until the recipient is back
+    Stored,
+    /// the recipient has it, acknowledged by the relay or the peer
+    Delivered,
+    Failed,
+}
+
+impl DeliveryStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeliveryStatus::Pending => "PENDING",
+            DeliveryStatus::Sent => "SENT",
+            DeliveryStatus::Stored => "STORED",
+            DeliveryStatus::Delivered => "DELIVERED",
+            DeliveryStatus::Failed => "FAILED",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "PENDING" => Some(DeliveryStatus::Pending),
+            "SENT" => Some(DeliveryStatus::Sent),
+            "STORED" => Some(DeliveryStatus::Stored),
+            "DELIVERED" => Some(DeliveryStatus::Delivered),
+            "FAILED" => Some(DeliveryStatus::Failed),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct ChatMessage {
+    pub message_id: String,
+    /// `constants::RELAY_CONVERSATION` or the user id of a direct chat
+    pub conversation: String,
+    pub from_user: String,
+    pub message: String,
+    /// only set on our own messages
+    pub status: Option<DeliveryStatus>,
+    /// Lamport time the chat was sent at, 0 for chats stored before
+    pub clock: u64,
+}
+
+/// Chat waiting in the OUTBOX table until the server acknowledges it
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct OutboxMessage {
+    pub message_id: String,
+    pub to_user: Option<String>,
+    pub message: String,
+    pub clock: u64,
+}
+
+/// `user_id` has seen the chat message `message_id` in `conversation`
+/// and everything before it
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct ReadPosition {
+    pub conversation: String,
+    pub user_id: String,
+    pub message_id: String,
+}
+
+/// X25519 key a contact encrypts with, as hex
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct ContactKey {
+    pub user_id: String,
+    pub public_key: String,
+}
+
+/// State of a file transfer
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TransferStatus {
+    Sending,
+    Receiving,
+    /// the receiver has the file and its SHA-256 matched
+    Complete,
+    Failed,
+}
+
+impl TransferStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransferStatus::Sending => "SENDING",
+            TransferStatus::Receiving => "RECEIVING",
+            TransferStatus::Complete => "COMPLETE",
+            TransferStatus::Failed => "FAILED",
+        }
+    }
+}
+
+/// File sent or received in a conversation, stored in the ATTACHMENTS table
+#[derive(Debug, Clone, PartialEq)]
+pub struct Attachment {
+    pub transfer_id: String,
+    pub conversation: String,
+    pub from_user: String,
+    pub file_name: String,
+    pub file_size: u64,
+    pub sha256: String,
+    /// the file sent, or where the received one was saved
+    pub path: String,
+    pub status: TransferStatus,
 }
@@ -1,13 +1,16 @@
 use std::io;
 
-use crate::db::{
-    self,
-    models
+use crate::{
+    constants,
+    db::{
+        self,
+        models
+    }
 };
 
 pub fn get_user_info() -> io::Result<Option<models::UserInfo>> {
     let connection = db::get_connection()?;
-    let query = "SELECT USER_NAME, USER_ID, JOINED_AT FROM USER_INFO LIMIT 1";
+    let query = "SELECT USER_NAME, USER_ID, JOINED_AT, PUBLIC_KEY, SECRET_KEY, SIGNING_KEY FROM USER_INFO LIMIT 1";
 
     let mut found = false;
     let mut res = models::UserInfo::default();
@@ -18,6 +21,9 @@ pub fn get_user_info() -> io::Result<Option<models::UserInfo>> {
                 "USER_NAME" => res.user_name = val.unwrap().to_string(),
                 "USER_ID" => res.user_id = val.unwrap().to_string(),
                 "JOINED_AT" => res.joined_at = val.unwrap().to_string(),
+                "PUBLIC_KEY" => res.public_key = val.unwrap_or_default().to_string(),
+                "SECRET_KEY" => res.secret_key = val.unwrap_or_default().to_string(),
+                "SIGNING_KEY" => res.signing_key = val.unwrap_or_default().to_string(),
                 _ => {}
             }
         }
@@ -34,14 +40,332 @@ pub fn get_user_info() -> io::Result<Option<models::UserInfo>> {
 }
 
 pub fn save_user_details(user_info: models::UserInfo) -> io::Result<()> {
+    let query = "INSERT INTO USER_INFO (USER_NAME, USER_ID, PUBLIC_KEY, SECRET_KEY, SIGNING_KEY) VALUES (?, ?, ?, ?, ?)";
+
+    let connection = db::get_connection()?;
+    let mut statement = connection.prepare(query).map_err(db::to_io_error)?;
+    statement.bind(1, user_info.user_name.as_str()).map_err(db::to_io_error)?;
+    statement.bind(2, user_info.user_id.as_str()).map_err(db::to_io_error)?;
+    statement.bind(3, user_info.public_key.as_str()).map_err(db::to_io_error)?;
+    statement.bind(4, user_info.secret_key.as_str()).map_err(db::to_io_error)?;
+    statement.bind(5, user_info.signing_key.as_str()).map_err(db::to_io_error)?;
+    statement.next().map_err(db::to_io_error)?;
+
+    Ok(())
+}
+
+/// Stores the key pair of a user registered before encryption existed
+pub fn save_key_pair(user_id: &str, public_key: &str, secret_key: &str) -> io::Result<()> {
+    let query = "UPDATE USER_INFO SET PUBLIC_KEY = ?, SECRET_KEY = ? WHERE USER_ID = ?";
+
+    let connection = db::get_connection()?;
+    let mut statement = connection.prepare(query).map_err(db::to_io_error)?;
+    statement.bind(1, public_key).map_err(db::to_io_error)?;
+    statement.bind(2, secret_key).map_err(db::to_io_error)?;
+    statement.bind(3, user_id).map_err(db::to_io_error)?;
+    statement.next().map_err(db::to_io_error)?;
+
+    Ok(())
+}
+
+/// Stores the signing key of a user registered before relays checked user ids
+pub fn save_signing_key(user_id: &str, signing_key: &str) -> io::Result<()> {
+    let query = "UPDATE USER_INFO SET SIGNING_KEY = ? WHERE USER_ID = ?";
+
+    let connection = db::get_connection()?;
+    let mut statement = connection.prepare(query).map_err(db::to_io_error)?;
+    statement.bind(1, signing_key).map_err(db::to_io_error)?;
+    statement.bind(2, user_id).map_err(db::to_io_error)?;
+    statement.next().map_err(db::to_io_error)?;
+
+    Ok(())
+}
+
+pub fn save_message(message: &models::ChatMessage) -> io::Result<()> {
+    let query = "INSERT INTO MESSAGES (MESSAGE_ID, CONVERSATION, FROM_USER, MESSAGE, STATUS, CLOCK) \
+        VALUES (?, ?, ?, ?, ?, ?)";
+
+    let connection = db::get_connection()?;
+    let mut statement = connection.prepare(query).map_err(db::to_io_error)?;
+    statement.bind(1, message.message_id.as_str()).map_err(db::to_io_error)?;
+    statement.bind(2, message.conversation.as_str()).map_err(db::to_io_error)?;
+    statement.bind(3, message.from_user.as_str()).map_err(db::to_io_error)?;
+    statement.bind(4, message.message.as_str()).map_err(db::to_io_error)?;
+    match message.status {
+        Some(status) => statement.bind(5, status.as_str()),
+        None => statement.bind(5, ()),
+    }.map_err(db::to_io_error)?;
+    statement.bind(6, message.clock as i64).map_err(db::to_io_error)?;
+    statement.next().map_err(db::to_io_error)?;
+
+    Ok(())
+}
+
+/// Whether the message `message_id` is stored already
+pub fn has_message(message_id: &str) -> io::Result<bool> {
+    let query = "SELECT 1 FROM MESSAGES WHERE MESSAGE_ID = ? LIMIT 1";
+
+    let connection = db::get_connection()?;
+    let mut statement = connection.prepare(query).map_err(db::to_io_error)?;
+    statement.bind(1, message_id).map_err(db::to_io_error)?;
+
+    Ok(statement.next().map_err(db::to_io_error)? == sqlite::State::Row)
+}
+
+/// Unix time of the latest message others sent us, `None` if there is none
+pub fn get_last_received_time(user_id: &str) -> io::Result<Option<u64>> {
+    let query = "SELECT CAST(strftime('%s', MAX(RECEIVED_TIME)) AS INTEGER) FROM MESSAGES \
+        WHERE FROM_USER != ?";
+
+    let connection = db::get_connection()?;
+    let mut statement = connection.prepare(query).map_err(db::to_io_error)?;
+    statement.bind(1, user_id).map_err(db::to_io_error)?;
+    statement.next().map_err(db::to_io_error)?;
+
+    let time = statement.read::<Option<i64>>(0).map_err(db::to_io_error)?;
+    Ok(time.map(|time| time.max(0) as u64))
+}
+
+pub fn set_message_status(message_id: &str, status: models::DeliveryStatus) -> io::Result<()> {
+    let query = "UPDATE MESSAGES SET STATUS = ? WHERE MESSAGE_ID = ?";
+
+    let connection = db::get_connection()?;
+    let mut statement = connection.prepare(query).map_err(db::to_io_error)?;
+    statement.bind(1, status.as_str()).map_err(db::to_io_error)?;
+    statement.bind(2, message_id).map_err(db::to_io_error)?;
+    statement.next().map_err(db::to_io_error)?;
+
+    Ok(())
+}
+
+pub fn get_message_status(message_id: &str) -> io::Result<Option<models::DeliveryStatus>> {
+    let query = "SELECT STATUS FROM MESSAGES WHERE MESSAGE_ID = ?";
+
+    let connection = db::get_connection()?;
+    let mut statement = connection.prepare(query).map_err(db::to_io_error)?;
+    statement.bind(1, message_id).map_err(db::to_io_error)?;
+
+    match statement.next().map_err(db::to_io_error)? {
+        sqlite::State::Row => {
+            let status = statement.read::<Option<String>>(0).map_err(db::to_io_error)?;
+            Ok(status.as_deref().and_then(models::DeliveryStatus::parse))
+        },
+        sqlite::State::Done => Ok(None),
+    }
+}
+
+/// Latest Lamport time of the stored chats, 0 if there are none
+pub fn get_last_clock() -> io::Result<u64> {
+    let query = "SELECT MAX(CLOCK) FROM MESSAGES";
+
+    let connection = db::get_connection()?;
+    let mut statement = connection.prepare(query).map_err(db::to_io_error)?;
+    statement.next().map_err(db::to_io_error)?;
+
+    let clock = statement.read::<Option<i64>>(0).map_err(db::to_io_error)?;
+    Ok(clock.unwrap_or_default().max(0) as u64)
+}
+
+/// Returns the latest `limit` messages in the order they were sent,
+/// chats at the same Lamport time in the order they were stored
+pub fn get_messages(limit: usize) -> io::Result<Vec<models::ChatMessage>> {
     let query = format!(
-        "INSERT INTO USER_INFO (USER_NAME, USER_ID) VALUES ('{}', '{}')",
-        user_info.user_name, user_info.user_id
+        "SELECT MESSAGE_ID, CONVERSATION, FROM_USER, MESSAGE, STATUS, CLOCK FROM ( \
+            SELECT ROWID, MESSAGE_ID, CONVERSATION, FROM_USER, MESSAGE, STATUS, CLOCK FROM MESSAGES \
+            ORDER BY ROWID DESC LIMIT {} \
+        ) ORDER BY CLOCK ASC, ROWID ASC",
+        limit
     );
 
     let connection = db::get_connection()?;
-    return match connection.execute(query) {
-        Ok(_) => Ok(()),
-        Err(e) => Err(io::Error::new(io::ErrorKind::Other, e.message.unwrap()))
+    let mut statement = connection.prepare(query).map_err(db::to_io_error)?;
+
+    let mut messages = Vec::new();
+    while let sqlite::State::Row = statement.next().map_err(db::to_io_error)? {
+        let status = statement.read::<Option<String>>(4).map_err(db::to_io_error)?;
+        messages.push(models::ChatMessage {
+            // messages stored before ids and conversations existed have none
+            message_id: statement.read::<Option<String>>(0).map_err(db::to_io_error)?
+                .unwrap_or_default(),
+            conversation: statement.read::<Option<String>>(1).map_err(db::to_io_error)?
+                .unwrap_or_else(|| constants::RELAY_CONVERSATION.to_string()),
+            from_user: statement.read::<String>(2).map_err(db::to_io_error)?,
+            message: statement.read::<String>(3).map_err(db::to_io_error)?,
+            status: status.as_deref().and_then(models::DeliveryStatus::parse),
+            clock: statement.read::<i64>(5).map_err(db::to_io_error)?.max(0) as u64,
+        });
+    }
+
+    Ok(messages)
+}
+
+/// Queues a chat for the server, it stays queued until `remove_from_outbox`
+pub fn queue_message(message: &models::OutboxMessage) -> io::Result<()> {
+    let query = "INSERT OR IGNORE INTO OUTBOX (MESSAGE_ID, TO_USER, MESSAGE, CLOCK) VALUES (?, ?, ?, ?)";
+
+    let connection = db::get_connection()?;
+    let mut statement = connection.prepare(query).map_err(db::to_io_error)?;
+    statement.bind(1, message.message_id.as_str()).map_err(db::to_io_error)?;
+    match &message.to_user {
+        Some(to_user) => statement.bind(2, to_user.as_str()),
+        None => statement.bind(2, ()),
+    }.map_err(db::to_io_error)?;
+    statement.bind(3, message.message.as_str()).map_err(db::to_io_error)?;
+    statement.bind(4, message.clock as i64).map_err(db::to_io_error)?;
+    statement.next().map_err(db::to_io_error)?;
+
+    Ok(())
+}
+
+/// Returns the queued chats in the order they were queued
+pub fn get_outbox() -> io::Result<Vec<models::OutboxMessage>> {
+    let query = "SELECT MESSAGE_ID, TO_USER, MESSAGE, CLOCK FROM OUTBOX ORDER BY ROWID ASC";
+
+    let connection = db::get_connection()?;
+    let mut statement = connection.prepare(query).map_err(db::to_io_error)?;
+
+    let mut messages = Vec::new();
+    while let sqlite::State::Row = statement.next().map_err(db::to_io_error)? {
+        messages.push(models::OutboxMessage {
+            message_id: statement.read::<String>(0).map_err(db::to_io_error)?,
+            to_user: statement.read::<Option<String>>(1).map_err(db::to_io_error)?,
+            message: statement.read::<String>(2).map_err(db::to_io_error)?,
+            clock: statement.read::<i64>(3).map_err(db::to_io_error)?.max(0) as u64,
+        });
+    }
+
+    Ok(messages)
+}
+
+pub fn remove_from_outbox(message_id: &str) -> io::Result<()> {
+    let query = "DELETE FROM OUTBOX WHERE MESSAGE_ID = ?";
+
+    let connection = db::get_connection()?;
+    let mut statement = connection.prepare(query).map_err(db::to_io_error)?;
+    statement.bind(1, message_id).map_err(db::to_io_error)?;
+    statement.next().map_err(db::to_io_error)?;
+
+    Ok(())
+}
+
+/// Stores how far `user_id` has read `conversation`, replacing the
+/// previous position
+pub fn set_read_position(position: &models::ReadPosition) -> io::Result<()> {
+    let connection = db::get_connection()?;
+
+    let mut statement = connection
+        .prepare("UPDATE READ_POSITIONS SET MESSAGE_ID = ? WHERE CONVERSATION = ? AND USER_ID = ?")
+        .map_err(db::to_io_error)?;
+    statement.bind(1, position.message_id.as_str()).map_err(db::to_io_error)?;
+    statement.bind(2, position.conversation.as_str()).map_err(db::to_io_error)?;
+    statement.bind(3, position.user_id.as_str()).map_err(db::to_io_error)?;
+    statement.next().map_err(db::to_io_error)?;
+
+    if connection.change_count() > 0 {
+        return Ok(());
+    }
+
+    let mut statement = connection
+        .prepare("INSERT INTO READ_POSITIONS (CONVERSATION, USER_ID, MESSAGE_ID) VALUES (?, ?, ?)")
+        .map_err(db::to_io_error)?;
+    statement.bind(1, position.conversation.as_str()).map_err(db::to_io_error)?;
+    statement.bind(2, position.user_id.as_str()).map_err(db::to_io_error)?;
+    statement.bind(3, position.message_id.as_str()).map_err(db::to_io_error)?;
+    statement.next().map_err(db::to_io_error)?;
+
+    Ok(())
+}
+
+pub fn get_read_positions() -> io::Result<Vec<models::ReadPosition>> {
+    let query = "SELECT CONVERSATION, USER_ID, MESSAGE_ID FROM READ_POSITIONS";
+
+    let connection = db::get_connection()?;
+    let mut statement = connection.prepare(query).map_err(db::to_io_error)?;
+
+    let mut positions = Vec::new();
+    while let sqlite::State::Row = statement.next().map_err(db::to_io_error)? {
+        positions.push(models::ReadPosition {
+            conversation: statement.read::<String>(0).map_err(db::to_io_error)?,
+            user_id: statement.read::<String>(1).map_err(db::to_io_error)?,
+            message_id: statement.read::<String>(2).map_err(db::to_io_error)?,
+        });
     }
-}
\ No newline at end of file
+
+    Ok(positions)
+}
+
+/// Stores a file transfer, replacing the earlier state of the same transfer
+pub fn save_attachment(attachment: &models::Attachment) -> io::Result<()> {
+    let query = "INSERT OR REPLACE INTO ATTACHMENTS \
+        (TRANSFER_ID, CONVERSATION, FROM_USER, FILE_NAME, FILE_SIZE, SHA256, PATH, STATUS) \
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?)";
+
+    let connection = db::get_connection()?;
+    let mut statement = connection.prepare(query).map_err(db::to_io_error)?;
+    statement.bind(1, attachment.transfer_id.as_str()).map_err(db::to_io_error)?;
+    statement.bind(2, attachment.conversation.as_str()).map_err(db::to_io_error)?;
+    statement.bind(3, attachment.from_user.as_str()).map_err(db::to_io_error)?;
+    statement.bind(4, attachment.file_name.as_str()).map_err(db::to_io_error)?;
+    statement.bind(5, attachment.file_size as i64).map_err(db::to_io_error)?;
+    statement.bind(6, attachment.sha256.as_str()).map_err(db::to_io_error)?;
+    statement.bind(7, attachment.path.as_str()).map_err(db::to_io_error)?;
+    statement.bind(8, attachment.status.as_str()).map_err(db::to_io_error)?;
+    statement.next().map_err(db::to_io_error)?;
+
+    Ok(())
+}
+
+/// Stores the key `user_id` encrypts with, replacing the one known before
+pub fn save_contact_key(contact_key: &models::ContactKey) -> io::Result<()> {
+    let query = "INSERT OR REPLACE INTO CONTACT_KEYS (USER_ID, PUBLIC_KEY) VALUES (?, ?)";
+
+    let connection = db::get_connection()?;
+    let mut statement = connection.prepare(query).map_err(db::to_io_error)?;
+    statement.bind(1, contact_key.user_id.as_str()).map_err(db::to_io_error)?;
+    statement.bind(2, contact_key.public_key.as_str()).map_err(db::to_io_error)?;
+    statement.next().map_err(db::to_io_error)?;
+
+    Ok(())
+}
+
+pub fn get_contact_keys() -> io::Result<Vec<models::ContactKey>> {
+    let query = "SELECT USER_ID, PUBLIC_KEY FROM CONTACT_KEYS";
+
+    let connection = db::get_connection()?;
+    let mut statement = connection.prepare(query).map_err(db::to_io_error)?;
+
+    let mut keys = Vec::new();
+    while let sqlite::State::Row = statement.next().map_err(db::to_io_error)? {
+        keys.push(models::ContactKey {
+            user_id: statement.read::<String>(0).map_err(db::to_io_error)?,
+            public_key: statement.read::<String>(1).map_err(db::to_io_error)?,
+        });
+    }
+
+    Ok(keys)
+}
+
+pub fn get_setting(key: &str) -> io::Result<Option<String>> {
+    let query = "SELECT VALUE FROM SETTINGS WHERE KEY = ?";
+
+    let connection = db::get_connection()?;
+    let mut statement = connection.prepare(query).map_err(db::to_io_error)?;
+    statement.bind(1, key).map_err(db::to_io_error)?;
+
+    match statement.next().map_err(db::to_io_error)? {
+        sqlite::State::Row => Ok(Some(statement.read::<String>(0).map_err(db::to_io_error)?)),
+        sqlite::State::Done => Ok(None),
+    }
+}
+
+pub fn set_setting(key: &str, value: &str) -> io::Result<()> {
+    let query = "INSERT OR REPLACE INTO SETTINGS (KEY, VALUE) VALUES (?, ?)";
+
+    let connection = db::get_connection()?;
+    let mut statement = connection.prepare(query).map_err(db::to_io_error)?;
+    statement.bind(1, key).map_err(db::to_io_error)?;
+    statement.bind(2, value).map_err(db::to_io_error)?;
+    statement.next().map_err(db::to_io_error)?;
+
+    Ok(())
+}
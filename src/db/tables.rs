@@ -23,8 +23,8 @@ pub fn create_all() -> io::Result<()> {
     let connection = match sqlite::open(db_path) {
         Ok(conn) => conn,
         Err(e) => {
-            return Err(io::Error::new(
-                io::ErrorKind::Other, e.message.unwrap()
+            return Err(io::Error::other(
+                e.message.unwrap()
             ));
         }
     };
@@ -43,16 +43,60 @@ fn create_tables_from_details(
         match conn.execute(table_query) {
             Ok(_) => {}
             Err(e) => {
-                return Err(io::Error::new(
-                    io::ErrorKind::Other, e.message.unwrap()
+                return Err(io::Error::other(
+                    e.message.unwrap()
                 ));
             }
         }
+
+        add_missing_columns(&conn, table_detail)?;
+    }
+
+    Ok(())
+}
+
+/// Adds the columns that were introduced after the table was created,
+/// such columns have to be nullable or have a constant default
+fn add_missing_columns(
+    conn: &sqlite::Connection,
+    table_detail: &TableDetail
+) -> io::Result<()> {
+    let mut existing: Vec<String> = Vec::new();
+    conn.iterate(format!("PRAGMA table_info(\"{}\")", table_detail.name), |pairs| {
+        for &(col, val) in pairs.iter() {
+            if let ("name", Some(name)) = (col, val) {
+                existing.push(name.to_string());
+            }
+        }
+
+        true
+    }).map_err(db::to_io_error)?;
+
+    for column_detail in &table_detail.columns {
+        if existing.contains(&column_detail.name) {
+            continue;
+        }
+
+        let query = format!(
+            "ALTER TABLE \"{}\" ADD COLUMN {}",
+            table_detail.name, get_column_definition(column_detail)
+        );
+        conn.execute(query).map_err(db::to_io_error)?;
     }
 
     Ok(())
 }
 
+fn get_column_definition(column_detail: &ColumnDetail) -> String {
+    let mut definition = format!("[{}] {}", column_detail.name, column_detail.column_type);
+
+    if let Some(constraints) = &column_detail.constraints {
+        definition.push_str(format!(" {}", constraints.join(" ")).as_str())
+    }
+
+    definition
+}
+
 fn get_create_table_query(table_details: &TableDetail) -> String {
     let mut query = String::new();
 
@@ -60,20 +104,14 @@ fn get_create_table_query(table_details: &TableDetail) -> String {
     query.push_str(format!("CREATE TABLE IF NOT EXISTS \"{}\" (", table_name).as_str());
     let mut count: usize = 0;
     for column_detail in &table_details.columns {
-        query.push_str(format!(
-            "[{}] {}", column_detail.name, column_detail.column_type
-        ).as_str());
-
-        if let Some(constraints) = &column_detail.constraints {
-            query.push_str(format!(" {}", constraints.join(" ")).as_str())
-        }
+        query.push_str(get_column_definition(column_detail).as_str());
 
-        count = count + 1;
+        count += 1;
         if count != table_details.columns.len() {
-            query.push_str(",");
+            query.push(',');
         }
     }
-    query.push_str(")");
+    query.push(')');
 
     query
 }
@@ -84,3 +122,54 @@ fn get_table_details() -> io::Result<TableDetails> {
 
     Ok(details)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn column_names(conn: &sqlite::Connection, table: &str) -> Vec<String> {
+        let mut names = Vec::new();
+        conn.iterate(format!("PRAGMA table_info(\"{}\")", table), |pairs| {
+            for &(col, val) in pairs.iter() {
+                if col == "name" {
+                    names.push(val.unwrap().to_string());
+                }
+            }
+            true
+        }).unwrap();
+
+        names
+    }
+
+    #[test]
+    fn test_columns_are_added_to_existing_tables() {
+        let path = std::env::temp_dir().join(format!("oisg-tables-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let conn = sqlite::open(&path).unwrap();
+        // MESSAGES as created by the first release
+        conn.execute(
+            "CREATE TABLE \"MESSAGES\" ([FROM_USER] VARCHAR(50) NOT NULL, [MESSAGE] VARCHAR(255), \
+            [RECEIVED_TIME] TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL)"
+        ).unwrap();
+        conn.execute("INSERT INTO MESSAGES (FROM_USER, MESSAGE) VALUES ('alice', 'hi')").unwrap();
+
+        // creating twice is a no-op
+        create_tables_from_details(conn, get_table_details().unwrap()).unwrap();
+        create_tables_from_details(sqlite::open(&path).unwrap(), get_table_details().unwrap()).unwrap();
+
+        let conn = sqlite::open(&path).unwrap();
+        let names = column_names(&conn, "MESSAGES");
+        assert!(names.contains(&"MESSAGE_ID".to_string()));
+        assert!(names.contains(&"STATUS".to_string()));
+        assert!(names.contains(&"CLOCK".to_string()));
+        assert!(!column_names(&conn, "OUTBOX").is_empty());
+
+        let mut statement = conn.prepare("SELECT COUNT(*) FROM MESSAGES").unwrap();
+        statement.next().unwrap();
+        assert_eq!(statement.read::<i64>(0).unwrap(), 1);
+
+        drop(statement);
+        drop(conn);
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,11 @@
+pub mod app;
+pub mod common;
+pub mod components;
+pub mod config;
+pub mod constants;
+pub mod crypto;
+pub mod db;
+pub mod net;
+pub mod server;
+pub mod styles;
+pub mod transfer;
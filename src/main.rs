@@ -1,11 +1,3 @@
-mod components;
-mod app;
-mod styles;
-mod common;
-mod constants;
-mod db;
-mod net;
-
 use std::{
     error::Error,
     io,
@@ -25,31 +17,42 @@ use crossbeam_channel::{
     Select, Receiver,
     unbounded
 };
-use crate::{
+use oisg::{
+    app,
+    db,
     components::{
         BaseComponent, DrawableComponent
     },
     common::app_event::AppEvent,
-    app::event_receiver::EventReceiver
+    app::event_receiver::EventReceiver,
+    config::Config,
+    net::Network
 };
 
 fn main() -> Result<(), Box<dyn Error>> {
+    let config = Config::from_args()?;
+
     // ensuring db exists, if not create one
     db::ensure_db_exists()?;
 
+    let (tx_notification, rx_notification) = unbounded::<AppEvent>();
+
+    // network events share the notification channel
+    let network = Network::connect(&config, tx_notification.clone())?;
+
     // setup terminal for drawing
     enable_raw_mode()?;
     io::stdout().execute(EnterAlternateScreen)?;
 
     let mut terminal = setup_new_terminal(io::stdout())?;
 
-    let (tx_notification, rx_notification) = unbounded::<AppEvent>();
     let event_receiver = EventReceiver::new();
     let rx_input = event_receiver.receiver();
 
     // create application
     let mut application = app::application::Application::new(
         db::operations::get_user_info()?,
+        network,
         tx_notification
     );
 
@@ -104,7 +107,7 @@ fn setup_new_terminal<W: io::Write>(
     Ok(terminal)
 }
 
-///
+/// Blocks until any of the `receivers` has an event and returns it
 fn select_event(receivers: &[&Receiver<AppEvent>]) -> io::Result<AppEvent> {
     let mut select = Select::new();
     for receiver in receivers {
@@ -114,7 +117,7 @@ fn select_event(receivers: &[&Receiver<AppEvent>]) -> io::Result<AppEvent> {
     let operation = select.select();
     let index = operation.index();
 
-    match operation.recv(&receivers[index]) {
+    match operation.recv(receivers[index]) {
         Ok(evt) => Ok(evt),
         Err(_) => Ok(AppEvent::None)
     }
@@ -0,0 +1,99 @@
+use std::time::Duration;
+use rand::{ rngs::StdRng, Rng, SeedableRng };
+
+const BASE_DELAY: Duration = Duration::from_secs(1);
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// `Backoff` spaces out reconnection attempts: the delay doubles after
+/// every failed attempt, up to a limit, and is randomised so clients
+/// dropped together do not come back together
+pub struct Backoff {
+    attempt: u32,
+    /// picks the jitter, seeded when runs have to be repeatable
+    rng: StdRng,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Backoff {
+    pub fn new() -> Self {
+        Self::with_rng(StdRng::from_entropy())
+    }
+
+    /// Backoff with the same delays on every run
+    pub fn seeded(seed: u64) -> Self {
+        Self::with_rng(StdRng::seed_from_u64(seed))
+    }
+
+    fn with_rng(rng: StdRng) -> Self {
+        Backoff {
+            attempt: 0,
+            rng,
+        }
+    }
+
+    /// Delay before the next attempt
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = delay_for(self.attempt, self.rng.gen());
+        self.attempt = self.attempt.saturating_add(1);
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+/// Delay for the `attempt`th retry, `jitter` in `[0, 1)` picks a point in
+/// the upper half of the exponential delay
+fn delay_for(attempt: u32, jitter: f64) -> Duration {
+    let exponential = BASE_DELAY
+        .checked_mul(2u32.saturating_pow(attempt))
+        .map_or(MAX_DELAY, |delay| delay.min(MAX_DELAY));
+
+    exponential / 2 + exponential.mul_f64(jitter / 2.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay_grows_exponentially() {
+        assert_eq!(delay_for(0, 0.0), Duration::from_millis(500));
+        assert_eq!(delay_for(1, 0.0), Duration::from_secs(1));
+        assert_eq!(delay_for(3, 0.0), Duration::from_secs(4));
+        assert_eq!(delay_for(3, 0.5), Duration::from_secs(6));
+    }
+
+    #[test]
+    fn test_delay_is_capped() {
+        assert_eq!(delay_for(10, 0.0), MAX_DELAY / 2);
+        assert!(delay_for(u32::MAX, 0.99) < MAX_DELAY);
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut backoff = Backoff::new();
+        for _ in 0..5 {
+            backoff.next_delay();
+        }
+        assert!(backoff.next_delay() >= Duration::from_secs(16));
+
+        backoff.reset();
+        assert!(backoff.next_delay() < BASE_DELAY);
+    }
+
+    #[test]
+    fn test_seeded() {
+        let mut a = Backoff::seeded(7);
+        let mut b = Backoff::seeded(7);
+        for _ in 0..5 {
+            assert_eq!(a.next_delay(), b.next_delay());
+        }
+    }
+}
@@ -0,0 +1,182 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    time::{ Duration, Instant },
+};
+use crate::net::{
+    self,
+    protocol::Message,
+    transport::{ Protocol, Transport }
+};
+
+/// Multicast group clients announce themselves on
+pub const DISCOVERY_ADDR: &str = "239.255.42.99:7880";
+pub const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5);
+/// A peer that has not announced itself for this long is considered gone
+pub const PEER_TIMEOUT: Duration = Duration::from_secs(16);
+
+/// Another oisg client found on the local network
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Peer {
+    pub user_id: String,
+    pub user_name: String,
+    /// address of the peer's TCP listener
+    pub addr: SocketAddr,
+}
+
+/// `PeerTable` remembers when each peer was last heard of
+#[derive(Default)]
+pub struct PeerTable {
+    peers: HashMap<String, (Peer, Instant)>,
+}
+
+impl PeerTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records an announcement, returns `true` if the peer is new
+    /// or its details changed
+    pub fn seen(&mut self, peer: Peer, now: Instant) -> bool {
+        let changed = self.peers.get(&peer.user_id)
+            .is_none_or(|(known, _)| *known != peer);
+
+        self.peers.insert(peer.user_id.clone(), (peer, now));
+        changed
+    }
+
+    /// Removes and returns the peers that timed out
+    pub fn expire(&mut self, now: Instant) -> Vec<Peer> {
+        let expired: Vec<String> = self.peers.iter()
+            .filter(|(_, (_, last_seen))| now.duration_since(*last_seen) > PEER_TIMEOUT)
+            .map(|(user_id, _)| user_id.clone())
+            .collect();
+
+        expired.into_iter()
+            .filter_map(|user_id| self.peers.remove(&user_id))
+            .map(|(peer, _)| peer)
+            .collect()
+    }
+
+    pub fn get(&self, user_id: &str) -> Option<&Peer> {
+        self.peers.get(user_id).map(|(peer, _)| peer)
+    }
+}
+
+/// `Discovery` announces the local user on the LAN multicast group and
+/// keeps the table of the peers heard on it
+pub struct Discovery<C, L> {
+    group: C,
+    listener: L,
+    port: u16,
+    peers: PeerTable,
+}
+
+impl<C: Copy + PartialEq, L: Copy + PartialEq> Discovery<C, L> {
+    /// Joins the multicast group, `port` is the TCP port that is announced
+    pub fn start<S, T: Transport<S, Conn = C, Listener = L>>(transport: &T, port: u16) -> io::Result<Self> {
+        let group_addr = net::resolve(DISCOVERY_ADDR)?;
+        let (listener, _) = transport.listen(Protocol::Udp, group_addr)?;
+        let group = transport.connect(Protocol::Udp, group_addr)?;
+
+        Ok(Discovery {
+            group,
+            listener,
+            port,
+            peers: PeerTable::new(),
+        })
+    }
+
+    /// `true` if `listener` is the multicast listener
+    pub fn owns(&self, listener: L) -> bool {
+        listener == self.listener
+    }
+
+    /// `true` if `conn` is what we announce on
+    pub fn is_group(&self, conn: C) -> bool {
+        conn == self.group
+    }
+
+    pub fn announce<S, T: Transport<S, Conn = C>>(&self, transport: &T, user_id: &str, user_name: &str) {
+        net::send_to(transport, self.group, &Message::Announce {
+            user_id: user_id.to_string(),
+            user_name: user_name.to_string(),
+            port: self.port,
+        });
+    }
+
+    /// Handles a datagram from the group, returns the peer if it is new
+    /// or changed. Our own announcements are ignored.
+    pub fn receive(&mut self, from: SocketAddr, data: &[u8], own_user_id: Option<&str>) -> Option<Peer> {
+        let (user_id, user_name, port) = match net::protocol::decode(data) {
+            Ok(Message::Announce { user_id, user_name, port }) => (user_id, user_name, port),
+            _ => return None,
+        };
+
+        if Some(user_id.as_str()) == own_user_id {
+            return None;
+        }
+
+        let peer = Peer {
+            user_id,
+            user_name,
+            addr: SocketAddr::new(from.ip(), port),
+        };
+
+        if self.peers.seen(peer.clone(), Instant::now()) {
+            Some(peer)
+        } else {
+            None
+        }
+    }
+
+    pub fn expire(&mut self) -> Vec<Peer> {
+        self.peers.expire(Instant::now())
+    }
+
+    pub fn peer(&self, user_id: &str) -> Option<&Peer> {
+        self.peers.get(user_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(user_id: &str, port: u16) -> Peer {
+        Peer {
+            user_id: user_id.to_string(),
+            user_name: user_id.to_uppercase(),
+            addr: SocketAddr::from(([192, 168, 1, 20], port)),
+        }
+    }
+
+    #[test]
+    fn test_seen_reports_new_and_changed_peers() {
+        let mut table = PeerTable::new();
+        let now = Instant::now();
+
+        assert!(table.seen(peer("alice", 7000), now));
+        assert!(!table.seen(peer("alice", 7000), now + ANNOUNCE_INTERVAL));
+        assert!(table.seen(peer("alice", 7001), now + ANNOUNCE_INTERVAL * 2));
+
+        assert_eq!(table.get("alice"), Some(&peer("alice", 7001)));
+    }
+
+    #[test]
+    fn test_expire() {
+        let mut table = PeerTable::new();
+        let now = Instant::now();
+
+        table.seen(peer("alice", 7000), now);
+        table.seen(peer("bob", 7000), now + ANNOUNCE_INTERVAL * 2);
+
+        assert!(table.expire(now + PEER_TIMEOUT).is_empty());
+
+        let expired = table.expire(now + PEER_TIMEOUT + ANNOUNCE_INTERVAL);
+        assert_eq!(expired, vec![peer("alice", 7000)]);
+        assert_eq!(table.get("alice"), None);
+        assert!(table.get("bob").is_some());
+    }
+}
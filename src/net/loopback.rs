@@ -0,0 +1,617 @@
+use std::{
+    collections::{ BTreeMap, HashMap, HashSet },
+    io,
+    net::{ IpAddr, SocketAddr },
+    sync::{ Arc, Mutex },
+    time::Duration,
+};
+use rand::{ rngs::StdRng, Rng, SeedableRng };
+use crate::net::transport::{ Event, Protocol, Transport };
+
+/// First port handed out to listeners bound to port 0 and to outgoing connections
+const FIRST_PORT: u16 = 40000;
+
+/// `Faults` is what a `Loopback` does to the data sent over it. Every
+/// send is one frame, so frames are delayed, lost or repeated as a whole.
+/// Like TCP, streams only ever get the delays and keep their order, loss,
+/// repetition and reordering are for datagrams. Streams break with
+/// `Loopback::disconnect` instead.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct Faults {
+    /// every send takes at least this long to arrive
+    pub latency: Duration,
+    /// and up to this much longer, picked at random for each send
+    pub jitter: Duration,
+    /// datagrams may overtake earlier ones of the same connection, without
+    /// it the jitter only delays them
+    pub reorder: bool,
+    /// chance of a datagram arriving twice
+    pub duplicate: f64,
+    /// chance of a datagram never arriving
+    pub loss: f64,
+}
+
+/// Connection of a `LoopbackTransport`
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct LoopConn(u64);
+
+/// Listener of a `LoopbackTransport`
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct LoopListener(u64);
+
+/// Events waiting on the `Loopback` for a node, timers carry the key
+/// of the signal kept by the node
+#[derive(Debug)]
+enum Packet {
+    Connected(LoopConn, bool),
+    Accepted(LoopConn, LoopListener),
+    Message(LoopConn, Vec<u8>),
+    Datagram(LoopListener, SocketAddr, Vec<u8>),
+    Disconnected(LoopConn),
+    Timer(u64),
+}
+
+struct Conn {
+    node: IpAddr,
+    local: SocketAddr,
+    remote: SocketAddr,
+    /// the other end, `None` for UDP
+    peer: Option<LoopConn>,
+    protocol: Protocol,
+}
+
+struct Hub {
+    /// virtual time, only moved by `Loopback::advance`
+    now: Duration,
+    faults: Faults,
+    /// seeds the generator of each node
+    rng: StdRng,
+    /// picks the faults of what is sent to each node, so what one node
+    /// gets does not depend on the order things were sent to others in
+    node_rngs: HashMap<IpAddr, StdRng>,
+    /// when the last send on each connection arrives, later ones
+    /// arrive after it unless they are datagrams and reordering is on
+    last_due: HashMap<LoopConn, Duration>,
+    next_id: u64,
+    next_port: HashMap<IpAddr, u16>,
+    /// packets by when they are due and in which order they were queued
+    queue: BTreeMap<(Duration, u64), (IpAddr, Packet)>,
+    listeners: HashMap<SocketAddr, Vec<(IpAddr, LoopListener, Protocol)>>,
+    conns: HashMap<LoopConn, Conn>,
+    stopped: HashSet<IpAddr>,
+}
+
+impl Hub {
+    fn new(seed: u64) -> Self {
+        Hub {
+            now: Duration::ZERO,
+            faults: Faults::default(),
+            rng: StdRng::seed_from_u64(seed),
+            node_rngs: HashMap::new(),
+            last_due: HashMap::new(),
+            next_id: 0,
+            next_port: HashMap::new(),
+            queue: BTreeMap::new(),
+            listeners: HashMap::new(),
+            conns: HashMap::new(),
+            stopped: HashSet::new(),
+        }
+    }
+
+    fn id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    fn port(&mut self, ip: IpAddr) -> u16 {
+        let port = self.next_port.entry(ip).or_insert(FIRST_PORT);
+        *port += 1;
+        *port
+    }
+
+    fn push(&mut self, node: IpAddr, packet: Packet, delay: Duration) {
+        let key = (self.now + delay, self.id());
+        self.queue.insert(key, (node, packet));
+    }
+
+    fn node_rng(&mut self, node: IpAddr) -> &mut StdRng {
+        let rng = &mut self.rng;
+        self.node_rngs.entry(node).or_insert_with(|| StdRng::seed_from_u64(rng.gen()))
+    }
+
+    /// Queues data sent to `to` on `node` as the faults have it, only
+    /// `datagram`s are lost, repeated or reordered
+    fn push_data(&mut self, node: IpAddr, to: LoopConn, datagram: bool, packet: impl Fn() -> Packet) {
+        let faults = self.faults;
+        let rng = self.node_rng(node);
+        if datagram && rng.gen_bool(faults.loss) {
+            return;
+        }
+
+        let copies = if datagram && rng.gen_bool(faults.duplicate) { 2 } else { 1 };
+        let jitters: Vec<Duration> = (0..copies).map(|_| faults.jitter.mul_f64(rng.gen())).collect();
+        for jitter in jitters {
+            let mut due = self.now + faults.latency + jitter;
+            if !(datagram && faults.reorder) {
+                due = due.max(self.last_due.get(&to).copied().unwrap_or_default());
+                self.last_due.insert(to, due);
+            }
+
+            let key = (due, self.id());
+            self.queue.insert(key, (node, packet()));
+        }
+    }
+
+    /// Queues data for the other end of `conn`, `false` if there is none
+    fn push_peer(&mut self, conn: LoopConn, data: &[u8]) -> bool {
+        let peer = match self.conns.get(&conn).and_then(|conn| conn.peer) {
+            Some(peer) => peer,
+            None => return false,
+        };
+        let node = match self.conns.get(&peer) {
+            Some(conn) => conn.node,
+            None => return false,
+        };
+
+        self.push_data(node, peer, false, || Packet::Message(peer, data.to_vec()));
+        true
+    }
+
+    /// Forgets `conn`, what is still on its way to it is dropped and
+    /// the other end sees it drop once it got what was sent before
+    fn close(&mut self, conn: LoopConn) {
+        self.last_due.remove(&conn);
+        if let Some(Conn { peer: Some(peer), .. }) = self.conns.remove(&conn) {
+            if let Some(node) = self.conns.get(&peer).map(|conn| conn.node) {
+                self.conns.get_mut(&peer).unwrap().peer = None;
+                let delay = self.last_due.get(&peer).map_or(Duration::ZERO, |due| due.saturating_sub(self.now));
+                self.push(node, Packet::Disconnected(peer), delay);
+            }
+        }
+    }
+
+    /// Connections of `node` to other nodes or of other nodes to it
+    fn conns_of(&self, node: IpAddr) -> Vec<LoopConn> {
+        self.conns.iter()
+            .filter(|(_, conn)| conn.node == node)
+            .map(|(id, _)| *id)
+            .collect()
+    }
+}
+
+/// `Loopback` is an in-memory network. Its nodes are `LoopbackTransport`s
+/// told apart by IP address. Nothing runs on its own: `receive` hands out
+/// what is due and `advance` moves the virtual clock to what is due next,
+/// so tests over it see the same order of events every time.
+#[derive(Clone)]
+pub struct Loopback {
+    hub: Arc<Mutex<Hub>>,
+}
+
+impl Default for Loopback {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Loopback {
+    pub fn new() -> Self {
+        Self::with_seed(0)
+    }
+
+    /// Network whose faults are picked by a random generator seeded with `seed`
+    pub fn with_seed(seed: u64) -> Self {
+        Loopback {
+            hub: Arc::new(Mutex::new(Hub::new(seed))),
+        }
+    }
+
+    /// Applies `faults` to everything sent from now on
+    pub fn set_faults(&self, faults: Faults) {
+        self.hub.lock().unwrap().faults = faults;
+    }
+
+    /// Breaks every connection of the node `ip`, both ends see it drop
+    pub fn disconnect(&self, ip: IpAddr) {
+        let mut hub = self.hub.lock().unwrap();
+        // UDP has no connection to break
+        for conn in hub.conns_of(ip) {
+            if hub.conns.get(&conn).is_some_and(|conn| conn.peer.is_some()) {
+                hub.close(conn);
+                hub.push(ip, Packet::Disconnected(conn), Duration::ZERO);
+            }
+        }
+    }
+
+    /// A node of the network with the address `ip`
+    pub fn node<S: Send + 'static>(&self, ip: IpAddr) -> LoopbackTransport<S> {
+        // nodes are made in the same order on every run, unlike sends
+        self.hub.lock().unwrap().node_rng(ip);
+
+        LoopbackTransport {
+            hub: Arc::clone(&self.hub),
+            ip,
+            signals: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Time since the network was created
+    pub fn now(&self) -> Duration {
+        self.hub.lock().unwrap().now
+    }
+
+    /// Moves the clock to when the next event not due yet is, `false`
+    /// if nothing is pending
+    pub fn advance(&self) -> bool {
+        let mut hub = self.hub.lock().unwrap();
+        let now = hub.now;
+        let next = hub.queue.iter()
+            .find(|((due, _), (node, _))| *due > now && !hub.stopped.contains(node))
+            .map(|((due, _), _)| *due);
+
+        match next {
+            Some(due) => {
+                hub.now = due;
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+/// A node on a `Loopback` network, see `Transport`
+pub struct LoopbackTransport<S> {
+    hub: Arc<Mutex<Hub>>,
+    ip: IpAddr,
+    /// signals of this node waiting for their timer
+    signals: Arc<Mutex<HashMap<u64, S>>>,
+}
+
+impl<S> Clone for LoopbackTransport<S> {
+    fn clone(&self) -> Self {
+        LoopbackTransport {
+            hub: Arc::clone(&self.hub),
+            ip: self.ip,
+            signals: Arc::clone(&self.signals),
+        }
+    }
+}
+
+impl<S> LoopbackTransport<S> {
+    pub fn ip(&self) -> IpAddr {
+        self.ip
+    }
+}
+
+impl<S: Send + 'static> Transport<S> for LoopbackTransport<S> {
+    type Conn = LoopConn;
+    type Listener = LoopListener;
+
+    fn connect(&self, protocol: Protocol, addr: SocketAddr) -> io::Result<LoopConn> {
+        let mut hub = self.hub.lock().unwrap();
+        let conn = LoopConn(hub.id());
+        let listener = hub.listeners.get(&addr)
+            .and_then(|listeners| listeners.iter().find(|(_, _, listening)| *listening == protocol))
+            .copied();
+
+        let local = SocketAddr::new(self.ip, hub.port(self.ip));
+        hub.conns.insert(conn, Conn { node: self.ip, local, remote: addr, peer: None, protocol });
+        if protocol == Protocol::Udp {
+            hub.push(self.ip, Packet::Connected(conn, true), Duration::ZERO);
+            return Ok(conn);
+        }
+
+        match listener {
+            Some((node, listener, _)) => {
+                let accepted = LoopConn(hub.id());
+                hub.conns.insert(accepted, Conn { node, local: addr, remote: local, peer: Some(conn), protocol });
+                hub.conns.get_mut(&conn).unwrap().peer = Some(accepted);
+
+                hub.push(self.ip, Packet::Connected(conn, true), Duration::ZERO);
+                hub.push(node, Packet::Accepted(accepted, listener), Duration::ZERO);
+            },
+            None => {
+                hub.conns.remove(&conn);
+                hub.push(self.ip, Packet::Connected(conn, false), Duration::ZERO);
+            },
+        }
+
+        Ok(conn)
+    }
+
+    fn listen(&self, protocol: Protocol, addr: SocketAddr) -> io::Result<(LoopListener, SocketAddr)> {
+        let mut hub = self.hub.lock().unwrap();
+        let ip = match addr.ip().is_unspecified() {
+            true => self.ip,
+            // UDP listeners join a group shared with other nodes
+            false => addr.ip(),
+        };
+        let port = match addr.port() {
+            0 => hub.port(ip),
+            port => port,
+        };
+        let addr = SocketAddr::new(ip, port);
+
+        let taken = hub.listeners.get(&addr)
+            .is_some_and(|listeners| listeners.iter().any(|(node, _, listening)| {
+                *listening == protocol && (protocol != Protocol::Udp || *node == self.ip)
+            }));
+        if taken {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("{} is in use", addr)));
+        }
+
+        let listener = LoopListener(hub.id());
+        hub.listeners.entry(addr).or_default().push((self.ip, listener, protocol));
+        Ok((listener, addr))
+    }
+
+    fn send(&self, conn: LoopConn, data: &[u8]) -> bool {
+        let mut hub = self.hub.lock().unwrap();
+        let (from, remote, protocol) = match hub.conns.get(&conn) {
+            Some(conn) => (conn.local, conn.remote, conn.protocol),
+            None => return false,
+        };
+
+        if protocol != Protocol::Udp {
+            return hub.push_peer(conn, data);
+        }
+
+        let listeners = hub.listeners.get(&remote).cloned().unwrap_or_default();
+        for (node, listener, _) in listeners.into_iter().filter(|(_, _, protocol)| *protocol == Protocol::Udp) {
+            hub.push_data(node, conn, true, || Packet::Datagram(listener, from, data.to_vec()));
+        }
+        true
+    }
+
+    /// Reaches the UDP connection bound to `addr`, like a socket bound to it
+    fn send_datagram(&self, _listener: LoopListener, addr: SocketAddr, data: &[u8]) -> bool {
+        let mut hub = self.hub.lock().unwrap();
+        let to = hub.conns.iter()
+            .find(|(_, conn)| conn.protocol == Protocol::Udp && conn.local == addr)
+            .map(|(id, conn)| (*id, conn.node));
+
+        if let Some((conn, node)) = to {
+            hub.push_data(node, conn, true, || Packet::Message(conn, data.to_vec()));
+        }
+        true
+    }
+
+    fn close(&self, conn: LoopConn) {
+        self.hub.lock().unwrap().close(conn);
+    }
+
+    fn remote_addr(&self, conn: LoopConn) -> SocketAddr {
+        self.hub.lock().unwrap().conns.get(&conn)
+            .map(|conn| conn.remote)
+            .unwrap_or_else(|| SocketAddr::new(self.ip, 0))
+    }
+
+    fn signal(&self, signal: S, delay: Duration) {
+        let mut hub = self.hub.lock().unwrap();
+        let key = hub.id();
+        self.signals.lock().unwrap().insert(key, signal);
+        hub.push(self.ip, Packet::Timer(key), delay);
+    }
+
+    fn receive(&self) -> Option<Event<LoopConn, LoopListener, S>> {
+        let mut hub = self.hub.lock().unwrap();
+        if hub.stopped.contains(&self.ip) {
+            return None;
+        }
+
+        let now = hub.now;
+        loop {
+            let key = *hub.queue.iter()
+                .take_while(|((due, _), _)| *due <= now)
+                .find(|(_, (node, _))| *node == self.ip)?
+                .0;
+            let (_, packet) = hub.queue.remove(&key)?;
+
+            return Some(match packet {
+                Packet::Connected(conn, ok) => Event::Connected(conn, ok),
+                Packet::Accepted(conn, listener) => Event::Accepted(conn, listener),
+                // the connection was closed while it was on its way
+                Packet::Message(conn, _) if !hub.conns.contains_key(&conn) => continue,
+                Packet::Message(conn, data) => Event::Message(conn, data),
+                Packet::Datagram(listener, from, data) => Event::Datagram(listener, from, data),
+                Packet::Disconnected(conn) => {
+                    hub.conns.remove(&conn);
+                    hub.last_due.remove(&conn);
+                    Event::Disconnected(conn)
+                },
+                Packet::Timer(key) => Event::Signal(self.signals.lock().unwrap().remove(&key)?),
+            });
+        }
+    }
+
+    /// Closes every connection of the node, its peers see them drop
+    fn stop(&self) {
+        let mut hub = self.hub.lock().unwrap();
+        let ip = self.ip;
+        for conn in hub.conns_of(ip) {
+            hub.close(conn);
+        }
+
+        hub.listeners.values_mut().for_each(|listeners| listeners.retain(|(node, _, _)| *node != ip));
+        hub.stopped.insert(ip);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use super::*;
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(10, 0, 0, last))
+    }
+
+    /// Everything the node has to do now
+    fn drain<S: Send + 'static>(node: &LoopbackTransport<S>) -> Vec<Event<LoopConn, LoopListener, S>> {
+        std::iter::from_fn(|| node.receive()).collect()
+    }
+
+    #[test]
+    fn test_connect_and_send() {
+        let network = Loopback::new();
+        let server = network.node::<()>(ip(1));
+        let client = network.node::<()>(ip(2));
+
+        let (listener, addr) = server.listen(Protocol::Tcp, "0.0.0.0:7878".parse().unwrap()).unwrap();
+        assert_eq!(addr, "10.0.0.1:7878".parse().unwrap());
+
+        let conn = client.connect(Protocol::Tcp, addr).unwrap();
+        assert!(matches!(drain(&client)[..], [Event::Connected(c, true)] if c == conn));
+        let accepted = match drain(&server)[..] {
+            [Event::Accepted(accepted, l)] if l == listener => accepted,
+            ref other => panic!("expected accept, got {:?}", other),
+        };
+        assert_eq!(server.remote_addr(accepted).ip(), ip(2));
+
+        assert!(client.send(conn, b"one"));
+        assert!(client.send(conn, b"two"));
+        let received: Vec<Vec<u8>> = drain(&server).into_iter()
+            .map(|event| match event {
+                Event::Message(c, data) if c == accepted => data,
+                other => panic!("expected message, got {:?}", other),
+            })
+            .collect();
+        assert_eq!(received, vec![b"one".to_vec(), b"two".to_vec()]);
+
+        server.close(accepted);
+        assert!(matches!(drain(&client)[..], [Event::Disconnected(c)] if c == conn));
+        assert!(!client.send(conn, b"gone"));
+    }
+
+    /// Connection from 10.0.0.2 to a listener on 10.0.0.1, both ends
+    fn connected(network: &Loopback) -> (LoopbackTransport<()>, LoopConn, LoopbackTransport<()>, LoopConn) {
+        let server = network.node::<()>(ip(1));
+        let client = network.node::<()>(ip(2));
+        let (_, addr) = server.listen(Protocol::Tcp, "0.0.0.0:7878".parse().unwrap()).unwrap();
+        let conn = client.connect(Protocol::Tcp, addr).unwrap();
+        drain(&client);
+        let accepted = match drain(&server)[..] {
+            [Event::Accepted(accepted, _)] => accepted,
+            ref other => panic!("expected accept, got {:?}", other),
+        };
+
+        (client, conn, server, accepted)
+    }
+
+    #[test]
+    fn test_faults() {
+        let network = Loopback::with_seed(7);
+        let server = network.node::<()>(ip(1));
+        let client = network.node::<()>(ip(2));
+        let addr: SocketAddr = "10.0.0.1:7879".parse().unwrap();
+        server.listen(Protocol::Udp, addr).unwrap();
+        let conn = client.connect(Protocol::Udp, addr).unwrap();
+        drain(&client);
+
+        network.set_faults(Faults { latency: Duration::from_millis(50), duplicate: 1.0, ..Faults::default() });
+        assert!(client.send(conn, b"twice"));
+        assert!(drain(&server).is_empty());
+        assert!(network.advance());
+        assert_eq!(network.now(), Duration::from_millis(50));
+        assert!(matches!(&drain(&server)[..], [Event::Datagram(_, _, a), Event::Datagram(_, _, b)] if a == b"twice" && b == b"twice"));
+
+        network.set_faults(Faults { loss: 1.0, ..Faults::default() });
+        assert!(client.send(conn, b"lost"));
+        assert!(!network.advance());
+        assert!(drain(&server).is_empty());
+    }
+
+    #[test]
+    fn test_streams_are_only_delayed() {
+        let network = Loopback::with_seed(7);
+        let (client, conn, server, _) = connected(&network);
+
+        network.set_faults(Faults { latency: Duration::from_millis(50), duplicate: 1.0, loss: 1.0, ..Faults::default() });
+        assert!(client.send(conn, b"once"));
+        assert!(drain(&server).is_empty());
+        assert!(network.advance());
+        assert!(matches!(&drain(&server)[..], [Event::Message(_, data)] if data == b"once"));
+    }
+
+    #[test]
+    fn test_streams_keep_their_order() {
+        let network = Loopback::with_seed(7);
+        let (client, conn, server, _) = connected(&network);
+
+        network.set_faults(Faults { jitter: Duration::from_secs(1), reorder: true, ..Faults::default() });
+        for n in 0..20u8 {
+            assert!(client.send(conn, &[n]));
+        }
+        client.close(conn);
+
+        let mut events = vec![];
+        while network.advance() {
+            events.extend(drain(&server));
+        }
+        let received: Vec<u8> = events.iter()
+            .filter_map(|event| match event {
+                Event::Message(_, data) => Some(data[0]),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(received, (0..20).collect::<Vec<u8>>());
+        // what was sent arrives before the connection drops
+        assert!(matches!(events.last(), Some(Event::Disconnected(_))));
+    }
+
+    #[test]
+    fn test_disconnect() {
+        let network = Loopback::new();
+        let (client, conn, server, accepted) = connected(&network);
+
+        network.disconnect(ip(2));
+        assert!(matches!(drain(&client)[..], [Event::Disconnected(c)] if c == conn));
+        assert!(matches!(drain(&server)[..], [Event::Disconnected(c)] if c == accepted));
+        assert!(!client.send(conn, b"gone"));
+        assert!(!server.send(accepted, b"gone"));
+    }
+
+    #[test]
+    fn test_connection_refused() {
+        let network = Loopback::new();
+        let client = network.node::<()>(ip(2));
+
+        let conn = client.connect(Protocol::Tcp, "10.0.0.1:7878".parse().unwrap()).unwrap();
+        assert!(matches!(drain(&client)[..], [Event::Connected(c, false)] if c == conn));
+    }
+
+    #[test]
+    fn test_signals_wait_for_the_clock() {
+        let network = Loopback::new();
+        let node = network.node::<u32>(ip(1));
+
+        node.signal(2, Duration::from_secs(5));
+        node.signal(1, Duration::ZERO);
+        assert!(matches!(drain(&node)[..], [Event::Signal(1)]));
+
+        assert!(network.advance());
+        assert_eq!(network.now(), Duration::from_secs(5));
+        assert!(matches!(drain(&node)[..], [Event::Signal(2)]));
+        assert!(!network.advance());
+    }
+
+    #[test]
+    fn test_udp_group() {
+        let network = Loopback::new();
+        let alice = network.node::<()>(ip(1));
+        let bob = network.node::<()>(ip(2));
+        let group: SocketAddr = "239.255.42.99:7880".parse().unwrap();
+
+        alice.listen(Protocol::Udp, group).unwrap();
+        let (bob_listener, _) = bob.listen(Protocol::Udp, group).unwrap();
+
+        let conn = alice.connect(Protocol::Udp, group).unwrap();
+        assert!(alice.send(conn, b"hi"));
+        assert!(matches!(
+            &drain(&bob)[..],
+            [Event::Datagram(l, from, data)] if *l == bob_listener && from.ip() == ip(1) && data == b"hi"
+        ));
+        // our own datagrams come back, like multicast loopback
+        assert!(matches!(drain(&alice)[..], [Event::Connected(c, true), Event::Datagram(_, _, _)] if c == conn));
+    }
+}
@@ -1,17 +1,374 @@
-use message_io::node::{
-  self, NodeHandler, NodeListener
+pub mod backoff;
+pub mod discovery;
+pub mod loopback;
+pub mod peer;
+pub mod protocol;
+pub mod reliable;
+#[cfg(test)]
+mod sim;
+pub mod tls;
+pub mod transport;
+#[cfg(unix)]
+pub mod unix;
+mod worker;
+
+use std::{
+    io,
+    net::{ SocketAddr, ToSocketAddrs },
+    path::PathBuf,
+    sync::{ Arc, Mutex },
+    thread,
+    time::Duration,
+};
+use crossbeam_channel::Sender;
+use crate::{
+    common::app_event::{ AppEvent, NetworkEvent },
+    config::Config,
+    crypto::SigningKeyPair
+};
+use self::{
+    backoff::Backoff,
+    discovery::Discovery,
+    peer::Identity,
+    tls::TlsStream,
+    transport::{ self as sockets, Protocol, Sockets, Transport }
+};
+
+pub use self::{
+    protocol::Message,
+    worker::Signal
 };
+pub(crate) use self::worker::Worker;
+
+/// Scheme of server addresses reached over WebSocket
+pub const WS_SCHEME: &str = "ws://";
+/// Scheme of server addresses reached over TCP, the default
+pub const TCP_SCHEME: &str = "tcp://";
+/// Scheme of server addresses reached over UDP, see `reliable`
+pub const UDP_SCHEME: &str = "udp://";
+
+/// `Network` keeps the connection to the chat server, reconnecting
+/// when it drops, listens for peers and takes part in LAN discovery. A
+/// worker drives the `Transport` on a background thread and forwards
+/// everything it receives as `AppEvent::NetworkEvent`
+pub struct Network<T: Transport<Signal> = Sockets<Signal>> {
+    transport: T,
+    /// the server connection, `None` while disconnected
+    server: Arc<Mutex<Option<ServerLink<T::Conn>>>>,
+    local_port: u16,
+}
 
-pub enum Message {
+/// Where the server is, a network address or the path of a Unix socket
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum ServerAddr {
+    Net(Protocol, SocketAddr),
+    Unix(PathBuf),
 }
 
-///
-pub struct Network {
-  handler: NodeHandler<Message>,
-  listener: NodeListener<Message>
+impl ServerAddr {
+    /// Starts connecting to the server, `Event::Connected` tells how it went
+    pub fn connect<S, T: Transport<S>>(&self, transport: &T) -> io::Result<T::Conn> {
+        match self {
+            ServerAddr::Net(protocol, addr) => transport.connect(*protocol, *addr),
+            ServerAddr::Unix(path) => transport.connect_unix(path),
+        }
+    }
+}
+
+/// An established connection to the server, encrypted when TLS is on
+pub(crate) struct ServerLink<C> {
+    pub conn: C,
+    pub tls: Option<TlsStream>,
+}
+
+impl<C: Copy> ServerLink<C> {
+    pub fn send<S, T: Transport<S, Conn = C>>(&mut self, transport: &T, message: &Message) -> bool {
+        send_frame(transport, self.conn, self.tls.as_mut(), message)
+    }
 }
 
 impl Network {
-  fn new() {
-  }
+    pub fn connect(config: &Config, tx_event: Sender<AppEvent>) -> io::Result<Self> {
+        let (network, worker) = Self::with_transport(config, tx_event, sockets::sockets(), Backoff::new())?;
+        thread::spawn(move || worker.run());
+
+        Ok(network)
+    }
+}
+
+impl<T: Transport<Signal>> Network<T> {
+    /// Sets the network up on `transport`, reconnecting after the delays
+    /// of `backoff`. Nothing happens until the returned worker is run.
+    pub(crate) fn with_transport(
+        config: &Config,
+        tx_event: Sender<AppEvent>,
+        transport: T,
+        backoff: Backoff
+    ) -> io::Result<(Self, Worker<T>)> {
+        let (server_addr, name) = server_endpoint(&config.server_addr)?;
+        let tls = match &config.tls {
+            Some(trust) => Some((tls::client_config(trust)?, name)),
+            None => None,
+        };
+        // a server that is not up yet is retried like a dropped connection
+        let server = server_addr.connect(&transport);
+        let (_, local_addr) = transport.listen(Protocol::Tcp, SocketAddr::from(([0, 0, 0, 0], config.port)))?;
+
+        // discovery is best effort, chatting through the server still works without it
+        let discovery = match config.discovery {
+            true => Discovery::start(&transport, local_addr.port())
+                .map_err(|e| {
+                    let _ = tx_event.send(AppEvent::NetworkEvent(
+                        NetworkEvent::DiscoveryUnavailable(e.to_string())
+                    ));
+                })
+                .ok(),
+            false => None,
+        };
+
+        let connected = Arc::new(Mutex::new(None));
+        let worker = Worker::new(
+            transport.clone(),
+            tx_event,
+            (server_addr, server),
+            Arc::clone(&connected),
+            tls,
+            discovery,
+            backoff
+        );
+
+        let network = Network {
+            transport,
+            server: connected,
+            local_port: local_addr.port(),
+        };
+        Ok((network, worker))
+    }
+
+    /// Port other clients can connect to us on
+    pub fn local_port(&self) -> u16 {
+        self.local_port
+    }
+
+    /// Sets the user we are, the server is greeted and the LAN
+    /// is told about us from now on
+    pub fn set_identity(&self, user_id: &str, user_name: &str, public_key: Vec<u8>, signing_key: SigningKeyPair) {
+        self.transport.signal(Signal::Identity(Identity {
+            user_id: user_id.to_string(),
+            user_name: user_name.to_string(),
+            public_key,
+            signing_key,
+        }), Duration::ZERO);
+    }
+
+    /// Opens a direct connection to the client listening on `addr`,
+    /// `NetworkEvent::PeerConnected` follows once it said hello
+    pub fn connect_peer(&self, addr: SocketAddr) {
+        self.transport.signal(Signal::ConnectPeer(addr), Duration::ZERO);
+    }
+
+    /// Closes the direct connection to `user_id`
+    pub fn disconnect_peer(&self, user_id: &str) {
+        self.transport.signal(Signal::DisconnectPeer(user_id.to_string()), Duration::ZERO);
+    }
+
+    /// Sends `message` straight to the connected peer `user_id`, for chat
+    /// messages `NetworkEvent::MessageSent` or `MessageFailed` follows
+    pub fn send_direct(&self, user_id: &str, message: Message) {
+        self.transport.signal(Signal::SendDirect {
+            user_id: user_id.to_string(),
+            message,
+        }, Duration::ZERO);
+    }
+
+    /// Sends `message` to the server, returns `false` if we are not
+    /// connected or it could not be written to the connection
+    pub fn send(&self, message: &Message) -> bool {
+        match self.server.lock().unwrap().as_mut() {
+            Some(link) => link.send(&self.transport, message),
+            None => false,
+        }
+    }
+}
+
+impl<T: Transport<Signal>> Drop for Network<T> {
+    fn drop(&mut self) {
+        self.send(&Message::Bye);
+        self.transport.stop();
+    }
+}
+
+pub(crate) fn send_to<S, T: Transport<S>>(transport: &T, conn: T::Conn, message: &Message) -> bool {
+    let frame = protocol::encode(message);
+    transport.send(conn, &frame)
+}
+
+/// Writes `message` to `conn`, through `tls` if the connection has a session
+pub(crate) fn send_frame<S, T: Transport<S>>(
+    transport: &T,
+    conn: T::Conn,
+    tls: Option<&mut TlsStream>,
+    message: &Message
+) -> bool {
+    let frame = protocol::encode(message);
+    let data = match tls {
+        Some(tls) => match tls.send(&frame) {
+            Ok(()) => tls.outgoing(),
+            Err(_) => return false,
+        },
+        None => frame,
+    };
+
+    transport.send(conn, &data)
+}
+
+/// Where `addr` is and the TLS server name of it, a `unix://` path is
+/// connected to straight over its socket
+fn server_endpoint(addr: &str) -> io::Result<(ServerAddr, String)> {
+    #[cfg(unix)]
+    if let Some(path) = addr.strip_prefix(unix::UNIX_SCHEME) {
+        return Ok((ServerAddr::Unix(PathBuf::from(path)), "localhost".to_string()));
+    }
+
+    let (transport, addr) = split_scheme(addr);
+    Ok((ServerAddr::Net(transport, resolve(addr)?), server_name(addr)))
+}
+
+/// Protocol and `host:port` of a server address, `ws://host:port` is
+/// reached over WebSocket, `udp://host:port` over UDP and anything else over TCP
+pub(crate) fn split_scheme(addr: &str) -> (Protocol, &str) {
+    if let Some(rest) = addr.strip_prefix(WS_SCHEME) {
+        // the relay takes any path
+        return (Protocol::Ws, rest.split('/').next().unwrap_or_default());
+    }
+
+    match addr.strip_prefix(UDP_SCHEME) {
+        Some(rest) => (Protocol::Udp, rest),
+        None => (Protocol::Tcp, addr.strip_prefix(TCP_SCHEME).unwrap_or(addr)),
+    }
+}
+
+/// Host part of `host:port`, the name the server certificate must be for
+fn server_name(addr: &str) -> String {
+    let host = match addr.rsplit_once(':') {
+        Some((host, port)) if port.chars().all(|c| c.is_ascii_digit()) => host,
+        _ => addr,
+    };
+
+    host.trim_start_matches('[').trim_end_matches(']').to_string()
+}
+
+pub(crate) fn resolve(addr: &str) -> io::Result<SocketAddr> {
+    addr.to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Not able to resolve address {}", addr)
+        ))
+}
+
+#[cfg(test)]
+mod tests {
+    use crossbeam_channel::{ unbounded, Receiver };
+    use super::*;
+    use crate::net::protocol::Body;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn start(user_id: &str) -> (Network, Receiver<AppEvent>) {
+        let (tx, rx) = unbounded();
+        // nothing listens on the discard port, the relay is not needed here
+        let config = Config {
+            server_addr: "127.0.0.1:9".to_string(),
+            port: 0,
+            discovery: false,
+            tls: None,
+        };
+        let network = Network::connect(&config, tx).unwrap();
+        network.set_identity(user_id, &user_id.to_uppercase(), vec![], SigningKeyPair::generate());
+
+        (network, rx)
+    }
+
+    /// Waits for the first event `f` accepts, skipping the others
+    fn wait_for<T>(rx: &Receiver<AppEvent>, f: impl Fn(NetworkEvent) -> Option<T>) -> T {
+        loop {
+            match rx.recv_timeout(TIMEOUT) {
+                Ok(AppEvent::NetworkEvent(event)) => if let Some(value) = f(event) {
+                    return value;
+                },
+                Ok(_) => {},
+                Err(e) => panic!("no expected network event: {}", e),
+            }
+        }
+    }
+
+    fn chat(id: &str, text: &str) -> Message {
+        Message::Chat {
+            id: id.to_string(),
+            from: String::new(),
+            to: None,
+            body: Body::Plain(text.to_string()),
+            clock: 1,
+        }
+    }
+
+    #[test]
+    fn test_split_scheme() {
+        assert_eq!(split_scheme("chat.local:7878"), (Protocol::Tcp, "chat.local:7878"));
+        assert_eq!(split_scheme("tcp://chat.local:7878"), (Protocol::Tcp, "chat.local:7878"));
+        assert_eq!(split_scheme("ws://chat.local:8080"), (Protocol::Ws, "chat.local:8080"));
+        assert_eq!(split_scheme("ws://chat.local:8080/oisg"), (Protocol::Ws, "chat.local:8080"));
+        assert_eq!(split_scheme("udp://chat.local:7879"), (Protocol::Udp, "chat.local:7879"));
+    }
+
+    #[test]
+    fn test_server_name() {
+        assert_eq!(server_name("chat.example.org:7878"), "chat.example.org");
+        assert_eq!(server_name("127.0.0.1:7878"), "127.0.0.1");
+        assert_eq!(server_name("[::1]:7878"), "::1");
+        assert_eq!(server_name("localhost"), "localhost");
+    }
+
+    #[test]
+    fn test_direct_chat_between_peers() {
+        let (alice, alice_rx) = start("alice");
+        let (bob, bob_rx) = start("bob");
+
+        alice.connect_peer(SocketAddr::from(([127, 0, 0, 1], bob.local_port())));
+
+        let connected = |event| match event {
+            NetworkEvent::PeerConnected { user_id, .. } => Some(user_id),
+            _ => None,
+        };
+        assert_eq!(wait_for(&alice_rx, connected), "bob");
+        assert_eq!(wait_for(&bob_rx, connected), "alice");
+
+        let received = |event| match event {
+            NetworkEvent::MessageReceived(Message::Chat { from, body: Body::Plain(text), .. }) => Some((from, text)),
+            _ => None,
+        };
+
+        alice.send_direct("bob", chat("a1", "hi bob"));
+        assert_eq!(wait_for(&bob_rx, received), ("alice".to_string(), "hi bob".to_string()));
+        assert_eq!(
+            wait_for(&alice_rx, |event| match event {
+                NetworkEvent::MessageReceived(Message::Ack { id }) => Some(id),
+                _ => None,
+            }),
+            "a1"
+        );
+
+        bob.send_direct("alice", chat("b1", "hi alice"));
+        assert_eq!(wait_for(&alice_rx, received), ("bob".to_string(), "hi alice".to_string()));
+
+        bob.send_direct("carol", chat("b2", "anyone?"));
+        assert_eq!(
+            wait_for(&bob_rx, |event| match event {
+                NetworkEvent::MessageFailed { id, .. } => Some(id),
+                _ => None,
+            }),
+            "b2"
+        );
+    }
 }
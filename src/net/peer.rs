@@ -0,0 +1,309 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+};
+use crate::{
+    common::app_event::NetworkEvent,
+    constants,
+    crypto::SigningKeyPair,
+    net::protocol::Message
+};
+
+/// The local user as introduced to servers and peers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    pub user_id: String,
+    pub user_name: String,
+    pub public_key: Vec<u8>,
+    /// proves to the relay that `user_id` is ours
+    pub signing_key: SigningKeyPair,
+}
+
+impl Identity {
+    pub fn hello(&self) -> Message {
+        Message::Hello {
+            version: constants::APP_VERSION.to_string(),
+            user_id: self.user_id.clone(),
+            user_name: self.user_name.clone(),
+            public_key: self.public_key.clone(),
+        }
+    }
+
+    /// Answer to the relay's `Challenge`
+    pub fn auth(&self, nonce: &[u8]) -> Message {
+        Message::Auth {
+            signing_key: self.signing_key.public_bytes(),
+            signature: self.signing_key.sign_challenge(&self.user_id, nonce),
+        }
+    }
+}
+
+struct Session {
+    /// `true` if we opened the connection
+    initiated: bool,
+    /// `true` once we sent our hello
+    introduced: bool,
+    user_id: Option<String>,
+}
+
+/// `Peers` keeps the direct connections to other clients. The side that
+/// connects says hello first and the other side answers with its own
+/// hello, after that both can chat. Like `server::relay::Relay` it does
+/// no I/O, the caller delivers the returned `(connection, message)` pairs.
+pub struct Peers<C> {
+    sessions: HashMap<C, Session>,
+    connections: HashMap<String, C>,
+}
+
+impl<C: Copy + Eq + Hash> Default for Peers<C> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<C: Copy + Eq + Hash> Peers<C> {
+    pub fn new() -> Self {
+        Peers {
+            sessions: HashMap::new(),
+            connections: HashMap::new(),
+        }
+    }
+
+    pub fn is_peer(&self, conn: C) -> bool {
+        self.sessions.contains_key(&conn)
+    }
+
+    /// Connection of the peer `user_id` once the handshake is done
+    pub fn connection(&self, user_id: &str) -> Option<C> {
+        self.connections.get(user_id).copied()
+    }
+
+    /// Starts tracking a new connection, returns the hello to send
+    /// if we are the one who connected
+    pub fn opened(&mut self, conn: C, initiated: bool, identity: Option<&Identity>) -> Vec<(C, Message)> {
+        let hello = match (initiated, identity) {
+            (true, Some(identity)) => vec![(conn, identity.hello())],
+            _ => vec![],
+        };
+        self.sessions.insert(conn, Session {
+            initiated,
+            introduced: !hello.is_empty(),
+            user_id: None,
+        });
+
+        hello
+    }
+
+    /// Says the hellos that had to wait for our identity to be known
+    pub fn introduce(&mut self, identity: &Identity) -> Vec<(C, Message)> {
+        self.sessions.iter_mut()
+            .filter(|(_, session)| !session.introduced && (session.initiated || session.user_id.is_some()))
+            .map(|(conn, session)| {
+                session.introduced = true;
+                (*conn, identity.hello())
+            })
+            .collect()
+    }
+
+    /// Forgets the connection, returns the event to report if
+    /// the peer had introduced itself
+    pub fn closed(&mut self, conn: C) -> Option<NetworkEvent> {
+        let user_id = self.sessions.remove(&conn)?.user_id?;
+
+        if self.connections.get(&user_id) == Some(&conn) {
+            self.connections.remove(&user_id);
+        }
+        Some(NetworkEvent::PeerDisconnected(user_id))
+    }
+
+    pub fn handle(
+        &mut self,
+        conn: C,
+        message: Message,
+        identity: Option<&Identity>
+    ) -> (Vec<(C, Message)>, Option<NetworkEvent>) {
+        let session = match self.sessions.get_mut(&conn) {
+            Some(session) => session,
+            None => return (vec![], None),
+        };
+
+        match message {
+            Message::Hello { user_id, user_name, public_key, .. } => {
+                session.user_id = Some(user_id.clone());
+                let reply = match (session.introduced, identity) {
+                    (false, Some(identity)) => {
+                        session.introduced = true;
+                        vec![(conn, identity.hello())]
+                    },
+                    _ => vec![],
+                };
+
+                // the latest connection of a peer takes over
+                self.connections.insert(user_id.clone(), conn);
+                (reply, Some(NetworkEvent::PeerConnected { user_id, user_name, public_key }))
+            },
+            Message::Chat { id, to, body, clock, .. } => {
+                // peers chat only after hello, and always as themselves
+                let from = match &session.user_id {
+                    Some(user_id) => user_id.clone(),
+                    None => return (vec![], None),
+                };
+
+                let chat = Message::Chat { id: id.clone(), from, to, body, clock };
+                (vec![(conn, Message::Ack { id })], Some(NetworkEvent::MessageReceived(chat)))
+            },
+            Message::Read { to, id, .. } => {
+                let by = match &session.user_id {
+                    Some(user_id) => user_id.clone(),
+                    None => return (vec![], None),
+                };
+
+                (vec![], Some(NetworkEvent::MessageReceived(Message::Read { by, to, id })))
+            },
+            Message::Typing { .. } | Message::StoppedTyping { .. } => {
+                let from = match &session.user_id {
+                    Some(user_id) => user_id.clone(),
+                    None => return (vec![], None),
+                };
+                let typing = match message {
+                    Message::Typing { to, .. } => Message::Typing { from, to },
+                    Message::StoppedTyping { to, .. } => Message::StoppedTyping { from, to },
+                    _ => return (vec![], None),
+                };
+
+                (vec![], Some(NetworkEvent::MessageReceived(typing)))
+            },
+            Message::File { id, to, part, .. } => {
+                let from = match &session.user_id {
+                    Some(user_id) => user_id.clone(),
+                    None => return (vec![], None),
+                };
+
+                (vec![], Some(NetworkEvent::MessageReceived(Message::File { id, from, to, part })))
+            },
+            Message::Ack { .. } => (vec![], Some(NetworkEvent::MessageReceived(message))),
+            Message::Ping => (vec![(conn, Message::Pong)], None),
+            Message::Bye => (vec![], self.closed(conn)),
+            Message::Welcome { .. } | Message::Stored { .. } | Message::Error { .. } | Message::Pong |
+            Message::Announce { .. } | Message::Presence { .. } |
+            Message::PublicKey { .. } | Message::Join { .. } | Message::Leave { .. } |
+            Message::HistoryRequest { .. } | Message::HistoryEnd { .. } |
+            Message::Challenge { .. } | Message::Auth { .. } |
+            Message::PeerHello { .. } | Message::Federated { .. } => (vec![], None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::protocol::Body;
+
+    fn identity(user_id: &str) -> Identity {
+        Identity {
+            user_id: user_id.to_string(),
+            user_name: user_id.to_uppercase(),
+            public_key: vec![user_id.len() as u8; 32],
+            signing_key: SigningKeyPair::generate(),
+        }
+    }
+
+    fn chat(text: &str) -> Message {
+        Message::Chat {
+            id: "m1".to_string(),
+            from: "spoofed".to_string(),
+            to: None,
+            body: Body::Plain(text.to_string()),
+            clock: 1,
+        }
+    }
+
+    /// Runs the handshake between alice (connection 0 on her side)
+    /// and bob (connection 1 on his side)
+    fn handshake() -> (Peers<usize>, Peers<usize>) {
+        let alice_id = identity("alice");
+        let bob_id = identity("bob");
+        let mut alice = Peers::new();
+        let mut bob = Peers::new();
+
+        let hello = alice.opened(0, true, Some(&alice_id));
+        assert_eq!(hello, vec![(0, alice_id.hello())]);
+        assert!(bob.opened(1, false, Some(&bob_id)).is_empty());
+
+        let (reply, event) = bob.handle(1, hello[0].1.clone(), Some(&bob_id));
+        assert_eq!(reply, vec![(1, bob_id.hello())]);
+        assert!(matches!(event, Some(NetworkEvent::PeerConnected { user_id, .. }) if user_id == "alice"));
+
+        let (reply, event) = alice.handle(0, reply[0].1.clone(), Some(&alice_id));
+        assert!(reply.is_empty());
+        assert!(matches!(event, Some(NetworkEvent::PeerConnected { user_id, .. }) if user_id == "bob"));
+
+        (alice, bob)
+    }
+
+    #[test]
+    fn test_handshake() {
+        let (alice, bob) = handshake();
+
+        assert_eq!(alice.connection("bob"), Some(0));
+        assert_eq!(bob.connection("alice"), Some(1));
+        assert_eq!(alice.connection("carol"), None);
+    }
+
+    #[test]
+    fn test_chat_both_ways() {
+        let (mut alice, mut bob) = handshake();
+
+        let (reply, event) = bob.handle(1, chat("hi bob"), None);
+        assert_eq!(reply, vec![(1, Message::Ack { id: "m1".to_string() })]);
+        match event {
+            Some(NetworkEvent::MessageReceived(Message::Chat { from, body: Body::Plain(text), .. })) => {
+                assert_eq!(from, "alice");
+                assert_eq!(text, "hi bob");
+            },
+            other => panic!("expected chat, got {:?}", other),
+        }
+
+        let (_, event) = alice.handle(0, chat("hi alice"), None);
+        assert!(matches!(
+            event, Some(NetworkEvent::MessageReceived(Message::Chat { from, .. })) if from == "bob"
+        ));
+    }
+
+    #[test]
+    fn test_chat_before_hello_is_ignored() {
+        let mut bob: Peers<usize> = Peers::new();
+        bob.opened(3, false, Some(&identity("bob")));
+
+        assert_eq!(bob.handle(3, chat("hi"), None), (vec![], None));
+        assert_eq!(bob.handle(4, chat("hi"), None), (vec![], None));
+    }
+
+    #[test]
+    fn test_hello_waits_for_identity() {
+        let bob_id = identity("bob");
+        let mut bob: Peers<usize> = Peers::new();
+        bob.opened(1, false, None);
+        bob.opened(2, true, None);
+
+        // alice says hello before bob knows who he is
+        let (reply, event) = bob.handle(1, identity("alice").hello(), None);
+        assert!(reply.is_empty());
+        assert!(event.is_some());
+
+        let mut out = bob.introduce(&bob_id);
+        out.sort_by_key(|(conn, _)| *conn);
+        assert_eq!(out, vec![(1, bob_id.hello()), (2, bob_id.hello())]);
+        assert!(bob.introduce(&bob_id).is_empty());
+    }
+
+    #[test]
+    fn test_closed() {
+        let (mut alice, _) = handshake();
+
+        assert!(matches!(alice.closed(0), Some(NetworkEvent::PeerDisconnected(user_id)) if user_id == "bob"));
+        assert_eq!(alice.connection("bob"), None);
+        assert!(!alice.is_peer(0));
+        assert!(alice.closed(0).is_none());
+    }
+}
@@ -0,0 +1,626 @@
+use std::{
+    fmt,
+    sync::atomic::{ AtomicU32, Ordering },
+    time::{ SystemTime, UNIX_EPOCH },
+};
+use serde::{ Serialize, Deserialize };
+
+/// Version of the wire format, bump it whenever `Message` changes in a
+/// way older clients can not decode
+pub const PROTOCOL_VERSION: u16 = 4;
+
+/// Frame header, payload length (`u32`) followed by the protocol version (`u16`)
+pub const FRAME_HEADER_LEN: usize = 6;
+const LENGTH_LEN: usize = 4;
+const VERSION_LEN: usize = 2;
+
+/// Frames bigger than this are rejected without being buffered
+pub const MAX_FRAME_LEN: usize = 1024 * 1024;
+
+/// Rooms are addressed like users, by a name starting with this
+pub const ROOM_PREFIX: char = '#';
+/// Users of another relay are addressed as `user_id@relay`
+pub const RELAY_SEPARATOR: char = '@';
+const MAX_ROOM_LEN: usize = 50;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorCode {
+    VersionMismatch,
+    NotRegistered,
+    UnknownRecipient,
+    InvalidMessage,
+    /// the connection sends more than the server allows
+    RateLimited,
+    /// the user id belongs to someone else, or the challenge was not signed
+    AuthFailed,
+}
+
+impl ErrorCode {
+    /// Whether the message may be taken when sent again later, after
+    /// signing in or slowing down
+    pub fn is_transient(&self) -> bool {
+        matches!(self, ErrorCode::NotRegistered | ErrorCode::RateLimited)
+    }
+}
+
+/// Whether a user is around to chat
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Presence {
+    Online,
+    /// no input for a while
+    Away,
+    Offline,
+}
+
+impl Presence {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Presence::Online => "online",
+            Presence::Away => "away",
+            Presence::Offline => "offline",
+        }
+    }
+}
+
+/// Content of a chat message
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Body {
+    /// readable by the relay, used for chats sent to everyone
+    Plain(String),
+    /// end-to-end encrypted for the one recipient, see `crypto::Keyring`
+    Sealed {
+        nonce: Vec<u8>,
+        ciphertext: Vec<u8>,
+    },
+}
+
+/// Step of a file transfer between two users, see `transfer::Transfers`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FilePart {
+    /// the sender offers the file, offered again to resume after a reconnect
+    Offer {
+        name: String,
+        size: u64,
+        /// SHA-256 of the whole file as hex
+        sha256: String,
+    },
+    /// the receiver has every chunk before `received`
+    Ack {
+        received: u64,
+    },
+    Chunk {
+        index: u64,
+        data: Vec<u8>,
+    },
+    /// the receiver has the whole file, `verified` if its SHA-256 matched
+    Done {
+        verified: bool,
+    },
+    /// one of the others end-to-end encrypted for the one recipient, see
+    /// `crypto::Keyring::seal_part`
+    Sealed {
+        nonce: Vec<u8>,
+        ciphertext: Vec<u8>,
+    },
+}
+
+/// `Message` is the unit of data exchanged between clients and the relay
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Message {
+    /// First message of a client, tells the server who owns the connection
+    Hello {
+        version: String,
+        user_id: String,
+        user_name: String,
+        /// X25519 key others encrypt for us with, empty if we have none
+        public_key: Vec<u8>,
+    },
+    /// Answer of the relay to `Hello`, the client proves the user id is
+    /// its own by signing `nonce`
+    Challenge {
+        nonce: Vec<u8>,
+    },
+    /// Answer to `Challenge`, `signature` is made with the Ed25519 key
+    /// `signing_key` belongs to
+    Auth {
+        signing_key: Vec<u8>,
+        signature: Vec<u8>,
+    },
+    /// Answer to `Auth` once the user has been registered, `relay` is the
+    /// name others reach the user by, empty if the relay does not peer
+    Welcome {
+        version: String,
+        relay: String,
+    },
+    /// `to` is the receiving user_id, `None` sends to everyone
+    Chat {
+        id: String,
+        from: String,
+        to: Option<String>,
+        body: Body,
+        /// Lamport time of the sender, see `common::clock::LamportClock`
+        clock: u64,
+    },
+    /// Confirms the chat message with the given id was received
+    Ack {
+        id: String,
+    },
+    /// The relay keeps the chat message `id` for a recipient that is not
+    /// around, `Ack` follows once the recipient has it
+    Stored {
+        id: String,
+    },
+    /// `by` has seen the chat message `id` of `to`, and the ones before it
+    Read {
+        by: String,
+        to: String,
+        id: String,
+    },
+    /// `id` is the chat message the error is about, if any
+    Error {
+        code: ErrorCode,
+        message: String,
+        id: Option<String>,
+    },
+    Ping,
+    Pong,
+    /// Sent before closing the connection on purpose
+    Bye,
+    /// Periodic LAN broadcast of a client, `port` is its TCP listening port
+    Announce {
+        user_id: String,
+        user_name: String,
+        port: u16,
+    },
+    /// `from` is typing a message to `to`, `None` is everyone
+    Typing {
+        from: String,
+        to: Option<String>,
+    },
+    /// `from` stopped typing without sending
+    StoppedTyping {
+        from: String,
+        to: Option<String>,
+    },
+    /// Presence of `user_id`, sent by clients for themselves and by the
+    /// relay to tell everyone else
+    Presence {
+        user_id: String,
+        user_name: String,
+        presence: Presence,
+    },
+    /// Sent by the relay so users can encrypt chats for `user_id`
+    PublicKey {
+        user_id: String,
+        public_key: Vec<u8>,
+    },
+    /// Part of the file transfer `id` between `from` and `to`
+    File {
+        id: String,
+        from: String,
+        to: String,
+        part: FilePart,
+    },
+    /// `user_id` is a member of `room`. Clients send it to join, creating
+    /// the room if nobody is in it, the relay to tell the members.
+    Join {
+        room: String,
+        user_id: String,
+    },
+    /// `user_id` left `room`, sent like `Join`
+    Leave {
+        room: String,
+        user_id: String,
+    },
+    /// Asks the relay for the chats it relayed to us since `since`, in
+    /// seconds since the unix epoch. They are sent as `Chat` messages.
+    HistoryRequest {
+        since: u64,
+    },
+    /// Follows the `count` chats sent for a `HistoryRequest`
+    HistoryEnd {
+        count: u32,
+    },
+    /// First message on a link between two relays, names the relay sending it
+    PeerHello {
+        version: String,
+        relay: String,
+    },
+    /// A message passed on between relays. `via` names the relays it went
+    /// through, the one it started at first, and its user ids carry the
+    /// relay of the user as `user_id@relay`.
+    Federated {
+        via: Vec<String>,
+        message: Box<Message>,
+    },
+}
+
+impl Message {
+    pub fn error(code: ErrorCode, message: &str) -> Self {
+        Message::Error {
+            code,
+            message: message.to_string(),
+            id: None,
+        }
+    }
+
+    /// Error about the chat message `id`
+    pub fn error_for(id: &str, code: ErrorCode, message: &str) -> Self {
+        Message::Error {
+            code,
+            message: message.to_string(),
+            id: Some(id.to_string()),
+        }
+    }
+}
+
+/// Whether `to` of a message names a room rather than a user
+pub fn is_room(to: &str) -> bool {
+    to.starts_with(ROOM_PREFIX)
+}
+
+/// `#` followed by up to 49 characters that are not spaces or commas
+pub fn is_valid_room(room: &str) -> bool {
+    is_room(room)
+        && room.len() > ROOM_PREFIX.len_utf8()
+        && room.len() <= MAX_ROOM_LEN
+        && !room.chars().any(|c| c.is_whitespace() || c == ',')
+}
+
+/// Splits `user_id@relay` into the user id and the relay, `None` for
+/// users of the relay we are on
+pub fn split_relay(user_id: &str) -> (&str, Option<&str>) {
+    match user_id.rsplit_once(RELAY_SEPARATOR) {
+        Some((user_id, relay)) => (user_id, Some(relay)),
+        None => (user_id, None),
+    }
+}
+
+/// Returns a unique id for a new chat message of `user_id`
+pub fn new_message_id(user_id: &str) -> String {
+    // ids made within the same clock tick still differ by the counter
+    static COUNTER: AtomicU32 = AtomicU32::new(0);
+
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+
+    format!("{}-{:x}-{:x}", user_id, nanos, count)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameError {
+    /// The frame was written with another protocol version
+    VersionMismatch {
+        local: u16,
+        remote: u16,
+    },
+    /// The announced frame length exceeds `MAX_FRAME_LEN`
+    TooLarge(usize),
+    /// The payload could not be decoded into a `Message`
+    Malformed(String),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::VersionMismatch { local, remote } => write!(
+                f, "protocol version mismatch, local v{} remote v{}", local, remote
+            ),
+            FrameError::TooLarge(len) => write!(f, "frame of {} bytes is too large", len),
+            FrameError::Malformed(e) => write!(f, "malformed message: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+/// Encodes `message` into a length-prefixed frame
+pub fn encode(message: &Message) -> Vec<u8> {
+    encode_with_version(message, PROTOCOL_VERSION)
+}
+
+fn encode_with_version(message: &Message, version: u16) -> Vec<u8> {
+    // serializing an in-memory enum of strings can not fail
+    let payload = bincode::serialize(message).unwrap_or_default();
+
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+    frame.extend_from_slice(&((VERSION_LEN + payload.len()) as u32).to_be_bytes());
+    frame.extend_from_slice(&version.to_be_bytes());
+    frame.extend_from_slice(&payload);
+
+    frame
+}
+
+/// Decodes a buffer holding exactly one frame, as received from
+/// datagram transports
+pub fn decode(data: &[u8]) -> Result<Message, FrameError> {
+    let mut decoder = FrameDecoder::new();
+    decoder.push(data);
+
+    decoder.next_message()
+        .unwrap_or_else(|| Err(FrameError::Malformed("incomplete frame".to_string())))
+}
+
+/// Bytes `message` takes on the wire, header included
+pub fn frame_len(message: &Message) -> usize {
+    FRAME_HEADER_LEN + bincode::serialized_size(message).unwrap_or_default() as usize
+}
+
+/// `FrameDecoder` collects the bytes of a stream and splits them into messages
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+    max_len: usize,
+    // bytes still to be thrown away from an oversized frame
+    skip: usize,
+}
+
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self::with_max_len(MAX_FRAME_LEN)
+    }
+
+    /// Decoder rejecting messages longer than `max_len`, capped at `MAX_FRAME_LEN`
+    pub fn with_max_len(max_len: usize) -> Self {
+        FrameDecoder {
+            buffer: Vec::new(),
+            max_len: max_len.min(MAX_FRAME_LEN),
+            skip: 0,
+        }
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        let skipped = self.skip.min(data.len());
+        self.skip -= skipped;
+        self.buffer.extend_from_slice(&data[skipped..]);
+    }
+
+    /// Returns the next complete message, `None` when more data is needed
+    pub fn next_message(&mut self) -> Option<Result<Message, FrameError>> {
+        if self.buffer.len() < LENGTH_LEN {
+            return None;
+        }
+
+        let mut length = [0u8; LENGTH_LEN];
+        length.copy_from_slice(&self.buffer[..LENGTH_LEN]);
+        let length = u32::from_be_bytes(length) as usize;
+
+        if length > self.max_len + VERSION_LEN {
+            self.discard(length);
+            return Some(Err(FrameError::TooLarge(length)));
+        }
+
+        if length < VERSION_LEN {
+            self.discard(length);
+            return Some(Err(FrameError::Malformed("frame without version".to_string())));
+        }
+
+        if self.buffer.len() < LENGTH_LEN + length {
+            return None;
+        }
+
+        let frame: Vec<u8> = self.buffer.drain(..LENGTH_LEN + length).skip(LENGTH_LEN).collect();
+        let version = u16::from_be_bytes([frame[0], frame[1]]);
+        if version != PROTOCOL_VERSION {
+            return Some(Err(FrameError::VersionMismatch {
+                local: PROTOCOL_VERSION,
+                remote: version,
+            }));
+        }
+
+        Some(
+            bincode::deserialize(&frame[VERSION_LEN..])
+                .map_err(|e| FrameError::Malformed(e.to_string()))
+        )
+    }
+
+    /// Drops the current frame of `length` bytes, including the
+    /// part that has not been received yet
+    fn discard(&mut self, length: usize) {
+        let available = (self.buffer.len() - LENGTH_LEN).min(length);
+        self.buffer.drain(..LENGTH_LEN + available);
+        self.skip = length - available;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chat(text: &str) -> Message {
+        Message::Chat {
+            id: "alice-1".to_string(),
+            from: "alice".to_string(),
+            to: Some("bob".to_string()),
+            body: Body::Plain(text.to_string()),
+            clock: 1,
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let messages = vec![
+            Message::Hello {
+                version: "0.1.0".to_string(),
+                user_id: "alice".to_string(),
+                user_name: "Alice".to_string(),
+                public_key: vec![7; 32],
+            },
+            Message::Challenge { nonce: vec![3; 32] },
+            Message::Auth { signing_key: vec![4; 32], signature: vec![5; 64] },
+            Message::Welcome { version: "0.1.0".to_string(), relay: "london".to_string() },
+            chat("नमस्ते"),
+            Message::Chat {
+                id: "alice-2".to_string(),
+                from: "alice".to_string(),
+                to: Some("bob".to_string()),
+                body: Body::Sealed { nonce: vec![1; 12], ciphertext: vec![2; 40] },
+                clock: 2,
+            },
+            Message::Ack { id: "alice-1".to_string() },
+            Message::Stored { id: "alice-1".to_string() },
+            Message::error(ErrorCode::UnknownRecipient, "no such user"),
+            Message::error_for("alice-1", ErrorCode::UnknownRecipient, "no such user"),
+            Message::Read {
+                by: "bob".to_string(),
+                to: "alice".to_string(),
+                id: "alice-1".to_string(),
+            },
+            Message::Ping,
+            Message::Pong,
+            Message::Bye,
+            Message::Announce {
+                user_id: "alice".to_string(),
+                user_name: "Alice".to_string(),
+                port: 7879,
+            },
+            Message::File {
+                id: "alice-3".to_string(),
+                from: "alice".to_string(),
+                to: "bob".to_string(),
+                part: FilePart::Chunk { index: 2, data: vec![0xff; 1024] },
+            },
+            Message::File {
+                id: "alice-3".to_string(),
+                from: "alice".to_string(),
+                to: "bob".to_string(),
+                part: FilePart::Sealed { nonce: vec![1; 12], ciphertext: vec![2; 1040] },
+            },
+            Message::Join { room: "#rust".to_string(), user_id: "alice".to_string() },
+            Message::Leave { room: "#rust".to_string(), user_id: "alice".to_string() },
+            Message::HistoryRequest { since: 1_700_000_000 },
+            Message::HistoryEnd { count: 3 },
+            Message::PeerHello { version: "0.1.0".to_string(), relay: "london".to_string() },
+            Message::Federated {
+                via: vec!["london".to_string(), "paris".to_string()],
+                message: Box::new(Message::Join { room: "#rust".to_string(), user_id: "alice@london".to_string() }),
+            },
+        ];
+
+        let mut decoder = FrameDecoder::new();
+        for message in messages.iter() {
+            decoder.push(&encode(message));
+        }
+
+        for message in messages {
+            assert_eq!(decoder.next_message(), Some(Ok(message)));
+        }
+        assert_eq!(decoder.next_message(), None);
+    }
+
+    #[test]
+    fn test_message_ids_are_unique() {
+        let ids: std::collections::HashSet<String> = (0..1000)
+            .map(|_| new_message_id("alice"))
+            .collect();
+
+        assert_eq!(ids.len(), 1000);
+        assert!(ids.iter().all(|id| id.starts_with("alice-")));
+    }
+
+    #[test]
+    fn test_decode_datagram() {
+        let frame = encode(&Message::Ping);
+
+        assert_eq!(decode(&frame), Ok(Message::Ping));
+        assert!(decode(&frame[..frame.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_partial_frames() {
+        let frame = encode(&chat("split across reads"));
+        let mut decoder = FrameDecoder::new();
+
+        for byte in frame[..frame.len() - 1].iter() {
+            decoder.push(&[*byte]);
+            assert_eq!(decoder.next_message(), None);
+        }
+
+        decoder.push(&frame[frame.len() - 1..]);
+        assert_eq!(decoder.next_message(), Some(Ok(chat("split across reads"))));
+    }
+
+    #[test]
+    fn test_version_mismatch() {
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&encode_with_version(&chat("from the future"), PROTOCOL_VERSION + 1));
+        decoder.push(&encode(&Message::Ping));
+
+        assert_eq!(decoder.next_message(), Some(Err(FrameError::VersionMismatch {
+            local: PROTOCOL_VERSION,
+            remote: PROTOCOL_VERSION + 1,
+        })));
+
+        // the stream stays in sync after a mismatch
+        assert_eq!(decoder.next_message(), Some(Ok(Message::Ping)));
+    }
+
+    #[test]
+    fn test_too_large_frame_is_skipped() {
+        let length = (MAX_FRAME_LEN + VERSION_LEN + 1) as u32;
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&length.to_be_bytes());
+        decoder.push(&[0u8; 1024]);
+
+        assert_eq!(decoder.next_message(), Some(Err(FrameError::TooLarge(length as usize))));
+
+        let mut rest = vec![0u8; length as usize - 1024];
+        rest.extend_from_slice(&encode(&Message::Pong));
+        decoder.push(&rest);
+
+        assert_eq!(decoder.next_message(), Some(Ok(Message::Pong)));
+    }
+
+    #[test]
+    fn test_max_frame_len() {
+        let frame = encode(&chat("a little too long"));
+        assert_eq!(frame_len(&chat("a little too long")), frame.len());
+
+        let max_len = frame.len() - FRAME_HEADER_LEN - 1;
+        let mut decoder = FrameDecoder::with_max_len(max_len);
+        decoder.push(&frame);
+        decoder.push(&encode(&Message::Ping));
+
+        assert!(matches!(decoder.next_message(), Some(Err(FrameError::TooLarge(_)))));
+        assert_eq!(decoder.next_message(), Some(Ok(Message::Ping)));
+    }
+
+    #[test]
+    fn test_malformed_payload() {
+        let mut frame = Vec::new();
+        frame.extend_from_slice(&5u32.to_be_bytes());
+        frame.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+        frame.extend_from_slice(&[0xff, 0xff, 0xff]);
+
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&frame);
+        decoder.push(&encode(&Message::Bye));
+
+        assert!(matches!(decoder.next_message(), Some(Err(FrameError::Malformed(_)))));
+        assert_eq!(decoder.next_message(), Some(Ok(Message::Bye)));
+    }
+
+    #[test]
+    fn test_room_names() {
+        assert!(is_valid_room("#rust"));
+        assert!(!is_valid_room("#"));
+        assert!(!is_valid_room("rust"));
+        assert!(!is_valid_room("#rust lang"));
+        assert!(!is_valid_room(&format!("#{}", "r".repeat(50))));
+        assert!(is_room("#rust") && !is_room("alice"));
+    }
+
+    #[test]
+    fn test_split_relay() {
+        assert_eq!(split_relay("bob@berlin"), ("bob", Some("berlin")));
+        assert_eq!(split_relay("bob"), ("bob", None));
+    }
+}
@@ -0,0 +1,630 @@
+//! Reliable, ordered streams over UDP
+//!
+//! `Reliable` wraps a `Transport` so UDP connections behave like TCP
+//! ones: what is sent is cut into numbered segments, each is acknowledged
+//! and sent again until it is, and the other side hands the data on in
+//! order and only once. There is no connection setup, the first segment
+//! opens the stream on the listener and `Event::Accepted` follows. A
+//! stream is gone once it is closed from the other side or nothing has
+//! been acknowledged for a while.
+//!
+//! Multicast is left alone, discovery keeps seeing plain datagrams. TCP
+//! and WebSocket pass straight through.
+
+use std::{
+    collections::{ BTreeMap, HashMap, HashSet, VecDeque },
+    hash::Hash,
+    io,
+    net::SocketAddr,
+    sync::{ Arc, Mutex },
+    time::Duration,
+};
+use crate::net::transport::{ Event, Protocol, Transport };
+
+/// Bytes of data in a segment, small enough for a datagram anywhere
+pub const SEGMENT_LEN: usize = 1200;
+/// How often segments that were not acknowledged are sent again
+pub const RETRANSMIT_INTERVAL: Duration = Duration::from_millis(200);
+/// Segments in flight at once
+const WINDOW: u64 = 64;
+/// Segments kept for a gap before it is filled
+const RECEIVE_WINDOW: u64 = 4 * WINDOW;
+/// Retransmissions without anything acknowledged before the other side is given up on
+const GIVE_UP: u32 = 25;
+
+/// Kind (`u8`) and sequence number (`u64`) in front of every segment
+const HEADER_LEN: usize = 9;
+const DATA: u8 = 0;
+/// acknowledges every segment before its sequence number
+const ACK: u8 = 1;
+/// the stream was closed
+const FIN: u8 = 2;
+
+fn segment(kind: u8, seq: u64, data: &[u8]) -> Vec<u8> {
+    let mut segment = Vec::with_capacity(HEADER_LEN + data.len());
+    segment.push(kind);
+    segment.extend_from_slice(&seq.to_be_bytes());
+    segment.extend_from_slice(data);
+    segment
+}
+
+fn parse(datagram: &[u8]) -> Option<(u8, u64, &[u8])> {
+    if datagram.len() < HEADER_LEN {
+        return None;
+    }
+
+    let seq = u64::from_be_bytes(datagram[1..HEADER_LEN].try_into().ok()?);
+    Some((datagram[0], seq, &datagram[HEADER_LEN..]))
+}
+
+/// What a `Stream` made of a datagram
+#[derive(Debug, Default, PartialEq)]
+pub struct Received {
+    /// data to hand on, in order
+    pub data: Vec<Vec<u8>>,
+    /// datagrams to send back
+    pub replies: Vec<Vec<u8>>,
+    /// the other side closed the stream
+    pub closed: bool,
+}
+
+/// Both directions of one stream. It does no I/O, the caller sends the
+/// returned datagrams.
+#[derive(Default)]
+pub struct Stream {
+    next_seq: u64,
+    /// segments not acknowledged yet with the retransmissions since one
+    /// was, `None` while outside the window
+    unacked: BTreeMap<u64, (Vec<u8>, Option<u32>)>,
+    /// sequence number of the next segment to hand on
+    expected: u64,
+    /// segments that arrived after a gap
+    early: BTreeMap<u64, Vec<u8>>,
+    /// retransmissions since the other side acknowledged anything
+    silent: u32,
+}
+
+impl Stream {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cuts `data` into segments, returns the ones to send now
+    pub fn send(&mut self, data: &[u8]) -> Vec<Vec<u8>> {
+        for chunk in data.chunks(SEGMENT_LEN) {
+            self.unacked.insert(self.next_seq, (segment(DATA, self.next_seq, chunk), None));
+            self.next_seq += 1;
+        }
+
+        self.fill_window()
+    }
+
+    pub fn receive(&mut self, datagram: &[u8]) -> Received {
+        let mut received = Received::default();
+        match parse(datagram) {
+            Some((DATA, seq, data)) => {
+                if seq >= self.expected && seq < self.expected + RECEIVE_WINDOW {
+                    self.early.entry(seq).or_insert_with(|| data.to_vec());
+                }
+                while let Some(data) = self.early.remove(&self.expected) {
+                    received.data.push(data);
+                    self.expected += 1;
+                }
+
+                // repeated segments are acknowledged again, the first ack may be lost
+                received.replies.push(segment(ACK, self.expected, &[]));
+            },
+            Some((ACK, seq, _)) => {
+                let before = self.unacked.len();
+                self.unacked.retain(|unacked, _| *unacked >= seq);
+                if self.unacked.len() < before {
+                    self.silent = 0;
+                    received.replies = self.fill_window();
+                }
+            },
+            Some((FIN, _, _)) => received.closed = true,
+            _ => {},
+        }
+
+        received
+    }
+
+    /// Called every `RETRANSMIT_INTERVAL`, returns the segments to send
+    /// again or `None` if the other side is not answering
+    pub fn tick(&mut self) -> Option<Vec<Vec<u8>>> {
+        if self.unacked.is_empty() {
+            return Some(vec![]);
+        }
+
+        self.silent += 1;
+        if self.silent > GIVE_UP {
+            return None;
+        }
+
+        // segments sent since the last tick get a chance to be acknowledged first
+        let mut resend = vec![];
+        for (segment, waited) in self.unacked.values_mut() {
+            match waited {
+                Some(0) => *waited = Some(1),
+                Some(_) => {
+                    *waited = Some(0);
+                    resend.push(segment.clone());
+                },
+                None => break,
+            }
+        }
+
+        Some(resend)
+    }
+
+    /// `true` while segments wait to be acknowledged
+    pub fn is_busy(&self) -> bool {
+        !self.unacked.is_empty()
+    }
+
+    /// Segments that came into the window and were never sent
+    fn fill_window(&mut self) -> Vec<Vec<u8>> {
+        let end = self.unacked.keys().next().map_or(0, |first| first + WINDOW);
+        self.unacked.range_mut(..end)
+            .filter(|(_, (_, waited))| waited.is_none())
+            .map(|(_, (segment, waited))| {
+                *waited = Some(0);
+                segment.clone()
+            })
+            .collect()
+    }
+}
+
+/// Signal of a node under `Reliable`, it needs timers of its own
+#[derive(Debug)]
+pub enum Wake<S> {
+    Signal(S),
+    Retransmit,
+}
+
+/// Connection of a `Reliable` transport
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum ReliableConn<C> {
+    /// TCP, WebSocket or multicast, as the inner transport has it
+    Plain(C),
+    Udp(u64),
+}
+
+/// Where the datagrams of a stream go
+#[derive(Copy, Clone)]
+enum Route<C, L> {
+    /// a UDP connection we opened
+    Conn(C),
+    /// a client of one of our UDP listeners
+    Listener(L, SocketAddr),
+}
+
+/// A stream and where its datagrams go
+struct Link<C, L> {
+    route: Route<C, L>,
+    stream: Stream,
+}
+
+struct State<T: Transport<Wake<S>>, S: Send + 'static> {
+    next_id: u64,
+    streams: HashMap<u64, Link<T::Conn, T::Listener>>,
+    /// streams of the UDP connections we opened
+    connected: HashMap<T::Conn, u64>,
+    /// streams of the clients of our UDP listeners
+    accepted: HashMap<(T::Listener, SocketAddr), u64>,
+    /// UDP listeners that take streams
+    listeners: HashSet<T::Listener>,
+    /// events made of what the inner transport handed out, not handed on yet
+    events: VecDeque<Event<ReliableConn<T::Conn>, T::Listener, S>>,
+    /// a `Wake::Retransmit` is on its way
+    ticking: bool,
+}
+
+impl<S: Send + 'static, T: Transport<Wake<S>>> State<T, S> {
+    fn open(&mut self, route: Route<T::Conn, T::Listener>) -> u64 {
+        self.next_id += 1;
+        self.streams.insert(self.next_id, Link { route, stream: Stream::new() });
+        self.next_id
+    }
+
+    fn forget(&mut self, id: u64) -> Option<Route<T::Conn, T::Listener>> {
+        let Link { route, .. } = self.streams.remove(&id)?;
+        match route {
+            Route::Conn(conn) => self.connected.remove(&conn),
+            Route::Listener(listener, addr) => self.accepted.remove(&(listener, addr)),
+        };
+
+        Some(route)
+    }
+}
+
+/// `Reliable` runs streams over the UDP of the transport it wraps, see
+/// the module documentation
+pub struct Reliable<T: Transport<Wake<S>>, S: Send + 'static> {
+    inner: T,
+    state: Arc<Mutex<State<T, S>>>,
+}
+
+impl<S: Send + 'static, T: Transport<Wake<S>>> Reliable<T, S> {
+    pub fn new(inner: T) -> Self {
+        Reliable {
+            inner,
+            state: Arc::new(Mutex::new(State {
+                next_id: 0,
+                streams: HashMap::new(),
+                connected: HashMap::new(),
+                accepted: HashMap::new(),
+                listeners: HashSet::new(),
+                events: VecDeque::new(),
+                ticking: false,
+            })),
+        }
+    }
+
+    /// Sends `datagrams` along `route`, what does not get there is sent again
+    fn send_route(&self, route: Route<T::Conn, T::Listener>, datagrams: Vec<Vec<u8>>) {
+        for datagram in datagrams {
+            match route {
+                Route::Conn(conn) => self.inner.send(conn, &datagram),
+                Route::Listener(listener, addr) => self.inner.send_datagram(listener, addr, &datagram),
+            };
+        }
+    }
+
+    /// Starts the retransmission timer unless it runs already
+    fn tick_later(&self, state: &mut State<T, S>) {
+        if !state.ticking {
+            state.ticking = true;
+            self.inner.signal(Wake::Retransmit, RETRANSMIT_INTERVAL);
+        }
+    }
+
+    /// Hands `datagram` to stream `id`, the events it makes are queued
+    fn stream_datagram(&self, state: &mut State<T, S>, id: u64, datagram: &[u8]) {
+        let (route, received) = match state.streams.get_mut(&id) {
+            Some(Link { route, stream }) => (*route, stream.receive(datagram)),
+            None => return,
+        };
+
+        self.send_route(route, received.replies);
+        state.events.extend(received.data.into_iter().map(|data| Event::Message(ReliableConn::Udp(id), data)));
+        if received.closed {
+            self.lost(state, id);
+        }
+    }
+
+    /// Forgets stream `id` the other side is done with
+    fn lost(&self, state: &mut State<T, S>, id: u64) {
+        if let Some(Route::Conn(conn)) = state.forget(id) {
+            self.inner.close(conn);
+        }
+        state.events.push_back(Event::Disconnected(ReliableConn::Udp(id)));
+    }
+
+    /// A datagram on a listener taking streams, only the first segment
+    /// of a stream opens it
+    fn listener_datagram(&self, state: &mut State<T, S>, listener: T::Listener, from: SocketAddr, datagram: &[u8]) {
+        let id = match state.accepted.get(&(listener, from)) {
+            Some(id) => *id,
+            None => match parse(datagram) {
+                Some((DATA, 0, _)) => {
+                    let id = state.open(Route::Listener(listener, from));
+                    state.accepted.insert((listener, from), id);
+                    state.events.push_back(Event::Accepted(ReliableConn::Udp(id), listener));
+                    id
+                },
+                // a later segment overtook the first, or a stream we forgot
+                // about that is given up on once nothing gets acknowledged
+                _ => return,
+            },
+        };
+
+        self.stream_datagram(state, id, datagram);
+    }
+
+    fn retransmit(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.ticking = false;
+
+        let ids: Vec<u64> = state.streams.keys().copied().collect();
+        for id in ids {
+            let (route, resend) = match state.streams.get_mut(&id) {
+                Some(Link { route, stream }) => (*route, stream.tick()),
+                None => continue,
+            };
+
+            match resend {
+                Some(datagrams) => self.send_route(route, datagrams),
+                None => self.lost(&mut state, id),
+            }
+        }
+
+        if state.streams.values().any(|link| link.stream.is_busy()) {
+            self.tick_later(&mut state);
+        }
+    }
+}
+
+impl<S: Send + 'static, T: Transport<Wake<S>>> Clone for Reliable<T, S> {
+    fn clone(&self) -> Self {
+        Reliable {
+            inner: self.inner.clone(),
+            state: Arc::clone(&self.state),
+        }
+    }
+}
+
+/// Whether `addr` gets streams, multicast is left to discovery
+fn is_stream(protocol: Protocol, addr: SocketAddr) -> bool {
+    protocol == Protocol::Udp && !addr.ip().is_multicast()
+}
+
+impl<S: Send + 'static, T: Transport<Wake<S>>> Transport<S> for Reliable<T, S> {
+    type Conn = ReliableConn<T::Conn>;
+    type Listener = T::Listener;
+
+    fn connect(&self, protocol: Protocol, addr: SocketAddr) -> io::Result<Self::Conn> {
+        let conn = self.inner.connect(protocol, addr)?;
+        if !is_stream(protocol, addr) {
+            return Ok(ReliableConn::Plain(conn));
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let id = state.open(Route::Conn(conn));
+        state.connected.insert(conn, id);
+        Ok(ReliableConn::Udp(id))
+    }
+
+    fn listen(&self, protocol: Protocol, addr: SocketAddr) -> io::Result<(T::Listener, SocketAddr)> {
+        let (listener, local_addr) = self.inner.listen(protocol, addr)?;
+        if is_stream(protocol, addr) {
+            self.state.lock().unwrap().listeners.insert(listener);
+        }
+
+        Ok((listener, local_addr))
+    }
+
+    fn send(&self, conn: Self::Conn, data: &[u8]) -> bool {
+        let id = match conn {
+            ReliableConn::Plain(conn) => return self.inner.send(conn, data),
+            ReliableConn::Udp(id) => id,
+        };
+
+        let mut state = self.state.lock().unwrap();
+        let (route, datagrams) = match state.streams.get_mut(&id) {
+            Some(Link { route, stream }) => (*route, stream.send(data)),
+            None => return false,
+        };
+        self.tick_later(&mut state);
+
+        // only a stream that is gone fails, the rest is sent again if lost
+        self.send_route(route, datagrams);
+        true
+    }
+
+    fn send_datagram(&self, listener: T::Listener, addr: SocketAddr, data: &[u8]) -> bool {
+        self.inner.send_datagram(listener, addr, data)
+    }
+
+    /// Tells the other side, it is not told again if that gets lost
+    fn close(&self, conn: Self::Conn) {
+        let id = match conn {
+            ReliableConn::Plain(conn) => return self.inner.close(conn),
+            ReliableConn::Udp(id) => id,
+        };
+
+        let route = self.state.lock().unwrap().forget(id);
+        if let Some(route) = route {
+            self.send_route(route, vec![segment(FIN, 0, &[])]);
+            if let Route::Conn(conn) = route {
+                self.inner.close(conn);
+            }
+        }
+    }
+
+    fn remote_addr(&self, conn: Self::Conn) -> SocketAddr {
+        let id = match conn {
+            ReliableConn::Plain(conn) => return self.inner.remote_addr(conn),
+            ReliableConn::Udp(id) => id,
+        };
+
+        let route = self.state.lock().unwrap().streams.get(&id).map(|link| link.route);
+        match route {
+            Some(Route::Conn(conn)) => self.inner.remote_addr(conn),
+            Some(Route::Listener(_, addr)) => addr,
+            None => SocketAddr::from(([0, 0, 0, 0], 0)),
+        }
+    }
+
+    fn signal(&self, signal: S, delay: Duration) {
+        self.inner.signal(Wake::Signal(signal), delay);
+    }
+
+    fn receive(&self) -> Option<Event<Self::Conn, T::Listener, S>> {
+        loop {
+            if let Some(event) = self.state.lock().unwrap().events.pop_front() {
+                return Some(event);
+            }
+
+            // not locked while the inner transport waits, others may send meanwhile
+            let event = self.inner.receive()?;
+            let mut state = self.state.lock().unwrap();
+            match event {
+                Event::Signal(Wake::Signal(signal)) => return Some(Event::Signal(signal)),
+                Event::Signal(Wake::Retransmit) => {
+                    drop(state);
+                    self.retransmit();
+                },
+                Event::Connected(conn, ok) => match state.connected.get(&conn).copied() {
+                    Some(id) => {
+                        if !ok {
+                            state.forget(id);
+                        }
+                        return Some(Event::Connected(ReliableConn::Udp(id), ok));
+                    },
+                    None => return Some(Event::Connected(ReliableConn::Plain(conn), ok)),
+                },
+                Event::Accepted(conn, listener) => return Some(Event::Accepted(ReliableConn::Plain(conn), listener)),
+                Event::Message(conn, data) => match state.connected.get(&conn).copied() {
+                    Some(id) => self.stream_datagram(&mut state, id, &data),
+                    None => return Some(Event::Message(ReliableConn::Plain(conn), data)),
+                },
+                Event::Datagram(listener, from, data) if state.listeners.contains(&listener) => {
+                    self.listener_datagram(&mut state, listener, from, &data);
+                },
+                Event::Datagram(listener, from, data) => return Some(Event::Datagram(listener, from, data)),
+                Event::Disconnected(conn) => return Some(Event::Disconnected(ReliableConn::Plain(conn))),
+            }
+        }
+    }
+
+    fn stop(&self) {
+        self.inner.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{ IpAddr, Ipv4Addr };
+    use crate::net::loopback::{ Faults, LoopConn, LoopListener, Loopback, LoopbackTransport };
+    use super::*;
+
+    type Node = Reliable<LoopbackTransport<Wake<()>>, ()>;
+
+    /// Delivers every datagram in `datagrams` to `stream`, returns what it handed on
+    fn deliver(stream: &mut Stream, datagrams: &[Vec<u8>]) -> (Vec<u8>, Vec<Vec<u8>>) {
+        let mut data = vec![];
+        let mut replies = vec![];
+        for datagram in datagrams {
+            let received = stream.receive(datagram);
+            data.extend(received.data.concat());
+            replies.extend(received.replies);
+        }
+
+        (data, replies)
+    }
+
+    #[test]
+    fn test_segments_are_handed_on_in_order_once() {
+        let mut alice = Stream::new();
+        let mut bob = Stream::new();
+
+        let data: Vec<u8> = (0..5 * SEGMENT_LEN).map(|n| n as u8).collect();
+        let mut segments = alice.send(&data);
+        assert_eq!(segments.len(), 5);
+
+        // reversed and repeated
+        segments.reverse();
+        let repeated = segments[1].clone();
+        segments.push(repeated);
+        let (received, acks) = deliver(&mut bob, &segments);
+        assert_eq!(received, data);
+
+        assert!(alice.is_busy());
+        deliver(&mut alice, &acks);
+        assert!(!alice.is_busy());
+    }
+
+    #[test]
+    fn test_lost_segments_are_sent_again() {
+        let mut alice = Stream::new();
+        let mut bob = Stream::new();
+
+        let segments = alice.send(b"one");
+        assert_eq!(alice.tick(), Some(vec![]));
+        // the first try was lost
+        let resent = alice.tick().unwrap();
+        assert_eq!(resent, segments);
+
+        let (received, acks) = deliver(&mut bob, &resent);
+        assert_eq!(received, b"one");
+        deliver(&mut alice, &acks);
+        assert_eq!(alice.tick(), Some(vec![]));
+    }
+
+    #[test]
+    fn test_window() {
+        let mut alice = Stream::new();
+        let mut bob = Stream::new();
+
+        let data = vec![7; (WINDOW as usize + 3) * SEGMENT_LEN];
+        let first = alice.send(&data);
+        assert_eq!(first.len(), WINDOW as usize);
+
+        let (_, acks) = deliver(&mut bob, &first[..1]);
+        let (_, more) = deliver(&mut alice, &acks);
+        assert_eq!(more.len(), 1);
+    }
+
+    #[test]
+    fn test_silent_side_is_given_up() {
+        let mut alice = Stream::new();
+        alice.send(b"anyone?");
+
+        for _ in 0..GIVE_UP {
+            assert!(alice.tick().is_some());
+        }
+        assert_eq!(alice.tick(), None);
+    }
+
+    #[test]
+    fn test_fin_closes() {
+        let mut bob = Stream::new();
+        assert!(bob.receive(&segment(FIN, 0, &[])).closed);
+        assert_eq!(bob.receive(&[1, 2]), Received::default());
+    }
+
+    /// Events of `node` until the network has nothing left to do
+    fn run(network: &Loopback, node: &Node, other: &Node) -> Vec<Event<ReliableConn<LoopConn>, LoopListener, ()>> {
+        let mut events = vec![];
+        loop {
+            while let Some(event) = node.receive() {
+                events.push(event);
+            }
+            while other.receive().is_some() {}
+            if !network.advance() {
+                return events;
+            }
+        }
+    }
+
+    #[test]
+    fn test_streams_over_loopback() {
+        let network = Loopback::with_seed(3);
+        let server = Reliable::new(network.node(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))));
+        let client = Reliable::new(network.node(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2))));
+        let (listener, addr) = server.listen(Protocol::Udp, "0.0.0.0:7879".parse().unwrap()).unwrap();
+
+        network.set_faults(Faults {
+            jitter: Duration::from_millis(100),
+            reorder: true,
+            duplicate: 0.2,
+            loss: 0.2,
+            ..Faults::default()
+        });
+        let conn = client.connect(Protocol::Udp, addr).unwrap();
+        for n in 0..50u8 {
+            assert!(client.send(conn, &[n]));
+        }
+
+        let events = run(&network, &server, &client);
+        let accepted = match events.first() {
+            Some(Event::Accepted(accepted, l)) if *l == listener => *accepted,
+            other => panic!("expected accept, got {:?}", other),
+        };
+        let received: Vec<u8> = events.iter()
+            .filter_map(|event| match event {
+                Event::Message(c, data) if *c == accepted => Some(data[0]),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(received, (0..50).collect::<Vec<u8>>());
+        assert_eq!(server.remote_addr(accepted).ip(), IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)));
+
+        network.set_faults(Faults::default());
+        assert!(server.send(accepted, b"back"));
+        client.close(conn);
+        let events = run(&network, &server, &client);
+        assert!(matches!(events[..], [Event::Disconnected(c)] if c == accepted));
+    }
+}
@@ -0,0 +1,413 @@
+//! Simulated chats over a `Loopback` network
+//!
+//! A relay and a few clients run in memory while the network delays
+//! what is sent and drops connections, and over UDP also reorders,
+//! repeats and loses datagrams. The clients
+//! keep their chats with the `Chats` of `ApplicationUI`, each in a
+//! database of its own. Once the network is healed every chat has to be
+//! acknowledged and to have reached everyone else exactly once.
+//!
+//! Everything is stepped on the test thread in virtual time. The seed
+//! picks the faults, what the users do and the reconnection delays, so
+//! it fully determines a run and a failing seed can be replayed.
+
+use std::{
+    collections::HashMap,
+    net::{ IpAddr, Ipv4Addr },
+    path::PathBuf,
+    sync::atomic::{ AtomicUsize, Ordering },
+    time::Duration,
+};
+use crossbeam_channel::{ unbounded, Receiver };
+use rand::{ rngs::StdRng, Rng, SeedableRng };
+use crate::{
+    app::chats::Chats,
+    common::app_event::{ AppEvent, NetworkEvent },
+    config::Config,
+    crypto::SigningKeyPair,
+    db::{
+        self,
+        models::{ ChatMessage, DeliveryStatus }
+    },
+    net::{
+        backoff::Backoff,
+        loopback::{ Faults, Loopback, LoopbackTransport },
+        protocol::{ Body, Message },
+        reliable::{ Reliable, Wake },
+        transport::Transport,
+        Network,
+        Signal,
+        Worker
+    },
+    server::{ config::ServerConfig, Server }
+};
+
+const TCP_ADDR: &str = "10.0.0.1:7878";
+const UDP_ADDR: &str = "udp://10.0.0.1:7879";
+/// How long users chat while the network misbehaves
+const STORM: Duration = Duration::from_secs(60);
+/// How long the healed network has to deliver what is left
+const CALM: Duration = Duration::from_secs(120);
+/// Users act at random times up to this far apart
+const ACTION_INTERVAL: Duration = Duration::from_millis(800);
+/// Chance of an action being a dropped connection instead of a chat
+const DISCONNECT_CHANCE: f64 = 0.1;
+
+/// Tells the databases of clients of simulations running at once apart
+static DATABASES: AtomicUsize = AtomicUsize::new(0);
+
+type Node<S> = Reliable<LoopbackTransport<Wake<S>>, S>;
+
+fn ip(last: u8) -> IpAddr {
+    IpAddr::V4(Ipv4Addr::new(10, 0, 0, last))
+}
+
+/// A client keeping its chats like `ApplicationUI` does
+struct SimClient {
+    user_id: String,
+    ip: IpAddr,
+    network: Network<Node<Signal>>,
+    worker: Worker<Node<Signal>>,
+    rx: Receiver<AppEvent>,
+    connected: bool,
+    /// times the connection to the relay broke
+    drops: usize,
+    written: usize,
+    /// its MESSAGES and OUTBOX
+    db_path: PathBuf,
+    chats: Chats,
+    /// text of the chats it wrote by id, ids differ between runs
+    texts: HashMap<String, String>,
+    /// what happened to it since the last step
+    events: Vec<String>,
+}
+
+impl SimClient {
+    fn new(loopback: &Loopback, server_addr: &str, user_id: &str, ip: IpAddr, seed: u64) -> Self {
+        let (tx, rx) = unbounded();
+        let config = Config {
+            server_addr: server_addr.to_string(),
+            port: 0,
+            discovery: false,
+            tls: None,
+        };
+        let transport = Reliable::new(loopback.node(ip));
+        let (network, worker) = Network::with_transport(&config, tx, transport, Backoff::seeded(seed)).unwrap();
+        network.set_identity(user_id, user_id, vec![], SigningKeyPair::generate());
+
+        let db_path = std::env::temp_dir().join(format!(
+            "oisg-sim-{}-{}.db",
+            std::process::id(),
+            DATABASES.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = std::fs::remove_file(&db_path);
+        let chats = db::with_path(&db_path, || {
+            db::ensure_db_exists().unwrap();
+            Chats::new(user_id)
+        });
+
+        SimClient {
+            user_id: user_id.to_string(),
+            ip,
+            network,
+            worker,
+            rx,
+            connected: false,
+            drops: 0,
+            written: 0,
+            db_path,
+            chats,
+            texts: HashMap::new(),
+            events: vec![],
+        }
+    }
+
+    /// Handles what there is to do now, `false` if there was nothing
+    fn step(&mut self) -> bool {
+        let busy = self.worker.step();
+        let events: Vec<AppEvent> = self.rx.try_iter().collect();
+        let handled = !events.is_empty();
+        for event in events {
+            if let AppEvent::NetworkEvent(event) = event {
+                db::with_path(&self.db_path.clone(), || self.network_event(event));
+            }
+        }
+
+        busy || handled
+    }
+
+    /// Writes a chat to everyone, sent right away if connected
+    fn write(&mut self) {
+        self.written += 1;
+        let db_path = self.db_path.clone();
+        db::with_path(&db_path, || {
+            let text = format!("message {}", self.written);
+            let chat = self.chats.write(None, text.clone());
+            if let Message::Chat { id, .. } = &chat {
+                self.texts.insert(id.clone(), text);
+            }
+            self.chats.store(&chat, Some(DeliveryStatus::Pending));
+            self.chats.queue(&chat).unwrap();
+            if self.connected && self.network.send(&chat) {
+                if let Message::Chat { id, .. } = &chat {
+                    self.chats.set_status(id, DeliveryStatus::Sent);
+                }
+            }
+        });
+    }
+
+    fn network_event(&mut self, event: NetworkEvent) {
+        if let Some(description) = self.describe(&event) {
+            self.events.push(description);
+        }
+
+        match event {
+            NetworkEvent::Connected => self.connected = true,
+            NetworkEvent::Disconnected => {
+                self.connected = false;
+                self.drops += 1;
+            },
+            NetworkEvent::MessageReceived(message) => self.add_message(message),
+            _ => {},
+        }
+    }
+
+    fn add_message(&mut self, message: Message) {
+        match message {
+            Message::Welcome { .. } => {
+                let network = &self.network;
+                self.chats.welcomed(Ok::<Message, ()>, |message| network.send(message)).unwrap();
+            },
+            Message::Chat { .. } if !self.chats.receive(&message) => {},
+            Message::Chat { .. } => {
+                self.chats.store(&message, None);
+            },
+            Message::Stored { id } => {
+                self.chats.stored(&id);
+            },
+            Message::Ack { id } => self.chats.acknowledged(&id),
+            Message::Error { code, id: Some(id), .. } if !code.is_transient() => self.chats.refused(&id),
+            _ => {},
+        }
+    }
+
+    /// What `event` is, the same in every run of a seed
+    fn describe(&self, event: &NetworkEvent) -> Option<String> {
+        let description = match event {
+            NetworkEvent::Connected => "connected".to_string(),
+            NetworkEvent::Disconnected => "disconnected".to_string(),
+            NetworkEvent::MessageReceived(Message::Welcome { .. }) => "welcomed".to_string(),
+            NetworkEvent::MessageReceived(Message::Chat { from, body: Body::Plain(text), clock, .. }) => {
+                format!("got {} of {} at {}", text, from, clock)
+            },
+            NetworkEvent::MessageReceived(Message::Ack { id }) => {
+                format!("ack of {}", self.texts.get(id).map_or("?", String::as_str))
+            },
+            NetworkEvent::MessageReceived(Message::Stored { id }) => {
+                format!("stored {}", self.texts.get(id).map_or("?", String::as_str))
+            },
+            NetworkEvent::MessageReceived(Message::HistoryEnd { count }) => format!("history of {}", count),
+            _ => return None,
+        };
+
+        Some(description)
+    }
+
+    /// The chats it stored, in the order they were sent
+    fn messages(&self) -> Vec<ChatMessage> {
+        db::with_path(&self.db_path, || db::operations::get_messages(i64::MAX as usize).unwrap())
+    }
+
+    fn queued(&self) -> usize {
+        db::with_path(&self.db_path, || db::operations::get_outbox().unwrap().len())
+    }
+}
+
+impl Drop for SimClient {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.db_path);
+    }
+}
+
+/// A relay and its clients on one `Loopback` network
+struct Sim {
+    loopback: Loopback,
+    server: Server<Node<()>>,
+    clients: Vec<SimClient>,
+    /// wakes the driver when the next user acts
+    ticker: LoopbackTransport<()>,
+    rng: StdRng,
+    /// what happened to which client when, in order
+    events: Vec<String>,
+}
+
+impl Sim {
+    fn new(server_addr: &str, seed: u64, users: u8) -> Self {
+        let loopback = Loopback::with_seed(seed);
+        let config = ServerConfig {
+            udp_addr: Some("0.0.0.0:7879".to_string()),
+            ..ServerConfig::default()
+        };
+        let server = Server::bind_with(&config, Reliable::new(loopback.node(ip(1)))).unwrap();
+        let clients = (0..users)
+            .map(|n| SimClient::new(&loopback, server_addr, &format!("user{}", n), ip(n + 2), seed + n as u64))
+            .collect();
+        let ticker = loopback.node(ip(255));
+
+        Sim {
+            loopback,
+            server,
+            clients,
+            ticker,
+            rng: StdRng::seed_from_u64(seed),
+            events: vec![],
+        }
+    }
+
+    /// Runs everyone until nobody has anything left to do, no time passes
+    fn settle(&mut self) {
+        loop {
+            let mut busy = self.server.step();
+            for client in self.clients.iter_mut() {
+                busy |= client.step();
+
+                let now = self.loopback.now();
+                let user_id = &client.user_id;
+                self.events.extend(client.events.drain(..).map(|event| format!("{:?} {} {}", now, user_id, event)));
+            }
+            if !busy {
+                break;
+            }
+        }
+    }
+
+    /// Runs the network for `duration`, with users chatting and
+    /// losing their connection if `chatty`
+    fn run(&mut self, duration: Duration, chatty: bool) {
+        let end = self.loopback.now() + duration;
+        if chatty {
+            self.schedule_action();
+        }
+
+        loop {
+            self.settle();
+            if chatty && self.ticker.receive().is_some() {
+                self.act();
+                self.schedule_action();
+                continue;
+            }
+
+            if self.loopback.now() >= end || !self.loopback.advance() {
+                break;
+            }
+        }
+    }
+
+    fn schedule_action(&mut self) {
+        let delay = ACTION_INTERVAL.mul_f64(self.rng.gen());
+        self.ticker.signal((), delay);
+    }
+
+    fn act(&mut self) {
+        let n = self.rng.gen_range(0..self.clients.len());
+        match self.rng.gen_bool(DISCONNECT_CHANCE) {
+            true => self.loopback.disconnect(self.clients[n].ip),
+            false => self.clients[n].write(),
+        }
+    }
+
+    /// Chats through a network with `faults` with the relay at
+    /// `server_addr`, then heals it and checks that everything got where
+    /// it had to
+    fn check(server_addr: &str, seed: u64, users: u8, faults: Faults) -> Sim {
+        let mut sim = Sim::new(server_addr, seed, users);
+        sim.loopback.set_faults(faults);
+        sim.run(STORM, true);
+
+        sim.loopback.set_faults(Faults::default());
+        sim.run(CALM, false);
+
+        // every chat as its author stored it
+        let messages: Vec<Vec<ChatMessage>> = sim.clients.iter().map(SimClient::messages).collect();
+        let written: Vec<&ChatMessage> = sim.clients.iter().zip(messages.iter())
+            .flat_map(|(client, stored)| stored.iter().filter(|stored| stored.from_user == client.user_id))
+            .collect();
+        assert_eq!(written.len(), sim.clients.iter().map(|client| client.written).sum::<usize>());
+        assert!(written.len() > users as usize, "seed {}: nobody chatted", seed);
+
+        for chat in written.iter() {
+            assert_eq!(chat.status, Some(DeliveryStatus::Delivered), "seed {}: {}", seed, chat.message_id);
+        }
+
+        for (client, stored) in sim.clients.iter().zip(messages.iter()) {
+            assert!(client.connected, "seed {}: {} is not connected", seed, client.user_id);
+            let queued = client.queued();
+            assert_eq!(queued, 0, "seed {}: {} still has {} queued", seed, client.user_id, queued);
+
+            let mut ids: Vec<&str> = stored.iter().map(|stored| stored.message_id.as_str()).collect();
+            ids.sort_unstable();
+            ids.dedup();
+            assert_eq!(ids.len(), stored.len(), "seed {}: {} stored a chat twice", seed, client.user_id);
+
+            for chat in written.iter() {
+                let received = match stored.iter().find(|stored| stored.message_id == chat.message_id) {
+                    Some(received) => received,
+                    None => panic!("seed {}: {} never got {}", seed, client.user_id, chat.message_id),
+                };
+                assert_eq!(received.from_user, chat.from_user);
+                assert_eq!(received.clock, chat.clock, "seed {}: {} of {}", seed, chat.message_id, client.user_id);
+            }
+        }
+
+        sim
+    }
+}
+
+#[test]
+fn test_reliable_network() {
+    Sim::check(TCP_ADDR, 1, 3, Faults::default());
+}
+
+#[test]
+fn test_seed_determines_run() {
+    let faults = Faults {
+        latency: Duration::from_millis(20),
+        jitter: Duration::from_millis(300),
+        ..Faults::default()
+    };
+    let first = Sim::check(TCP_ADDR, 5, 3, faults);
+    let second = Sim::check(TCP_ADDR, 5, 3, faults);
+
+    assert!(first.events.iter().any(|event| event.ends_with("disconnected")));
+    assert_eq!(first.events, second.events);
+}
+
+/// Streams are only slow, what breaks them is the connections the sim drops
+#[test]
+fn test_slow_network() {
+    for seed in 0..8 {
+        Sim::check(TCP_ADDR, seed, 4, Faults {
+            latency: Duration::from_millis(20),
+            jitter: Duration::from_millis(300),
+            ..Faults::default()
+        });
+    }
+}
+
+#[test]
+fn test_faulty_network_over_udp() {
+    for seed in 0..8 {
+        let sim = Sim::check(UDP_ADDR, seed, 4, Faults {
+            latency: Duration::from_millis(20),
+            jitter: Duration::from_millis(300),
+            reorder: true,
+            duplicate: 0.1,
+            loss: 0.1,
+        });
+
+        // the faults never broke a stream
+        for client in sim.clients.iter() {
+            assert_eq!(client.drops, 0, "seed {}: {} lost its stream", seed, client.user_id);
+        }
+    }
+}
@@ -0,0 +1,347 @@
+use std::{
+    fs,
+    io::{ self, Read, Write },
+    sync::Arc,
+};
+use rustls::{
+    client::danger::{ HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier },
+    crypto::{ self, CryptoProvider },
+    pki_types::{ CertificateDer, PrivateKeyDer, ServerName, UnixTime },
+    CertificateError, ClientConfig, ClientConnection, Connection, DigitallySignedStruct,
+    OtherError, RootCertStore, ServerConfig, ServerConnection, SignatureScheme
+};
+use sha2::{ Digest, Sha256 };
+
+/// How the client decides whether to trust the server certificate
+#[derive(Debug, Clone, PartialEq)]
+pub enum TrustAnchor {
+    /// certificates issued by the public web CAs
+    WebPki,
+    /// certificates issued by the CA in this PEM file
+    CaFile(String),
+    /// only the certificate with this SHA-256 fingerprint, as hex
+    Pinned(String),
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(crypto::ring::default_provider())
+}
+
+fn to_io_error<E: std::error::Error + Send + Sync + 'static>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, e)
+}
+
+pub fn client_config(trust: &TrustAnchor) -> io::Result<Arc<ClientConfig>> {
+    let builder = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(to_io_error)?;
+
+    let config = match trust {
+        TrustAnchor::WebPki => {
+            let roots = RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            };
+            builder.with_root_certificates(roots).with_no_client_auth()
+        },
+        TrustAnchor::CaFile(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(path)? {
+                roots.add(cert).map_err(to_io_error)?;
+            }
+            builder.with_root_certificates(roots).with_no_client_auth()
+        },
+        TrustAnchor::Pinned(fingerprint) => {
+            let verifier = PinnedVerifier {
+                fingerprint: normalize_fingerprint(fingerprint)?,
+                provider: provider(),
+            };
+            builder.dangerous()
+                .with_custom_certificate_verifier(Arc::new(verifier))
+                .with_no_client_auth()
+        },
+    };
+
+    Ok(Arc::new(config))
+}
+
+pub fn server_config(cert_file: &str, key_file: &str) -> io::Result<Arc<ServerConfig>> {
+    let certs = load_certs(cert_file)?;
+    let key = load_key(key_file)?;
+
+    let config = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(to_io_error)?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(to_io_error)?;
+
+    Ok(Arc::new(config))
+}
+
+/// SHA-256 fingerprint of the first certificate in a PEM file, what
+/// clients pin with `--pin`
+pub fn fingerprint_of_file(cert_file: &str) -> io::Result<String> {
+    Ok(fingerprint(&load_certs(cert_file)?[0]))
+}
+
+pub fn fingerprint(cert: &CertificateDer<'_>) -> String {
+    hex::encode(Sha256::digest(cert.as_ref()))
+}
+
+/// Accepts fingerprints with or without `:` separators, in any case
+fn normalize_fingerprint(fingerprint: &str) -> io::Result<String> {
+    let normalized: String = fingerprint.chars()
+        .filter(|c| *c != ':')
+        .collect::<String>()
+        .to_lowercase();
+
+    match normalized.len() == 64 && normalized.chars().all(|c| c.is_ascii_hexdigit()) {
+        true => Ok(normalized),
+        false => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a SHA-256 fingerprint", fingerprint)
+        )),
+    }
+}
+
+fn load_certs(path: &str) -> io::Result<Vec<CertificateDer<'static>>> {
+    let data = fs::read(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))?;
+    let certs = rustls_pemfile::certs(&mut data.as_slice())
+        .collect::<io::Result<Vec<_>>>()?;
+
+    match certs.is_empty() {
+        true => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("no certificate found in {}", path)
+        )),
+        false => Ok(certs),
+    }
+}
+
+fn load_key(path: &str) -> io::Result<PrivateKeyDer<'static>> {
+    let data = fs::read(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))?;
+
+    rustls_pemfile::private_key(&mut data.as_slice())?
+        .ok_or_else(|| io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("no private key found in {}", path)
+        ))
+}
+
+/// Trusts exactly one certificate, whoever issued it and whatever
+/// name it is for. Useful for self-signed relays.
+#[derive(Debug)]
+struct PinnedVerifier {
+    fingerprint: String,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let actual = fingerprint(end_entity);
+        if actual == self.fingerprint {
+            return Ok(ServerCertVerified::assertion());
+        }
+
+        let reason = io::Error::other(format!(
+            "certificate fingerprint {} does not match the pinned {}",
+            actual, self.fingerprint
+        ));
+        Err(rustls::Error::InvalidCertificate(CertificateError::Other(OtherError(Arc::new(reason)))))
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// `TlsStream` is a TLS session over a connection someone else reads and
+/// writes: bytes received go in through `receive`, and what `outgoing`
+/// returns has to be written to the connection
+pub struct TlsStream {
+    conn: Connection,
+}
+
+impl TlsStream {
+    /// `server_name` is the host the client connects to, a name or an IP
+    pub fn client(config: Arc<ClientConfig>, server_name: &str) -> io::Result<Self> {
+        let name = ServerName::try_from(server_name.to_string()).map_err(to_io_error)?;
+        let conn = ClientConnection::new(config, name).map_err(to_io_error)?;
+
+        Ok(TlsStream { conn: conn.into() })
+    }
+
+    pub fn server(config: Arc<ServerConfig>) -> io::Result<Self> {
+        let conn = ServerConnection::new(config).map_err(to_io_error)?;
+
+        Ok(TlsStream { conn: conn.into() })
+    }
+
+    pub fn is_handshaking(&self) -> bool {
+        self.conn.is_handshaking()
+    }
+
+    /// Processes TLS records received, returns the plaintext they carried.
+    /// After an error `outgoing` holds the alert for the other side.
+    pub fn receive(&mut self, mut data: &[u8]) -> Result<Vec<u8>, rustls::Error> {
+        let mut plaintext = Vec::new();
+
+        while !data.is_empty() {
+            self.conn.read_tls(&mut data)
+                .map_err(|e| rustls::Error::General(e.to_string()))?;
+            self.conn.process_new_packets()?;
+
+            let mut buffer = [0u8; 4096];
+            loop {
+                match self.conn.reader().read(&mut buffer) {
+                    Ok(0) => break,
+                    Ok(n) => plaintext.extend_from_slice(&buffer[..n]),
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => return Err(rustls::Error::General(e.to_string())),
+                }
+            }
+        }
+
+        Ok(plaintext)
+    }
+
+    /// Encrypts `plaintext`, it is sent once the handshake is done
+    pub fn send(&mut self, plaintext: &[u8]) -> io::Result<()> {
+        self.conn.writer().write_all(plaintext)
+    }
+
+    /// TLS records waiting to be written to the connection
+    pub fn outgoing(&mut self) -> Vec<u8> {
+        let mut out = Vec::new();
+        while self.conn.wants_write() {
+            if self.conn.write_tls(&mut out).is_err() {
+                break;
+            }
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::path::PathBuf;
+    use super::*;
+
+    /// Self-signed certificate for localhost written to temp files,
+    /// returns the certificate and key paths
+    pub(crate) fn self_signed(name: &str) -> (String, String) {
+        let certified = rcgen::generate_simple_self_signed(vec![
+            "localhost".to_string(),
+            "127.0.0.1".to_string(),
+        ]).unwrap();
+
+        let dir = std::env::temp_dir();
+        let path = |ext: &str| -> PathBuf {
+            dir.join(format!("oisg-{}-{}.{}", name, std::process::id(), ext))
+        };
+        fs::write(path("crt"), certified.cert.pem()).unwrap();
+        fs::write(path("key"), certified.key_pair.serialize_pem()).unwrap();
+
+        (path("crt").to_string_lossy().to_string(), path("key").to_string_lossy().to_string())
+    }
+
+    /// Moves records between the two sides until neither has anything to say
+    fn pump(client: &mut TlsStream, server: &mut TlsStream) -> Result<(Vec<u8>, Vec<u8>), rustls::Error> {
+        let (mut to_client, mut to_server) = (Vec::new(), Vec::new());
+        loop {
+            let from_client = client.outgoing();
+            let from_server = server.outgoing();
+            if from_client.is_empty() && from_server.is_empty() {
+                return Ok((to_client, to_server));
+            }
+
+            to_server.extend(server.receive(&from_client)?);
+            to_client.extend(client.receive(&from_server)?);
+        }
+    }
+
+    fn streams(name: &str, trust: impl Fn(&str) -> TrustAnchor) -> (TlsStream, TlsStream) {
+        let (cert, key) = self_signed(name);
+        let client = TlsStream::client(client_config(&trust(&cert)).unwrap(), "localhost").unwrap();
+        let server = TlsStream::server(server_config(&cert, &key).unwrap()).unwrap();
+
+        (client, server)
+    }
+
+    #[test]
+    fn test_handshake_with_ca() {
+        let (mut client, mut server) = streams("tls-ca", |cert| TrustAnchor::CaFile(cert.to_string()));
+
+        client.send(b"hello relay").unwrap();
+        let (_, to_server) = pump(&mut client, &mut server).unwrap();
+        assert!(!client.is_handshaking());
+        assert_eq!(to_server, b"hello relay");
+
+        server.send(b"hello client").unwrap();
+        let (to_client, _) = pump(&mut client, &mut server).unwrap();
+        assert_eq!(to_client, b"hello client");
+    }
+
+    #[test]
+    fn test_pinned_fingerprint() {
+        let (mut client, mut server) = streams("tls-pin", |cert| {
+            // pins are accepted in the usual colon separated form too
+            let fingerprint = fingerprint_of_file(cert).unwrap().to_uppercase();
+            let pin: Vec<String> = fingerprint.as_bytes()
+                .chunks(2)
+                .map(|pair| String::from_utf8_lossy(pair).to_string())
+                .collect();
+            TrustAnchor::Pinned(pin.join(":"))
+        });
+        assert!(pump(&mut client, &mut server).is_ok());
+        assert!(!client.is_handshaking());
+
+        let (mut client, mut server) = streams("tls-bad-pin", |_| TrustAnchor::Pinned("ab".repeat(32)));
+        let e = pump(&mut client, &mut server).unwrap_err();
+        assert!(e.to_string().contains("does not match the pinned"), "{}", e);
+    }
+
+    #[test]
+    fn test_untrusted_certificate() {
+        // a CA file with some other certificate does not vouch for this one
+        let (other, _) = self_signed("tls-other");
+        let (mut client, mut server) = streams("tls-untrusted", |_| TrustAnchor::CaFile(other.clone()));
+
+        assert!(matches!(pump(&mut client, &mut server), Err(rustls::Error::InvalidCertificate(_))));
+    }
+
+    #[test]
+    fn test_invalid_options() {
+        assert!(normalize_fingerprint("abcd").is_err());
+        assert!(client_config(&TrustAnchor::CaFile("/nonexistent/ca.pem".to_string())).is_err());
+        assert!(server_config("/nonexistent/cert.pem", "/nonexistent/key.pem").is_err());
+    }
+}
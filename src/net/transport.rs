@@ -0,0 +1,243 @@
+use std::{
+    collections::VecDeque,
+    fmt,
+    hash::Hash,
+    io,
+    net::SocketAddr,
+    path::Path,
+    sync::{
+        atomic::{ AtomicBool, Ordering },
+        Arc, Mutex
+    },
+    time::Duration,
+};
+use message_io::{
+    events::{ self, EventReceiver, EventSender },
+    network::{ self, Endpoint, NetworkController, NetworkProcessor, ResourceId, SendStatus },
+    node::StoredNetEvent
+};
+
+use crate::net::reliable::{ Reliable, Wake };
+#[cfg(unix)]
+use crate::net::unix::{ Ready, Unix };
+
+/// Protocol of a connection or listener, only `Tcp`, `Udp` and `Ws` are used
+pub use message_io::network::Transport as Protocol;
+
+/// What the client and the relay run on: message-io, with reliable
+/// streams over its UDP and Unix sockets next to it
+#[cfg(unix)]
+pub type Sockets<S> = Unix<Reliable<MessageIo<Wake<Ready<S>>>, Ready<S>>, S>;
+
+/// What the client and the relay run on: message-io, with reliable
+/// streams over its UDP
+#[cfg(not(unix))]
+pub type Sockets<S> = Reliable<MessageIo<Wake<S>>, S>;
+
+/// A new node of `Sockets`
+#[cfg(unix)]
+pub fn sockets<S: Send + 'static>() -> Sockets<S> {
+    Unix::new(Reliable::new(MessageIo::new()))
+}
+
+/// A new node of `Sockets`
+#[cfg(not(unix))]
+pub fn sockets<S: Send + 'static>() -> Sockets<S> {
+    Reliable::new(MessageIo::new())
+}
+
+/// How long `MessageIo::receive` polls the sockets before checking for signals
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// What a `Transport` hands to the worker driving it
+#[derive(Debug)]
+pub enum Event<C, L, S> {
+    /// a connection we opened is up, or `false` if it could not be made
+    Connected(C, bool),
+    /// a client connected to one of our listeners
+    Accepted(C, L),
+    Message(C, Vec<u8>),
+    /// a datagram arrived on a UDP listener from the address
+    Datagram(L, SocketAddr, Vec<u8>),
+    /// the other side closed the connection or it broke
+    Disconnected(C),
+    /// a signal sent with `Transport::signal`, once its delay is over
+    Signal(S),
+}
+
+/// `Transport` is what the client and the relay workers talk to the
+/// network through. Handles are cheap to clone and all refer to the same
+/// node, signals of type `S` are how a node wakes itself up.
+///
+/// `MessageIo` is the real thing, `Loopback` connects nodes in memory.
+pub trait Transport<S>: Clone + Send + 'static {
+    type Conn: Copy + Eq + Hash + fmt::Debug + Send;
+    type Listener: Copy + Eq + Hash + fmt::Debug + Send;
+
+    /// Starts connecting to `addr`, `Event::Connected` tells how it went
+    fn connect(&self, protocol: Protocol, addr: SocketAddr) -> io::Result<Self::Conn>;
+
+    /// Listens on `addr`, returns the listener and the address it is bound to
+    fn listen(&self, protocol: Protocol, addr: SocketAddr) -> io::Result<(Self::Listener, SocketAddr)>;
+
+    /// Starts connecting to the Unix socket at `path`, like `connect`
+    fn connect_unix(&self, _path: &Path) -> io::Result<Self::Conn> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "Unix sockets are not available on this transport"))
+    }
+
+    /// Listens on a Unix socket at `path`
+    fn listen_unix(&self, _path: &Path) -> io::Result<Self::Listener> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "Unix sockets are not available on this transport"))
+    }
+
+    /// Writes `data` to the connection, `false` if it could not be
+    fn send(&self, conn: Self::Conn, data: &[u8]) -> bool;
+
+    /// Sends `data` from the UDP listener to `addr`, how datagrams are answered
+    fn send_datagram(&self, listener: Self::Listener, addr: SocketAddr, data: &[u8]) -> bool;
+
+    /// Closes the connection, no `Event::Disconnected` follows for it
+    fn close(&self, conn: Self::Conn);
+
+    /// Address of the other side of the connection
+    fn remote_addr(&self, conn: Self::Conn) -> SocketAddr;
+
+    /// Whether the connection came over a Unix socket. Its `remote_addr`
+    /// is loopback then and says nothing about who is on the other side.
+    fn is_local(&self, _conn: Self::Conn) -> bool {
+        false
+    }
+
+    /// Hands `signal` back to the node after `delay`
+    fn signal(&self, signal: S, delay: Duration);
+
+    /// Next event of the node, `None` once it was stopped or, for
+    /// transports driven by the caller, there is nothing to do yet
+    fn receive(&self) -> Option<Event<Self::Conn, Self::Listener, S>>;
+
+    /// Stops the node, `receive` returns `None` from now on
+    fn stop(&self);
+}
+
+/// `MessageIo` is a message-io node. Its sockets are polled by whoever
+/// calls `receive`, so events are handled on the thread that reads them.
+pub struct MessageIo<S: Send + 'static>(Arc<Node<S>>);
+
+struct Node<S: Send + 'static> {
+    network: NetworkController,
+    signals: EventSender<S>,
+    running: AtomicBool,
+    poller: Mutex<Poller<S>>,
+}
+
+struct Poller<S: Send + 'static> {
+    processor: NetworkProcessor,
+    signals: EventReceiver<S>,
+    /// events of the last poll not handed out yet
+    events: VecDeque<StoredNetEvent>,
+}
+
+impl<S: Send + 'static> MessageIo<S> {
+    pub fn new() -> Self {
+        let (network, processor) = network::split();
+        let (signals, signal_receiver) = events::split();
+
+        MessageIo(Arc::new(Node {
+            network,
+            signals,
+            running: AtomicBool::new(true),
+            poller: Mutex::new(Poller {
+                processor,
+                signals: signal_receiver,
+                events: VecDeque::new(),
+            }),
+        }))
+    }
+}
+
+impl<S: Send + 'static> Default for MessageIo<S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S: Send + 'static> Clone for MessageIo<S> {
+    fn clone(&self) -> Self {
+        MessageIo(Arc::clone(&self.0))
+    }
+}
+
+impl<S: Send + 'static> Transport<S> for MessageIo<S> {
+    type Conn = Endpoint;
+    type Listener = ResourceId;
+
+    fn connect(&self, protocol: Protocol, addr: SocketAddr) -> io::Result<Endpoint> {
+        self.0.network.connect(protocol, addr).map(|(endpoint, _)| endpoint)
+    }
+
+    fn listen(&self, protocol: Protocol, addr: SocketAddr) -> io::Result<(ResourceId, SocketAddr)> {
+        self.0.network.listen(protocol, addr)
+    }
+
+    fn send(&self, conn: Endpoint, data: &[u8]) -> bool {
+        self.0.network.send(conn, data) == SendStatus::Sent
+    }
+
+    fn send_datagram(&self, listener: ResourceId, addr: SocketAddr, data: &[u8]) -> bool {
+        self.0.network.send(Endpoint::from_listener(listener, addr), data) == SendStatus::Sent
+    }
+
+    fn close(&self, conn: Endpoint) {
+        self.0.network.remove(conn.resource_id());
+    }
+
+    fn remote_addr(&self, conn: Endpoint) -> SocketAddr {
+        conn.addr()
+    }
+
+    fn signal(&self, signal: S, delay: Duration) {
+        match delay.is_zero() {
+            true => self.0.signals.send(signal),
+            false => {
+                self.0.signals.send_with_timer(signal, delay);
+            },
+        }
+    }
+
+    fn receive(&self) -> Option<Event<Endpoint, ResourceId, S>> {
+        let mut poller = self.0.poller.lock().unwrap();
+        let Poller { processor, signals, events } = &mut *poller;
+
+        while self.0.running.load(Ordering::Relaxed) {
+            // try_receive() misses signals while a timer is pending
+            if let Some(signal) = signals.receive_timeout(Duration::ZERO) {
+                return Some(Event::Signal(signal));
+            }
+
+            let event = match events.pop_front() {
+                Some(event) => event,
+                None => {
+                    processor.process_poll_event(Some(POLL_INTERVAL), |event| events.push_back(event.into()));
+                    continue;
+                },
+            };
+
+            return Some(match event {
+                StoredNetEvent::Connected(endpoint, ok) => Event::Connected(endpoint, ok),
+                StoredNetEvent::Accepted(endpoint, listener) => Event::Accepted(endpoint, listener),
+                // UDP listeners report datagrams on their own resource
+                StoredNetEvent::Message(endpoint, data) if endpoint.resource_id().is_local() => {
+                    Event::Datagram(endpoint.resource_id(), endpoint.addr(), data)
+                },
+                StoredNetEvent::Message(endpoint, data) => Event::Message(endpoint, data),
+                StoredNetEvent::Disconnected(endpoint) => Event::Disconnected(endpoint),
+            });
+        }
+
+        None
+    }
+
+    fn stop(&self) {
+        self.0.running.store(false, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,392 @@
+//! Unix domain socket transport
+//!
+//! message-io only speaks TCP, UDP and WebSocket, so `Unix` wraps a
+//! `Transport` and runs Unix sockets next to it: every socket is read and
+//! written on threads of its own, what it reads is queued and the inner
+//! transport is woken up to hand it out. Framing, TLS and the rest of the protocol run
+//! over the socket as over TCP, who may connect is decided by the
+//! permissions of the socket file.
+
+use std::{
+    collections::{ HashMap, VecDeque },
+    io::{ self, Read, Write },
+    net::{ Ipv4Addr, Shutdown, SocketAddr },
+    os::unix::net::{ UnixListener as SocketListener, UnixStream },
+    path::{ Path, PathBuf },
+    sync::{ Arc, Mutex },
+    thread,
+    time::Duration,
+};
+use crossbeam_channel::{ self as channel, Receiver, Sender, TrySendError };
+use crate::net::transport::{ Event, Protocol, Transport };
+
+/// Scheme of server addresses reached over a Unix socket, `unix:///run/oisg.sock`
+pub const UNIX_SCHEME: &str = "unix://";
+
+/// Bytes read from a socket at once
+const READ_LEN: usize = 64 * 1024;
+/// How long the writer of a socket waits for a client that does not read before it is given up on
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Frames waiting to be written to a socket, a client further behind is disconnected
+const QUEUE_LEN: usize = 1024;
+
+/// Signal of a node under `Unix`, its sockets wake it up when they have events
+#[derive(Debug)]
+pub enum Ready<S> {
+    Signal(S),
+    Socket,
+}
+
+/// Connection of a `Unix` transport
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum UnixConn<C> {
+    /// TCP, WebSocket or UDP, as the inner transport has it
+    Net(C),
+    Socket(u64),
+}
+
+/// Listener of a `Unix` transport
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum UnixListener<L> {
+    Net(L),
+    Socket(u64),
+}
+
+type UnixEvent<T, S> = Event<
+    UnixConn<<T as Transport<Ready<S>>>::Conn>,
+    UnixListener<<T as Transport<Ready<S>>>::Listener>,
+    S
+>;
+
+/// An open socket and the queue of its writer
+struct Socket {
+    stream: Arc<UnixStream>,
+    frames: Sender<Vec<u8>>,
+}
+
+struct State<T: Transport<Ready<S>>, S: Send + 'static> {
+    next_id: u64,
+    /// open sockets, connected and accepted
+    sockets: HashMap<u64, Socket>,
+    /// paths of our listeners, removed once we stop
+    listeners: HashMap<u64, PathBuf>,
+    /// what the sockets read, not handed out yet
+    events: VecDeque<UnixEvent<T, S>>,
+    stopped: bool,
+}
+
+/// `Unix` adds Unix sockets to the transport it wraps, see the module
+/// documentation
+pub struct Unix<T: Transport<Ready<S>>, S: Send + 'static> {
+    inner: T,
+    state: Arc<Mutex<State<T, S>>>,
+}
+
+impl<S: Send + 'static, T: Transport<Ready<S>>> Unix<T, S> {
+    pub fn new(inner: T) -> Self {
+        Unix {
+            inner,
+            state: Arc::new(Mutex::new(State {
+                next_id: 0,
+                sockets: HashMap::new(),
+                listeners: HashMap::new(),
+                events: VecDeque::new(),
+                stopped: false,
+            })),
+        }
+    }
+
+    /// Queues `event` and wakes up whoever waits in `receive`
+    fn push(&self, event: UnixEvent<T, S>) {
+        self.state.lock().unwrap().events.push_back(event);
+        self.inner.signal(Ready::Socket, Duration::ZERO);
+    }
+
+    /// Keeps `stream` and reads and writes it on threads of its own,
+    /// `opened` is queued before anything read from it
+    fn open(&self, stream: UnixStream, opened: impl FnOnce(u64) -> UnixEvent<T, S>) -> io::Result<u64> {
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        let reader = stream.try_clone()?;
+        let writer = stream.try_clone()?;
+        let (frames, queue) = channel::bounded(QUEUE_LEN);
+
+        let mut state = self.state.lock().unwrap();
+        state.next_id += 1;
+        let id = state.next_id;
+        state.sockets.insert(id, Socket { stream: Arc::new(stream), frames });
+        drop(state);
+        self.push(opened(id));
+
+        let unix = self.clone();
+        thread::spawn(move || unix.read(id, reader));
+        let unix = self.clone();
+        thread::spawn(move || unix.write(id, writer, queue));
+        Ok(id)
+    }
+
+    /// Drops a socket that closed or broke, sockets we closed ourselves go quietly
+    fn broken(&self, id: u64) {
+        let socket = self.state.lock().unwrap().sockets.remove(&id);
+        if let Some(socket) = socket {
+            let _ = socket.stream.shutdown(Shutdown::Both);
+            self.push(Event::Disconnected(UnixConn::Socket(id)));
+        }
+    }
+
+    fn read(&self, id: u64, mut reader: UnixStream) {
+        let mut buffer = vec![0u8; READ_LEN];
+        loop {
+            match reader.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(len) => self.push(Event::Message(UnixConn::Socket(id), buffer[..len].to_vec())),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(_) => break,
+            }
+        }
+
+        self.broken(id);
+    }
+
+    /// Writes the frames queued for the socket until it is closed
+    fn write(&self, id: u64, mut writer: UnixStream, queue: Receiver<Vec<u8>>) {
+        for frame in queue {
+            if writer.write_all(&frame).is_err() {
+                // the frame may be half written, nothing after it would make sense
+                self.broken(id);
+                break;
+            }
+        }
+
+        // closed, what was queued before is written by now
+        let _ = writer.shutdown(Shutdown::Both);
+    }
+
+    fn accept(&self, id: u64, listener: SocketListener) {
+        for stream in listener.incoming() {
+            if self.state.lock().unwrap().stopped {
+                break;
+            }
+
+            if let Ok(stream) = stream {
+                let _ = self.open(stream, |conn| Event::Accepted(UnixConn::Socket(conn), UnixListener::Socket(id)));
+            }
+        }
+    }
+}
+
+impl<S: Send + 'static, T: Transport<Ready<S>>> Clone for Unix<T, S> {
+    fn clone(&self) -> Self {
+        Unix {
+            inner: self.inner.clone(),
+            state: Arc::clone(&self.state),
+        }
+    }
+}
+
+impl<S: Send + 'static, T: Transport<Ready<S>>> Transport<S> for Unix<T, S> {
+    type Conn = UnixConn<T::Conn>;
+    type Listener = UnixListener<T::Listener>;
+
+    fn connect(&self, protocol: Protocol, addr: SocketAddr) -> io::Result<Self::Conn> {
+        self.inner.connect(protocol, addr).map(UnixConn::Net)
+    }
+
+    fn listen(&self, protocol: Protocol, addr: SocketAddr) -> io::Result<(Self::Listener, SocketAddr)> {
+        self.inner.listen(protocol, addr).map(|(listener, addr)| (UnixListener::Net(listener), addr))
+    }
+
+    /// Connects right away, fails with why the socket could not be
+    /// reached, `Event::Connected` follows otherwise like for TCP
+    fn connect_unix(&self, path: &Path) -> io::Result<Self::Conn> {
+        let stream = UnixStream::connect(path)?;
+        self.open(stream, |id| Event::Connected(UnixConn::Socket(id), true)).map(UnixConn::Socket)
+    }
+
+    /// Binds `path`, replacing a socket left behind by a server that is gone
+    fn listen_unix(&self, path: &Path) -> io::Result<Self::Listener> {
+        let listener = bind(path)?;
+
+        let mut state = self.state.lock().unwrap();
+        state.next_id += 1;
+        let id = state.next_id;
+        state.listeners.insert(id, path.to_path_buf());
+        drop(state);
+
+        let unix = self.clone();
+        thread::spawn(move || unix.accept(id, listener));
+        Ok(UnixListener::Socket(id))
+    }
+
+    fn send(&self, conn: Self::Conn, data: &[u8]) -> bool {
+        let id = match conn {
+            UnixConn::Net(conn) => return self.inner.send(conn, data),
+            UnixConn::Socket(id) => id,
+        };
+
+        let queued = match self.state.lock().unwrap().sockets.get(&id) {
+            Some(socket) => socket.frames.try_send(data.to_vec()),
+            None => return false,
+        };
+        match queued {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                // the client stopped reading
+                self.broken(id);
+                false
+            },
+            Err(TrySendError::Disconnected(_)) => false,
+        }
+    }
+
+    fn send_datagram(&self, listener: Self::Listener, addr: SocketAddr, data: &[u8]) -> bool {
+        match listener {
+            UnixListener::Net(listener) => self.inner.send_datagram(listener, addr, data),
+            UnixListener::Socket(_) => false,
+        }
+    }
+
+    fn close(&self, conn: Self::Conn) {
+        let id = match conn {
+            UnixConn::Net(conn) => return self.inner.close(conn),
+            UnixConn::Socket(id) => id,
+        };
+
+        // the writer shuts the socket down once the frames queued before are written
+        self.state.lock().unwrap().sockets.remove(&id);
+    }
+
+    /// Unix sockets have no address, they are all this host
+    fn remote_addr(&self, conn: Self::Conn) -> SocketAddr {
+        match conn {
+            UnixConn::Net(conn) => self.inner.remote_addr(conn),
+            UnixConn::Socket(_) => SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+        }
+    }
+
+    fn is_local(&self, conn: Self::Conn) -> bool {
+        match conn {
+            UnixConn::Net(conn) => self.inner.is_local(conn),
+            UnixConn::Socket(_) => true,
+        }
+    }
+
+    fn signal(&self, signal: S, delay: Duration) {
+        self.inner.signal(Ready::Signal(signal), delay);
+    }
+
+    fn receive(&self) -> Option<Event<Self::Conn, Self::Listener, S>> {
+        loop {
+            if let Some(event) = self.state.lock().unwrap().events.pop_front() {
+                return Some(event);
+            }
+
+            return Some(match self.inner.receive()? {
+                Event::Signal(Ready::Signal(signal)) => Event::Signal(signal),
+                // the event is queued already
+                Event::Signal(Ready::Socket) => continue,
+                Event::Connected(conn, ok) => Event::Connected(UnixConn::Net(conn), ok),
+                Event::Accepted(conn, listener) => Event::Accepted(UnixConn::Net(conn), UnixListener::Net(listener)),
+                Event::Message(conn, data) => Event::Message(UnixConn::Net(conn), data),
+                Event::Datagram(listener, addr, data) => Event::Datagram(UnixListener::Net(listener), addr, data),
+                Event::Disconnected(conn) => Event::Disconnected(UnixConn::Net(conn)),
+            });
+        }
+    }
+
+    /// Also closes the sockets and removes the socket files of our listeners
+    fn stop(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.stopped = true;
+        for (_, socket) in state.sockets.drain() {
+            let _ = socket.stream.shutdown(Shutdown::Both);
+        }
+        let listeners: Vec<PathBuf> = state.listeners.drain().map(|(_, path)| path).collect();
+        drop(state);
+
+        for path in listeners {
+            // wakes the listener up to see it is stopped
+            let _ = UnixStream::connect(&path);
+            let _ = std::fs::remove_file(&path);
+        }
+        self.inner.stop();
+    }
+}
+
+/// Binds `path`, replacing a socket left behind by a server that is gone
+fn bind(path: &Path) -> io::Result<SocketListener> {
+    match SocketListener::bind(path) {
+        Err(e) if e.kind() == io::ErrorKind::AddrInUse && UnixStream::connect(path).is_err() => {
+            std::fs::remove_file(path)?;
+            SocketListener::bind(path)
+        },
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::transport::{ self, Sockets };
+
+    #[test]
+    fn test_socket_roundtrip() {
+        let path = std::env::temp_dir().join(format!("oisg-unix-{}.sock", std::process::id()));
+        let server: Sockets<()> = transport::sockets();
+        let client: Sockets<()> = transport::sockets();
+
+        let listener = server.listen_unix(&path).unwrap();
+        let conn = client.connect_unix(&path).unwrap();
+        assert!(matches!(client.receive(), Some(Event::Connected(c, true)) if c == conn));
+
+        let accepted = match server.receive() {
+            Some(Event::Accepted(accepted, l)) if l == listener => accepted,
+            other => panic!("expected accept, got {:?}", other),
+        };
+        assert!(server.is_local(accepted));
+
+        assert!(client.send(conn, b"ping"));
+        assert!(matches!(server.receive(), Some(Event::Message(c, data)) if c == accepted && data == b"ping"));
+        assert!(server.send(accepted, b"pong"));
+        assert!(matches!(client.receive(), Some(Event::Message(c, data)) if c == conn && data == b"pong"));
+
+        client.close(conn);
+        assert!(matches!(server.receive(), Some(Event::Disconnected(c)) if c == accepted));
+
+        server.stop();
+        client.stop();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_client_that_does_not_read_is_dropped() {
+        let path = std::env::temp_dir().join(format!("oisg-stalled-{}.sock", std::process::id()));
+        let server: Sockets<()> = transport::sockets();
+        server.listen_unix(&path).unwrap();
+
+        // connected, but never read from
+        let _stalled = UnixStream::connect(&path).unwrap();
+        let accepted = match server.receive() {
+            Some(Event::Accepted(accepted, _)) => accepted,
+            other => panic!("expected accept, got {:?}", other),
+        };
+
+        // sending never waits for the client, it is dropped once it is too far behind
+        let frame = vec![0u8; 16 * 1024];
+        let sent = (0..4 * QUEUE_LEN).take_while(|_| server.send(accepted, &frame)).count();
+        assert!(sent < 4 * QUEUE_LEN);
+        assert!(matches!(server.receive(), Some(Event::Disconnected(c)) if c == accepted));
+
+        server.stop();
+    }
+
+    #[test]
+    fn test_connect_to_missing_socket() {
+        let path = std::env::temp_dir().join(format!("oisg-missing-{}.sock", std::process::id()));
+        let client: Sockets<()> = transport::sockets();
+
+        let e = client.connect_unix(&path).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+
+        client.stop();
+    }
+}
@@ -0,0 +1,423 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::{ Arc, Mutex },
+    time::Duration,
+};
+use crossbeam_channel::Sender;
+use rustls::ClientConfig;
+use crate::{
+    common::app_event::{ AppEvent, NetworkEvent, Notification },
+    constants,
+    net::{
+        self,
+        backoff::Backoff,
+        discovery::{ self, Discovery },
+        peer::{ Identity, Peers },
+        protocol::{ ErrorCode, FrameDecoder, FrameError, Message },
+        tls::TlsStream,
+        transport::{ Event, Protocol, Transport },
+        ServerAddr, ServerLink
+    }
+};
+
+/// Signals the `Network` sends to its background thread
+pub enum Signal {
+    Identity(Identity),
+    Announce,
+    Heartbeat,
+    Reconnect,
+    ConnectPeer(SocketAddr),
+    DisconnectPeer(String),
+    SendDirect {
+        user_id: String,
+        message: Message,
+    },
+}
+
+/// `Worker` owns the state of the network thread: the connection to the
+/// server, the direct connections to peers and the LAN discovery
+pub(crate) struct Worker<T: Transport<Signal>> {
+    transport: T,
+    tx_event: Sender<AppEvent>,
+    /// where the server is, connected to again on reconnect
+    server_addr: ServerAddr,
+    /// connection of the current server connection attempt
+    server: Option<T::Conn>,
+    /// set while connected, shared with `Network::send`
+    connected: Arc<Mutex<Option<ServerLink<T::Conn>>>>,
+    /// client configuration and server name when the server talks TLS
+    tls: Option<(Arc<ClientConfig>, String)>,
+    /// TLS session of the server connection until its handshake is done
+    handshake: Option<TlsStream>,
+    /// the server failed verification or refused our user id, retrying would not help
+    halted: bool,
+    backoff: Backoff,
+    decoders: HashMap<T::Conn, FrameDecoder>,
+    peers: Peers<T::Conn>,
+    discovery: Option<Discovery<T::Conn, T::Listener>>,
+    identity: Option<Identity>,
+}
+
+impl<T: Transport<Signal>> Worker<T> {
+    pub fn new(
+        transport: T,
+        tx_event: Sender<AppEvent>,
+        (server_addr, server): (ServerAddr, io::Result<T::Conn>),
+        connected: Arc<Mutex<Option<ServerLink<T::Conn>>>>,
+        tls: Option<(Arc<ClientConfig>, String)>,
+        discovery: Option<Discovery<T::Conn, T::Listener>>,
+        backoff: Backoff
+    ) -> Self {
+        let mut worker = Worker {
+            transport,
+            tx_event,
+            server_addr,
+            server: None,
+            connected,
+            tls,
+            handshake: None,
+            halted: false,
+            backoff,
+            decoders: HashMap::new(),
+            peers: Peers::new(),
+            discovery,
+            identity: None,
+        };
+
+        if worker.discovery.is_some() {
+            worker.transport.signal(Signal::Announce, Duration::ZERO);
+        }
+        worker.transport.signal(Signal::Heartbeat, constants::HEARTBEAT_INTERVAL);
+        worker.connecting(server);
+        worker
+    }
+
+    /// Processes events until the transport is stopped
+    pub fn run(mut self) {
+        while self.step() {}
+    }
+
+    /// Processes the next event, `false` if the transport had none
+    pub fn step(&mut self) -> bool {
+        match self.transport.receive() {
+            Some(event) => self.handle(event),
+            None => return false,
+        }
+
+        true
+    }
+
+    fn send_event(&self, event: NetworkEvent) {
+        let _ = self.tx_event.send(AppEvent::NetworkEvent(event));
+    }
+
+    fn deliver(&self, out: Vec<(T::Conn, Message)>) {
+        for (conn, message) in out {
+            net::send_to(&self.transport, conn, &message);
+        }
+    }
+
+    fn signal(&mut self, signal: Signal) {
+        match signal {
+            Signal::Identity(identity) => {
+                let out = self.peers.introduce(&identity);
+                self.deliver(out);
+
+                self.identity = Some(identity);
+                self.send_hello();
+                self.announce();
+            },
+            Signal::Announce => {
+                self.announce();
+
+                if let Some(discovery) = self.discovery.as_mut() {
+                    for peer in discovery.expire() {
+                        self.send_event(NetworkEvent::PeerLost(peer.user_id));
+                    }
+                }
+
+                self.transport.signal(Signal::Announce, discovery::ANNOUNCE_INTERVAL);
+            },
+            Signal::Heartbeat => {
+                self.send_server(&Message::Ping);
+                self.transport.signal(Signal::Heartbeat, constants::HEARTBEAT_INTERVAL);
+            },
+            Signal::Reconnect => {
+                let server = self.server_addr.connect(&self.transport);
+                self.connecting(server);
+            },
+            Signal::ConnectPeer(addr) => {
+                if let Err(e) = self.transport.connect(Protocol::Tcp, addr) {
+                    self.send_event(NetworkEvent::PeerUnreachable(format!("{}: {}", addr, e)));
+                }
+            },
+            Signal::DisconnectPeer(user_id) => {
+                if let Some(conn) = self.peers.connection(&user_id) {
+                    self.transport.close(conn);
+                    self.decoders.remove(&conn);
+                    if let Some(event) = self.peers.closed(conn) {
+                        self.send_event(event);
+                    }
+                }
+            },
+            Signal::SendDirect { user_id, message } => {
+                let sent = self.peers.connection(&user_id)
+                    .map(|conn| net::send_to(&self.transport, conn, &message))
+                    .unwrap_or(false);
+
+                let id = match message {
+                    Message::Chat { id, .. } => id,
+                    _ => return,
+                };
+                self.send_event(match sent {
+                    true => NetworkEvent::MessageSent(id),
+                    false => NetworkEvent::MessageFailed {
+                        id,
+                        reason: format!("{} is not connected", user_id),
+                    },
+                });
+            },
+        }
+    }
+
+    fn handle(&mut self, event: Event<T::Conn, T::Listener, Signal>) {
+        match event {
+            Event::Connected(conn, true) if Some(conn) == self.server => {
+                match &self.tls {
+                    Some((config, server_name)) => {
+                        match TlsStream::client(Arc::clone(config), server_name) {
+                            Ok(mut tls) => {
+                                let hello = tls.outgoing();
+                                self.transport.send(conn, &hello);
+                                self.handshake = Some(tls);
+                            },
+                            Err(e) => self.tls_failed(conn, e.to_string()),
+                        }
+                    },
+                    None => self.established(conn, None),
+                }
+            },
+            Event::Connected(conn, false) if Some(conn) == self.server => {
+                self.send_event(NetworkEvent::ConnectionFailed(None));
+                self.schedule_reconnect();
+            },
+            // UDP reports the multicast group as connected too
+            Event::Connected(conn, _) if self.discovery.as_ref().is_some_and(|discovery| discovery.is_group(conn)) => {},
+            Event::Connected(conn, true) => self.peer_opened(conn, true),
+            Event::Connected(conn, false) => {
+                let addr = self.transport.remote_addr(conn);
+                self.send_event(NetworkEvent::PeerUnreachable(format!("not able to connect to {}", addr)));
+            },
+            Event::Accepted(conn, _) => self.peer_opened(conn, false),
+            Event::Message(conn, data) if Some(conn) == self.server && self.tls.is_some() => {
+                match self.tls_receive(conn, &data) {
+                    Ok(plaintext) => {
+                        for result in self.frames(conn, &plaintext) {
+                            match result {
+                                Ok(message) => self.server_message(conn, message),
+                                Err(e) => self.send_event(NetworkEvent::ProtocolError(e)),
+                            }
+                        }
+                    },
+                    Err(reason) => self.tls_failed(conn, reason),
+                }
+            },
+            Event::Message(conn, data) if Some(conn) == self.server || self.peers.is_peer(conn) => {
+                for result in self.frames(conn, &data) {
+                    match result {
+                        Ok(message) if Some(conn) == self.server => self.server_message(conn, message),
+                        Ok(message) => {
+                            let (out, event) = self.peers.handle(conn, message, self.identity.as_ref());
+                            self.deliver(out);
+
+                            if let Some(event) = event {
+                                if let NetworkEvent::PeerDisconnected(_) = event {
+                                    self.transport.close(conn);
+                                    self.decoders.remove(&conn);
+                                }
+                                self.send_event(event);
+                            }
+                        },
+                        Err(e) => self.send_event(NetworkEvent::ProtocolError(e)),
+                    }
+                }
+            },
+            // a connection we no longer know of
+            Event::Message(_, _) => {},
+            Event::Datagram(listener, from, data) => {
+                let own_user_id = self.identity.as_ref().map(|identity| identity.user_id.as_str());
+                let peer = match self.discovery.as_mut() {
+                    Some(discovery) if discovery.owns(listener) => {
+                        discovery.receive(from, &data, own_user_id)
+                    },
+                    _ => None,
+                };
+
+                if let Some(peer) = peer {
+                    self.send_event(NetworkEvent::PeerDiscovered(peer));
+                }
+            },
+            // we closed it ourselves after the failure was reported
+            Event::Disconnected(conn) if Some(conn) == self.server && self.halted => {},
+            Event::Disconnected(conn) if Some(conn) == self.server => {
+                *self.connected.lock().unwrap() = None;
+                self.handshake = None;
+                self.decoders.remove(&conn);
+                self.send_event(NetworkEvent::Disconnected);
+                self.schedule_reconnect();
+            },
+            Event::Disconnected(conn) => {
+                self.decoders.remove(&conn);
+                if let Some(event) = self.peers.closed(conn) {
+                    self.send_event(event);
+                }
+            },
+            Event::Signal(signal) => self.signal(signal),
+        }
+    }
+
+    fn frames(&mut self, conn: T::Conn, data: &[u8]) -> Vec<Result<Message, FrameError>> {
+        let decoder = self.decoders.entry(conn).or_default();
+        decoder.push(data);
+
+        let mut messages = vec![];
+        while let Some(result) = decoder.next_message() {
+            messages.push(result);
+        }
+
+        messages
+    }
+
+    /// Passes TLS records from the server through its session, returns
+    /// the plaintext or why the session failed
+    fn tls_receive(&mut self, conn: T::Conn, data: &[u8]) -> Result<Vec<u8>, String> {
+        if let Some(mut tls) = self.handshake.take() {
+            let result = tls.receive(data);
+            let records = tls.outgoing();
+            self.transport.send(conn, &records);
+
+            let plaintext = result.map_err(|e| e.to_string())?;
+            match tls.is_handshaking() {
+                true => self.handshake = Some(tls),
+                false => self.established(conn, Some(tls)),
+            }
+            return Ok(plaintext);
+        }
+
+        let mut connected = self.connected.lock().unwrap();
+        let tls = match connected.as_mut().and_then(|link| link.tls.as_mut()) {
+            Some(tls) => tls,
+            None => return Ok(vec![]),
+        };
+
+        let result = tls.receive(data);
+        let records = tls.outgoing();
+        self.transport.send(conn, &records);
+        result.map_err(|e| e.to_string())
+    }
+
+    fn established(&mut self, conn: T::Conn, tls: Option<TlsStream>) {
+        *self.connected.lock().unwrap() = Some(ServerLink { conn, tls });
+        self.backoff.reset();
+        self.decoders.insert(conn, FrameDecoder::new());
+        self.send_event(NetworkEvent::Connected);
+        self.send_hello();
+    }
+
+    /// Gives up on the server, a certificate we do not trust will not
+    /// become trusted by connecting again
+    fn tls_failed(&mut self, conn: T::Conn, reason: String) {
+        self.halt(conn);
+        self.send_event(NetworkEvent::TlsFailed(reason));
+    }
+
+    /// Gives up on the server, our user id is taken by someone else
+    fn auth_failed(&mut self, conn: T::Conn, reason: String) {
+        self.halt(conn);
+        self.send_event(NetworkEvent::AuthFailed(reason));
+    }
+
+    fn halt(&mut self, conn: T::Conn) {
+        self.halted = true;
+        self.handshake = None;
+        *self.connected.lock().unwrap() = None;
+        self.transport.close(conn);
+        self.decoders.remove(&conn);
+    }
+
+    /// Waits for the server connection being opened, or reports why it
+    /// could not be and tries again later
+    fn connecting(&mut self, server: io::Result<T::Conn>) {
+        match server {
+            Ok(server) => self.server = Some(server),
+            Err(e) => {
+                self.server = None;
+                self.send_event(NetworkEvent::ConnectionFailed(Some(e.to_string())));
+                self.schedule_reconnect();
+            },
+        }
+    }
+
+    fn schedule_reconnect(&mut self) {
+        if self.halted {
+            return;
+        }
+
+        let delay = self.backoff.next_delay();
+        self.transport.signal(Signal::Reconnect, delay);
+        self.send_event(NetworkEvent::Reconnecting(delay));
+    }
+
+    fn server_message(&mut self, conn: T::Conn, message: Message) {
+        match message {
+            Message::Ping => {
+                self.send_server(&Message::Pong);
+            },
+            Message::Challenge { nonce } => {
+                if let Some(identity) = &self.identity {
+                    self.send_server(&identity.auth(&nonce));
+                }
+            },
+            Message::Error { code: ErrorCode::AuthFailed, message, .. } => self.auth_failed(conn, message),
+            // answer to our heartbeat, nothing for the UI
+            Message::Pong => {},
+            Message::Join { room, user_id } => {
+                let _ = self.tx_event.send(AppEvent::NotificationEvent(Notification::UserJoined { room, user_id }));
+            },
+            Message::Leave { room, user_id } => {
+                let _ = self.tx_event.send(AppEvent::NotificationEvent(Notification::UserLeft { room, user_id }));
+            },
+            message => self.send_event(NetworkEvent::MessageReceived(message)),
+        }
+    }
+
+    fn peer_opened(&mut self, conn: T::Conn, initiated: bool) {
+        self.decoders.insert(conn, FrameDecoder::new());
+        let out = self.peers.opened(conn, initiated, self.identity.as_ref());
+        self.deliver(out);
+    }
+
+    /// Introduces the user to the server, nothing to do until
+    /// the user has registered
+    fn send_hello(&self) {
+        if let Some(identity) = &self.identity {
+            self.send_server(&identity.hello());
+        }
+    }
+
+    /// Sends `message` to the server if we are connected
+    fn send_server(&self, message: &Message) -> bool {
+        match self.connected.lock().unwrap().as_mut() {
+            Some(link) => link.send(&self.transport, message),
+            None => false,
+        }
+    }
+
+    fn announce(&self) {
+        if let (Some(discovery), Some(identity)) = (&self.discovery, &self.identity) {
+            discovery.announce(&self.transport, &identity.user_id, &identity.user_name);
+        }
+    }
+}
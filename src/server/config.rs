@@ -0,0 +1,254 @@
+use std::{
+    io,
+    str::FromStr,
+    time::Duration,
+};
+use crate::{
+    config::value_of,
+    constants,
+    net::protocol,
+    server::limits::Limits
+};
+
+/// Another relay to peer with, given as `<name>@<host:port>`
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerConfig {
+    pub name: String,
+    /// address the relay accepts links from other relays on
+    pub addr: String,
+}
+
+/// `ServerConfig` holds the options the relay server was started with
+#[derive(Debug, PartialEq)]
+pub struct ServerConfig {
+    pub listen_addr: String,
+    /// address to accept oisg clients over WebSocket on, off when `None`
+    pub ws_addr: Option<String>,
+    /// address to accept oisg clients over UDP on, off when `None`
+    pub udp_addr: Option<String>,
+    /// path of a Unix socket to accept local oisg clients on, off when `None`
+    pub unix_path: Option<String>,
+    /// address of the IRC gateway, off when `None`
+    pub irc_addr: Option<String>,
+    /// PEM certificate chain, clients are served over TLS when set
+    pub cert_file: Option<String>,
+    /// PEM private key of the certificate
+    pub key_file: Option<String>,
+    /// what a single connection may send
+    pub limits: Limits,
+    /// name users of other relays reach ours by, as `user_id@name`
+    pub name: Option<String>,
+    /// address to accept links from other relays on, off when `None`
+    pub link_addr: Option<String>,
+    /// relays to peer with, each of them has to list this one as well
+    pub peers: Vec<PeerConfig>,
+    /// directory the relay keeps the keys user ids are bound to in,
+    /// they are forgotten on restart when `None`
+    pub state_dir: Option<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            listen_addr: constants::DEFAULT_LISTEN_ADDR.to_string(),
+            ws_addr: None,
+            udp_addr: None,
+            unix_path: None,
+            irc_addr: None,
+            cert_file: None,
+            key_file: None,
+            limits: Limits::default(),
+            name: None,
+            link_addr: None,
+            peers: vec![],
+            state_dir: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Parses the command line, the state is kept in `~/.oisg/server`
+    /// unless `--state` says otherwise
+    pub fn from_args() -> io::Result<Self> {
+        let mut config = Self::parse(std::env::args().skip(1))?;
+        if config.state_dir.is_none() {
+            let home = home::home_dir().ok_or_else(|| io::Error::new(
+                io::ErrorKind::NotFound,
+                "No home directory to keep the relay state in, give --state"
+            ))?;
+            config.state_dir = Some(home.join(".oisg").join("server").to_string_lossy().into_owned());
+        }
+
+        Ok(config)
+    }
+
+    /// Parses command line options
+    ///
+    /// `--listen <host:port>` address to accept oisg clients on
+    /// `--ws <host:port>` address to accept oisg clients over WebSocket on
+    /// `--udp <host:port>` address to accept oisg clients over UDP on
+    /// `--unix <path>` Unix socket to accept local oisg clients on
+    /// `--irc <host:port>` address to accept IRC clients on
+    /// `--cert <file>` PEM certificate to serve clients over TLS with
+    /// `--key <file>` PEM private key of the certificate
+    /// `--max-messages <n>` messages a connection may send per second
+    /// `--max-bytes <n>` bytes a connection may send per second
+    /// `--max-frame <n>` largest message in bytes
+    /// `--ban <secs>` how long connections that keep flooding are refused
+    /// `--name <relay>` name of this relay for other relays
+    /// `--link <host:port>` address to accept links from other relays on
+    /// `--peer <relay>@<host:port>` relay to peer with, may be repeated
+    /// `--state <dir>` directory to keep the keys of user ids in
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> io::Result<Self> {
+        let mut config = ServerConfig::default();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-l" | "--listen" => config.listen_addr = value_of(&arg, args.next())?,
+                "--ws" => config.ws_addr = Some(value_of(&arg, args.next())?),
+                "--udp" => config.udp_addr = Some(value_of(&arg, args.next())?),
+                "--unix" => config.unix_path = Some(value_of(&arg, args.next())?),
+                "--irc" => config.irc_addr = Some(value_of(&arg, args.next())?),
+                "--cert" => config.cert_file = Some(value_of(&arg, args.next())?),
+                "--key" => config.key_file = Some(value_of(&arg, args.next())?),
+                "--max-messages" => config.limits.messages_per_sec = number_of(&arg, args.next())?,
+                "--max-bytes" => config.limits.bytes_per_sec = number_of(&arg, args.next())?,
+                "--max-frame" => config.limits.max_frame_len = number_of(&arg, args.next())?,
+                "--ban" => config.limits.ban = Duration::from_secs(number_of(&arg, args.next())?),
+                "--name" => config.name = Some(value_of(&arg, args.next())?),
+                "--link" => config.link_addr = Some(value_of(&arg, args.next())?),
+                "--peer" => config.peers.push(peer_of(&arg, args.next())?),
+                "--state" => config.state_dir = Some(value_of(&arg, args.next())?),
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("Unknown option {}", arg)
+                    ));
+                }
+            }
+        }
+
+        if config.cert_file.is_some() != config.key_file.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--cert and --key have to be given together"
+            ));
+        }
+
+        let invalid = |message: &str| Err(io::Error::new(io::ErrorKind::InvalidInput, message.to_string()));
+        match &config.name {
+            Some(name) if !is_valid_relay(name) => return invalid(&format!("{} is not a relay name", name)),
+            Some(name) if config.peers.iter().any(|peer| peer.name == *name) => {
+                return invalid("a relay can not peer with itself");
+            },
+            Some(_) => {},
+            None if !config.peers.is_empty() || config.link_addr.is_some() => {
+                return invalid("--name has to be given to peer with other relays");
+            },
+            None => {},
+        }
+        if !config.peers.is_empty() && config.link_addr.is_none() {
+            return invalid("--link has to be given to peer with other relays");
+        }
+
+        Ok(config)
+    }
+}
+
+/// Letters, digits, `-`, `_` and `.`, up to 50 of them
+fn is_valid_relay(name: &str) -> bool {
+    !name.is_empty() && name.len() <= 50
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+}
+
+fn peer_of(arg: &str, value: Option<String>) -> io::Result<PeerConfig> {
+    let value = value_of(arg, value)?;
+    match value.split_once(protocol::RELAY_SEPARATOR) {
+        Some((name, addr)) if is_valid_relay(name) && !addr.is_empty() => Ok(PeerConfig {
+            name: name.to_string(),
+            addr: addr.to_string(),
+        }),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} takes <relay>@<host:port>, not {}", arg, value)
+        )),
+    }
+}
+
+fn number_of<T: FromStr>(arg: &str, value: Option<String>) -> io::Result<T> {
+    value_of(arg, value)?
+        .parse()
+        .map_err(|_| io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid value for {}", arg)
+        ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> io::Result<ServerConfig> {
+        ServerConfig::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_listen_option() {
+        assert_eq!(parse(&[]).unwrap(), ServerConfig::default());
+        assert_eq!(parse(&["--listen", "127.0.0.1:9000"]).unwrap().listen_addr, "127.0.0.1:9000");
+        assert!(parse(&["--listen"]).is_err());
+        assert!(parse(&["--port", "1"]).is_err());
+
+        assert_eq!(parse(&["--irc", "0.0.0.0:6667"]).unwrap().irc_addr.as_deref(), Some("0.0.0.0:6667"));
+        assert_eq!(parse(&["--ws", "0.0.0.0:8080"]).unwrap().ws_addr.as_deref(), Some("0.0.0.0:8080"));
+        assert_eq!(parse(&["--udp", "0.0.0.0:7879"]).unwrap().udp_addr.as_deref(), Some("0.0.0.0:7879"));
+        assert_eq!(parse(&["--unix", "/run/oisg.sock"]).unwrap().unix_path.as_deref(), Some("/run/oisg.sock"));
+        assert_eq!(parse(&["--state", "/var/lib/oisg"]).unwrap().state_dir.as_deref(), Some("/var/lib/oisg"));
+        assert!(parse(&["--state"]).is_err());
+    }
+
+    #[test]
+    fn test_tls_options() {
+        let config = parse(&["--cert", "cert.pem", "--key", "key.pem"]).unwrap();
+        assert_eq!(config.cert_file.as_deref(), Some("cert.pem"));
+        assert_eq!(config.key_file.as_deref(), Some("key.pem"));
+
+        assert!(parse(&["--cert", "cert.pem"]).is_err());
+        assert!(parse(&["--key", "key.pem"]).is_err());
+    }
+
+    #[test]
+    fn test_limit_options() {
+        let config = parse(&["--max-messages", "5", "--max-bytes", "2048", "--max-frame", "512", "--ban", "30"]).unwrap();
+        assert_eq!(config.limits, Limits {
+            messages_per_sec: 5,
+            bytes_per_sec: 2048,
+            max_frame_len: 512,
+            ban: Duration::from_secs(30),
+        });
+
+        assert!(parse(&["--max-messages", "many"]).is_err());
+        assert!(parse(&["--ban"]).is_err());
+    }
+
+    #[test]
+    fn test_peer_options() {
+        let config = parse(&[
+            "--name", "london", "--link", "0.0.0.0:7880",
+            "--peer", "berlin@10.1.0.5:7880", "--peer", "paris@relay.paris:7880",
+        ]).unwrap();
+        assert_eq!(config.name.as_deref(), Some("london"));
+        assert_eq!(config.link_addr.as_deref(), Some("0.0.0.0:7880"));
+        assert_eq!(config.peers, vec![
+            PeerConfig { name: "berlin".to_string(), addr: "10.1.0.5:7880".to_string() },
+            PeerConfig { name: "paris".to_string(), addr: "relay.paris:7880".to_string() },
+        ]);
+
+        assert!(parse(&["--name", "london"]).is_ok());
+        assert!(parse(&["--name", "lon don"]).is_err());
+        assert!(parse(&["--link", "0.0.0.0:7880", "--peer", "berlin@10.1.0.5:7880"]).is_err());
+        assert!(parse(&["--name", "london", "--peer", "berlin@10.1.0.5:7880"]).is_err());
+        assert!(parse(&["--name", "london", "--link", "0.0.0.0:7880", "--peer", "10.1.0.5:7880"]).is_err());
+        assert!(parse(&["--name", "london", "--link", "0.0.0.0:7880", "--peer", "london@10.1.0.5:7880"]).is_err());
+    }
+}
@@ -0,0 +1,544 @@
+use std::{
+    collections::{ HashMap, HashSet },
+    time::Instant,
+};
+use crate::{
+    common::clock::LamportClock,
+    constants,
+    net::protocol::{ self, Body, ErrorCode, Message }
+};
+
+/// Name the gateway puts in front of its own replies
+const SERVER_NAME: &str = "oisg";
+/// Lines longer than this are thrown away, IRC allows 512 bytes
+const MAX_LINE_LEN: usize = 4096;
+/// Bytes of a line we send, without the CR LF, longer chats are split
+const IRC_LINE_LEN: usize = 510;
+const MAX_NICK_LEN: usize = 30;
+/// Stands for `protocol::RELAY_SEPARATOR` in nicks, which can not have a `@`
+const NICK_RELAY_SEPARATOR: char = '/';
+
+/// What a line from an IRC client asks the server for
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    /// both NICK and USER were given, the user can be signed in
+    SignIn {
+        nick: String,
+        user_name: String,
+    },
+    /// message for the relay, sent by the user of the session
+    Relay(Message),
+    /// line to send back as it is
+    Reply(String),
+    Quit,
+}
+
+/// `IrcSession` translates between one IRC client and the relay. Like
+/// `Relay` it does no I/O: lines from the client become `Command`s and
+/// relay messages for the user become IRC lines.
+pub struct IrcSession {
+    buffer: Vec<u8>,
+    nick: Option<String>,
+    user_name: Option<String>,
+    /// `SignIn` was returned, waiting for the relay to welcome or refuse us
+    signing_in: bool,
+    registered: bool,
+    channels: HashSet<String>,
+    /// members the relay named before our own join of the room came back
+    names: HashMap<String, Vec<String>>,
+    /// IRC clients have no clock, this one stands in for it
+    clock: LamportClock,
+    last_ping: Instant,
+}
+
+impl IrcSession {
+    pub fn new(now: Instant) -> Self {
+        IrcSession {
+            buffer: Vec::new(),
+            nick: None,
+            user_name: None,
+            signing_in: false,
+            registered: false,
+            channels: HashSet::new(),
+            names: HashMap::new(),
+            clock: LamportClock::default(),
+            last_ping: now,
+        }
+    }
+
+    /// Takes bytes from the client, returns the commands of the complete lines
+    pub fn receive(&mut self, data: &[u8]) -> Vec<Command> {
+        self.buffer.extend_from_slice(data);
+
+        let mut commands = vec![];
+        while let Some(end) = self.buffer.iter().position(|byte| *byte == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            if let Some((command, params)) = parse_line(line.trim_end_matches(['\r', '\n'])) {
+                commands.extend(self.command(&command, params));
+            }
+        }
+
+        if self.buffer.len() > MAX_LINE_LEN {
+            self.buffer.clear();
+        }
+
+        commands
+    }
+
+    /// Whether the client should be pinged, clients idle in a channel
+    /// would otherwise time out
+    pub fn ping_due(&mut self, now: Instant) -> bool {
+        if now.saturating_duration_since(self.last_ping) < constants::HEARTBEAT_INTERVAL {
+            return false;
+        }
+
+        self.last_ping = now;
+        true
+    }
+
+    pub fn ping() -> String {
+        format!("PING :{}", SERVER_NAME)
+    }
+
+    fn command(&mut self, command: &str, params: Vec<String>) -> Vec<Command> {
+        match command {
+            "NICK" => self.set_nick(params),
+            "USER" if self.registered || self.signing_in => vec![self.reply("462", ":You may not reregister")],
+            "USER" if params.len() < 4 => vec![self.reply("461", "USER :Not enough parameters")],
+            "USER" => {
+                let user_name = match params[3].trim() {
+                    "" => params[0].clone(),
+                    real_name => real_name.to_string(),
+                };
+                self.user_name = Some(user_name);
+                self.sign_in()
+            },
+            "PING" => vec![Command::Reply(format!(
+                ":{} PONG {} :{}",
+                SERVER_NAME,
+                SERVER_NAME,
+                params.first().map(String::as_str).unwrap_or(SERVER_NAME)
+            ))],
+            // capabilities are not supported, clients go on without them
+            "PONG" | "CAP" => vec![],
+            "QUIT" => vec![Command::Quit],
+            _ if !self.registered => vec![self.reply("451", ":You have not registered")],
+            "JOIN" => self.join(params),
+            "PART" => self.part(params),
+            "PRIVMSG" => self.privmsg(params),
+            _ => vec![self.reply("421", &format!("{} :Unknown command", command))],
+        }
+    }
+
+    fn set_nick(&mut self, params: Vec<String>) -> Vec<Command> {
+        let nick = match params.into_iter().next() {
+            Some(nick) => nick,
+            None => return vec![self.reply("431", ":No nickname given")],
+        };
+        if !is_valid_nick(&nick) {
+            return vec![self.reply("432", &format!("{} :Erroneous nickname", nick))];
+        }
+        if self.registered || self.signing_in {
+            return vec![self.notice("changing nick is not supported, reconnect with the new one")];
+        }
+
+        self.nick = Some(nick);
+        self.sign_in()
+    }
+
+    fn sign_in(&mut self) -> Vec<Command> {
+        match (&self.nick, &self.user_name) {
+            (Some(nick), Some(user_name)) => {
+                self.signing_in = true;
+                vec![Command::SignIn {
+                    nick: nick.clone(),
+                    user_name: user_name.clone(),
+                }]
+            },
+            _ => vec![],
+        }
+    }
+
+    fn join(&mut self, params: Vec<String>) -> Vec<Command> {
+        let channels = match params.first() {
+            Some(channels) => channels,
+            None => return vec![self.reply("461", "JOIN :Not enough parameters")],
+        };
+
+        channels.split(',')
+            .map(|room| match protocol::is_valid_room(room) {
+                true => Command::Relay(Message::Join {
+                    room: room.to_string(),
+                    user_id: self.nick().to_string(),
+                }),
+                false => self.reply("403", &format!("{} :No such channel", room)),
+            })
+            .collect()
+    }
+
+    fn part(&mut self, params: Vec<String>) -> Vec<Command> {
+        let channels = match params.first() {
+            Some(channels) => channels,
+            None => return vec![self.reply("461", "PART :Not enough parameters")],
+        };
+
+        channels.split(',')
+            .map(|room| match self.channels.contains(room) {
+                true => Command::Relay(Message::Leave {
+                    room: room.to_string(),
+                    user_id: self.nick().to_string(),
+                }),
+                false => self.reply("442", &format!("{} :You're not on that channel", room)),
+            })
+            .collect()
+    }
+
+    fn privmsg(&mut self, params: Vec<String>) -> Vec<Command> {
+        let (targets, text) = match params.as_slice() {
+            [] => return vec![self.reply("411", ":No recipient given (PRIVMSG)")],
+            [_] => return vec![self.reply("412", ":No text to send")],
+            [targets, text, ..] => (targets, text),
+        };
+
+        let clock = self.clock.tick();
+        targets.split(',')
+            .map(|target| Command::Relay(Message::Chat {
+                id: protocol::new_message_id(self.nick()),
+                from: self.nick().to_string(),
+                to: Some(match protocol::is_room(target) {
+                    true => target.to_string(),
+                    false => user_id_of(target),
+                }),
+                body: Body::Plain(text.clone()),
+                clock,
+            }))
+            .collect()
+    }
+
+    /// IRC lines telling the client about `message`, empty if IRC has
+    /// nothing like it
+    pub fn render(&mut self, message: &Message) -> Vec<String> {
+        match message {
+            Message::Welcome { version, .. } => {
+                self.signing_in = false;
+                self.registered = true;
+                let nick = self.nick().to_string();
+                vec![
+                    self.numeric("001", &format!(":Welcome to oisg, {}", nick)),
+                    self.numeric("002", &format!(":Your host is {}, running version {}", SERVER_NAME, version)),
+                    self.numeric("422", ":MOTD File is missing"),
+                ]
+            },
+            Message::Chat { from, to, body, clock, .. } => {
+                self.clock.observe(*clock);
+                let text = match body {
+                    Body::Plain(text) => text.as_str(),
+                    Body::Sealed { .. } => "[encrypted message]",
+                };
+                // IRC has no chats to everyone, they come as notices
+                let (verb, target, prefix) = match to {
+                    Some(room) if protocol::is_room(room) => ("PRIVMSG", room.as_str(), ""),
+                    Some(_) => ("PRIVMSG", self.nick(), ""),
+                    None => ("NOTICE", self.nick(), "[everyone] "),
+                };
+
+                // what comes before the text, it has to fit too
+                let head = format!("{} {} {} :{}", source(from), verb, clean_word(target), prefix);
+                let len = IRC_LINE_LEN.saturating_sub(head.len()).max(1);
+                text.lines()
+                    .flat_map(|line| split_line(&clean(line), len))
+                    .map(|line| format!("{}{}", head, line))
+                    .collect()
+            },
+            Message::Join { room, user_id } if user_id == self.nick() => {
+                if !self.channels.insert(room.clone()) {
+                    return vec![];
+                }
+
+                let mut names = self.names.remove(room).unwrap_or_default();
+                names.push(nick_of(user_id));
+                let room = clean_word(room);
+                vec![
+                    format!("{} JOIN {}", source(user_id), room),
+                    self.numeric("353", &format!("= {} :{}", room, names.join(" "))),
+                    self.numeric("366", &format!("{} :End of /NAMES list", room)),
+                ]
+            },
+            Message::Join { room, user_id } => match self.channels.contains(room) {
+                true => vec![format!("{} JOIN {}", source(user_id), clean_word(room))],
+                false => {
+                    self.names.entry(room.clone()).or_default().push(nick_of(user_id));
+                    vec![]
+                },
+            },
+            Message::Leave { room, user_id } => {
+                let ours = user_id == self.nick();
+                let shown = match ours {
+                    true => self.channels.remove(room),
+                    false => self.channels.contains(room),
+                };
+
+                match shown {
+                    true => vec![format!("{} PART {}", source(user_id), clean_word(room))],
+                    false => vec![],
+                }
+            },
+            Message::Error { code: ErrorCode::AuthFailed, message, .. } if self.signing_in => {
+                // the nick is taken, the client can pick another one
+                self.signing_in = false;
+                let nick = self.nick.take().unwrap_or_default();
+                vec![format!(":{} 433 * {} :{}", SERVER_NAME, nick, clean(message))]
+            },
+            Message::Error { message, .. } => vec![self.notice_line(message)],
+            _ => vec![],
+        }
+    }
+
+    fn nick(&self) -> &str {
+        self.nick.as_deref().unwrap_or("*")
+    }
+
+    fn numeric(&self, code: &str, rest: &str) -> String {
+        format!(":{} {} {} {}", SERVER_NAME, code, self.nick(), rest)
+    }
+
+    fn reply(&self, code: &str, rest: &str) -> Command {
+        Command::Reply(self.numeric(code, rest))
+    }
+
+    fn notice_line(&self, text: &str) -> String {
+        format!(":{} NOTICE {} :{}", SERVER_NAME, self.nick(), clean(text))
+    }
+
+    fn notice(&self, text: &str) -> Command {
+        Command::Reply(self.notice_line(text))
+    }
+}
+
+/// `:user!user@oisg`, where the lines of a user come from
+fn source(user_id: &str) -> String {
+    let nick = nick_of(user_id);
+    format!(":{}!{}@{}", nick, nick, SERVER_NAME)
+}
+
+/// Nick of `user_id`, `alice/london` for the user `alice@london` of another relay
+fn nick_of(user_id: &str) -> String {
+    clean_word(&user_id.replace(protocol::RELAY_SEPARATOR, &NICK_RELAY_SEPARATOR.to_string()))
+}
+
+/// `text` without control characters, a CR or NUL would end the line
+/// early and let whatever follows pass for a line of its own
+fn clean(text: &str) -> String {
+    text.chars().map(|c| if c.is_control() { ' ' } else { c }).collect()
+}
+
+/// `word` as a single parameter of a line, without spaces or control characters
+fn clean_word(word: &str) -> String {
+    word.chars().map(|c| if c.is_control() || c == ' ' { '_' } else { c }).collect()
+}
+
+/// Splits `line` into pieces of at most `len` bytes, between characters
+fn split_line(line: &str, len: usize) -> Vec<String> {
+    let mut pieces = vec![String::new()];
+    for c in line.chars() {
+        let piece = pieces.last_mut().unwrap();
+        if !piece.is_empty() && piece.len() + c.len_utf8() > len {
+            pieces.push(String::new());
+        }
+        pieces.last_mut().unwrap().push(c);
+    }
+    pieces
+}
+
+/// User id `nick` stands for, see `nick_of`
+fn user_id_of(nick: &str) -> String {
+    nick.replace(NICK_RELAY_SEPARATOR, &protocol::RELAY_SEPARATOR.to_string())
+}
+
+/// Splits `[:prefix] COMMAND params [:trailing]`, the prefix is ignored
+fn parse_line(line: &str) -> Option<(String, Vec<String>)> {
+    let mut rest = line.trim_start();
+    if rest.starts_with(':') {
+        rest = rest.split_once(' ').map(|(_, rest)| rest)?;
+    }
+
+    let (rest, trailing) = match rest.split_once(" :") {
+        Some((rest, trailing)) => (rest, Some(trailing)),
+        None => (rest, None),
+    };
+    let mut words = rest.split_whitespace();
+    let command = words.next()?.to_uppercase();
+
+    let mut params: Vec<String> = words.map(str::to_string).collect();
+    params.extend(trailing.map(str::to_string));
+
+    Some((command, params))
+}
+
+fn is_valid_nick(nick: &str) -> bool {
+    let special = |c: char| "-_[]\\`^{}|".contains(c);
+
+    nick.len() <= MAX_NICK_LEN && nick.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || special(c) && c != '-')
+        && nick.chars().all(|c| c.is_ascii_alphanumeric() || special(c))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signed_in(nick: &str) -> IrcSession {
+        let mut session = IrcSession::new(Instant::now());
+        let commands = session.receive(format!("CAP LS 302\r\nNICK {}\r\nUSER {} 0 * :{} Smith\r\n", nick, nick, nick).as_bytes());
+        assert_eq!(commands, vec![Command::SignIn {
+            nick: nick.to_string(),
+            user_name: format!("{} Smith", nick),
+        }]);
+
+        let lines = session.render(&Message::Welcome {
+            version: constants::APP_VERSION.to_string(),
+            relay: String::new(),
+        });
+        assert!(lines[0].starts_with(&format!(":oisg 001 {} ", nick)));
+
+        session
+    }
+
+    #[test]
+    fn test_parse_line() {
+        assert_eq!(parse_line("PRIVMSG #rust :hello there"), Some((
+            "PRIVMSG".to_string(),
+            vec!["#rust".to_string(), "hello there".to_string()]
+        )));
+        assert_eq!(parse_line(":alice!a@host join #rust"), Some(("JOIN".to_string(), vec!["#rust".to_string()])));
+        assert_eq!(parse_line("QUIT"), Some(("QUIT".to_string(), vec![])));
+        assert_eq!(parse_line(""), None);
+    }
+
+    #[test]
+    fn test_sign_in() {
+        let mut session = IrcSession::new(Instant::now());
+
+        // lines can arrive in pieces, nothing happens before registration
+        assert!(session.receive(b"NICK 9lives\r\nNICK al").iter().all(|c| matches!(c, Command::Reply(line) if line.contains(" 432 "))));
+        assert!(session.receive(b"ice\r\nJOIN #rust\r\n").iter().all(|c| matches!(c, Command::Reply(line) if line.contains(" 451 "))));
+        assert_eq!(session.receive(b"USER alice 0 * :Alice\n"), vec![Command::SignIn {
+            nick: "alice".to_string(),
+            user_name: "Alice".to_string(),
+        }]);
+
+        // a taken nick can be replaced
+        let lines = session.render(&Message::error(ErrorCode::AuthFailed, "user id alice is taken"));
+        assert_eq!(lines, vec![":oisg 433 * alice :user id alice is taken".to_string()]);
+        assert_eq!(session.receive(b"NICK alice2\r\n"), vec![Command::SignIn {
+            nick: "alice2".to_string(),
+            user_name: "Alice".to_string(),
+        }]);
+    }
+
+    #[test]
+    fn test_rooms() {
+        let mut session = signed_in("alice");
+
+        assert_eq!(session.receive(b"JOIN #rust,bad\r\n"), vec![
+            Command::Relay(Message::Join { room: "#rust".to_string(), user_id: "alice".to_string() }),
+            Command::Reply(":oisg 403 alice bad :No such channel".to_string()),
+        ]);
+
+        // members the relay names first are listed once we are in
+        assert!(session.render(&Message::Join { room: "#rust".to_string(), user_id: "bob".to_string() }).is_empty());
+        let lines = session.render(&Message::Join { room: "#rust".to_string(), user_id: "alice".to_string() });
+        assert_eq!(lines, vec![
+            ":alice!alice@oisg JOIN #rust".to_string(),
+            ":oisg 353 alice = #rust :bob alice".to_string(),
+            ":oisg 366 alice #rust :End of /NAMES list".to_string(),
+        ]);
+        assert_eq!(
+            session.render(&Message::Join { room: "#rust".to_string(), user_id: "carol".to_string() }),
+            vec![":carol!carol@oisg JOIN #rust".to_string()]
+        );
+
+        assert_eq!(session.receive(b"PART #go\r\n"), vec![
+            Command::Reply(":oisg 442 alice #go :You're not on that channel".to_string()),
+        ]);
+        assert_eq!(session.receive(b"PART #rust :bye\r\n"), vec![
+            Command::Relay(Message::Leave { room: "#rust".to_string(), user_id: "alice".to_string() }),
+        ]);
+    }
+
+    #[test]
+    fn test_chats() {
+        let mut session = signed_in("alice");
+
+        let commands = session.receive(b"PRIVMSG bob :hi bob\r\n");
+        assert!(matches!(&commands[..], [Command::Relay(Message::Chat { from, to: Some(to), body: Body::Plain(text), .. })]
+            if from == "alice" && to == "bob" && text == "hi bob"));
+
+        let chat = |to: Option<&str>, text: &str| Message::Chat {
+            id: "bob-1".to_string(),
+            from: "bob".to_string(),
+            to: to.map(str::to_string),
+            body: Body::Plain(text.to_string()),
+            clock: 7,
+        };
+        assert_eq!(session.render(&chat(Some("alice"), "hi")), vec![":bob!bob@oisg PRIVMSG alice :hi".to_string()]);
+        assert_eq!(session.render(&chat(Some("#rust"), "one\ntwo")), vec![
+            ":bob!bob@oisg PRIVMSG #rust :one".to_string(),
+            ":bob!bob@oisg PRIVMSG #rust :two".to_string(),
+        ]);
+        assert_eq!(session.render(&chat(None, "all")), vec![":bob!bob@oisg NOTICE alice :[everyone] all".to_string()]);
+
+        // answers are ordered after the chats the user has seen
+        let commands = session.receive(b"PRIVMSG bob :hi again\r\n");
+        assert!(matches!(&commands[..], [Command::Relay(Message::Chat { clock: 8, .. })]));
+
+        assert_eq!(session.receive(b"PING :abc\r\n"), vec![Command::Reply(":oisg PONG oisg :abc".to_string())]);
+        assert_eq!(session.receive(b"QUIT :later\r\n"), vec![Command::Quit]);
+    }
+
+    #[test]
+    fn test_chats_can_not_inject_lines() {
+        let mut session = signed_in("alice");
+        let chat = |from: &str, to: &str, text: &str| Message::Chat {
+            id: "bob-1".to_string(),
+            from: from.to_string(),
+            to: Some(to.to_string()),
+            body: Body::Plain(text.to_string()),
+            clock: 1,
+        };
+
+        // a lone CR would end the line for the IRC client
+        assert_eq!(session.render(&chat("bob", "#rust", "hi\r:admin!x@y PRIVMSG #rust :op me\0")), vec![
+            ":bob!bob@oisg PRIVMSG #rust :hi :admin!x@y PRIVMSG #rust :op me ".to_string(),
+        ]);
+        assert_eq!(session.render(&chat("bob\r\nQUIT", "#ru st", "hi")), vec![
+            ":bob__QUIT!bob__QUIT@oisg PRIVMSG #ru_st :hi".to_string(),
+        ]);
+
+        // long chats are split to fit the 512 bytes of a line
+        let lines = session.render(&chat("bob", "alice", &"é".repeat(600)));
+        assert_eq!(lines.len(), 3);
+        assert!(lines.iter().all(|line| line.len() + 2 <= 512 && line.starts_with(":bob!bob@oisg PRIVMSG alice :")));
+        let text: String = lines.iter().map(|line| line.split_once(" :").unwrap().1).collect();
+        assert_eq!(text, "é".repeat(600));
+    }
+
+    #[test]
+    fn test_federated_chats() {
+        let mut session = signed_in("alice");
+
+        // users of other relays get a nick without the `@` of their user id
+        let chat = Message::Chat {
+            id: "bob-1".to_string(),
+            from: "bob@berlin".to_string(),
+            to: Some("alice".to_string()),
+            body: Body::Plain("hi from berlin".to_string()),
+            clock: 1,
+        };
+        assert_eq!(session.render(&chat), vec![":bob/berlin!bob/berlin@oisg PRIVMSG alice :hi from berlin".to_string()]);
+
+        // and are answered by it
+        let commands = session.receive(b"PRIVMSG bob/berlin :hi bob\r\n");
+        assert!(matches!(&commands[..], [Command::Relay(Message::Chat { to: Some(to), .. })] if to == "bob@berlin"));
+    }
+}
@@ -0,0 +1,70 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io,
+    path::{ Path, PathBuf },
+};
+
+/// File in the state directory the bindings are kept in
+const KEYS_FILE_NAME: &str = "signing_keys";
+
+/// `KeyStore` keeps the Ed25519 key each user id is bound to on disk, so
+/// nobody can take over a user id by connecting first after a restart
+pub struct KeyStore {
+    path: PathBuf,
+}
+
+impl KeyStore {
+    /// Opens the store in `dir`, creating the directory if needed, and
+    /// returns the bindings saved before
+    pub fn open(dir: &Path) -> io::Result<(Self, HashMap<String, Vec<u8>>)> {
+        fs::create_dir_all(dir)?;
+        let store = KeyStore {
+            path: dir.join(KEYS_FILE_NAME),
+        };
+
+        let keys = match fs::read(&store.path) {
+            Ok(data) => bincode::deserialize(&data).map_err(|e| io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is damaged: {}", store.path.display(), e)
+            ))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        // fail now rather than on the first sign in if we can not write
+        store.save(&keys)?;
+
+        Ok((store, keys))
+    }
+
+    /// Replaces the saved bindings with `keys`
+    pub fn save(&self, keys: &HashMap<String, Vec<u8>>) -> io::Result<()> {
+        let data = bincode::serialize(keys).map_err(io::Error::other)?;
+
+        // a crash while writing leaves the previous file in place
+        let partial = self.path.with_extension("partial");
+        fs::write(&partial, data)?;
+        fs::rename(&partial, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keys_survive_reopening() {
+        let dir = std::env::temp_dir().join(format!("oisg-keys-{}", std::process::id()));
+        let (store, keys) = KeyStore::open(&dir).unwrap();
+        assert!(keys.is_empty());
+
+        let keys = HashMap::from([("alice".to_string(), vec![1u8; 32])]);
+        store.save(&keys).unwrap();
+        assert_eq!(KeyStore::open(&dir).unwrap().1, keys);
+
+        fs::write(dir.join(KEYS_FILE_NAME), b"\xff").unwrap();
+        assert!(KeyStore::open(&dir).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,180 @@
+use std::time::{ Duration, Instant };
+use crate::net::protocol::MAX_FRAME_LEN;
+
+/// Violations a connection can have in a row before it is disconnected
+const STRIKES: f64 = 5.0;
+/// A strike is forgiven after this long without new violations
+const STRIKE_DECAY: Duration = Duration::from_secs(10);
+
+/// `Limits` bound what a single connection may send to the relay
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Limits {
+    /// messages per second, bursts of twice as many are let through
+    pub messages_per_sec: u32,
+    /// bytes per second, bursts of twice as many are let through
+    pub bytes_per_sec: u32,
+    /// frames longer than this are rejected, at most `MAX_FRAME_LEN`
+    pub max_frame_len: usize,
+    /// how long repeat offenders are refused after being disconnected
+    pub ban: Duration,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            // enough for file transfers on a LAN
+            messages_per_sec: 200,
+            bytes_per_sec: 8 * 1024 * 1024,
+            max_frame_len: MAX_FRAME_LEN,
+            ban: Duration::from_secs(60),
+        }
+    }
+}
+
+/// What to do with a message a connection sent
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    /// drop the message and tell the sender
+    Reject,
+    /// drop the connection, it keeps breaking the limits
+    Disconnect,
+}
+
+/// Classic token bucket, `rate` tokens are added every second up to `capacity`
+#[derive(Debug)]
+struct TokenBucket {
+    capacity: f64,
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(capacity: f64, rate: f64, now: Instant) -> Self {
+        TokenBucket {
+            capacity,
+            rate,
+            tokens: capacity,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.updated = now;
+    }
+
+    fn has(&mut self, amount: f64, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= amount
+    }
+
+    /// Takes `amount` tokens, returns `false` without taking any if there
+    /// are not enough
+    fn take(&mut self, amount: f64, now: Instant) -> bool {
+        if !self.has(amount, now) {
+            return false;
+        }
+
+        self.tokens -= amount;
+        true
+    }
+}
+
+/// `RateLimiter` applies `Limits` to the messages of one connection
+#[derive(Debug)]
+pub struct RateLimiter {
+    messages: TokenBucket,
+    bytes: TokenBucket,
+    strikes: TokenBucket,
+}
+
+impl RateLimiter {
+    pub fn new(limits: &Limits, now: Instant) -> Self {
+        let messages = limits.messages_per_sec as f64;
+        let bytes = limits.bytes_per_sec as f64;
+
+        RateLimiter {
+            messages: TokenBucket::new(messages * 2.0, messages, now),
+            bytes: TokenBucket::new(bytes * 2.0, bytes, now),
+            strikes: TokenBucket::new(STRIKES, 1.0 / STRIKE_DECAY.as_secs_f64(), now),
+        }
+    }
+
+    /// Checks a message of `len` bytes received at `now`
+    pub fn check(&mut self, len: usize, now: Instant) -> Verdict {
+        // a message is let through only if both budgets allow it
+        if self.messages.has(1.0, now) && self.bytes.take(len as f64, now) {
+            self.messages.take(1.0, now);
+            return Verdict::Allow;
+        }
+
+        self.violation(now)
+    }
+
+    /// Counts a message that broke the limits in another way, like an
+    /// oversized frame
+    pub fn violation(&mut self, now: Instant) -> Verdict {
+        match self.strikes.take(1.0, now) {
+            true => Verdict::Reject,
+            false => Verdict::Disconnect,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> Limits {
+        Limits {
+            messages_per_sec: 2,
+            bytes_per_sec: 100,
+            ..Limits::default()
+        }
+    }
+
+    #[test]
+    fn test_message_rate() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::new(&limits(), now);
+
+        // a burst of twice the rate goes through
+        for _ in 0..4 {
+            assert_eq!(limiter.check(10, now), Verdict::Allow);
+        }
+        assert_eq!(limiter.check(10, now), Verdict::Reject);
+
+        // and the budget comes back with time
+        let later = now + Duration::from_millis(500);
+        assert_eq!(limiter.check(10, later), Verdict::Allow);
+        assert_eq!(limiter.check(10, later), Verdict::Reject);
+    }
+
+    #[test]
+    fn test_byte_rate() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::new(&limits(), now);
+
+        assert_eq!(limiter.check(150, now), Verdict::Allow);
+        assert_eq!(limiter.check(100, now), Verdict::Reject);
+        // the rejected message did not use up the message budget
+        assert_eq!(limiter.check(50, now), Verdict::Allow);
+    }
+
+    #[test]
+    fn test_repeat_offenders() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::new(&limits(), now);
+
+        for _ in 0..STRIKES as usize {
+            assert_eq!(limiter.violation(now), Verdict::Reject);
+        }
+        assert_eq!(limiter.violation(now), Verdict::Disconnect);
+
+        // strikes are forgiven over time
+        assert_eq!(limiter.violation(now + STRIKE_DECAY), Verdict::Reject);
+    }
+}
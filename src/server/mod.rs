@@ -0,0 +1,989 @@
+pub mod config;
+pub mod irc;
+pub mod keys;
+pub mod limits;
+pub mod relay;
+mod worker;
+
+use std::{
+    io,
+    net::SocketAddr,
+    path::{ Path, PathBuf },
+    time::Duration,
+};
+use crate::{
+    net::{
+        self,
+        tls,
+        transport::{ self, Event, Protocol, Sockets, Transport }
+    },
+    server::{
+        config::ServerConfig,
+        keys::KeyStore,
+        worker::{ Peer, Worker }
+    }
+};
+
+/// How often connections are checked for missed heartbeats
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// `Server` accepts oisg clients over TCP, and optionally WebSocket, UDP
+/// or a Unix socket, and relays their messages. Named servers also peer
+/// with other relays.
+pub struct Server<T: Transport<()> = Sockets<()>> {
+    transport: T,
+    worker: Worker<T>,
+    local_addr: SocketAddr,
+    /// address of the WebSocket listener
+    ws_addr: Option<SocketAddr>,
+    /// address of the UDP listener
+    udp_addr: Option<SocketAddr>,
+    /// path of the Unix socket
+    unix_path: Option<PathBuf>,
+    /// address of the IRC gateway
+    irc_addr: Option<SocketAddr>,
+    /// address other relays link to
+    link_addr: Option<SocketAddr>,
+}
+
+/// Handle to stop a running `Server` from another thread
+#[derive(Clone)]
+pub struct ServerHandle<T: Transport<()> = Sockets<()>> {
+    transport: T,
+}
+
+impl<T: Transport<()>> ServerHandle<T> {
+    pub fn stop(&self) {
+        self.transport.stop();
+    }
+}
+
+impl Server {
+    pub fn bind(config: &ServerConfig) -> io::Result<Self> {
+        Self::bind_with(config, transport::sockets())
+    }
+}
+
+impl<T: Transport<()>> Server<T> {
+    /// Binds the listeners of `config` on `transport`
+    pub fn bind_with(config: &ServerConfig, transport: T) -> io::Result<Self> {
+        let listen_addr = net::resolve(&config.listen_addr)?;
+        let tls = match (&config.cert_file, &config.key_file) {
+            (Some(cert_file), Some(key_file)) => Some(tls::server_config(cert_file, key_file)?),
+            _ => None,
+        };
+        let (_, local_addr) = transport.listen(Protocol::Tcp, listen_addr)?;
+        let ws_addr = match &config.ws_addr {
+            Some(ws_addr) => Some(transport.listen(Protocol::Ws, net::resolve(ws_addr)?)?.1),
+            None => None,
+        };
+        let udp_addr = match &config.udp_addr {
+            Some(udp_addr) => Some(transport.listen(Protocol::Udp, net::resolve(udp_addr)?)?.1),
+            None => None,
+        };
+        let unix_path = match &config.unix_path {
+            Some(unix_path) => {
+                let unix_path = PathBuf::from(unix_path);
+                transport.listen_unix(&unix_path)?;
+                Some(unix_path)
+            },
+            None => None,
+        };
+        let irc = match &config.irc_addr {
+            Some(irc_addr) => Some(transport.listen(Protocol::Tcp, net::resolve(irc_addr)?)?),
+            None => None,
+        };
+
+        let link = match &config.link_addr {
+            Some(link_addr) => Some(transport.listen(Protocol::Tcp, net::resolve(link_addr)?)?),
+            None => None,
+        };
+        let peers = config.peers.iter()
+            .map(|peer| Ok(Peer {
+                name: peer.name.clone(),
+                addr: net::resolve(&peer.addr)?,
+            }))
+            .collect::<io::Result<Vec<Peer>>>()?;
+
+        let mut worker = Worker::new(transport.clone(), tls, config.limits, irc.map(|(listener, _)| listener));
+        if let Some(name) = &config.name {
+            worker = worker.with_peers(name, link.map(|(listener, _)| listener), peers);
+        }
+        if let Some(state_dir) = &config.state_dir {
+            let (store, keys) = KeyStore::open(Path::new(state_dir))?;
+            worker = worker.with_store(store, keys);
+        }
+        transport.signal((), EXPIRE_INTERVAL);
+
+        Ok(Server {
+            transport,
+            worker,
+            local_addr,
+            ws_addr,
+            udp_addr,
+            unix_path,
+            irc_addr: irc.map(|(_, addr)| addr),
+            link_addr: link.map(|(_, addr)| addr),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Address WebSocket clients connect to, `None` if not listening for them
+    pub fn ws_addr(&self) -> Option<SocketAddr> {
+        self.ws_addr
+    }
+
+    /// Address UDP clients connect to, `None` if not listening for them
+    pub fn udp_addr(&self) -> Option<SocketAddr> {
+        self.udp_addr
+    }
+
+    /// Unix socket local clients connect to, `None` if not listening on one
+    pub fn unix_path(&self) -> Option<&Path> {
+        self.unix_path.as_deref()
+    }
+
+    /// Address IRC clients connect to, `None` without the gateway
+    pub fn irc_addr(&self) -> Option<SocketAddr> {
+        self.irc_addr
+    }
+
+    /// Address other relays link to, `None` if we do not peer
+    pub fn link_addr(&self) -> Option<SocketAddr> {
+        self.link_addr
+    }
+
+    pub fn handle(&self) -> ServerHandle<T> {
+        ServerHandle {
+            transport: self.transport.clone(),
+        }
+    }
+
+    /// Processes network events until the server is stopped
+    pub fn run(mut self) {
+        while self.step() {}
+    }
+
+    /// Processes the next event, `false` if the transport had none
+    pub fn step(&mut self) -> bool {
+        match self.transport.receive() {
+            Some(Event::Signal(())) => {
+                // clients that stopped sending heartbeats are gone
+                self.worker.expire();
+                self.transport.signal((), EXPIRE_INTERVAL);
+            },
+            Some(event) => self.worker.net_event(event),
+            None => return false,
+        }
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{ BufRead, BufReader, Write },
+        net::TcpStream,
+        thread,
+        time::Duration
+    };
+    use crossbeam_channel::{ unbounded, Receiver };
+    use crate::{
+        common::app_event::{ AppEvent, NetworkEvent, Notification },
+        config::Config,
+        crypto::SigningKeyPair,
+        net::{
+            Network,
+            Signal,
+            Worker,
+            backoff::Backoff,
+            loopback::{ Loopback, LoopbackTransport },
+            protocol::{ ErrorCode, Message, Presence },
+            tls::{ self, TrustAnchor }
+        },
+        server::{ config::PeerConfig, limits::Limits }
+    };
+    use super::*;
+    use crate::net::protocol::Body;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn start_server() -> (ServerHandle, String) {
+        start_server_on("127.0.0.1:0")
+    }
+
+    fn start_server_on(listen_addr: &str) -> (ServerHandle, String) {
+        start_with(ServerConfig {
+            listen_addr: listen_addr.to_string(),
+            ..ServerConfig::default()
+        })
+    }
+
+    /// Server with a fresh self-signed certificate for localhost, also
+    /// returns the certificate path
+    fn start_tls_server(name: &str) -> (ServerHandle, String, String) {
+        let (cert_file, key_file) = tls::tests::self_signed(name);
+        let (handle, addr) = start_with(ServerConfig {
+            listen_addr: "127.0.0.1:0".to_string(),
+            cert_file: Some(cert_file.clone()),
+            key_file: Some(key_file),
+            ..ServerConfig::default()
+        });
+
+        // the certificate is for localhost, not the address we bound
+        let port = addr.rsplit_once(':').unwrap().1;
+        (handle, format!("localhost:{}", port), cert_file)
+    }
+
+    fn start_with(config: ServerConfig) -> (ServerHandle, String) {
+        let server = Server::bind(&config).unwrap();
+        let addr = server.local_addr().to_string();
+        let handle = server.handle();
+
+        thread::spawn(move || server.run());
+
+        (handle, addr)
+    }
+
+    fn connect(addr: &str, user_id: &str) -> (Network, Receiver<AppEvent>) {
+        connect_with(addr, None, user_id)
+    }
+
+    fn connect_with(addr: &str, tls: Option<TrustAnchor>, user_id: &str) -> (Network, Receiver<AppEvent>) {
+        let (tx, rx) = unbounded();
+        let config = Config {
+            server_addr: addr.to_string(),
+            port: 0,
+            discovery: false,
+            tls,
+        };
+        let network = Network::connect(&config, tx).unwrap();
+
+        match rx.recv_timeout(TIMEOUT) {
+            Ok(AppEvent::NetworkEvent(NetworkEvent::Connected)) => {},
+            other => panic!("expected connection, got {:?}", other),
+        }
+
+        // the network answers the relay's challenge on its own
+        network.set_identity(user_id, user_id, vec![], SigningKeyPair::generate());
+
+        match rx.recv_timeout(TIMEOUT) {
+            Ok(AppEvent::NetworkEvent(NetworkEvent::MessageReceived(Message::Welcome { .. }))) => {},
+            other => panic!("expected welcome, got {:?}", other),
+        }
+
+        (network, rx)
+    }
+
+    /// Next message from the relay, skipping presence updates
+    fn next_message(rx: &Receiver<AppEvent>) -> Option<Message> {
+        loop {
+            match rx.recv_timeout(TIMEOUT) {
+                Ok(AppEvent::NetworkEvent(NetworkEvent::MessageReceived(Message::Presence { .. }))) => {},
+                Ok(AppEvent::NetworkEvent(NetworkEvent::MessageReceived(message))) => return Some(message),
+                _ => return None,
+            }
+        }
+    }
+
+    #[test]
+    fn test_relay_between_clients() {
+        let (server, addr) = start_server();
+
+        let (alice, alice_rx) = connect(&addr, "alice");
+        let (_bob, bob_rx) = connect(&addr, "bob");
+
+        let chat = Message::Chat {
+            id: "alice-1".to_string(),
+            from: "alice".to_string(),
+            to: Some("bob".to_string()),
+            body: Body::Plain("hello bob".to_string()),
+            clock: 1,
+        };
+        assert!(alice.send(&chat));
+
+        assert_eq!(next_message(&bob_rx), Some(chat));
+        assert_eq!(next_message(&alice_rx), Some(Message::Ack { id: "alice-1".to_string() }));
+
+        server.stop();
+    }
+
+    #[test]
+    fn test_relay_over_loopback() {
+        let network = Loopback::new();
+        let mut server = Server::bind_with(&ServerConfig::default(), network.node(([10, 0, 0, 1]).into())).unwrap();
+
+        type Client = (Network<LoopbackTransport<Signal>>, Worker<LoopbackTransport<Signal>>, Receiver<AppEvent>);
+        let mut clients: Vec<Client> = [("alice", 2), ("bob", 3)].into_iter()
+            .map(|(user_id, last)| {
+                let (tx, rx) = unbounded();
+                let config = Config {
+                    server_addr: "10.0.0.1:7878".to_string(),
+                    port: 0,
+                    discovery: false,
+                    tls: None,
+                };
+                let transport = network.node(([10, 0, 0, last]).into());
+                let (client, worker) = Network::with_transport(&config, tx, transport, Backoff::new()).unwrap();
+                client.set_identity(user_id, user_id, vec![], SigningKeyPair::generate());
+                (client, worker, rx)
+            })
+            .collect();
+
+        // runs everyone until nobody has anything left to do, no time passes
+        let mut settle = |clients: &mut [Client]| loop {
+            let mut busy = server.step();
+            for (_, worker, _) in clients.iter_mut() {
+                busy |= worker.step();
+            }
+            if !busy {
+                break;
+            }
+        };
+        settle(&mut clients);
+
+        let chat = Message::Chat {
+            id: "alice-1".to_string(),
+            from: "alice".to_string(),
+            to: Some("bob".to_string()),
+            body: Body::Plain("hello in memory".to_string()),
+            clock: 1,
+        };
+        assert!(clients[0].0.send(&chat));
+        settle(&mut clients);
+
+        let messages = |rx: &Receiver<AppEvent>| -> Vec<Message> {
+            rx.try_iter()
+                .filter_map(|event| match event {
+                    AppEvent::NetworkEvent(NetworkEvent::MessageReceived(message)) => Some(message),
+                    _ => None,
+                })
+                .filter(|message| matches!(message, Message::Chat { .. } | Message::Ack { .. }))
+                .collect()
+        };
+        assert_eq!(messages(&clients[1].2), vec![chat]);
+        assert_eq!(messages(&clients[0].2), vec![Message::Ack { id: "alice-1".to_string() }]);
+    }
+
+    #[test]
+    fn test_relay_over_websocket() {
+        let server = Server::bind(&ServerConfig {
+            listen_addr: "127.0.0.1:0".to_string(),
+            ws_addr: Some("127.0.0.1:0".to_string()),
+            ..ServerConfig::default()
+        }).unwrap();
+        let addr = server.local_addr().to_string();
+        let ws_addr = format!("ws://{}", server.ws_addr().unwrap());
+        let handle = server.handle();
+        thread::spawn(move || server.run());
+
+        // clients on either transport chat with each other
+        let (alice, alice_rx) = connect(&ws_addr, "alice");
+        let (bob, bob_rx) = connect(&addr, "bob");
+
+        let chat = |from: &str, to: &str| Message::Chat {
+            id: format!("{}-1", from),
+            from: from.to_string(),
+            to: Some(to.to_string()),
+            body: Body::Plain(format!("hello {}", to)),
+            clock: 1,
+        };
+        assert!(alice.send(&chat("alice", "bob")));
+        assert_eq!(next_message(&bob_rx), Some(chat("alice", "bob")));
+        assert!(bob.send(&chat("bob", "alice")));
+        assert_eq!(next_message(&alice_rx), Some(Message::Ack { id: "alice-1".to_string() }));
+        assert_eq!(next_message(&alice_rx), Some(chat("bob", "alice")));
+
+        handle.stop();
+    }
+
+    #[test]
+    fn test_relay_over_udp() {
+        let server = Server::bind(&ServerConfig {
+            listen_addr: "127.0.0.1:0".to_string(),
+            udp_addr: Some("127.0.0.1:0".to_string()),
+            ..ServerConfig::default()
+        }).unwrap();
+        let addr = server.local_addr().to_string();
+        let udp_addr = format!("udp://{}", server.udp_addr().unwrap());
+        let handle = server.handle();
+        thread::spawn(move || server.run());
+
+        let (alice, alice_rx) = connect(&udp_addr, "alice");
+        let (_bob, bob_rx) = connect(&addr, "bob");
+
+        // more than fits in a datagram
+        let chat = Message::Chat {
+            id: "alice-1".to_string(),
+            from: "alice".to_string(),
+            to: Some("bob".to_string()),
+            body: Body::Plain("hello bob ".repeat(1000)),
+            clock: 1,
+        };
+        assert!(alice.send(&chat));
+        assert_eq!(next_message(&bob_rx), Some(chat));
+        assert_eq!(next_message(&alice_rx), Some(Message::Ack { id: "alice-1".to_string() }));
+
+        handle.stop();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_relay_over_unix_socket() {
+        let unix_path = std::env::temp_dir().join(format!("oisg-relay-{}.sock", std::process::id()));
+        let server = Server::bind(&ServerConfig {
+            listen_addr: "127.0.0.1:0".to_string(),
+            unix_path: Some(unix_path.display().to_string()),
+            ..ServerConfig::default()
+        }).unwrap();
+        let addr = server.local_addr().to_string();
+        let handle = server.handle();
+        thread::spawn(move || server.run());
+
+        // clients on either transport chat with each other
+        let (alice, alice_rx) = connect(&format!("unix://{}", unix_path.display()), "alice");
+        let (_bob, bob_rx) = connect(&addr, "bob");
+
+        let chat = Message::Chat {
+            id: "alice-1".to_string(),
+            from: "alice".to_string(),
+            to: Some("bob".to_string()),
+            body: Body::Plain("hello over a unix socket".to_string()),
+            clock: 1,
+        };
+        assert!(alice.send(&chat));
+
+        assert_eq!(next_message(&bob_rx), Some(chat));
+        assert_eq!(next_message(&alice_rx), Some(Message::Ack { id: "alice-1".to_string() }));
+
+        handle.stop();
+        let _ = std::fs::remove_file(unix_path);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_missing_unix_socket_says_why() {
+        let unix_path = std::env::temp_dir().join(format!("oisg-gone-{}.sock", std::process::id()));
+        let (tx, rx) = unbounded();
+        let _network = Network::connect(&Config {
+            server_addr: format!("unix://{}", unix_path.display()),
+            port: 0,
+            discovery: false,
+            tls: None,
+        }, tx).unwrap();
+
+        match rx.recv_timeout(TIMEOUT) {
+            Ok(AppEvent::NetworkEvent(NetworkEvent::ConnectionFailed(Some(reason)))) => {
+                assert!(reason.contains("No such file"), "{}", reason);
+            },
+            other => panic!("expected connection failure, got {:?}", other),
+        }
+        match rx.recv_timeout(TIMEOUT) {
+            Ok(AppEvent::NetworkEvent(NetworkEvent::Reconnecting(_))) => {},
+            other => panic!("expected reconnect, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_relay_over_tls() {
+        let (server, addr, cert_file) = start_tls_server("server-tls");
+        let ca = Some(TrustAnchor::CaFile(cert_file.clone()));
+        let pin = Some(TrustAnchor::Pinned(tls::fingerprint_of_file(&cert_file).unwrap()));
+
+        let (alice, alice_rx) = connect_with(&addr, ca, "alice");
+        let (_bob, bob_rx) = connect_with(&addr, pin, "bob");
+
+        let chat = Message::Chat {
+            id: "alice-1".to_string(),
+            from: "alice".to_string(),
+            to: Some("bob".to_string()),
+            body: Body::Plain("hello over tls".to_string()),
+            clock: 1,
+        };
+        assert!(alice.send(&chat));
+
+        assert_eq!(next_message(&bob_rx), Some(chat));
+        assert_eq!(next_message(&alice_rx), Some(Message::Ack { id: "alice-1".to_string() }));
+
+        server.stop();
+    }
+
+    #[test]
+    fn test_untrusted_server_certificate() {
+        let (server, addr, _) = start_tls_server("server-tls-pin");
+        let (tx, rx) = unbounded();
+        let network = Network::connect(&Config {
+            server_addr: addr,
+            port: 0,
+            discovery: false,
+            tls: Some(TrustAnchor::Pinned("ab".repeat(32))),
+        }, tx).unwrap();
+
+        match rx.recv_timeout(TIMEOUT) {
+            Ok(AppEvent::NetworkEvent(NetworkEvent::TlsFailed(reason))) => {
+                assert!(reason.contains("does not match the pinned"), "{}", reason);
+            },
+            other => panic!("expected tls failure, got {:?}", other),
+        }
+        assert!(!network.send(&Message::Ping));
+
+        // no retries against a server we do not trust
+        assert!(rx.recv_timeout(Duration::from_millis(500)).is_err());
+
+        server.stop();
+    }
+
+    #[test]
+    fn test_rooms_between_clients() {
+        let (server, addr) = start_server();
+        let joined = |rx: &Receiver<AppEvent>| loop {
+            match rx.recv_timeout(TIMEOUT) {
+                Ok(AppEvent::NotificationEvent(Notification::UserJoined { room, user_id })) => return (room, user_id),
+                Ok(_) => {},
+                Err(e) => panic!("no join: {}", e),
+            }
+        };
+        let join = |user_id: &str| Message::Join { room: "#rust".to_string(), user_id: user_id.to_string() };
+
+        let (alice, alice_rx) = connect(&addr, "alice");
+        let (bob, bob_rx) = connect(&addr, "bob");
+
+        assert!(alice.send(&join("alice")));
+        assert_eq!(joined(&alice_rx), ("#rust".to_string(), "alice".to_string()));
+        assert!(bob.send(&join("bob")));
+        assert_eq!(joined(&bob_rx), ("#rust".to_string(), "alice".to_string()));
+        assert_eq!(joined(&bob_rx), ("#rust".to_string(), "bob".to_string()));
+        assert_eq!(joined(&alice_rx), ("#rust".to_string(), "bob".to_string()));
+
+        let chat = Message::Chat {
+            id: "alice-1".to_string(),
+            from: "alice".to_string(),
+            to: Some("#rust".to_string()),
+            body: Body::Plain("hello room".to_string()),
+            clock: 1,
+        };
+        assert!(alice.send(&chat));
+        assert_eq!(next_message(&bob_rx), Some(chat));
+
+        server.stop();
+    }
+
+    #[test]
+    fn test_history_for_late_clients() {
+        let (server, addr) = start_server();
+
+        let (alice, alice_rx) = connect(&addr, "alice");
+        let chat = Message::Chat {
+            id: "alice-1".to_string(),
+            from: "alice".to_string(),
+            to: None,
+            body: Body::Plain("anyone there?".to_string()),
+            clock: 1,
+        };
+        assert!(alice.send(&chat));
+        assert_eq!(next_message(&alice_rx), Some(Message::Ack { id: "alice-1".to_string() }));
+
+        // bob was not connected when alice wrote
+        let (bob, bob_rx) = connect(&addr, "bob");
+        assert!(bob.send(&Message::HistoryRequest { since: 0 }));
+        assert_eq!(next_message(&bob_rx), Some(chat));
+        assert_eq!(next_message(&bob_rx), Some(Message::HistoryEnd { count: 1 }));
+
+        server.stop();
+    }
+
+    /// Raw IRC client, reads line by line
+    struct IrcClient {
+        stream: TcpStream,
+        reader: BufReader<TcpStream>,
+    }
+
+    impl IrcClient {
+        fn connect(addr: SocketAddr, nick: &str) -> Self {
+            let stream = TcpStream::connect(addr).unwrap();
+            stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+            let mut client = IrcClient {
+                reader: BufReader::new(stream.try_clone().unwrap()),
+                stream,
+            };
+
+            client.send(&format!("NICK {}", nick));
+            client.send(&format!("USER {} 0 * :{}", nick, nick.to_uppercase()));
+            client.expect(" 001 ");
+            client
+        }
+
+        fn send(&mut self, line: &str) {
+            self.stream.write_all(format!("{}\r\n", line).as_bytes()).unwrap();
+        }
+
+        fn read_line(&mut self) -> String {
+            let mut line = String::new();
+            self.reader.read_line(&mut line).unwrap();
+            line.trim_end().to_string()
+        }
+
+        /// Skips lines until one containing `text`
+        fn expect(&mut self, text: &str) -> String {
+            loop {
+                let line = self.read_line();
+                assert!(!line.is_empty(), "connection closed waiting for {:?}", text);
+                if line.contains(text) {
+                    return line;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_irc_gateway() {
+        let server = Server::bind(&ServerConfig {
+            listen_addr: "127.0.0.1:0".to_string(),
+            irc_addr: Some("127.0.0.1:0".to_string()),
+            ..ServerConfig::default()
+        }).unwrap();
+        let addr = server.local_addr().to_string();
+        let irc_addr = server.irc_addr().unwrap();
+        let handle = server.handle();
+        thread::spawn(move || server.run());
+
+        let (alice, alice_rx) = connect(&addr, "alice");
+        assert!(alice.send(&Message::Join { room: "#rust".to_string(), user_id: "alice".to_string() }));
+        assert!(matches!(alice_rx.recv_timeout(TIMEOUT), Ok(AppEvent::NotificationEvent(Notification::UserJoined { .. }))));
+
+        // bob joins from IRC and shows up as a member
+        let mut bob = IrcClient::connect(irc_addr, "bob");
+        bob.send("JOIN #rust");
+        bob.expect(":bob!bob@oisg JOIN #rust");
+        assert!(bob.expect(" 353 ").ends_with(":alice bob"));
+        loop {
+            match alice_rx.recv_timeout(TIMEOUT) {
+                Ok(AppEvent::NotificationEvent(Notification::UserJoined { room, user_id })) => {
+                    assert_eq!((room.as_str(), user_id.as_str()), ("#rust", "bob"));
+                    break;
+                },
+                Ok(_) => {},
+                Err(e) => panic!("bob did not join: {}", e),
+            }
+        }
+
+        // chats go both ways, in the room and one to one
+        bob.send("PRIVMSG #rust :hello from irc");
+        assert!(matches!(next_message(&alice_rx), Some(Message::Chat { from, to: Some(to), body: Body::Plain(text), .. })
+            if from == "bob" && to == "#rust" && text == "hello from irc"));
+
+        assert!(alice.send(&Message::Chat {
+            id: "alice-1".to_string(),
+            from: "alice".to_string(),
+            to: Some("bob".to_string()),
+            body: Body::Plain("psst".to_string()),
+            clock: 1,
+        }));
+        assert_eq!(bob.expect("psst"), ":alice!alice@oisg PRIVMSG bob :psst");
+
+        // nobody else can take bob's nick while he is around
+        let mut impostor = TcpStream::connect(irc_addr).unwrap();
+        impostor.write_all(b"NICK bob\r\nUSER bob 0 * :Bob\r\n").unwrap();
+        impostor.set_read_timeout(Some(TIMEOUT)).unwrap();
+        let mut reply = String::new();
+        BufReader::new(impostor).read_line(&mut reply).unwrap();
+        assert!(reply.starts_with(":oisg 433 * bob "));
+
+        bob.send("PING :are-you-there");
+        assert_eq!(bob.expect("PONG"), ":oisg PONG oisg :are-you-there");
+
+        bob.send("QUIT :bye");
+        assert_eq!(bob.read_line(), "");
+
+        handle.stop();
+    }
+
+    /// Signs in as `user_id` with a new key and expects to be refused
+    fn assert_impostor_refused(addr: &str, user_id: &str) {
+        let (tx, rx) = unbounded();
+        let config = Config {
+            server_addr: addr.to_string(),
+            port: 0,
+            discovery: false,
+            tls: None,
+        };
+        let impostor = Network::connect(&config, tx).unwrap();
+        impostor.set_identity(user_id, user_id, vec![], SigningKeyPair::generate());
+
+        loop {
+            match rx.recv_timeout(TIMEOUT) {
+                Ok(AppEvent::NetworkEvent(NetworkEvent::AuthFailed(reason))) => {
+                    assert!(reason.contains(user_id));
+                    break;
+                },
+                Ok(AppEvent::NetworkEvent(NetworkEvent::MessageReceived(Message::Welcome { .. }))) => {
+                    panic!("impostor was welcomed");
+                },
+                Ok(_) => {},
+                Err(e) => panic!("impostor not refused: {}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn test_impostor_is_refused() {
+        let (server, addr) = start_server();
+        let (_alice, _alice_rx) = connect(&addr, "alice");
+
+        assert_impostor_refused(&addr, "alice");
+
+        server.stop();
+    }
+
+    #[test]
+    fn test_impostor_is_refused_after_restart() {
+        let state_dir = std::env::temp_dir().join(format!("oisg-state-{}", std::process::id()));
+        let config = || ServerConfig {
+            listen_addr: "127.0.0.1:0".to_string(),
+            state_dir: Some(state_dir.to_string_lossy().into_owned()),
+            ..ServerConfig::default()
+        };
+
+        let (server, addr) = start_with(config());
+        let (_alice, _alice_rx) = connect(&addr, "alice");
+        server.stop();
+
+        let (server, addr) = start_with(config());
+        assert_impostor_refused(&addr, "alice");
+        server.stop();
+
+        std::fs::remove_dir_all(&state_dir).unwrap();
+    }
+
+    #[test]
+    fn test_flooding_client_is_disconnected() {
+        let (server, addr) = start_with(ServerConfig {
+            listen_addr: "127.0.0.1:0".to_string(),
+            limits: Limits {
+                messages_per_sec: 1,
+                ..Limits::default()
+            },
+            ..ServerConfig::default()
+        });
+
+        let (alice, alice_rx) = connect(&addr, "alice");
+        for i in 0..20 {
+            alice.send(&Message::Chat {
+                id: format!("alice-{}", i),
+                from: "alice".to_string(),
+                to: None,
+                body: Body::Plain("spam".to_string()),
+                clock: 1,
+            });
+        }
+
+        let mut rate_limited = 0;
+        loop {
+            match alice_rx.recv_timeout(TIMEOUT) {
+                Ok(AppEvent::NetworkEvent(NetworkEvent::MessageReceived(
+                    Message::Error { code: ErrorCode::RateLimited, .. }
+                ))) => rate_limited += 1,
+                Ok(AppEvent::NetworkEvent(NetworkEvent::Disconnected)) => break,
+                Ok(_) => {},
+                Err(e) => panic!("not disconnected: {}", e),
+            }
+        }
+        // every dropped message is answered, then the ban is announced
+        assert!(rate_limited > 1);
+
+        // and reconnecting is refused while banned
+        let (tx, rx) = unbounded();
+        let config = Config {
+            server_addr: addr.clone(),
+            port: 0,
+            discovery: false,
+            tls: None,
+        };
+        let _network = Network::connect(&config, tx).unwrap();
+        loop {
+            match rx.recv_timeout(TIMEOUT) {
+                Ok(AppEvent::NetworkEvent(NetworkEvent::Disconnected)) => break,
+                Ok(_) => {},
+                Err(e) => panic!("not refused: {}", e),
+            }
+        }
+
+        server.stop();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_flooding_unix_client_locks_nobody_out() {
+        let unix_path = std::env::temp_dir().join(format!("oisg-flood-{}.sock", std::process::id()));
+        let (server, _) = start_with(ServerConfig {
+            listen_addr: "127.0.0.1:0".to_string(),
+            unix_path: Some(unix_path.display().to_string()),
+            limits: Limits {
+                messages_per_sec: 1,
+                ..Limits::default()
+            },
+            ..ServerConfig::default()
+        });
+        let unix_addr = format!("unix://{}", unix_path.display());
+
+        let (alice, alice_rx) = connect(&unix_addr, "alice");
+        for i in 0..20 {
+            alice.send(&Message::Chat {
+                id: format!("alice-{}", i),
+                from: "alice".to_string(),
+                to: None,
+                body: Body::Plain("spam".to_string()),
+                clock: 1,
+            });
+        }
+        loop {
+            match alice_rx.recv_timeout(TIMEOUT) {
+                Ok(AppEvent::NetworkEvent(NetworkEvent::Disconnected)) => break,
+                Ok(_) => {},
+                Err(e) => panic!("not disconnected: {}", e),
+            }
+        }
+
+        // everyone on the socket is loopback, the others are not banned with alice
+        let (_bob, _bob_rx) = connect(&unix_addr, "bob");
+
+        server.stop();
+        let _ = std::fs::remove_file(unix_path);
+    }
+
+    #[test]
+    fn test_presence_between_clients() {
+        let (server, addr) = start_server();
+        let presence = |user_id: &str, presence: Presence| Message::Presence {
+            user_id: user_id.to_string(),
+            user_name: user_id.to_string(),
+            presence,
+        };
+        let received = |rx: &Receiver<AppEvent>| match rx.recv_timeout(TIMEOUT) {
+            Ok(AppEvent::NetworkEvent(NetworkEvent::MessageReceived(message))) => Some(message),
+            _ => None,
+        };
+
+        let (_alice, alice_rx) = connect(&addr, "alice");
+        let (bob, bob_rx) = connect(&addr, "bob");
+
+        assert_eq!(received(&alice_rx), Some(presence("bob", Presence::Online)));
+        assert_eq!(received(&bob_rx), Some(presence("alice", Presence::Online)));
+
+        assert!(bob.send(&Message::Presence {
+            user_id: "bob".to_string(),
+            user_name: "bob".to_string(),
+            presence: Presence::Away,
+        }));
+        assert_eq!(received(&alice_rx), Some(presence("bob", Presence::Away)));
+
+        drop(bob);
+        assert_eq!(received(&alice_rx), Some(presence("bob", Presence::Offline)));
+
+        server.stop();
+    }
+
+    #[test]
+    fn test_client_reconnects_when_server_comes_up() {
+        // find a free port, nothing listens on it until the server starts
+        let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap()
+            .local_addr().unwrap()
+            .to_string();
+
+        let (tx, rx) = unbounded();
+        let _network = Network::connect(&Config {
+            server_addr: addr.clone(),
+            port: 0,
+            discovery: false,
+            tls: None,
+        }, tx).unwrap();
+
+        match rx.recv_timeout(TIMEOUT) {
+            Ok(AppEvent::NetworkEvent(NetworkEvent::ConnectionFailed(_))) => {},
+            other => panic!("expected connection failure, got {:?}", other),
+        }
+        match rx.recv_timeout(TIMEOUT) {
+            Ok(AppEvent::NetworkEvent(NetworkEvent::Reconnecting(_))) => {},
+            other => panic!("expected reconnect, got {:?}", other),
+        }
+
+        let (server, _) = start_server_on(&addr);
+
+        loop {
+            match rx.recv_timeout(TIMEOUT) {
+                Ok(AppEvent::NetworkEvent(NetworkEvent::Connected)) => break,
+                Ok(AppEvent::NetworkEvent(NetworkEvent::ConnectionFailed(_))) |
+                Ok(AppEvent::NetworkEvent(NetworkEvent::Reconnecting(_))) => {},
+                other => panic!("expected connection, got {:?}", other),
+            }
+        }
+
+        server.stop();
+    }
+
+    #[test]
+    fn test_federated_relays() {
+        // berlin sorts first and dials london, london only checks its address
+        let london = Server::bind(&ServerConfig {
+            listen_addr: "127.0.0.1:0".to_string(),
+            name: Some("london".to_string()),
+            link_addr: Some("127.0.0.1:0".to_string()),
+            peers: vec![PeerConfig { name: "berlin".to_string(), addr: "127.0.0.1:1".to_string() }],
+            ..ServerConfig::default()
+        }).unwrap();
+        let link_addr = london.link_addr().unwrap().to_string();
+        let london_addr = london.local_addr().to_string();
+        let london_handle = london.handle();
+        thread::spawn(move || london.run());
+
+        let (alice, alice_rx) = connect(&london_addr, "alice");
+        let (berlin, berlin_addr) = start_with(ServerConfig {
+            listen_addr: "127.0.0.1:0".to_string(),
+            name: Some("berlin".to_string()),
+            link_addr: Some("127.0.0.1:0".to_string()),
+            peers: vec![PeerConfig { name: "london".to_string(), addr: link_addr }],
+            ..ServerConfig::default()
+        });
+        let (_bob, bob_rx) = connect(&berlin_addr, "bob");
+
+        // alice hears of bob once the relays are linked
+        loop {
+            match alice_rx.recv_timeout(TIMEOUT) {
+                Ok(AppEvent::NetworkEvent(NetworkEvent::MessageReceived(Message::Presence { user_id, .. })))
+                    if user_id == "bob@berlin" => break,
+                Ok(_) => {},
+                Err(e) => panic!("relays not linked: {}", e),
+            }
+        }
+
+        assert!(alice.send(&Message::Chat {
+            id: "alice-1".to_string(),
+            from: "alice".to_string(),
+            to: Some("bob@berlin".to_string()),
+            body: Body::Plain("hello from london".to_string()),
+            clock: 1,
+        }));
+        // kept by london until berlin says bob has it
+        assert_eq!(next_message(&alice_rx), Some(Message::Stored { id: "alice-1".to_string() }));
+        assert_eq!(next_message(&alice_rx), Some(Message::Ack { id: "alice-1".to_string() }));
+        loop {
+            match next_message(&bob_rx) {
+                Some(Message::Chat { from, to, body, .. }) => {
+                    assert_eq!(from, "alice@london");
+                    assert_eq!(to.as_deref(), Some("bob"));
+                    assert_eq!(body, Body::Plain("hello from london".to_string()));
+                    break;
+                },
+                Some(_) => {},
+                None => panic!("chat did not cross the link"),
+            }
+        }
+
+        berlin.stop();
+        london_handle.stop();
+    }
+}
@@ -0,0 +1,1918 @@
+use std::{
+    collections::{ BTreeSet, HashMap, HashSet, VecDeque },
+    hash::Hash,
+    time::{ Duration, Instant, SystemTime, UNIX_EPOCH },
+};
+use crate::{
+    constants,
+    crypto,
+    net::protocol::{ self, ErrorCode, FilePart, Message, Presence }
+};
+
+/// Chats kept for each conversation, for clients catching up after a reconnect
+pub const HISTORY_LIMIT: usize = 500;
+
+/// Bytes of the nonce clients sign to prove their user id
+const NONCE_LEN: usize = 32;
+/// Ids of chats that came over links kept to drop the repeats
+const SEEN_LIMIT: usize = 4096;
+/// Stored chats whose senders are told once the recipient has them
+const STORED_LIMIT: usize = 4096;
+/// Tells acknowledgements among the seen ids apart from the chats they acknowledge
+const ACK_PREFIX: &str = "ack:";
+
+/// A chat kept in the history of its conversation
+struct Relayed {
+    /// unix time in seconds it was relayed at, clients ask for history since such a time
+    time: u64,
+    /// how many chats were kept before it, orders it against joins to rooms
+    seq: u64,
+    chat: Message,
+}
+
+/// A `Hello` waiting for the client to sign the challenge
+struct PendingHello {
+    user_id: String,
+    user_name: String,
+    public_key: Vec<u8>,
+    nonce: Vec<u8>,
+}
+
+/// `Relay` keeps track of which user owns each connection and decides
+/// where every incoming message has to go. It does no I/O, the caller
+/// delivers the returned `(connection, message)` pairs.
+pub struct Relay<C> {
+    users: HashMap<C, String>,
+    connections: HashMap<String, C>,
+    /// name and presence of each registered user
+    presence: HashMap<String, (String, Presence)>,
+    /// when each connection was last heard of
+    last_seen: HashMap<C, Instant>,
+    /// X25519 keys users introduced themselves with, the relay only
+    /// passes them on and can not read the chats sealed with them
+    public_keys: HashMap<String, Vec<u8>>,
+    /// members of each room by user id, they stay members while offline
+    rooms: HashMap<String, BTreeSet<String>>,
+    /// latest chats of each conversation with when they were relayed, see
+    /// `history_key`. Kept in memory only, a restart forgets them.
+    history: HashMap<String, VecDeque<Relayed>>,
+    /// `seq` of the next chat kept in the history
+    next_seq: u64,
+    /// `seq` of the first chat of each room each member may catch up on,
+    /// by room and user id. Chats from before they joined are not theirs.
+    joined: HashMap<(String, String), u64>,
+    /// connections that said hello and have not answered the challenge yet
+    challenges: HashMap<C, PendingHello>,
+    /// Ed25519 key of each user id, the first one seen owns the user id
+    signing_keys: HashMap<String, Vec<u8>>,
+    /// connections of users signed in without a key, like IRC users
+    guests: HashSet<C>,
+    /// name of this relay in `user_id@relay`, `None` if it does not peer
+    name: Option<String>,
+    /// links to other relays with the name of the relay at the other end
+    links: HashMap<C, String>,
+    /// link each relay we heard of is reached through
+    routes: HashMap<String, C>,
+    /// ids of the latest chats that came over links, oldest first
+    seen: VecDeque<String>,
+    /// ids of direct chats kept for a recipient that is not around, with
+    /// who sent them, oldest first
+    stored: VecDeque<(String, String)>,
+    /// `Ack`s for users that were away when their chats were handed on
+    acks: HashMap<String, Vec<String>>,
+}
+
+impl<C: Copy + Eq + Hash> Default for Relay<C> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<C: Copy + Eq + Hash> Relay<C> {
+    pub fn new() -> Self {
+        Relay {
+            users: HashMap::new(),
+            connections: HashMap::new(),
+            presence: HashMap::new(),
+            last_seen: HashMap::new(),
+            public_keys: HashMap::new(),
+            rooms: HashMap::new(),
+            history: HashMap::new(),
+            next_seq: 0,
+            joined: HashMap::new(),
+            challenges: HashMap::new(),
+            signing_keys: HashMap::new(),
+            guests: HashSet::new(),
+            name: None,
+            links: HashMap::new(),
+            routes: HashMap::new(),
+            seen: VecDeque::new(),
+            stored: VecDeque::new(),
+            acks: HashMap::new(),
+        }
+    }
+
+    /// Relay that peers with others as `name`
+    pub fn with_name(name: &str) -> Self {
+        Relay {
+            name: Some(name.to_string()),
+            ..Self::new()
+        }
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Ed25519 key each user id is bound to
+    pub fn signing_keys(&self) -> &HashMap<String, Vec<u8>> {
+        &self.signing_keys
+    }
+
+    /// Binds user ids to the keys they were bound to before a restart
+    pub fn load_signing_keys(&mut self, keys: HashMap<String, Vec<u8>>) {
+        self.signing_keys.extend(keys);
+    }
+
+    /// The hello starting a link to another relay
+    pub fn peer_hello(&self) -> Message {
+        Message::PeerHello {
+            version: constants::APP_VERSION.to_string(),
+            relay: self.name.clone().unwrap_or_default(),
+        }
+    }
+
+    pub fn is_link(&self, conn: C) -> bool {
+        self.links.contains_key(&conn)
+    }
+
+    /// Makes `conn` the link to `relay`, returns what the other relay
+    /// has to know of ours: who is around, their keys and their rooms
+    pub fn link(&mut self, conn: C, relay: String) -> Vec<(C, Message)> {
+        // the latest link to a relay takes over
+        if let Some(previous) = self.links.iter().find(|(_, linked)| **linked == relay).map(|(link, _)| *link) {
+            self.links.remove(&previous);
+            self.routes.values_mut()
+                .filter(|route| **route == previous)
+                .for_each(|route| *route = conn);
+        }
+        self.links.insert(conn, relay.clone());
+        self.routes.insert(relay, conn);
+
+        self.sync(conn)
+    }
+
+    /// Handles a message that came over a link to another relay
+    pub fn handle_link(&mut self, conn: C, message: Message) -> Vec<(C, Message)> {
+        if !self.links.contains_key(&conn) {
+            return vec![];
+        }
+
+        match message {
+            Message::Federated { via, message } => self.federated(conn, via, *message),
+            // a relay that lost a link asks the others again
+            Message::PeerHello { .. } => self.sync(conn),
+            Message::Ping => vec![(conn, Message::Pong)],
+            _ => vec![],
+        }
+    }
+
+    pub fn user_id(&self, conn: C) -> Option<&str> {
+        self.users.get(&conn).map(String::as_str)
+    }
+
+    pub fn user_count(&self) -> usize {
+        self.users.len()
+    }
+
+    /// Forgets the connection, returns the messages telling everyone
+    /// its user went offline
+    pub fn disconnect(&mut self, conn: C) -> Vec<(C, Message)> {
+        self.last_seen.remove(&conn);
+        self.challenges.remove(&conn);
+        self.guests.remove(&conn);
+        if self.links.remove(&conn).is_some() {
+            return self.unlink(conn);
+        }
+
+        let user_id = match self.users.remove(&conn) {
+            Some(user_id) => user_id,
+            None => return vec![],
+        };
+        if self.connections.get(&user_id) != Some(&conn) {
+            return vec![];
+        }
+
+        self.connections.remove(&user_id);
+        match self.presence.remove(&user_id) {
+            Some((user_name, _)) => {
+                let offline = Message::Presence {
+                    user_id: self.qualify(&user_id),
+                    user_name: user_name.clone(),
+                    presence: Presence::Offline,
+                };
+                let mut out = self.broadcast(conn, Message::Presence {
+                    user_id,
+                    user_name,
+                    presence: Presence::Offline,
+                });
+                out.extend(self.federate(None, &[], offline));
+                out
+            },
+            None => vec![],
+        }
+    }
+
+    /// Records that `conn` is still there
+    pub fn seen(&mut self, conn: C, now: Instant) {
+        self.last_seen.insert(conn, now);
+    }
+
+    /// Connections not heard of for longer than `timeout`, the caller
+    /// closes them and calls `disconnect`
+    pub fn expired(&self, now: Instant, timeout: Duration) -> Vec<C> {
+        self.last_seen.iter()
+            .filter(|(_, last_seen)| now.duration_since(**last_seen) > timeout)
+            .map(|(conn, _)| *conn)
+            .collect()
+    }
+
+    pub fn handle(&mut self, conn: C, message: Message) -> Vec<(C, Message)> {
+        match message {
+            Message::Hello { user_id, user_name, public_key, .. } => {
+                if user_id.contains(protocol::RELAY_SEPARATOR) {
+                    return vec![(conn, Message::error(
+                        ErrorCode::InvalidMessage,
+                        &format!("user ids can not contain {}", protocol::RELAY_SEPARATOR)
+                    ))];
+                }
+
+                // a repeated hello gets the challenge again, a new one would
+                // fail the answer to the first
+                let nonce = match self.challenges.get(&conn) {
+                    Some(pending) if pending.user_id == user_id => pending.nonce.clone(),
+                    _ => rand::random::<[u8; NONCE_LEN]>().to_vec(),
+                };
+                self.challenges.insert(conn, PendingHello {
+                    user_id,
+                    user_name,
+                    public_key,
+                    nonce: nonce.clone(),
+                });
+
+                vec![(conn, Message::Challenge { nonce })]
+            },
+            Message::Auth { signing_key, signature } => {
+                let hello = match self.challenges.remove(&conn) {
+                    Some(hello) => hello,
+                    None => return vec![(conn, Message::error(
+                        ErrorCode::NotRegistered,
+                        "say hello before signing in"
+                    ))],
+                };
+
+                if !crypto::verify_challenge(&signing_key, &hello.user_id, &hello.nonce, &signature) {
+                    return vec![(conn, Message::error(
+                        ErrorCode::AuthFailed,
+                        "the challenge was not signed with the key sent along"
+                    ))];
+                }
+                if self.connections.get(&hello.user_id).is_some_and(|other| self.guests.contains(other)) {
+                    return vec![(conn, Message::error(
+                        ErrorCode::AuthFailed,
+                        &format!("user id {} is in use by a guest", hello.user_id)
+                    ))];
+                }
+                match self.signing_keys.get(&hello.user_id) {
+                    Some(bound) if *bound != signing_key => return vec![(conn, Message::error(
+                        ErrorCode::AuthFailed,
+                        &format!("user id {} is registered on this server with another key", hello.user_id)
+                    ))],
+                    Some(_) => {},
+                    None => {
+                        self.signing_keys.insert(hello.user_id.clone(), signing_key);
+                    },
+                }
+
+                self.welcome(conn, hello)
+            },
+            Message::Join { room, .. } => {
+                let user_id = match self.users.get(&conn) {
+                    Some(user_id) => user_id.clone(),
+                    None => return vec![],
+                };
+                if !protocol::is_valid_room(&room) {
+                    return vec![(conn, Message::error(
+                        ErrorCode::InvalidMessage,
+                        &format!("{} is not a room name", room)
+                    ))];
+                }
+
+                let members = self.rooms.entry(room.clone()).or_default();
+                let joined = members.insert(user_id.clone());
+                if joined {
+                    self.joined.insert((room.clone(), user_id.clone()), self.next_seq);
+                }
+                let members = &self.rooms[&room];
+
+                // the newcomer learns who is in the room, the members about the newcomer
+                let mut out: Vec<(C, Message)> = members.iter()
+                    .filter(|member| **member != user_id)
+                    .map(|member| (conn, Message::Join {
+                        room: room.clone(),
+                        user_id: member.clone(),
+                    }))
+                    .collect();
+                let recipients = match joined {
+                    true => self.members(&room),
+                    false => vec![conn],
+                };
+                out.extend(recipients.into_iter().map(|recipient| (recipient, Message::Join {
+                    room: room.clone(),
+                    user_id: user_id.clone(),
+                })));
+                if joined {
+                    let user_id = self.qualify(&user_id);
+                    out.extend(self.federate(None, &[], Message::Join { room, user_id }));
+                }
+
+                out
+            },
+            Message::Leave { room, .. } => {
+                let user_id = match self.users.get(&conn) {
+                    Some(user_id) => user_id.clone(),
+                    None => return vec![],
+                };
+
+                // the leaver is told too, it is still a member until then
+                let recipients = self.members(&room);
+                let left = match self.rooms.get_mut(&room) {
+                    Some(members) => members.remove(&user_id),
+                    None => false,
+                };
+                if !left {
+                    return vec![];
+                }
+                self.joined.remove(&(room.clone(), user_id.clone()));
+                if self.rooms.get(&room).is_some_and(BTreeSet::is_empty) {
+                    self.rooms.remove(&room);
+                }
+
+                let mut out: Vec<(C, Message)> = recipients.into_iter()
+                    .map(|recipient| (recipient, Message::Leave {
+                        room: room.clone(),
+                        user_id: user_id.clone(),
+                    }))
+                    .collect();
+                let user_id = self.qualify(&user_id);
+                out.extend(self.federate(None, &[], Message::Leave { room, user_id }));
+
+                out
+            },
+            Message::Presence { presence, .. } => {
+                match self.users.get(&conn) {
+                    Some(user_id) => {
+                        let user_id = user_id.clone();
+                        let user_name = self.presence.get(&user_id)
+                            .map(|(name, _)| name.clone())
+                            .unwrap_or_else(|| user_id.clone());
+                        self.set_presence(conn, user_id, user_name, presence)
+                    },
+                    None => vec![],
+                }
+            },
+            Message::Chat { id, to, body, clock, .. } => {
+                // only registered connections can chat, and always as themselves
+                let from = match self.users.get(&conn) {
+                    Some(user_id) => user_id.clone(),
+                    None => return vec![(conn, Message::error_for(
+                        &id,
+                        ErrorCode::NotRegistered,
+                        "say hello before sending messages"
+                    ))],
+                };
+
+                // users of this relay may be addressed with its name as well
+                let to = to.map(|to| self.local_id(to));
+                let relay = to.as_deref()
+                    .filter(|to| !protocol::is_room(to))
+                    .and_then(|to| protocol::split_relay(to).1);
+                if let Some(relay) = relay {
+                    let relay = relay.to_string();
+                    return self.chat_to_relay(conn, &relay, Message::Chat { id, from, to, body, clock });
+                }
+
+                if let Some(room) = to.as_deref().filter(|to| protocol::is_room(to)) {
+                    if !self.is_member(room, &from) {
+                        return vec![(conn, Message::error_for(
+                            &id,
+                            ErrorCode::UnknownRecipient,
+                            &format!("join {} before sending to it", room)
+                        ))];
+                    }
+                }
+
+                // users that signed in before get what was sent while they were away
+                // with their history, only users nobody ever signed in as are unknown
+                let recipients = self.recipients(conn, to.as_deref());
+                let direct = to.as_deref().is_some_and(|to| !protocol::is_room(to));
+                let unknown = direct && to.as_deref().is_some_and(|to| !self.signing_keys.contains_key(to));
+                if recipients.is_empty() && unknown {
+                    return vec![(conn, Message::error_for(
+                        &id,
+                        ErrorCode::UnknownRecipient,
+                        &format!("{} is not connected", to.unwrap_or_default())
+                    ))];
+                }
+
+                let chat = Message::Chat {
+                    id: id.clone(),
+                    from: from.clone(),
+                    to: to.clone(),
+                    body: body.clone(),
+                    clock,
+                };
+                let mut out: Vec<(C, Message)> = recipients.into_iter()
+                    .map(|recipient| (recipient, chat.clone()))
+                    .collect();
+                // rooms span every linked relay, chats to everyone stay here
+                if to.as_deref().is_some_and(protocol::is_room) {
+                    self.saw(&id);
+                    out.extend(self.federate(None, &[], Message::Chat {
+                        id: id.clone(),
+                        from: self.qualify(&from),
+                        to,
+                        body,
+                        clock,
+                    }));
+                }
+                // the sender learns a direct chat was only kept until the
+                // recipient has it, see `delivered`
+                if out.is_empty() && direct {
+                    self.store(&id, &from);
+                    out.push((conn, Message::Stored { id }));
+                } else {
+                    out.push((conn, Message::Ack { id }));
+                }
+                self.remember(chat);
+
+                out
+            },
+            Message::HistoryRequest { since } => {
+                let user_id = match self.users.get(&conn) {
+                    Some(user_id) => user_id.clone(),
+                    None => return vec![],
+                };
+
+                let mut chats: Vec<&Relayed> = self.history.iter()
+                    .filter_map(|(key, chats)| Some((self.readable_from(&user_id, key)?, chats)))
+                    .flat_map(|(first, chats)| chats.iter().filter(move |relayed| relayed.seq >= first))
+                    .filter(|relayed| relayed.time >= since && !matches!(&relayed.chat, Message::Chat { from, .. } if *from == user_id))
+                    .collect();
+                chats.sort_by_key(|relayed| relayed.seq);
+
+                let count = chats.len() as u32;
+                let mut out: Vec<(C, Message)> = chats.into_iter()
+                    .map(|relayed| (conn, relayed.chat.clone()))
+                    .collect();
+                out.push((conn, Message::HistoryEnd { count }));
+
+                let ids: Vec<String> = out.iter()
+                    .filter_map(|(_, chat)| match chat {
+                        Message::Chat { id, .. } => Some(id.clone()),
+                        _ => None,
+                    })
+                    .collect();
+                for id in ids {
+                    out.extend(self.delivered(&id));
+                }
+
+                out
+            },
+            Message::Read { to, id, .. } => {
+                // receipts are best effort, nobody is told if they can not be delivered
+                let by = match self.users.get(&conn) {
+                    Some(user_id) => user_id.clone(),
+                    None => return vec![],
+                };
+
+                // readers of chats from other relays are told of like their authors
+                let to = self.local_id(to);
+                match protocol::split_relay(&to) {
+                    (_, Some(relay)) => match self.routes.get(relay) {
+                        Some(link) => self.to_link(*link, &[], Message::Read {
+                            by: self.qualify(&by),
+                            to: to.clone(),
+                            id,
+                        }).into_iter().collect(),
+                        None => vec![],
+                    },
+                    (_, None) => match self.connections.get(&to) {
+                        Some(recipient) => vec![(*recipient, Message::Read { by, to, id })],
+                        None => vec![],
+                    },
+                }
+            },
+            Message::Typing { .. } | Message::StoppedTyping { .. } => self.typing(conn, message),
+            Message::File { id, to, part, .. } => {
+                let from = match self.users.get(&conn) {
+                    Some(user_id) => user_id.clone(),
+                    None => return vec![],
+                };
+
+                match self.connections.get(&to) {
+                    Some(recipient) => vec![(*recipient, Message::File { id, from, to, part })],
+                    // the sender offers again once the recipient is back. Sealed
+                    // offers can not be told from the other parts and are dropped.
+                    None => match part {
+                        FilePart::Offer { .. } => vec![(conn, Message::error_for(
+                            &id,
+                            ErrorCode::UnknownRecipient,
+                            &format!("{} is not connected", to)
+                        ))],
+                        _ => vec![],
+                    },
+                }
+            },
+            Message::Ping => vec![(conn, Message::Pong)],
+            Message::Bye => self.disconnect(conn),
+            Message::Welcome { .. } | Message::Ack { .. } | Message::Stored { .. } |
+            Message::Error { .. } | Message::Pong | Message::Announce { .. } | Message::PublicKey { .. } |
+            Message::HistoryEnd { .. } | Message::Challenge { .. } |
+            // only links to other relays speak for them
+            Message::PeerHello { .. } | Message::Federated { .. } => vec![],
+        }
+    }
+
+    fn register(&mut self, conn: C, user_id: String) {
+        // a connection saying hello again only changes who it is
+        if let Some(previous) = self.users.remove(&conn) {
+            if self.connections.get(&previous) == Some(&conn) {
+                self.connections.remove(&previous);
+                self.presence.remove(&previous);
+            }
+        }
+
+        // the latest connection of a user takes over
+        if let Some(previous) = self.connections.insert(user_id.clone(), conn) {
+            self.users.remove(&previous);
+        }
+        self.users.insert(conn, user_id);
+    }
+
+    fn set_presence(&mut self, conn: C, user_id: String, user_name: String, presence: Presence) -> Vec<(C, Message)> {
+        self.presence.insert(user_id.clone(), (user_name.clone(), presence));
+
+        let mut out = self.broadcast(conn, Message::Presence { user_id: user_id.clone(), user_name: user_name.clone(), presence });
+        let user_id = self.qualify(&user_id);
+        out.extend(self.federate(None, &[], Message::Presence { user_id, user_name, presence }));
+        out
+    }
+
+    /// Tells everyone, here and on linked relays, the key of a user of ours
+    fn set_public_key(&mut self, conn: C, user_id: &str, public_key: Vec<u8>) -> Vec<(C, Message)> {
+        self.public_keys.insert(user_id.to_string(), public_key.clone());
+
+        let mut out = self.broadcast(conn, Message::PublicKey {
+            user_id: user_id.to_string(),
+            public_key: public_key.clone(),
+        });
+        let user_id = self.qualify(user_id);
+        out.extend(self.federate(None, &[], Message::PublicKey { user_id, public_key }));
+        out
+    }
+
+    /// `message` for every registered connection but `sender`
+    fn broadcast(&self, sender: C, message: Message) -> Vec<(C, Message)> {
+        self.recipients(sender, None)
+            .into_iter()
+            .map(|recipient| (recipient, message.clone()))
+            .collect()
+    }
+
+    /// Forwards a typing notification like a chat message, but silently
+    fn typing(&self, conn: C, message: Message) -> Vec<(C, Message)> {
+        let from = match self.users.get(&conn) {
+            Some(user_id) => user_id.clone(),
+            None => return vec![],
+        };
+        let (message, to) = match message {
+            Message::Typing { to, .. } => (Message::Typing { from, to: to.clone() }, to),
+            Message::StoppedTyping { to, .. } => (Message::StoppedTyping { from, to: to.clone() }, to),
+            _ => return vec![],
+        };
+
+        self.recipients(conn, to.as_deref())
+            .into_iter()
+            .map(|recipient| (recipient, message.clone()))
+            .collect()
+    }
+
+    /// Signs in a user that has no signing key, like an IRC user. Only
+    /// user ids nobody owns and nobody is using can be taken.
+    pub fn sign_in_guest(&mut self, conn: C, user_id: &str, user_name: &str) -> Vec<(C, Message)> {
+        if self.signing_keys.contains_key(user_id) || self.connections.contains_key(user_id) {
+            return vec![(conn, Message::error(
+                ErrorCode::AuthFailed,
+                &format!("user id {} is taken", user_id)
+            ))];
+        }
+
+        self.guests.insert(conn);
+        let mut out = self.welcome(conn, PendingHello {
+            user_id: user_id.to_string(),
+            user_name: user_name.to_string(),
+            public_key: vec![],
+            nonce: vec![],
+        });
+
+        // an empty key tells clients to chat with the guest unencrypted
+        out.extend(self.set_public_key(conn, user_id, vec![]));
+
+        out
+    }
+
+    /// Registers the user of a connection that proved its user id
+    fn welcome(&mut self, conn: C, hello: PendingHello) -> Vec<(C, Message)> {
+        let PendingHello { user_id, user_name, public_key, .. } = hello;
+        self.register(conn, user_id.clone());
+
+        // keys come before welcome so queued chats can be sealed once welcomed
+        let mut out: Vec<(C, Message)> = self.public_keys.iter()
+            .filter(|(other, _)| **other != user_id)
+            .map(|(other, key)| (conn, Message::PublicKey {
+                user_id: other.clone(),
+                public_key: key.clone(),
+            }))
+            .collect();
+        out.push((conn, Message::Welcome {
+            version: constants::APP_VERSION.to_string(),
+            relay: self.name.clone().unwrap_or_default(),
+        }));
+        // chats that reached their recipients while the sender was away
+        out.extend(self.acks.remove(&user_id).unwrap_or_default().into_iter()
+            .map(|id| (conn, Message::Ack { id })));
+        if !public_key.is_empty() {
+            out.extend(self.set_public_key(conn, &user_id, public_key));
+        }
+
+        // the newcomer learns who is around, and everyone else about the newcomer
+        out.extend(self.presence.iter()
+            .filter(|(other, _)| **other != user_id)
+            .map(|(other, (name, presence))| (conn, Message::Presence {
+                user_id: other.clone(),
+                user_name: name.clone(),
+                presence: *presence,
+            })));
+        out.extend(self.set_presence(conn, user_id.clone(), user_name, Presence::Online));
+
+        // and the members of the rooms it was in before
+        for (room, members) in self.rooms.iter().filter(|(_, members)| members.contains(&user_id)) {
+            out.extend(members.iter().map(|member| (conn, Message::Join {
+                room: room.clone(),
+                user_id: member.clone(),
+            })));
+        }
+
+        out
+    }
+
+    /// `user_id@relay` for a user of ours, as other relays know them
+    fn qualify(&self, user_id: &str) -> String {
+        match &self.name {
+            Some(name) => format!("{}{}{}", user_id, protocol::RELAY_SEPARATOR, name),
+            None => user_id.to_string(),
+        }
+    }
+
+    /// `user_id` without the name of this relay, users of other relays keep theirs
+    fn local_id(&self, user_id: String) -> String {
+        match protocol::split_relay(&user_id) {
+            (local, Some(relay)) if Some(relay) == self.name.as_deref() => local.to_string(),
+            _ => user_id,
+        }
+    }
+
+    /// Sends a chat of a user of ours on towards the relay of its recipient
+    fn chat_to_relay(&mut self, conn: C, relay: &str, chat: Message) -> Vec<(C, Message)> {
+        let (id, federated) = match &chat {
+            Message::Chat { id, from, to, body, clock } => (id.clone(), Message::Chat {
+                id: id.clone(),
+                from: self.qualify(from),
+                to: to.clone(),
+                body: body.clone(),
+                clock: *clock,
+            }),
+            _ => return vec![],
+        };
+
+        let out = match self.routes.get(relay) {
+            Some(link) => self.to_link(*link, &[], federated),
+            None => None,
+        };
+        match out {
+            // the relay at the other end acknowledges once the recipient has it
+            Some(out) => {
+                if let Message::Chat { from, .. } = &chat {
+                    self.store(&id, from);
+                }
+                self.remember(chat);
+                vec![out, (conn, Message::Stored { id })]
+            },
+            None => vec![(conn, Message::error_for(
+                &id,
+                ErrorCode::UnknownRecipient,
+                &format!("relay {} is not linked to this one", relay)
+            ))],
+        }
+    }
+
+    /// Remembers the id of a chat sent over links, `false` if it was seen before
+    fn saw(&mut self, id: &str) -> bool {
+        if self.seen.iter().any(|seen| seen == id) {
+            return false;
+        }
+
+        if self.seen.len() == SEEN_LIMIT {
+            self.seen.pop_front();
+        }
+        self.seen.push_back(id.to_string());
+        true
+    }
+
+    /// Keeps in mind that `from` waits to hear the chat `id` was delivered
+    fn store(&mut self, id: &str, from: &str) {
+        if self.stored.len() == STORED_LIMIT {
+            self.stored.pop_front();
+        }
+        self.stored.push_back((id.to_string(), from.to_string()));
+    }
+
+    /// Tells the sender of the stored chat `id` that its recipient has it.
+    /// Users that are away hear it when they sign in again, users of other
+    /// relays through the links.
+    fn delivered(&mut self, id: &str) -> Vec<(C, Message)> {
+        let from = match self.stored.iter().position(|(stored, _)| stored == id) {
+            Some(index) => self.stored.remove(index).map(|(_, from)| from).unwrap_or_default(),
+            None => return vec![],
+        };
+        self.ack(&from, id)
+    }
+
+    /// `Ack` of the chat `id` for its sender `from`
+    fn ack(&mut self, from: &str, id: &str) -> Vec<(C, Message)> {
+        let ack = Message::Ack { id: id.to_string() };
+        match protocol::split_relay(from) {
+            (_, Some(_)) => {
+                self.saw_ack(id);
+                self.federate(None, &[], ack)
+            },
+            (user_id, None) => match self.connections.get(user_id) {
+                Some(conn) => vec![(*conn, ack)],
+                None => {
+                    self.acks.entry(user_id.to_string()).or_default().push(id.to_string());
+                    vec![]
+                },
+            },
+        }
+    }
+
+    /// Like `saw` for the `Ack` of the chat `id`, which is passed on
+    /// over every link until it reaches the relay of the sender
+    fn saw_ack(&mut self, id: &str) -> bool {
+        self.saw(&format!("{}{}", ACK_PREFIX, id))
+    }
+
+    /// `message` for every link but `except` to a relay it has not been through
+    fn federate(&self, except: Option<C>, via: &[String], message: Message) -> Vec<(C, Message)> {
+        self.links.keys()
+            .filter(|link| Some(**link) != except)
+            .filter_map(|link| self.to_link(*link, via, message.clone()))
+            .collect()
+    }
+
+    /// `message` for `link` with us added to `via`, `None` if the relay
+    /// at the other end has seen it already
+    fn to_link(&self, link: C, via: &[String], message: Message) -> Option<(C, Message)> {
+        let name = self.name.as_ref()?;
+        let relay = self.links.get(&link)?;
+        if via.contains(relay) {
+            return None;
+        }
+
+        let mut via = via.to_vec();
+        via.push(name.clone());
+        Some((link, Message::Federated { via, message: Box::new(message) }))
+    }
+
+    /// Who is around, their keys and the members of every room, for a
+    /// relay that linked to us
+    fn sync(&self, link: C) -> Vec<(C, Message)> {
+        // users of other relays go with the relay they started at
+        let announce = |user_id: &str, message: Message| match protocol::split_relay(user_id) {
+            (_, Some(relay)) => self.to_link(link, &[relay.to_string()], message),
+            (_, None) => self.to_link(link, &[], message),
+        };
+
+        let presence = self.presence.iter()
+            .filter_map(|(user_id, (user_name, presence))| announce(user_id, Message::Presence {
+                user_id: self.qualify_known(user_id),
+                user_name: user_name.clone(),
+                presence: *presence,
+            }));
+        let keys = self.public_keys.iter()
+            .filter_map(|(user_id, public_key)| announce(user_id, Message::PublicKey {
+                user_id: self.qualify_known(user_id),
+                public_key: public_key.clone(),
+            }));
+        let members = self.rooms.iter()
+            .flat_map(|(room, members)| members.iter().map(move |member| (room, member)))
+            .filter_map(|(room, member)| announce(member, Message::Join {
+                room: room.clone(),
+                user_id: self.qualify_known(member),
+            }));
+
+        presence.chain(keys).chain(members).collect()
+    }
+
+    /// `user_id` of ours qualified, users of other relays are already
+    fn qualify_known(&self, user_id: &str) -> String {
+        match protocol::split_relay(user_id) {
+            (_, Some(_)) => user_id.to_string(),
+            (_, None) => self.qualify(user_id),
+        }
+    }
+
+    /// Forgets a closed link. The users of the relays we reached through
+    /// it are offline until another link tells us otherwise.
+    fn unlink(&mut self, link: C) -> Vec<(C, Message)> {
+        let lost: HashSet<String> = self.routes.iter()
+            .filter(|(_, route)| **route == link)
+            .map(|(relay, _)| relay.clone())
+            .collect();
+        self.routes.retain(|_, route| *route != link);
+
+        let offline: Vec<(String, String)> = self.presence.iter()
+            .filter(|(user_id, _)| protocol::split_relay(user_id).1.is_some_and(|relay| lost.contains(relay)))
+            .map(|(user_id, (user_name, _))| (user_id.clone(), user_name.clone()))
+            .collect();
+
+        let mut out = vec![];
+        for (user_id, user_name) in offline {
+            self.presence.remove(&user_id);
+            let relay = protocol::split_relay(&user_id).1.unwrap_or_default().to_string();
+            let message = Message::Presence { user_id, user_name, presence: Presence::Offline };
+            out.extend(self.broadcast(link, message.clone()));
+            out.extend(self.federate(Some(link), &[relay], message));
+        }
+
+        // the other links may still reach them
+        let hello = self.peer_hello();
+        out.extend(self.links.keys().map(|other| (*other, hello.clone())));
+
+        out
+    }
+
+    /// Handles a message passed on by another relay, hands it to our
+    /// users and passes it on to the relays it has not been through
+    fn federated(&mut self, link: C, via: Vec<String>, message: Message) -> Vec<(C, Message)> {
+        let origin = match via.first() {
+            Some(origin) if !via.iter().any(|relay| Some(relay) == self.name.as_ref()) => origin.clone(),
+            // it went round in a loop, or we do not peer at all
+            _ => return vec![],
+        };
+        let first_route = !self.routes.contains_key(&origin);
+        let route = *self.routes.entry(origin.clone()).or_insert(link);
+
+        // relays speak only for their own users
+        let of_origin = |user_id: &str| protocol::split_relay(user_id).1 == Some(origin.as_str());
+
+        match message {
+            Message::Chat { id, from, to, body, clock } if of_origin(&from) => {
+                if !self.saw(&id) {
+                    return vec![];
+                }
+
+                let chat = Message::Chat { id, from, to: to.clone(), body, clock };
+                let to = match to.as_deref() {
+                    Some(to) => to,
+                    None => return vec![],
+                };
+                match protocol::split_relay(to) {
+                    _ if protocol::is_room(to) => {
+                        let room = to;
+                        let mut out: Vec<(C, Message)> = self.members(room).into_iter()
+                            .map(|member| (member, chat.clone()))
+                            .collect();
+                        self.remember(chat.clone());
+                        out.extend(self.federate(Some(link), &via, chat));
+                        out
+                    },
+                    (user_id, Some(relay)) if Some(relay) == self.name.as_deref() => {
+                        // kept for the recipient to catch up on if offline
+                        let chat = match chat {
+                            Message::Chat { id, from, body, clock, .. } => Message::Chat {
+                                id,
+                                from,
+                                to: Some(user_id.to_string()),
+                                body,
+                                clock,
+                            },
+                            chat => chat,
+                        };
+                        let mut out: Vec<(C, Message)> = vec![];
+                        if let Message::Chat { id, from, .. } = &chat {
+                            match self.connections.get(user_id) {
+                                Some(recipient) => {
+                                    out.push((*recipient, chat.clone()));
+                                    out.extend(self.ack(from, id));
+                                },
+                                None => self.store(id, from),
+                            }
+                        }
+                        self.remember(chat);
+                        out
+                    },
+                    (_, Some(relay)) => match self.routes.get(relay) {
+                        Some(next) => self.to_link(*next, &via, chat).into_iter().collect(),
+                        None => vec![],
+                    },
+                    (_, None) => vec![],
+                }
+            },
+            Message::Read { by, to, id } if of_origin(&by) => {
+                let (user_id, relay) = match protocol::split_relay(&to) {
+                    (user_id, Some(relay)) => (user_id.to_string(), relay.to_string()),
+                    (_, None) => return vec![],
+                };
+
+                if Some(relay.as_str()) == self.name.as_deref() {
+                    return match self.connections.get(&user_id) {
+                        Some(recipient) => vec![(*recipient, Message::Read { by, to: user_id, id })],
+                        None => vec![],
+                    };
+                }
+                match self.routes.get(&relay) {
+                    Some(next) => self.to_link(*next, &via, Message::Read { by, to, id }).into_iter().collect(),
+                    None => vec![],
+                }
+            },
+            // the relay of the sender tells it, the others pass it on
+            Message::Ack { id } => {
+                if !self.saw_ack(&id) {
+                    return vec![];
+                }
+
+                match self.stored.iter().any(|(stored, _)| *stored == id) {
+                    true => self.delivered(&id),
+                    false => self.federate(Some(link), &via, Message::Ack { id }),
+                }
+            },
+            Message::Join { room, user_id } if of_origin(&user_id) && protocol::is_valid_room(&room) => {
+                if !self.rooms.entry(room.clone()).or_default().insert(user_id.clone()) {
+                    return vec![];
+                }
+                self.joined.insert((room.clone(), user_id.clone()), self.next_seq);
+
+                let join = Message::Join { room: room.clone(), user_id };
+                let mut out: Vec<(C, Message)> = self.members(&room).into_iter()
+                    .map(|member| (member, join.clone()))
+                    .collect();
+                out.extend(self.federate(Some(link), &via, join));
+                out
+            },
+            Message::Leave { room, user_id } if of_origin(&user_id) => {
+                let recipients = self.members(&room);
+                if !self.rooms.get_mut(&room).is_some_and(|members| members.remove(&user_id)) {
+                    return vec![];
+                }
+                self.joined.remove(&(room.clone(), user_id.clone()));
+                if self.rooms.get(&room).is_some_and(BTreeSet::is_empty) {
+                    self.rooms.remove(&room);
+                }
+
+                let leave = Message::Leave { room, user_id };
+                let mut out: Vec<(C, Message)> = recipients.into_iter()
+                    .map(|recipient| (recipient, leave.clone()))
+                    .collect();
+                out.extend(self.federate(Some(link), &via, leave));
+                out
+            },
+            // presence only counts from the link the relay is reached
+            // through, others may not have heard yet that it went away
+            Message::Presence { user_id, user_name, presence } if of_origin(&user_id) && (route == link || first_route) => {
+                let known = self.presence.get(&user_id);
+                if known == Some(&(user_name.clone(), presence)) || (known.is_none() && presence == Presence::Offline) {
+                    return vec![];
+                }
+
+                match presence {
+                    Presence::Offline => self.presence.remove(&user_id),
+                    _ => self.presence.insert(user_id.clone(), (user_name.clone(), presence)),
+                };
+                let message = Message::Presence { user_id, user_name, presence };
+                let mut out = self.broadcast(link, message.clone());
+                out.extend(self.federate(Some(link), &via, message));
+                out
+            },
+            Message::PublicKey { user_id, public_key } if of_origin(&user_id) => {
+                if self.public_keys.get(&user_id) == Some(&public_key) {
+                    return vec![];
+                }
+
+                self.public_keys.insert(user_id.clone(), public_key.clone());
+                let message = Message::PublicKey { user_id, public_key };
+                let mut out = self.broadcast(link, message.clone());
+                out.extend(self.federate(Some(link), &via, message));
+                out
+            },
+            _ => vec![],
+        }
+    }
+
+    /// Keeps a relayed chat, dropping the oldest of its conversation when full
+    fn remember(&mut self, chat: Message) {
+        let key = match &chat {
+            Message::Chat { from, to, .. } => history_key(from, to.as_deref()),
+            _ => return,
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+
+        let seq = self.next_seq;
+        self.next_seq += 1;
+
+        let chats = self.history.entry(key).or_default();
+        if chats.len() == HISTORY_LIMIT {
+            chats.pop_front();
+        }
+        chats.push_back(Relayed { time: now, seq, chat });
+    }
+
+    /// `seq` of the first chat of the conversation `key` that `user_id`
+    /// may catch up on, `None` if the conversation is not theirs
+    fn readable_from(&self, user_id: &str, key: &str) -> Option<u64> {
+        match key {
+            constants::RELAY_CONVERSATION => Some(0),
+            room if protocol::is_room(room) => self.joined.get(&(room.to_string(), user_id.to_string())).copied(),
+            pair => pair.split('\n').any(|member| member == user_id).then_some(0),
+        }
+    }
+
+    fn is_member(&self, room: &str, user_id: &str) -> bool {
+        self.rooms.get(room).is_some_and(|members| members.contains(user_id))
+    }
+
+    /// Connections of the members of `room` that are online
+    fn members(&self, room: &str) -> Vec<C> {
+        self.rooms.get(room)
+            .map(|members| members.iter()
+                .filter_map(|member| self.connections.get(member).copied())
+                .collect())
+            .unwrap_or_default()
+    }
+
+    fn recipients(&self, sender: C, to: Option<&str>) -> Vec<C> {
+        match to {
+            Some(room) if protocol::is_room(room) => {
+                // only members hear what is said in a room
+                match self.users.get(&sender) {
+                    Some(user_id) if self.is_member(room, user_id) => self.members(room)
+                        .into_iter()
+                        .filter(|conn| *conn != sender)
+                        .collect(),
+                    _ => vec![],
+                }
+            },
+            Some(user_id) => self.connections.get(user_id)
+                .copied()
+                .into_iter()
+                .collect(),
+            None => self.users.keys()
+                .copied()
+                .filter(|conn| *conn != sender)
+                .collect(),
+        }
+    }
+}
+
+/// The broadcast room, a room, or the two users of a direct conversation
+fn history_key(from: &str, to: Option<&str>) -> String {
+    match to {
+        None => constants::RELAY_CONVERSATION.to_string(),
+        Some(room) if protocol::is_room(room) => room.to_string(),
+        Some(to) => {
+            let mut users = [from, to];
+            users.sort_unstable();
+            users.join("\n")
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        crypto::SigningKeyPair,
+        net::protocol::Body
+    };
+
+    fn hello(user_id: &str) -> Message {
+        Message::Hello {
+            version: constants::APP_VERSION.to_string(),
+            user_id: user_id.to_string(),
+            user_name: user_id.to_string(),
+            public_key: vec![],
+        }
+    }
+
+    /// Key of `user_id` in these tests, the same for every connection of the user
+    fn signing_key(user_id: &str) -> SigningKeyPair {
+        let mut secret = [0u8; 32];
+        secret[..user_id.len()].copy_from_slice(user_id.as_bytes());
+        SigningKeyPair::from_bytes(secret)
+    }
+
+    /// Says `hello` and answers the challenge with `key`
+    fn sign_in_with(relay: &mut Relay<usize>, conn: usize, hello: Message, key: &SigningKeyPair) -> Vec<(usize, Message)> {
+        let user_id = match &hello {
+            Message::Hello { user_id, .. } => user_id.clone(),
+            _ => unreachable!(),
+        };
+        let nonce = match relay.handle(conn, hello).as_slice() {
+            [(_, Message::Challenge { nonce })] => nonce.clone(),
+            other => panic!("expected challenge, got {:?}", other),
+        };
+
+        relay.handle(conn, Message::Auth {
+            signing_key: key.public_bytes(),
+            signature: key.sign_challenge(&user_id, &nonce),
+        })
+    }
+
+    fn sign_in(relay: &mut Relay<usize>, conn: usize, hello: Message) -> Vec<(usize, Message)> {
+        let key = match &hello {
+            Message::Hello { user_id, .. } => signing_key(user_id),
+            _ => unreachable!(),
+        };
+        sign_in_with(relay, conn, hello, &key)
+    }
+
+    fn chat(to: Option<&str>, text: &str) -> Message {
+        Message::Chat {
+            id: "m1".to_string(),
+            from: "spoofed".to_string(),
+            to: to.map(str::to_string),
+            body: Body::Plain(text.to_string()),
+            clock: 1,
+        }
+    }
+
+    fn relay_with_users(users: &[&str]) -> Relay<usize> {
+        let mut relay = Relay::new();
+        for (conn, user_id) in users.iter().enumerate() {
+            let out = sign_in(&mut relay, conn, hello(user_id));
+            assert_eq!(out[0], (conn, Message::Welcome {
+                version: constants::APP_VERSION.to_string(),
+                relay: String::new(),
+            }));
+        }
+
+        relay
+    }
+
+    fn is_error(out: &[(usize, Message)], conn: usize, code: ErrorCode) -> bool {
+        out.len() == 1 && out[0].0 == conn && matches!(
+            &out[0].1, Message::Error { code: c, .. } if *c == code
+        )
+    }
+
+    #[test]
+    fn test_hello_registers_user() {
+        let relay = relay_with_users(&["alice", "bob"]);
+
+        assert_eq!(relay.user_count(), 2);
+        assert_eq!(relay.user_id(0), Some("alice"));
+        assert_eq!(relay.user_id(1), Some("bob"));
+    }
+
+    #[test]
+    fn test_user_id_is_bound_to_first_key() {
+        let mut relay = relay_with_users(&["alice"]);
+        relay.disconnect(0);
+
+        // someone else claiming alice's user id is turned away
+        let out = sign_in_with(&mut relay, 1, hello("alice"), &signing_key("mallory"));
+        assert!(is_error(&out, 1, ErrorCode::AuthFailed));
+        assert_eq!(relay.user_id(1), None);
+
+        // a signature that does not match the key is no good either
+        let nonce = match relay.handle(2, hello("alice")).as_slice() {
+            [(_, Message::Challenge { nonce })] => nonce.clone(),
+            other => panic!("expected challenge, got {:?}", other),
+        };
+        let out = relay.handle(2, Message::Auth {
+            signing_key: signing_key("alice").public_bytes(),
+            signature: signing_key("mallory").sign_challenge("alice", &nonce),
+        });
+        assert!(is_error(&out, 2, ErrorCode::AuthFailed));
+
+        // nor is answering a challenge that was never sent
+        assert!(is_error(&relay.handle(3, Message::Auth {
+            signing_key: signing_key("alice").public_bytes(),
+            signature: signing_key("alice").sign_challenge("alice", &nonce),
+        }), 3, ErrorCode::NotRegistered));
+
+        // alice herself gets back in
+        sign_in(&mut relay, 4, hello("alice"));
+        assert_eq!(relay.user_id(4), Some("alice"));
+    }
+
+    #[test]
+    fn test_repeated_hello_keeps_challenge() {
+        let mut relay = Relay::new();
+        let first = relay.handle(0, hello("alice"));
+        assert_eq!(relay.handle(0, hello("alice")), first);
+
+        // the answer to the first challenge still signs alice in
+        let out = match first.as_slice() {
+            [(_, Message::Challenge { nonce })] => relay.handle(0, Message::Auth {
+                signing_key: signing_key("alice").public_bytes(),
+                signature: signing_key("alice").sign_challenge("alice", nonce),
+            }),
+            other => panic!("expected challenge, got {:?}", other),
+        };
+        assert!(out.iter().any(|(_, message)| matches!(message, Message::Welcome { .. })));
+        assert_eq!(relay.user_id(0), Some("alice"));
+    }
+
+    #[test]
+    fn test_guests() {
+        let mut relay = relay_with_users(&["alice"]);
+        let empty_key = Message::PublicKey { user_id: "carol".to_string(), public_key: vec![] };
+
+        // user ids that are owned or in use can not be taken by guests
+        assert!(is_error(&relay.sign_in_guest(1, "alice", "Alice"), 1, ErrorCode::AuthFailed));
+        let out = relay.sign_in_guest(1, "carol", "Carol");
+        assert!(out.contains(&(1, Message::Welcome {
+            version: constants::APP_VERSION.to_string(),
+            relay: String::new(),
+        })));
+        assert!(out.contains(&(0, empty_key.clone())));
+        assert_eq!(relay.user_id(1), Some("carol"));
+
+        // nor can others sign in as a guest that is around
+        assert!(is_error(&sign_in(&mut relay, 2, hello("carol")), 2, ErrorCode::AuthFailed));
+        assert!(is_error(&relay.sign_in_guest(3, "carol", "Carol"), 3, ErrorCode::AuthFailed));
+
+        // newcomers learn the guest has no key
+        let out = sign_in(&mut relay, 4, hello("bob"));
+        assert!(out.contains(&(4, empty_key)));
+    }
+
+    #[test]
+    fn test_direct_chat() {
+        let mut relay = relay_with_users(&["alice", "bob", "carol"]);
+
+        let out = relay.handle(0, chat(Some("carol"), "hi"));
+        assert_eq!(out, vec![
+            (2, Message::Chat {
+                id: "m1".to_string(),
+                from: "alice".to_string(),
+                to: Some("carol".to_string()),
+                body: Body::Plain("hi".to_string()),
+                clock: 1,
+            }),
+            (0, Message::Ack { id: "m1".to_string() }),
+        ]);
+
+        let out = relay.handle(0, chat(Some("dave"), "hi"));
+        assert!(is_error(&out, 0, ErrorCode::UnknownRecipient));
+        assert!(matches!(&out[0].1, Message::Error { id: Some(id), .. } if id == "m1"));
+    }
+
+    #[test]
+    fn test_direct_chat_to_offline_user() {
+        let mut relay = relay_with_users(&["alice", "bob"]);
+        relay.disconnect(1);
+
+        // alice is told it was only stored
+        let out = relay.handle(0, chat(Some("bob"), "are you there?"));
+        assert_eq!(out, vec![(0, Message::Stored { id: "m1".to_string() })]);
+
+        // bob catches up once he is back, and alice learns he has it
+        sign_in(&mut relay, 2, hello("bob"));
+        let out = relay.handle(2, Message::HistoryRequest { since: 0 });
+        assert!(matches!(&out[0].1, Message::Chat { from, body: Body::Plain(text), .. } if from == "alice" && text == "are you there?"));
+        assert_eq!(out[1..], [
+            (2, Message::HistoryEnd { count: 1 }),
+            (0, Message::Ack { id: "m1".to_string() }),
+        ]);
+
+        // only once
+        let out = relay.handle(2, Message::HistoryRequest { since: 0 });
+        assert!(!out.iter().any(|(_, message)| matches!(message, Message::Ack { .. })));
+    }
+
+    #[test]
+    fn test_delivered_while_sender_away() {
+        let mut relay = relay_with_users(&["alice", "bob"]);
+        relay.disconnect(1);
+        relay.handle(0, chat(Some("bob"), "are you there?"));
+        relay.disconnect(0);
+
+        sign_in(&mut relay, 2, hello("bob"));
+        let out = relay.handle(2, Message::HistoryRequest { since: 0 });
+        assert!(!out.iter().any(|(_, message)| matches!(message, Message::Ack { .. })));
+
+        // alice hears of it right after the welcome
+        let out = sign_in(&mut relay, 3, hello("alice"));
+        let welcome = out.iter().position(|(_, message)| matches!(message, Message::Welcome { .. })).unwrap();
+        assert_eq!(out[welcome + 1], (3, Message::Ack { id: "m1".to_string() }));
+    }
+
+    #[test]
+    fn test_broadcast_chat() {
+        let mut relay = relay_with_users(&["alice", "bob", "carol"]);
+
+        let mut recipients: Vec<usize> = relay.handle(1, chat(None, "hi all"))
+            .into_iter()
+            .filter(|(_, message)| matches!(message, Message::Chat { .. }))
+            .map(|(conn, _)| conn)
+            .collect();
+        recipients.sort();
+
+        assert_eq!(recipients, vec![0, 2]);
+    }
+
+    #[test]
+    fn test_read_receipt() {
+        let mut relay = relay_with_users(&["alice", "bob"]);
+        let read = |to: &str| Message::Read {
+            by: "spoofed".to_string(),
+            to: to.to_string(),
+            id: "m1".to_string(),
+        };
+
+        assert_eq!(relay.handle(1, read("alice")), vec![(0, Message::Read {
+            by: "bob".to_string(),
+            to: "alice".to_string(),
+            id: "m1".to_string(),
+        })]);
+        assert!(relay.handle(1, read("carol")).is_empty());
+        assert!(relay.handle(7, read("alice")).is_empty());
+    }
+
+    #[test]
+    fn test_typing() {
+        let mut relay = relay_with_users(&["alice", "bob", "carol"]);
+        let typing = |to: Option<&str>| Message::Typing {
+            from: "spoofed".to_string(),
+            to: to.map(str::to_string),
+        };
+
+        assert_eq!(relay.handle(0, typing(Some("bob"))), vec![(1, Message::Typing {
+            from: "alice".to_string(),
+            to: Some("bob".to_string()),
+        })]);
+        assert_eq!(relay.handle(0, typing(None)).len(), 2);
+        assert!(relay.handle(0, typing(Some("dave"))).is_empty());
+        assert!(relay.handle(7, typing(None)).is_empty());
+    }
+
+    #[test]
+    fn test_file_transfer() {
+        let mut relay = relay_with_users(&["alice", "bob"]);
+        let file = |to: &str, part: FilePart| Message::File {
+            id: "f1".to_string(),
+            from: "spoofed".to_string(),
+            to: to.to_string(),
+            part,
+        };
+        let offer = FilePart::Offer { name: "notes.txt".to_string(), size: 3, sha256: "00".repeat(32) };
+
+        assert_eq!(relay.handle(0, file("bob", offer.clone())), vec![(1, Message::File {
+            id: "f1".to_string(),
+            from: "alice".to_string(),
+            to: "bob".to_string(),
+            part: offer.clone(),
+        })]);
+
+        // only offers to someone offline are answered, the chunks are dropped
+        let out = relay.handle(0, file("carol", offer));
+        assert!(is_error(&out, 0, ErrorCode::UnknownRecipient));
+        assert!(relay.handle(0, file("carol", FilePart::Ack { received: 1 })).is_empty());
+        assert!(relay.handle(7, file("bob", FilePart::Done { verified: true })).is_empty());
+    }
+
+    fn join(room: &str, user_id: &str) -> Message {
+        Message::Join { room: room.to_string(), user_id: user_id.to_string() }
+    }
+
+    #[test]
+    fn test_rooms() {
+        let mut relay = relay_with_users(&["alice", "bob", "carol"]);
+
+        assert_eq!(relay.handle(0, join("#rust", "spoofed")), vec![(0, join("#rust", "alice"))]);
+        assert_eq!(relay.handle(1, join("#rust", "bob")), vec![
+            (1, join("#rust", "alice")),
+            (0, join("#rust", "bob")),
+            (1, join("#rust", "bob")),
+        ]);
+        assert!(is_error(&relay.handle(2, join("rust", "carol")), 2, ErrorCode::InvalidMessage));
+
+        // only members are told, carol is not in the room
+        let out = relay.handle(0, chat(Some("#rust"), "hi"));
+        assert_eq!(out, vec![
+            (1, Message::Chat {
+                id: "m1".to_string(),
+                from: "alice".to_string(),
+                to: Some("#rust".to_string()),
+                body: Body::Plain("hi".to_string()),
+                clock: 1,
+            }),
+            (0, Message::Ack { id: "m1".to_string() }),
+        ]);
+        assert!(is_error(&relay.handle(2, chat(Some("#rust"), "hi")), 2, ErrorCode::UnknownRecipient));
+
+        let leave = Message::Leave { room: "#rust".to_string(), user_id: "alice".to_string() };
+        assert_eq!(relay.handle(0, leave.clone()), vec![(0, leave.clone()), (1, leave.clone())]);
+        assert!(relay.handle(0, leave).is_empty());
+
+        // the last member leaving closes the room
+        relay.handle(1, Message::Leave { room: "#rust".to_string(), user_id: "bob".to_string() });
+        assert!(relay.rooms.is_empty());
+    }
+
+    #[test]
+    fn test_rooms_kept_while_offline() {
+        let mut relay = relay_with_users(&["alice", "bob"]);
+        relay.handle(0, join("#rust", "alice"));
+        relay.handle(1, join("#rust", "bob"));
+
+        relay.disconnect(1);
+        assert_eq!(relay.handle(0, chat(Some("#rust"), "hi")), vec![(0, Message::Ack { id: "m1".to_string() })]);
+
+        let out = sign_in(&mut relay, 2, hello("bob"));
+        assert_eq!(out[out.len() - 2..], [(2, join("#rust", "alice")), (2, join("#rust", "bob"))]);
+    }
+
+    #[test]
+    fn test_history() {
+        let mut relay = relay_with_users(&["alice", "bob", "carol"]);
+        relay.handle(0, join("#rust", "alice"));
+        relay.handle(1, join("#rust", "bob"));
+
+        let sent = |id: &str, to: Option<&str>| Message::Chat {
+            id: id.to_string(),
+            from: "spoofed".to_string(),
+            to: to.map(str::to_string),
+            body: Body::Plain(id.to_string()),
+            clock: 1,
+        };
+        relay.handle(0, sent("everyone", None));
+        relay.handle(0, sent("room", Some("#rust")));
+        relay.handle(0, sent("to-bob", Some("bob")));
+        relay.handle(1, sent("to-alice", Some("alice")));
+
+        let ids = |relay: &mut Relay<usize>, conn: usize| -> Vec<String> {
+            relay.handle(conn, Message::HistoryRequest { since: 0 })
+                .into_iter()
+                .map(|(_, message)| match message {
+                    Message::Chat { id, .. } => id,
+                    Message::HistoryEnd { count } => format!("end {}", count),
+                    other => panic!("unexpected {:?}", other),
+                })
+                .collect()
+        };
+
+        // nobody is sent their own chats, or the ones of rooms and users they are not with
+        let mut bob = ids(&mut relay, 1);
+        bob.sort();
+        assert_eq!(bob, vec!["end 3", "everyone", "room", "to-bob"]);
+        assert_eq!(ids(&mut relay, 2), vec!["everyone", "end 1"]);
+        assert_eq!(
+            relay.handle(2, Message::HistoryRequest { since: u64::MAX }),
+            vec![(2, Message::HistoryEnd { count: 0 })]
+        );
+
+        for _ in 0..HISTORY_LIMIT {
+            relay.handle(1, sent("again", None));
+        }
+        assert_eq!(relay.history[constants::RELAY_CONVERSATION].len(), HISTORY_LIMIT);
+    }
+
+    #[test]
+    fn test_room_history_starts_at_join() {
+        let mut relay = relay_with_users(&["alice", "bob"]);
+        relay.handle(0, join("#rust", "alice"));
+
+        let sent = |relay: &mut Relay<usize>, id: &str| {
+            let mut chat = chat(Some("#rust"), id);
+            if let Message::Chat { id: chat_id, .. } = &mut chat {
+                *chat_id = id.to_string();
+            }
+            relay.handle(0, chat);
+        };
+        sent(&mut relay, "before");
+        relay.handle(1, join("#rust", "bob"));
+        sent(&mut relay, "after");
+
+        let history = |relay: &mut Relay<usize>| -> Vec<String> {
+            relay.handle(1, Message::HistoryRequest { since: 0 })
+                .into_iter()
+                .filter_map(|(_, message)| match message {
+                    Message::Chat { id, .. } => Some(id),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        // bob only catches up on what was said since he is in the room
+        assert_eq!(history(&mut relay), vec!["after"]);
+
+        // nor does leaving and joining again bring back what he missed
+        relay.handle(1, Message::Leave { room: "#rust".to_string(), user_id: "bob".to_string() });
+        sent(&mut relay, "away");
+        relay.handle(1, join("#rust", "bob"));
+        assert!(history(&mut relay).is_empty());
+    }
+
+    fn presence(user_id: &str, presence: Presence) -> Message {
+        Message::Presence {
+            user_id: user_id.to_string(),
+            user_name: user_id.to_string(),
+            presence,
+        }
+    }
+
+    #[test]
+    fn test_presence_on_hello_and_disconnect() {
+        let mut relay = relay_with_users(&["alice"]);
+
+        let out = sign_in(&mut relay, 1, hello("bob"));
+        assert_eq!(out[1..], [
+            (1, presence("alice", Presence::Online)),
+            (0, presence("bob", Presence::Online)),
+        ]);
+
+        assert_eq!(relay.disconnect(1), vec![(0, presence("bob", Presence::Offline))]);
+        assert!(relay.disconnect(1).is_empty());
+    }
+
+    #[test]
+    fn test_presence_update() {
+        let mut relay = relay_with_users(&["alice", "bob"]);
+
+        let away = Message::Presence {
+            user_id: "spoofed".to_string(),
+            user_name: "spoofed".to_string(),
+            presence: Presence::Away,
+        };
+        assert_eq!(relay.handle(0, away.clone()), vec![(1, presence("alice", Presence::Away))]);
+        assert!(relay.handle(7, away).is_empty());
+
+        // a later newcomer learns alice is away
+        let out = sign_in(&mut relay, 2, hello("carol"));
+        assert!(out.contains(&(2, presence("alice", Presence::Away))));
+    }
+
+    #[test]
+    fn test_expired_connections() {
+        let mut relay = relay_with_users(&["alice", "bob"]);
+        let now = Instant::now();
+        let timeout = Duration::from_secs(30);
+
+        relay.seen(0, now);
+        relay.seen(1, now + Duration::from_secs(20));
+        assert!(relay.expired(now + timeout, timeout).is_empty());
+        assert_eq!(relay.expired(now + Duration::from_secs(31), timeout), vec![0]);
+
+        relay.disconnect(0);
+        assert!(relay.expired(now + Duration::from_secs(31), timeout).is_empty());
+    }
+
+    #[test]
+    fn test_public_keys_and_sealed_chat() {
+        let mut relay = relay_with_users(&["alice"]);
+        let hello_with_key = |user_id: &str, key: u8| Message::Hello {
+            version: constants::APP_VERSION.to_string(),
+            user_id: user_id.to_string(),
+            user_name: user_id.to_string(),
+            public_key: vec![key; 32],
+        };
+        let public_key = |user_id: &str, key: u8| Message::PublicKey {
+            user_id: user_id.to_string(),
+            public_key: vec![key; 32],
+        };
+
+        let out = sign_in(&mut relay, 1, hello_with_key("bob", 2));
+        assert!(out.contains(&(0, public_key("bob", 2))));
+
+        // keys are known before the welcome
+        let out = sign_in(&mut relay, 2, hello_with_key("carol", 3));
+        assert_eq!(out[0], (2, public_key("bob", 2)));
+        assert!(matches!(out[1], (2, Message::Welcome { .. })));
+
+        let sealed = Body::Sealed { nonce: vec![0; 12], ciphertext: vec![9; 24] };
+        let out = relay.handle(1, Message::Chat {
+            id: "m1".to_string(),
+            from: "bob".to_string(),
+            to: Some("carol".to_string()),
+            body: sealed.clone(),
+            clock: 1,
+        });
+        assert!(matches!(&out[0], (2, Message::Chat { body, .. }) if *body == sealed));
+    }
+
+    #[test]
+    fn test_unregistered_connection_is_rejected() {
+        let mut relay = relay_with_users(&["alice"]);
+
+        let out = relay.handle(7, chat(None, "hi"));
+        assert!(is_error(&out, 7, ErrorCode::NotRegistered));
+    }
+
+    #[test]
+    fn test_ping_and_bye() {
+        let mut relay = relay_with_users(&["alice"]);
+
+        assert_eq!(relay.handle(0, Message::Ping), vec![(0, Message::Pong)]);
+
+        assert!(relay.handle(0, Message::Bye).is_empty());
+        assert_eq!(relay.user_count(), 0);
+    }
+
+    #[test]
+    fn test_reconnect_takes_over_user() {
+        let mut relay = relay_with_users(&["alice", "bob"]);
+        sign_in(&mut relay, 5, hello("alice"));
+
+        assert_eq!(relay.user_id(0), None);
+        assert_eq!(relay.user_id(5), Some("alice"));
+
+        let out = relay.handle(1, chat(Some("alice"), "hi"));
+        assert_eq!(out[0].0, 5);
+
+        // old connection going away does not unregister the new one
+        relay.disconnect(0);
+        assert_eq!(relay.handle(1, chat(Some("alice"), "hi"))[0].0, 5);
+
+        // alice is offline now, the chat waits in her history
+        relay.disconnect(5);
+        let out = relay.handle(1, chat(Some("alice"), "hi"));
+        assert_eq!(out, vec![(1, Message::Stored { id: "m1".to_string() })]);
+    }
+
+    /// Relays joined by links, the link to relay `b` is connection
+    /// `LINK + b` at every other relay
+    struct Federation {
+        relays: Vec<Relay<usize>>,
+        /// what users got, by relay
+        received: Vec<(usize, usize, Message)>,
+    }
+
+    const LINK: usize = 100;
+
+    impl Federation {
+        fn new(names: &[&str]) -> Self {
+            Federation {
+                relays: names.iter().map(|name| Relay::with_name(name)).collect(),
+                received: vec![],
+            }
+        }
+
+        fn sign_in(&mut self, relay: usize, conn: usize, user_id: &str) {
+            let hello = Message::Hello {
+                version: constants::APP_VERSION.to_string(),
+                user_id: user_id.to_string(),
+                user_name: user_id.to_string(),
+                public_key: vec![user_id.len() as u8; 32],
+            };
+            let out = sign_in(&mut self.relays[relay], conn, hello);
+            self.deliver(relay, out);
+        }
+
+        fn link(&mut self, a: usize, b: usize) {
+            let name_a = self.relays[a].name().unwrap().to_string();
+            let name_b = self.relays[b].name().unwrap().to_string();
+            let out_a = self.relays[a].link(LINK + b, name_b);
+            let out_b = self.relays[b].link(LINK + a, name_a);
+            self.deliver(a, out_a);
+            self.deliver(b, out_b);
+        }
+
+        fn unlink(&mut self, a: usize, b: usize) {
+            let out = self.relays[a].disconnect(LINK + b);
+            self.deliver(a, out);
+            let out = self.relays[b].disconnect(LINK + a);
+            self.deliver(b, out);
+        }
+
+        fn handle(&mut self, relay: usize, conn: usize, message: Message) {
+            let out = self.relays[relay].handle(conn, message);
+            self.deliver(relay, out);
+        }
+
+        /// Passes messages over links until none are left
+        fn deliver(&mut self, relay: usize, out: Vec<(usize, Message)>) {
+            let mut queue: VecDeque<(usize, usize, Message)> = out.into_iter()
+                .map(|(conn, message)| (relay, conn, message))
+                .collect();
+            while let Some((from, conn, message)) = queue.pop_front() {
+                if conn < LINK {
+                    self.received.push((from, conn, message));
+                    continue;
+                }
+                let to = conn - LINK;
+                queue.extend(self.relays[to].handle_link(LINK + from, message).into_iter()
+                    .map(|(conn, message)| (to, conn, message)));
+                assert!(queue.len() < 1000, "messages go round in a loop");
+            }
+        }
+
+        /// Everything user `conn` of `relay` got since last asked
+        fn take(&mut self, relay: usize, conn: usize) -> Vec<Message> {
+            let (taken, kept) = self.received.drain(..)
+                .partition(|(r, c, _)| *r == relay && *c == conn);
+            self.received = kept;
+            taken.into_iter().map(|(_, _, message)| message).collect()
+        }
+    }
+
+    fn chats(messages: &[Message]) -> Vec<(&str, &str)> {
+        messages.iter()
+            .filter_map(|message| match message {
+                Message::Chat { from, body: Body::Plain(text), .. } => Some((from.as_str(), text.as_str())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Whether `user_id` was said to be `presence`
+    fn went(messages: &[Message], user_id: &str, presence: Presence) -> bool {
+        messages.iter().any(|message| matches!(
+            message, Message::Presence { user_id: u, presence: p, .. } if u == user_id && *p == presence
+        ))
+    }
+
+    #[test]
+    fn test_federated_chat() {
+        let mut federation = Federation::new(&["london", "berlin"]);
+        federation.sign_in(0, 0, "alice");
+        federation.sign_in(1, 0, "bob");
+        federation.link(0, 1);
+
+        // users of the other relay are known once linked
+        assert_eq!(federation.relays[0].presence.get("bob@berlin"), Some(&("bob".to_string(), Presence::Online)));
+        assert!(federation.relays[1].public_keys.contains_key("alice@london"));
+        assert!(went(&federation.take(0, 0), "bob@berlin", Presence::Online));
+        assert!(went(&federation.take(1, 0), "alice@london", Presence::Online));
+
+        // berlin acknowledges once bob has it
+        federation.handle(0, 0, chat(Some("bob@berlin"), "hi bob"));
+        assert_eq!(federation.take(0, 0), vec![
+            Message::Stored { id: "m1".to_string() },
+            Message::Ack { id: "m1".to_string() },
+        ]);
+        assert_eq!(federation.take(1, 0), vec![Message::Chat {
+            id: "m1".to_string(),
+            from: "alice@london".to_string(),
+            to: Some("bob".to_string()),
+            body: Body::Plain("hi bob".to_string()),
+            clock: 1,
+        }]);
+
+        let mut reply = chat(Some("alice@london"), "hi alice");
+        if let Message::Chat { id, .. } = &mut reply {
+            *id = "m2".to_string();
+        }
+        federation.handle(1, 0, reply);
+        assert_eq!(chats(&federation.take(0, 0)), vec![("bob@berlin", "hi alice")]);
+
+        // read receipts cross the link like the chats they are about
+        federation.handle(1, 0, Message::Read {
+            by: "spoofed".to_string(),
+            to: "alice@london".to_string(),
+            id: "m1".to_string(),
+        });
+        assert_eq!(federation.take(0, 0), vec![Message::Read {
+            by: "bob@berlin".to_string(),
+            to: "alice".to_string(),
+            id: "m1".to_string(),
+        }]);
+
+        let out = federation.relays[0].handle(0, chat(Some("carol@paris"), "hi"));
+        assert!(is_error(&out, 0, ErrorCode::UnknownRecipient));
+
+        // relays speak only for their own users
+        let spoofed = Message::Federated {
+            via: vec!["berlin".to_string()],
+            message: Box::new(presence("alice@london", Presence::Away)),
+        };
+        assert!(federation.relays[0].handle_link(LINK + 1, spoofed.clone()).is_empty());
+        assert!(federation.relays[0].handle(0, spoofed).is_empty());
+
+        assert!(is_error(&federation.relays[0].handle(5, hello("eve@berlin")), 5, ErrorCode::InvalidMessage));
+    }
+
+    #[test]
+    fn test_federated_chat_to_offline_user() {
+        // london - berlin - paris
+        let mut federation = Federation::new(&["london", "berlin", "paris"]);
+        federation.sign_in(0, 0, "alice");
+        federation.sign_in(2, 0, "carol");
+        federation.link(0, 1);
+        federation.link(1, 2);
+        let out = federation.relays[2].disconnect(0);
+        federation.deliver(2, out);
+        federation.take(0, 0);
+
+        federation.handle(0, 0, chat(Some("carol@paris"), "hi carol"));
+        assert_eq!(federation.take(0, 0), vec![Message::Stored { id: "m1".to_string() }]);
+
+        // the ack finds its way back through berlin once carol catches up
+        federation.sign_in(2, 1, "carol");
+        federation.handle(2, 1, Message::HistoryRequest { since: 0 });
+        assert_eq!(chats(&federation.take(2, 1)), vec![("alice@london", "hi carol")]);
+        let acks: Vec<Message> = federation.take(0, 0).into_iter()
+            .filter(|message| matches!(message, Message::Ack { .. }))
+            .collect();
+        assert_eq!(acks, vec![Message::Ack { id: "m1".to_string() }]);
+    }
+
+    #[test]
+    fn test_federated_rooms() {
+        // london - berlin - paris
+        let mut federation = Federation::new(&["london", "berlin", "paris"]);
+        federation.sign_in(0, 0, "alice");
+        federation.sign_in(2, 0, "carol");
+        federation.link(0, 1);
+        federation.link(1, 2);
+
+        federation.handle(0, 0, join("#ops", "alice"));
+        federation.handle(2, 0, join("#ops", "carol"));
+        assert!(federation.take(2, 0).contains(&join("#ops", "alice@london")));
+        assert!(federation.take(0, 0).contains(&join("#ops", "carol@paris")));
+
+        federation.handle(0, 0, chat(Some("#ops"), "hi all"));
+        assert_eq!(chats(&federation.take(2, 0)), vec![("alice@london", "hi all")]);
+
+        // a direct chat takes two hops
+        let mut direct = chat(Some("alice@london"), "hi alice");
+        if let Message::Chat { id, .. } = &mut direct {
+            *id = "m2".to_string();
+        }
+        federation.handle(2, 0, direct);
+        assert_eq!(chats(&federation.take(0, 0)), vec![("carol@paris", "hi alice")]);
+
+        // closing the triangle makes no chat arrive twice, nor go round
+        federation.link(0, 2);
+        let mut again = chat(Some("#ops"), "hi again");
+        if let Message::Chat { id, .. } = &mut again {
+            *id = "m3".to_string();
+        }
+        federation.handle(0, 0, again);
+        assert_eq!(chats(&federation.take(2, 0)), vec![("alice@london", "hi again")]);
+
+        federation.handle(2, 0, Message::Leave { room: "#ops".to_string(), user_id: "carol".to_string() });
+        assert!(!federation.relays[0].is_member("#ops", "carol@paris"));
+        assert!(!federation.relays[1].is_member("#ops", "carol@paris"));
+    }
+
+    #[test]
+    fn test_link_loss() {
+        let mut federation = Federation::new(&["london", "berlin", "paris"]);
+        federation.sign_in(0, 0, "alice");
+        federation.sign_in(1, 0, "bob");
+        federation.sign_in(2, 0, "carol");
+        federation.link(0, 1);
+        federation.link(1, 2);
+        federation.take(0, 0);
+
+        federation.unlink(1, 2);
+        let got = federation.take(0, 0);
+        assert!(went(&got, "carol@paris", Presence::Offline));
+        assert!(!went(&got, "bob@berlin", Presence::Offline));
+        assert!(!federation.relays[0].presence.contains_key("carol@paris"));
+
+        // with a triangle, a lost link is gone round
+        federation.link(1, 2);
+        federation.link(0, 2);
+        federation.unlink(0, 1);
+        assert_eq!(federation.relays[0].presence.get("bob@berlin"), Some(&("bob".to_string(), Presence::Online)));
+        assert_eq!(federation.relays[1].presence.get("alice@london"), Some(&("alice".to_string(), Presence::Online)));
+
+        federation.take(1, 0);
+        federation.handle(0, 0, chat(Some("bob@berlin"), "still there?"));
+        assert_eq!(chats(&federation.take(1, 0)), vec![("alice@london", "still there?")]);
+
+        // users going away are heard of across the links
+        let out = federation.relays[1].disconnect(0);
+        federation.deliver(1, out);
+        assert!(went(&federation.take(0, 0), "bob@berlin", Presence::Offline));
+        assert!(!federation.relays[2].presence.contains_key("bob@berlin"));
+    }
+}
@@ -0,0 +1,449 @@
+use std::{
+    collections::{ HashMap, HashSet },
+    net::{ IpAddr, SocketAddr },
+    sync::Arc,
+    time::Instant,
+};
+use log::{ info, warn };
+use crate::{
+    constants,
+    net::{
+        self,
+        protocol::{ self, ErrorCode, FrameDecoder, FrameError, Message },
+        tls::TlsStream,
+        transport::{ Event, Protocol, Transport }
+    },
+    server::{
+        irc::{ Command, IrcSession },
+        keys::KeyStore,
+        limits::{ Limits, RateLimiter, Verdict },
+        relay::Relay
+    }
+};
+
+/// Another relay to peer with, see `ServerConfig::peers`
+pub(crate) struct Peer {
+    pub name: String,
+    pub addr: SocketAddr,
+}
+
+/// `Worker` owns the state of the server thread: the relay, the frame
+/// decoder, the rate limiter and, when TLS is on, the TLS session of
+/// every connection. IRC clients get an `IrcSession` instead of a decoder,
+/// links to other relays are neither encrypted nor rate limited.
+pub(crate) struct Worker<T: Transport<()>> {
+    transport: T,
+    relay: Relay<T::Conn>,
+    decoders: HashMap<T::Conn, FrameDecoder>,
+    tls: Option<Arc<rustls::ServerConfig>>,
+    sessions: HashMap<T::Conn, TlsStream>,
+    limits: Limits,
+    limiters: HashMap<T::Conn, RateLimiter>,
+    /// addresses disconnected for flooding, refused until the instant
+    banned: HashMap<IpAddr, Instant>,
+    /// listener of the IRC gateway, if it is on
+    irc_listener: Option<T::Listener>,
+    irc: HashMap<T::Conn, IrcSession>,
+    /// listener for links from other relays, if we peer
+    link_listener: Option<T::Listener>,
+    peers: Vec<Peer>,
+    /// connections to other relays, linked or still saying hello
+    link_conns: HashSet<T::Conn>,
+    /// links we opened ourselves, by the name of the relay we expect
+    dialed: HashMap<T::Conn, String>,
+    /// where the keys user ids are bound to are kept, if anywhere
+    store: Option<KeyStore>,
+    /// bindings the store holds, bindings are never dropped
+    saved: usize,
+}
+
+impl<T: Transport<()>> Worker<T> {
+    pub fn new(
+        transport: T,
+        tls: Option<Arc<rustls::ServerConfig>>,
+        limits: Limits,
+        irc_listener: Option<T::Listener>
+    ) -> Self {
+        Worker {
+            transport,
+            relay: Relay::new(),
+            decoders: HashMap::new(),
+            tls,
+            sessions: HashMap::new(),
+            limits,
+            limiters: HashMap::new(),
+            banned: HashMap::new(),
+            irc_listener,
+            irc: HashMap::new(),
+            link_listener: None,
+            peers: vec![],
+            link_conns: HashSet::new(),
+            dialed: HashMap::new(),
+            store: None,
+            saved: 0,
+        }
+    }
+
+    /// Peers with other relays as `name`, accepting their links on
+    /// `link_listener`. Of two relays the one whose name sorts first
+    /// opens the link.
+    pub fn with_peers(mut self, name: &str, link_listener: Option<T::Listener>, peers: Vec<Peer>) -> Self {
+        self.relay = Relay::with_name(name);
+        self.link_listener = link_listener;
+        self.peers = peers;
+        self.dial();
+        self
+    }
+
+    /// Binds user ids to the `keys` they had before and saves new
+    /// bindings in `store`
+    pub fn with_store(mut self, store: KeyStore, keys: HashMap<String, Vec<u8>>) -> Self {
+        self.saved = keys.len();
+        self.relay.load_signing_keys(keys);
+        self.store = Some(store);
+        self
+    }
+
+    pub fn net_event(&mut self, event: Event<T::Conn, T::Listener, ()>) {
+        match event {
+            Event::Accepted(conn, listener) => {
+                let addr = self.transport.remote_addr(conn);
+                info!("{} connected", addr);
+                let now = Instant::now();
+                // everyone on a Unix socket is loopback, one ban would lock them all out
+                let local = self.transport.is_local(conn);
+                if !local && self.banned.get(&addr.ip()).is_some_and(|until| *until > now) {
+                    return self.close(conn, "is banned");
+                }
+
+                self.relay.seen(conn, now);
+                if Some(listener) == self.link_listener {
+                    self.link_conns.insert(conn);
+                    self.decoders.insert(conn, FrameDecoder::new());
+                    return;
+                }
+
+                self.limiters.insert(conn, RateLimiter::new(&self.limits, now));
+                if Some(listener) == self.irc_listener {
+                    self.irc.insert(conn, IrcSession::new(now));
+                    return;
+                }
+
+                if let Some(config) = &self.tls {
+                    match TlsStream::server(Arc::clone(config)) {
+                        Ok(session) => {
+                            self.sessions.insert(conn, session);
+                        },
+                        Err(e) => return self.close(conn, &e.to_string()),
+                    }
+                }
+
+                self.decoders.insert(conn, FrameDecoder::with_max_len(self.limits.max_frame_len));
+            },
+            Event::Connected(conn, connected) => {
+                let relay = match self.dialed.get(&conn) {
+                    Some(relay) => relay.clone(),
+                    None => return,
+                };
+                if !connected {
+                    warn!("not able to reach relay {}", relay);
+                    self.dialed.remove(&conn);
+                    self.link_conns.remove(&conn);
+                    return;
+                }
+
+                self.relay.seen(conn, Instant::now());
+                self.decoders.insert(conn, FrameDecoder::new());
+                let hello = self.relay.peer_hello();
+                self.send(conn, &hello);
+            },
+            Event::Message(conn, data) if self.link_conns.contains(&conn) => {
+                self.relay.seen(conn, Instant::now());
+
+                let decoder = self.decoders.entry(conn).or_default();
+                decoder.push(&data);
+                let mut messages = vec![];
+                while let Some(result) = decoder.next_message() {
+                    messages.push(result);
+                }
+
+                for result in messages {
+                    match result {
+                        Ok(Message::Bye) => return self.close(conn, "left"),
+                        Ok(Message::PeerHello { relay, .. }) if !self.relay.is_link(conn) => {
+                            if !self.link(conn, relay) {
+                                return;
+                            }
+                        },
+                        Ok(message) => {
+                            let out = self.relay.handle_link(conn, message);
+                            self.deliver(out);
+                        },
+                        // relays speak the same protocol, something is badly off
+                        Err(e) => return self.close(conn, &e.to_string()),
+                    }
+                }
+            },
+            Event::Message(conn, data) if self.irc.contains_key(&conn) => {
+                let now = Instant::now();
+                self.relay.seen(conn, now);
+
+                let commands = match self.irc.get_mut(&conn) {
+                    Some(session) => session.receive(&data),
+                    None => return,
+                };
+                for command in commands {
+                    match command {
+                        Command::SignIn { nick, user_name } => {
+                            let out = self.relay.sign_in_guest(conn, &nick, &user_name);
+                            self.deliver(out);
+                        },
+                        Command::Relay(message) => {
+                            if !self.receive(conn, message, now) {
+                                return;
+                            }
+                        },
+                        Command::Reply(line) => self.send_line(conn, &line),
+                        Command::Quit => return self.close(conn, "left"),
+                    }
+                }
+            },
+            Event::Message(conn, data) => {
+                let now = Instant::now();
+                self.relay.seen(conn, now);
+
+                let data = match self.sessions.get_mut(&conn) {
+                    Some(session) => {
+                        let result = session.receive(&data);
+                        let records = session.outgoing();
+                        self.transport.send(conn, &records);
+
+                        match result {
+                            Ok(plaintext) => plaintext,
+                            Err(e) => return self.close(conn, &format!("tls error: {}", e)),
+                        }
+                    },
+                    None => data,
+                };
+
+                let max_len = self.limits.max_frame_len;
+                let decoder = self.decoders.entry(conn)
+                    .or_insert_with(|| FrameDecoder::with_max_len(max_len));
+                decoder.push(&data);
+
+                let mut messages = vec![];
+                while let Some(result) = decoder.next_message() {
+                    messages.push(result);
+                }
+
+                for result in messages {
+                    match result {
+                        Ok(Message::Bye) => return self.close(conn, "left"),
+                        Ok(message) => {
+                            if !self.receive(conn, message, now) {
+                                return;
+                            }
+                        },
+                        Err(e @ FrameError::VersionMismatch { .. }) => {
+                            // the client detects the mismatch on its own as well
+                            let message = Message::error(ErrorCode::VersionMismatch, &e.to_string());
+                            self.send(conn, &message);
+                            return self.close(conn, &e.to_string());
+                        },
+                        Err(e) => {
+                            let message = Message::error(ErrorCode::InvalidMessage, &e.to_string());
+                            self.send(conn, &message);
+
+                            let oversized = matches!(e, FrameError::TooLarge(_));
+                            if oversized && self.limiter(conn, now).violation(now) == Verdict::Disconnect {
+                                return self.ban(conn, now);
+                            }
+                        },
+                    }
+                }
+            },
+            Event::Disconnected(conn) => {
+                info!("{} disconnected", self.transport.remote_addr(conn));
+                self.forget(conn);
+            },
+            // expiry is driven by `Server`
+            Event::Datagram(_, _, _) | Event::Signal(()) => {},
+        }
+    }
+
+    /// Closes the connections that stopped sending heartbeats and lifts
+    /// the bans that are over. IRC clients are pinged, they do not send
+    /// heartbeats on their own.
+    pub fn expire(&mut self) {
+        let now = Instant::now();
+        self.banned.retain(|_, until| *until > now);
+
+        let due: Vec<T::Conn> = self.irc.iter_mut()
+            .filter_map(|(conn, session)| session.ping_due(now).then_some(*conn))
+            .collect();
+        for conn in due {
+            self.send_line(conn, &IrcSession::ping());
+        }
+
+        // links are kept alive by both ends, a relay that went away is tried again
+        let links: Vec<T::Conn> = self.link_conns.iter()
+            .copied()
+            .filter(|conn| self.relay.is_link(*conn))
+            .collect();
+        for conn in links {
+            self.send(conn, &Message::Ping);
+        }
+        self.dial();
+
+        for conn in self.relay.expired(now, constants::PRESENCE_TIMEOUT) {
+            self.close(conn, "timed out");
+        }
+    }
+
+    /// Opens the links we are to open that are not open
+    fn dial(&mut self) {
+        let name = match self.relay.name() {
+            Some(name) => name.to_string(),
+            None => return,
+        };
+        let due: Vec<(String, SocketAddr)> = self.peers.iter()
+            .filter(|peer| peer.name > name && !self.dialed.values().any(|relay| *relay == peer.name))
+            .map(|peer| (peer.name.clone(), peer.addr))
+            .collect();
+
+        for (relay, addr) in due {
+            match self.transport.connect(Protocol::Tcp, addr) {
+                Ok(conn) => {
+                    self.dialed.insert(conn, relay);
+                    self.link_conns.insert(conn);
+                },
+                Err(e) => warn!("not able to reach relay {}: {}", relay, e),
+            }
+        }
+    }
+
+    /// Links to the relay that said hello on `conn` if it is one of our
+    /// peers, returns `false` if the connection was closed
+    fn link(&mut self, conn: T::Conn, relay: String) -> bool {
+        let expected = match self.dialed.get(&conn) {
+            Some(dialed) => *dialed == relay,
+            // anyone can connect, only the address of the peer may say it is the peer
+            None => {
+                let ip = self.transport.remote_addr(conn).ip();
+                self.peers.iter().any(|peer| peer.name == relay && peer.addr.ip() == ip)
+            },
+        };
+        if !expected {
+            self.close(conn, &format!("is not the relay {} we peer with", relay));
+            return false;
+        }
+
+        if !self.dialed.contains_key(&conn) {
+            let hello = self.relay.peer_hello();
+            self.send(conn, &hello);
+        }
+        info!("linked to relay {}", relay);
+        let out = self.relay.link(conn, relay);
+        self.deliver(out);
+
+        true
+    }
+
+    /// Hands a message of `conn` to the relay if the limits allow it,
+    /// returns `false` if the connection was closed
+    fn receive(&mut self, conn: T::Conn, message: Message, now: Instant) -> bool {
+        match self.limiter(conn, now).check(protocol::frame_len(&message), now) {
+            Verdict::Allow => {
+                let out = self.relay.handle(conn, message);
+                self.save_keys();
+                self.deliver(out);
+            },
+            Verdict::Reject => {
+                let text = "too many messages, slow down";
+                let error = match &message {
+                    Message::Chat { id, .. } => Message::error_for(id, ErrorCode::RateLimited, text),
+                    _ => Message::error(ErrorCode::RateLimited, text),
+                };
+                self.send(conn, &error);
+            },
+            Verdict::Disconnect => {
+                self.ban(conn, now);
+                return false;
+            },
+        }
+
+        true
+    }
+
+    /// Saves the bindings if a user id was bound since the last save, a
+    /// failed save is tried again with the next message
+    fn save_keys(&mut self) {
+        let keys = self.relay.signing_keys();
+        if let Some(store) = &self.store {
+            if keys.len() != self.saved && store.save(keys).is_ok() {
+                self.saved = keys.len();
+            }
+        }
+    }
+
+    fn limiter(&mut self, conn: T::Conn, now: Instant) -> &mut RateLimiter {
+        let limits = &self.limits;
+        self.limiters.entry(conn).or_insert_with(|| RateLimiter::new(limits, now))
+    }
+
+    /// Disconnects a connection that keeps breaking the limits and
+    /// refuses its address for a while. Unix socket connections have no
+    /// address of their own, only the connection goes.
+    fn ban(&mut self, conn: T::Conn, now: Instant) {
+        if self.transport.is_local(conn) {
+            self.send(conn, &Message::error(ErrorCode::RateLimited, "too many messages, disconnected"));
+            return self.close(conn, "disconnected for flooding");
+        }
+
+        let message = format!("too many messages, disconnected for {} seconds", self.limits.ban.as_secs());
+        self.send(conn, &Message::error(ErrorCode::RateLimited, &message));
+
+        self.banned.insert(self.transport.remote_addr(conn).ip(), now + self.limits.ban);
+        self.close(conn, "banned for flooding");
+    }
+
+    fn send(&mut self, conn: T::Conn, message: &Message) -> bool {
+        if let Some(session) = self.irc.get_mut(&conn) {
+            let lines = session.render(message);
+            for line in lines {
+                self.send_line(conn, &line);
+            }
+            return true;
+        }
+
+        net::send_frame(&self.transport, conn, self.sessions.get_mut(&conn), message)
+    }
+
+    /// Sends a line to an IRC client
+    fn send_line(&self, conn: T::Conn, line: &str) {
+        self.transport.send(conn, format!("{}\r\n", line).as_bytes());
+    }
+
+    fn deliver(&mut self, out: Vec<(T::Conn, Message)>) {
+        for (recipient, message) in out {
+            self.send(recipient, &message);
+        }
+    }
+
+    fn close(&mut self, conn: T::Conn, reason: &str) {
+        info!("{} {}", self.transport.remote_addr(conn), reason);
+        self.transport.close(conn);
+        self.forget(conn);
+    }
+
+    fn forget(&mut self, conn: T::Conn) {
+        let out = self.relay.disconnect(conn);
+        self.deliver(out);
+        self.decoders.remove(&conn);
+        self.sessions.remove(&conn);
+        self.limiters.remove(&conn);
+        self.irc.remove(&conn);
+        self.link_conns.remove(&conn);
+        self.dialed.remove(&conn);
+    }
+}
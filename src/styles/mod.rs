@@ -1,4 +1,8 @@
 use tui::style::{Color, Modifier, Style};
+use crate::{
+    db::models::{ DeliveryStatus, TransferStatus },
+    net::protocol::Presence
+};
 
 pub fn cursor_style(focus: bool) -> Style {
     if !focus {
@@ -56,4 +60,66 @@ pub fn border_style(focus: bool) -> Style {
         } else {
             Color::Gray
         })
+}
+
+pub fn notice_style(error: bool) -> Style {
+    Style::default()
+        .fg(if error {
+            Color::Red
+        } else {
+            Color::DarkGray
+        })
+        .add_modifier(Modifier::ITALIC)
+}
+
+pub fn presence_style(presence: Presence) -> Style {
+    Style::default()
+        .fg(match presence {
+            Presence::Online => Color::Green,
+            Presence::Away => Color::Yellow,
+            Presence::Offline => Color::DarkGray,
+        })
+}
+
+pub fn encrypted_style() -> Style {
+    Style::default()
+        .fg(Color::Green)
+}
+
+pub fn typing_style() -> Style {
+    Style::default()
+        .fg(Color::Gray)
+        .add_modifier(Modifier::ITALIC)
+}
+
+/// Headings of the sections in the List pane
+pub fn section_style() -> Style {
+    Style::default().fg(Color::Gray).add_modifier(Modifier::BOLD)
+}
+
+pub fn transfer_style(status: TransferStatus) -> Style {
+    match status {
+        TransferStatus::Complete => Style::default().fg(Color::Green),
+        TransferStatus::Failed => Style::default().fg(Color::Red),
+        _ => Style::default().fg(Color::Cyan),
+    }
+}
+
+pub fn status_style(connected: bool) -> Style {
+    Style::default()
+        .bg(if connected {
+            Color::Green
+        } else {
+            Color::Red
+        })
+        .fg(Color::Black)
+}
+
+pub fn delivery_style(status: DeliveryStatus) -> Style {
+    Style::default()
+        .fg(match status {
+            DeliveryStatus::Pending | DeliveryStatus::Sent | DeliveryStatus::Stored => Color::DarkGray,
+            DeliveryStatus::Delivered => Color::Green,
+            DeliveryStatus::Failed => Color::Red,
+        })
 }
\ No newline at end of file
@@ -0,0 +1,595 @@
+use std::{
+    collections::HashMap,
+    fs::{ self, File, OpenOptions },
+    io::{ self, Read, Seek, SeekFrom, Write },
+    path::{ Path, PathBuf },
+};
+use sha2::{ Digest, Sha256 };
+use crate::{
+    constants,
+    db::models::TransferStatus,
+    net::protocol::{ FilePart, Message }
+};
+
+/// Bytes of file data in each `FilePart::Chunk`
+pub const CHUNK_SIZE: u64 = 32 * 1024;
+/// Chunks sent ahead of the last acknowledged one
+const WINDOW: u64 = 8;
+/// Name received files are saved under when the offered one is unusable
+const FALLBACK_NAME: &str = "download";
+
+/// Where received files are saved, `~/.oisg/downloads`
+pub fn downloads_dir() -> io::Result<PathBuf> {
+    let home_dir = home::home_dir()
+        .ok_or_else(|| io::Error::other("Not able to get home directory"))?;
+
+    Ok(home_dir.join(format!(".{}", constants::APP_NAME)).join(constants::DOWNLOADS_DIR_NAME))
+}
+
+/// State of a transfer as shown to the user
+#[derive(Debug, Clone, PartialEq)]
+pub struct Progress {
+    pub id: String,
+    /// the user we send to or receive from
+    pub user_id: String,
+    pub outgoing: bool,
+    pub name: String,
+    pub size: u64,
+    /// bytes the receiver has
+    pub done: u64,
+    pub sha256: String,
+    /// the file sent, or where the received one is saved
+    pub path: PathBuf,
+    pub status: TransferStatus,
+}
+
+fn chunk_count(size: u64) -> u64 {
+    size.div_ceil(CHUNK_SIZE)
+}
+
+fn bytes_of(chunks: u64, size: u64) -> u64 {
+    (chunks * CHUNK_SIZE).min(size)
+}
+
+struct Outgoing {
+    to: String,
+    file: File,
+    path: PathBuf,
+    name: String,
+    size: u64,
+    sha256: String,
+    /// chunks the receiver confirmed
+    acked: u64,
+    /// next chunk to send
+    next: u64,
+    status: TransferStatus,
+}
+
+impl Outgoing {
+    fn offer(&self) -> FilePart {
+        FilePart::Offer {
+            name: self.name.clone(),
+            size: self.size,
+            sha256: self.sha256.clone(),
+        }
+    }
+
+    fn progress(&self, id: &str) -> Progress {
+        Progress {
+            id: id.to_string(),
+            user_id: self.to.clone(),
+            outgoing: true,
+            name: self.name.clone(),
+            size: self.size,
+            done: bytes_of(self.acked, self.size),
+            sha256: self.sha256.clone(),
+            path: self.path.clone(),
+            status: self.status,
+        }
+    }
+
+    fn read_chunk(&mut self, index: u64) -> io::Result<Vec<u8>> {
+        let start = index * CHUNK_SIZE;
+        let mut data = vec![0; (self.size - start).min(CHUNK_SIZE) as usize];
+
+        self.file.seek(SeekFrom::Start(start))?;
+        self.file.read_exact(&mut data)?;
+
+        Ok(data)
+    }
+}
+
+struct Incoming {
+    from: String,
+    name: String,
+    size: u64,
+    sha256: String,
+    /// the partial download, renamed once complete
+    part_path: PathBuf,
+    file: Option<File>,
+    /// chunks written to the partial download
+    received: u64,
+    /// where the file is saved once complete
+    path: PathBuf,
+    status: TransferStatus,
+}
+
+impl Incoming {
+    fn progress(&self, id: &str) -> Progress {
+        Progress {
+            id: id.to_string(),
+            user_id: self.from.clone(),
+            outgoing: false,
+            name: self.name.clone(),
+            size: self.size,
+            done: bytes_of(self.received, self.size),
+            sha256: self.sha256.clone(),
+            path: self.path.clone(),
+            status: self.status,
+        }
+    }
+}
+
+/// `Transfers` sends files in chunks and receives the ones offered to us.
+/// Receivers confirm chunks as they write them, after a reconnect the
+/// sender offers the file again and continues from the last confirmed
+/// chunk. Like `crypto::Keyring` it does not touch the network, the
+/// returned messages are for the caller to send.
+pub struct Transfers {
+    user_id: String,
+    downloads: PathBuf,
+    outgoing: HashMap<String, Outgoing>,
+    incoming: HashMap<String, Incoming>,
+}
+
+impl Transfers {
+    pub fn new(user_id: &str, downloads: PathBuf) -> Self {
+        Transfers {
+            user_id: user_id.to_string(),
+            downloads,
+            outgoing: HashMap::new(),
+            incoming: HashMap::new(),
+        }
+    }
+
+    /// Offers the file at `path` to `to`, returns the offer to send
+    pub fn offer(&mut self, id: &str, to: &str, path: &Path) -> io::Result<(Message, Progress)> {
+        let mut file = File::open(path)?;
+        if !file.metadata()?.is_file() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a file", path.display())
+            ));
+        }
+
+        let sha256 = hash(&mut file)?;
+        let size = file.metadata()?.len();
+        let outgoing = Outgoing {
+            to: to.to_string(),
+            file,
+            path: path.to_path_buf(),
+            name: sanitize_name(&path.to_string_lossy()),
+            size,
+            sha256,
+            acked: 0,
+            next: 0,
+            status: TransferStatus::Sending,
+        };
+
+        let offer = self.file_message(id, to, outgoing.offer());
+        let progress = outgoing.progress(id);
+        self.outgoing.insert(id.to_string(), outgoing);
+
+        Ok((offer, progress))
+    }
+
+    /// Offers the unfinished transfers to `to` again, or to everyone if
+    /// `None`, they continue from the last chunk the receiver confirmed
+    pub fn resume(&mut self, to: Option<&str>) -> Vec<Message> {
+        let mut offers = vec![];
+
+        for (id, outgoing) in self.outgoing.iter_mut() {
+            if outgoing.status != TransferStatus::Sending || to.is_some_and(|to| to != outgoing.to) {
+                continue;
+            }
+
+            outgoing.next = outgoing.acked;
+            offers.push((id.clone(), outgoing.to.clone(), outgoing.offer()));
+        }
+
+        offers.into_iter()
+            .map(|(id, to, offer)| self.file_message(&id, &to, offer))
+            .collect()
+    }
+
+    /// Handles a `Message::File`, returns the messages to send back and
+    /// the transfer's new state
+    pub fn handle(&mut self, message: Message) -> (Vec<Message>, Option<Progress>) {
+        let (id, from, part) = match message {
+            Message::File { id, from, part, .. } => (id, from, part),
+            _ => return (vec![], None),
+        };
+
+        match part {
+            FilePart::Offer { name, size, sha256 } => self.offered(id, from, name, size, sha256),
+            FilePart::Chunk { index, data } => self.chunk(id, &from, index, &data),
+            FilePart::Ack { received } => self.acked(id, &from, received),
+            FilePart::Done { verified } => {
+                let outgoing = match self.outgoing.get_mut(&id) {
+                    Some(outgoing) if outgoing.to == from => outgoing,
+                    _ => return (vec![], None),
+                };
+
+                outgoing.acked = chunk_count(outgoing.size);
+                outgoing.status = match verified {
+                    true => TransferStatus::Complete,
+                    false => TransferStatus::Failed,
+                };
+                (vec![], Some(outgoing.progress(&id)))
+            },
+            // opened before they get here, see `crypto::Keyring::open_part`
+            FilePart::Sealed { .. } => (vec![], None),
+        }
+    }
+
+    fn offered(&mut self, id: String, from: String, name: String, size: u64, sha256: String) -> (Vec<Message>, Option<Progress>) {
+        if let Some(incoming) = self.incoming.get(&id) {
+            if incoming.from != from {
+                return (vec![], None);
+            }
+
+            // offered again after a reconnect
+            let reply = match incoming.status {
+                TransferStatus::Complete => FilePart::Done { verified: true },
+                TransferStatus::Failed => FilePart::Done { verified: false },
+                _ => FilePart::Ack { received: incoming.received },
+            };
+            let progress = incoming.progress(&id);
+            return (vec![self.file_message(&id, &from, reply)], Some(progress));
+        }
+
+        let mut incoming = Incoming {
+            from: from.clone(),
+            name: sanitize_name(&name),
+            size,
+            sha256,
+            part_path: self.downloads.join(format!("{}.part", sanitize_id(&id))),
+            file: None,
+            received: 0,
+            path: PathBuf::new(),
+            status: TransferStatus::Receiving,
+        };
+
+        let reply = match self.open_part(&mut incoming) {
+            Ok(()) if incoming.received == chunk_count(size) => self.finish(&mut incoming),
+            Ok(()) => FilePart::Ack { received: incoming.received },
+            Err(_) => {
+                incoming.status = TransferStatus::Failed;
+                FilePart::Done { verified: false }
+            },
+        };
+
+        let progress = incoming.progress(&id);
+        self.incoming.insert(id.clone(), incoming);
+        (vec![self.file_message(&id, &from, reply)], Some(progress))
+    }
+
+    /// Opens the partial download, keeping the whole chunks of an
+    /// earlier attempt
+    fn open_part(&self, incoming: &mut Incoming) -> io::Result<()> {
+        fs::create_dir_all(&self.downloads)?;
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&incoming.part_path)?;
+
+        let chunks = chunk_count(incoming.size);
+        incoming.received = (file.metadata()?.len() / CHUNK_SIZE).min(chunks);
+
+        let kept = bytes_of(incoming.received, incoming.size);
+        file.set_len(kept)?;
+        file.seek(SeekFrom::Start(kept))?;
+        incoming.file = Some(file);
+
+        Ok(())
+    }
+
+    fn chunk(&mut self, id: String, from: &str, index: u64, data: &[u8]) -> (Vec<Message>, Option<Progress>) {
+        let mut incoming = match self.incoming.remove(&id) {
+            Some(incoming) if incoming.from == from && incoming.status == TransferStatus::Receiving => incoming,
+            Some(incoming) => {
+                self.incoming.insert(id, incoming);
+                return (vec![], None);
+            },
+            None => return (vec![], None),
+        };
+
+        let reply = match index.cmp(&incoming.received) {
+            // sent again after a resume, tell the sender how far we are
+            std::cmp::Ordering::Less => Some(FilePart::Ack { received: incoming.received }),
+            std::cmp::Ordering::Greater => None,
+            std::cmp::Ordering::Equal => {
+                let start = index * CHUNK_SIZE;
+                let expected = (incoming.size - start.min(incoming.size)).min(CHUNK_SIZE);
+
+                let written = match (data.len() as u64 == expected, incoming.file.as_mut()) {
+                    (true, Some(file)) => file.write_all(data),
+                    _ => Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected chunk")),
+                };
+
+                match written {
+                    Ok(()) => {
+                        incoming.received += 1;
+                        match incoming.received == chunk_count(incoming.size) {
+                            true => Some(self.finish(&mut incoming)),
+                            false => Some(FilePart::Ack { received: incoming.received }),
+                        }
+                    },
+                    Err(_) => {
+                        incoming.status = TransferStatus::Failed;
+                        incoming.file = None;
+                        let _ = fs::remove_file(&incoming.part_path);
+                        Some(FilePart::Done { verified: false })
+                    },
+                }
+            },
+        };
+
+        let progress = incoming.progress(&id);
+        let out = reply.map(|reply| self.file_message(&id, from, reply)).into_iter().collect();
+        self.incoming.insert(id, incoming);
+        (out, Some(progress))
+    }
+
+    /// Checks the complete download and moves it next to the others
+    fn finish(&self, incoming: &mut Incoming) -> FilePart {
+        let verified = match incoming.file.take() {
+            Some(mut file) => file.flush()
+                .and_then(|_| file.seek(SeekFrom::Start(0)))
+                .and_then(|_| hash(&mut file))
+                .is_ok_and(|sha256| sha256 == incoming.sha256),
+            None => false,
+        };
+
+        let path = unique_path(&self.downloads, &incoming.name);
+        if verified && fs::rename(&incoming.part_path, &path).is_ok() {
+            incoming.path = path;
+            incoming.status = TransferStatus::Complete;
+            FilePart::Done { verified: true }
+        } else {
+            let _ = fs::remove_file(&incoming.part_path);
+            incoming.status = TransferStatus::Failed;
+            FilePart::Done { verified: false }
+        }
+    }
+
+    fn acked(&mut self, id: String, from: &str, received: u64) -> (Vec<Message>, Option<Progress>) {
+        let outgoing = match self.outgoing.get_mut(&id) {
+            Some(outgoing) if outgoing.to == from && outgoing.status == TransferStatus::Sending => outgoing,
+            _ => return (vec![], None),
+        };
+
+        let chunks = chunk_count(outgoing.size);
+        outgoing.acked = outgoing.acked.max(received.min(chunks));
+        outgoing.next = outgoing.next.max(outgoing.acked);
+
+        let mut parts = vec![];
+        while outgoing.next < chunks && outgoing.next < outgoing.acked + WINDOW {
+            match outgoing.read_chunk(outgoing.next) {
+                Ok(data) => {
+                    parts.push(FilePart::Chunk { index: outgoing.next, data });
+                    outgoing.next += 1;
+                },
+                Err(_) => {
+                    outgoing.status = TransferStatus::Failed;
+                    break;
+                },
+            }
+        }
+
+        let progress = outgoing.progress(&id);
+        let to = outgoing.to.clone();
+        let out = parts.into_iter()
+            .map(|part| self.file_message(&id, &to, part))
+            .collect();
+        (out, Some(progress))
+    }
+
+    fn file_message(&self, id: &str, to: &str, part: FilePart) -> Message {
+        Message::File {
+            id: id.to_string(),
+            from: self.user_id.clone(),
+            to: to.to_string(),
+            part,
+        }
+    }
+}
+
+/// SHA-256 of everything left in `reader`, as hex
+fn hash(reader: &mut impl Read) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(reader, &mut hasher)?;
+
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// File name part of a name we were sent, never a path out of the downloads
+fn sanitize_name(name: &str) -> String {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default().trim();
+
+    match name.is_empty() || name == "." || name == ".." {
+        true => FALLBACK_NAME.to_string(),
+        false => name.to_string(),
+    }
+}
+
+fn sanitize_id(id: &str) -> String {
+    id.chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+        .collect()
+}
+
+/// `dir/name`, or `dir/name (n)` if that exists already
+fn unique_path(dir: &Path, name: &str) -> PathBuf {
+    let path = dir.join(name);
+    if !path.exists() {
+        return path;
+    }
+
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{}", extension)),
+        _ => (name, String::new()),
+    };
+
+    (1..)
+        .map(|n| dir.join(format!("{} ({}){}", stem, n, extension)))
+        .find(|path| !path.exists())
+        .unwrap_or(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fresh directory under the temp dir
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("oisg-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write_file(dir: &Path, name: &str, size: usize) -> PathBuf {
+        let path = dir.join(name);
+        let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+        fs::write(&path, data).unwrap();
+        path
+    }
+
+    /// Delivers messages between alice and bob until nothing is left to
+    /// send, `deliver` can drop them. Returns the last progress of each.
+    fn pump(
+        alice: &mut Transfers,
+        bob: &mut Transfers,
+        first: Message,
+        mut deliver: impl FnMut(&Message) -> bool
+    ) -> (Option<Progress>, Option<Progress>) {
+        let (mut alice_progress, mut bob_progress) = (None, None);
+        let mut queue = vec![first];
+
+        while !queue.is_empty() {
+            let message = queue.remove(0);
+            if !deliver(&message) {
+                continue;
+            }
+
+            let to_bob = matches!(&message, Message::File { to, .. } if to == "bob");
+            let (out, progress) = match to_bob {
+                true => bob.handle(message),
+                false => alice.handle(message),
+            };
+            queue.extend(out);
+            match to_bob {
+                true => bob_progress = progress.or(bob_progress),
+                false => alice_progress = progress.or(alice_progress),
+            }
+        }
+
+        (alice_progress, bob_progress)
+    }
+
+    #[test]
+    fn test_send_file() {
+        let dir = temp_dir("transfer-send");
+        let path = write_file(&dir, "notes.txt", 5 * CHUNK_SIZE as usize / 2);
+        let mut alice = Transfers::new("alice", dir.join("alice"));
+        let mut bob = Transfers::new("bob", dir.join("bob"));
+
+        let (offer, progress) = alice.offer("a1", "bob", &path).unwrap();
+        assert_eq!(progress.status, TransferStatus::Sending);
+        assert_eq!(progress.done, 0);
+
+        let (sent, received) = pump(&mut alice, &mut bob, offer, |_| true);
+        let (sent, received) = (sent.unwrap(), received.unwrap());
+        assert_eq!(sent.status, TransferStatus::Complete);
+        assert_eq!(received.status, TransferStatus::Complete);
+        assert_eq!(received.done, received.size);
+        assert_eq!(received.path, dir.join("bob").join("notes.txt"));
+        assert_eq!(fs::read(&received.path).unwrap(), fs::read(&path).unwrap());
+
+        // the same name again does not overwrite the first download
+        let (offer, _) = alice.offer("a2", "bob", &path).unwrap();
+        let (_, received) = pump(&mut alice, &mut bob, offer, |_| true);
+        assert_eq!(received.unwrap().path, dir.join("bob").join("notes (1).txt"));
+    }
+
+    #[test]
+    fn test_resume_after_reconnect() {
+        let dir = temp_dir("transfer-resume");
+        let path = write_file(&dir, "photo.jpg", 20 * CHUNK_SIZE as usize + 10);
+        let mut alice = Transfers::new("alice", dir.join("alice"));
+        let mut bob = Transfers::new("bob", dir.join("bob"));
+
+        // the connection drops after bob wrote a few chunks
+        let (offer, _) = alice.offer("a1", "bob", &path).unwrap();
+        let mut delivered = 0;
+        let (sent, received) = pump(&mut alice, &mut bob, offer, |_| {
+            delivered += 1;
+            delivered <= 6
+        });
+        assert_eq!(sent.unwrap().status, TransferStatus::Sending);
+        let received = received.unwrap();
+        assert!(received.done > 0 && received.done < received.size);
+
+        // bob restarted in between, the partial download is picked up again
+        let mut bob = Transfers::new("bob", dir.join("bob"));
+        let mut chunks = vec![];
+        let offers = alice.resume(Some("bob"));
+        assert_eq!(offers.len(), 1);
+        let (sent, received) = pump(&mut alice, &mut bob, offers[0].clone(), |message| {
+            if let Message::File { part: FilePart::Chunk { index, .. }, .. } = message {
+                chunks.push(*index);
+            }
+            true
+        });
+
+        assert_eq!(sent.unwrap().status, TransferStatus::Complete);
+        let received = received.unwrap();
+        assert_eq!(fs::read(&received.path).unwrap(), fs::read(&path).unwrap());
+        assert!(chunks[0] > 0, "resumed from chunk {}", chunks[0]);
+        assert!(alice.resume(None).is_empty());
+    }
+
+    #[test]
+    fn test_corrupted_file() {
+        let dir = temp_dir("transfer-corrupt");
+        let path = write_file(&dir, "data.bin", 3 * CHUNK_SIZE as usize);
+        let mut alice = Transfers::new("alice", dir.join("alice"));
+        let mut bob = Transfers::new("bob", dir.join("bob"));
+
+        let (offer, _) = alice.offer("a1", "bob", &path).unwrap();
+        let offer = match offer {
+            Message::File { id, from, to, part: FilePart::Offer { name, size, .. } } => Message::File {
+                id, from, to,
+                part: FilePart::Offer { name, size, sha256: "00".repeat(32) },
+            },
+            other => panic!("expected offer, got {:?}", other),
+        };
+
+        let (sent, received) = pump(&mut alice, &mut bob, offer, |_| true);
+        assert_eq!(sent.unwrap().status, TransferStatus::Failed);
+        assert_eq!(received.unwrap().status, TransferStatus::Failed);
+        assert!(!dir.join("bob").join("data.bin").exists());
+    }
+
+    #[test]
+    fn test_sanitize_name() {
+        assert_eq!(sanitize_name("/home/alice/notes.txt"), "notes.txt");
+        assert_eq!(sanitize_name("..\\..\\evil.exe"), "evil.exe");
+        assert_eq!(sanitize_name("../"), FALLBACK_NAME);
+        assert_eq!(sanitize_name(".."), FALLBACK_NAME);
+        assert_eq!(sanitize_id("../../a-1_b"), "a-1_b");
+    }
+}